@@ -0,0 +1,57 @@
+use corrosion::client::read::{decode_tokens_into, deserialize_token_set};
+use divan::Bencher;
+use quilkin_types::TokenSet;
+
+#[global_allocator]
+static ALLOC: divan::AllocProfiler = divan::AllocProfiler::system();
+
+fn main() {
+    divan::main();
+}
+
+const ROWS: usize = 1000;
+
+/// Encoded token sets, as they are read from the `tokens` column
+fn rows() -> Vec<String> {
+    (0..ROWS as u32)
+        .map(|i| {
+            let ts: TokenSet = (0..(i % 8) + 1)
+                .map(|t| (i * 8 + t).to_le_bytes()[..2 + t as usize % 3].to_vec())
+                .collect();
+
+            match corrosion::client::write::try_token_set_to_sql(&ts).unwrap() {
+                corrosion::api::SqliteParam::Text(text) => text.to_string(),
+                other => panic!("unexpected token set encoding {other:?}"),
+            }
+        })
+        .collect()
+}
+
+/// Decoding each row into a new token set
+#[divan::bench]
+fn allocating(b: Bencher<'_, '_>) {
+    let rows = rows();
+
+    b.counter(divan::counter::ItemsCount::new(ROWS))
+        .bench_local(|| {
+            for row in &rows {
+                divan::black_box(deserialize_token_set(row).unwrap());
+            }
+        });
+}
+
+/// Decoding each row into the same token set and scratch buffer
+#[divan::bench]
+fn reused(b: Bencher<'_, '_>) {
+    let rows = rows();
+    let mut scratch = Vec::new();
+    let mut ts = TokenSet::default();
+
+    b.counter(divan::counter::ItemsCount::new(ROWS))
+        .bench_local(|| {
+            for row in &rows {
+                decode_tokens_into(row, &mut scratch, &mut ts).unwrap();
+                divan::black_box(&ts);
+            }
+        });
+}
@@ -0,0 +1,47 @@
+use corrosion::client::read::parse_endpoint;
+use divan::Bencher;
+
+fn main() {
+    divan::main();
+}
+
+const ENDPOINTS: usize = 10_000;
+
+/// A deterministic mix of IPv4, IPv6 and hostname endpoints, in the form they
+/// are written to the database
+fn endpoints(kind: &str) -> Vec<String> {
+    (0..ENDPOINTS as u32)
+        .map(|i| {
+            let port = 1024 + (i % 60000);
+            let kind = match kind {
+                "mixed" => ["ipv4", "ipv6", "name"][i as usize % 3],
+                kind => kind,
+            };
+
+            match kind {
+                "ipv4" => format!(
+                    "|{}:{port}",
+                    std::net::Ipv4Addr::from_bits(i.wrapping_mul(2654435761))
+                ),
+                "ipv6" => format!(
+                    "|{}:{port}",
+                    std::net::Ipv6Addr::from_bits(((i as u128) << 64) | 0xf0cc)
+                ),
+                "name" => format!("game-{i}.servers.example.com:{port}"),
+                kind => unreachable!("unknown endpoint kind {kind}"),
+            }
+        })
+        .collect()
+}
+
+#[divan::bench(args = ["ipv4", "ipv6", "name", "mixed"])]
+fn parse(b: Bencher<'_, '_>, kind: &str) {
+    let endpoints = endpoints(kind);
+
+    b.counter(divan::counter::ItemsCount::new(ENDPOINTS))
+        .bench_local(|| {
+            for ep in &endpoints {
+                divan::black_box(parse_endpoint(divan::black_box(ep)).unwrap());
+            }
+        });
+}
@@ -0,0 +1,75 @@
+use corrosion::client::write::{self, Server};
+use divan::Bencher;
+use quilkin_types::{Endpoint, IcaoCode, TokenSet};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+
+fn main() {
+    divan::main();
+}
+
+const SERVERS: usize = 1000;
+const PEER: SocketAddrV6 = SocketAddrV6::new(Ipv6Addr::from_bits(0xaaffeeff), 8999, 0, 0);
+
+/// The same rows upserted by `prep` in the db tests
+fn rows() -> Vec<(Endpoint, IcaoCode, TokenSet)> {
+    (0..SERVERS as u32)
+        .map(|i| {
+            (
+                Endpoint::new(Ipv4Addr::from_bits(i).into(), i as u16),
+                IcaoCode::new_testing(*b"BOOP"),
+                [i.to_ne_bytes()].into(),
+            )
+        })
+        .collect()
+}
+
+fn connection() -> rusqlite::Connection {
+    let conn = rusqlite::Connection::open_in_memory().unwrap();
+    conn.execute_batch(corrosion::schema::SCHEMA).unwrap();
+    conn
+}
+
+fn execute(mut conn: rusqlite::Connection, statements: &[corrosion::api::Statement]) {
+    let tx = conn.transaction().unwrap();
+    write::execute_all_conn(&tx, statements).unwrap();
+    tx.commit().unwrap();
+}
+
+/// Upserting each server individually
+#[divan::bench]
+fn looped(b: Bencher<'_, '_>) {
+    let rows = rows();
+
+    b.counter(divan::counter::ItemsCount::new(SERVERS))
+        .with_inputs(connection)
+        .bench_local_values(|conn| {
+            let mut statements = write::Statements::<0>::new();
+            let mut s = Server::for_peer(PEER, &mut statements);
+            for (endpoint, icao, tokens) in &rows {
+                s.upsert(endpoint, *icao, tokens).unwrap();
+            }
+
+            execute(conn, &statements);
+        });
+}
+
+/// Upserting all of the servers with [`Server::upsert_many`]
+#[divan::bench]
+fn many(b: Bencher<'_, '_>) {
+    let rows = rows();
+    let rows: Vec<_> = rows
+        .iter()
+        .map(|(endpoint, icao, tokens)| (endpoint.clone(), *icao, tokens))
+        .collect();
+
+    b.counter(divan::counter::ItemsCount::new(SERVERS))
+        .with_inputs(connection)
+        .bench_local_values(|conn| {
+            let mut statements = write::Statements::<0>::new();
+            Server::for_peer(PEER, &mut statements)
+                .upsert_many(&rows)
+                .unwrap();
+
+            execute(conn, &statements);
+        });
+}
@@ -1,15 +1,24 @@
 //! Deserialization of changes sent from a corrosion agent
 
-pub use corro_api_types::{QueryEvent, SqliteValue};
-use eyre::ContextCompat as _;
+pub use corro_api_types::{ChangeType, QueryEvent, SqliteValue};
+use eyre::{ContextCompat as _, WrapErr as _};
 use quilkin_types::{AddressKind, Endpoint, IcaoCode, TokenSet};
 use serde::{
     Deserialize,
     de::{self, SeqAccess},
 };
-use std::{collections::BTreeSet, fmt, str::FromStr};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 
 pub trait FromSqlValue: Sized {
+    /// The number of columns read by [`Self::from_sql`], used to read tuples
+    /// from successive columns
+    const COLUMNS: usize;
+
     fn from_sql(values: &[SqliteValue]) -> eyre::Result<Self>;
 }
 
@@ -20,50 +29,615 @@ pub struct ServerRow {
     pub tokens: TokenSet,
 }
 
+impl ServerRow {
+    /// Returns true if the server accepts the specified token
+    #[inline]
+    pub fn accepts_token(&self, token: &[u8]) -> bool {
+        self.tokens.0.contains(token)
+    }
+
+    /// Returns true if the server accepts at least one of the specified tokens
+    #[inline]
+    pub fn accepts_any(&self, tokens: &[&[u8]]) -> bool {
+        tokens.iter().any(|tok| self.accepts_token(tok))
+    }
+}
+
+/// A decoded event from a [`ServerSubscription`]
+#[derive(Debug, PartialEq)]
+pub enum ServerEvent {
+    /// A server was added, either by the initial query or a later change
+    Insert(ServerRow),
+    /// The ICAO or tokens of an existing server changed
+    Update(ServerRow),
+    /// A server was removed
+    Delete(ServerRow),
+    /// All of the servers that existed when the subscription was created have
+    /// been received
+    EndOfQuery,
+}
+
+/// The outcome of [`ServerSubscription::resume`]
+#[derive(Debug, PartialEq)]
+pub enum Resumed {
+    /// The events received while the subscription was paused, in the order
+    /// they were received
+    Events(Vec<ServerEvent>),
+    /// More events were received while the subscription was paused than
+    /// could be buffered, so they were discarded
+    ///
+    /// The caller needs to resubscribe and pass the rows of the initial query
+    /// to [`ServerSubscription::resync`] to catch up
+    Resubscribe,
+}
+
+/// The events buffered while a [`ServerSubscription`] is paused
+struct Paused {
+    events: Vec<QueryEvent>,
+    capacity: usize,
+    overflowed: bool,
+}
+
+/// A typed wrapper around a subscription to `SELECT endpoint,icao,tokens FROM servers`
+///
+/// Decodes each [`QueryEvent`] into a [`ServerEvent`], and tracks the current
+/// set of servers
+#[derive(Default)]
+pub struct ServerSubscription {
+    servers: BTreeMap<Endpoint, (IcaoCode, TokenSet)>,
+    dedup: bool,
+    paused: Option<Paused>,
+}
+
+impl ServerSubscription {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// If enabled, changes that don't alter the tracked set of servers, eg.
+    /// a duplicate insert with the same ICAO and tokens, are suppressed
+    pub fn dedup_changes(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// The servers as of the last processed event
+    #[inline]
+    pub fn servers(&self) -> &BTreeMap<Endpoint, (IcaoCode, TokenSet)> {
+        &self.servers
+    }
+
+    /// Pauses processing, events passed to [`Self::process`] are buffered,
+    /// up to `capacity` events, until [`Self::resume`] is called
+    pub fn pause(&mut self, capacity: usize) {
+        if self.paused.is_none() {
+            self.paused = Some(Paused {
+                events: Vec::new(),
+                capacity,
+                overflowed: false,
+            });
+        }
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// Resumes processing, applying the events buffered while paused
+    ///
+    /// If an event fails to be processed the remaining buffered events are
+    /// discarded
+    pub fn resume(&mut self) -> eyre::Result<Resumed> {
+        let Some(paused) = self.paused.take() else {
+            return Ok(Resumed::Events(Vec::new()));
+        };
+
+        if paused.overflowed {
+            return Ok(Resumed::Resubscribe);
+        }
+
+        let mut events = Vec::with_capacity(paused.events.len());
+        for event in paused.events {
+            events.extend(self.process(event)?);
+        }
+        Ok(Resumed::Events(events))
+    }
+
+    /// Replaces the tracked servers with the rows of a new subscription's
+    /// initial query, returning the changes between the two
+    ///
+    /// This is used to catch up after [`Resumed::Resubscribe`]
+    pub fn resync(&mut self, snapshot: impl IntoIterator<Item = ServerRow>) -> Vec<ServerEvent> {
+        let mut previous = std::mem::take(&mut self.servers);
+        let mut events = Vec::new();
+
+        for row in snapshot {
+            let current = (row.icao, row.tokens.clone());
+            let prev = previous.remove(&row.endpoint);
+            self.servers.insert(row.endpoint.clone(), current.clone());
+            match prev {
+                Some(prev) if prev == current => {}
+                Some(_) => events.push(ServerEvent::Update(row)),
+                None => events.push(ServerEvent::Insert(row)),
+            }
+        }
+
+        events.extend(previous.into_iter().map(|(endpoint, (icao, tokens))| {
+            ServerEvent::Delete(ServerRow {
+                endpoint,
+                icao,
+                tokens,
+            })
+        }));
+        events
+    }
+
+    /// Processes the next event from the subscription, returning `None` if
+    /// the event is not relevant to the consumer, was suppressed as a
+    /// duplicate, or was buffered because the subscription is paused
+    pub fn process(&mut self, event: QueryEvent) -> eyre::Result<Option<ServerEvent>> {
+        if let Some(paused) = &mut self.paused {
+            if paused.overflowed {
+                // Nothing buffered will be used, the caller needs to resubscribe
+            } else if paused.events.len() < paused.capacity {
+                paused.events.push(event);
+            } else {
+                paused.overflowed = true;
+                paused.events = Vec::new();
+            }
+            return Ok(None);
+        }
+
+        let (kind, row) = match event {
+            QueryEvent::Columns(_) => return Ok(None),
+            QueryEvent::EndOfQuery { .. } => return Ok(Some(ServerEvent::EndOfQuery)),
+            QueryEvent::Error(error) => eyre::bail!("subscription error: {error}"),
+            QueryEvent::Row(_, row) => (ChangeType::Insert, row),
+            QueryEvent::Change(kind, _, row, _) => (kind, row),
+        };
+
+        let row = ServerRow::from_sql(&row)?;
+
+        let changed = if let ChangeType::Delete = kind {
+            self.servers.remove(&row.endpoint).is_some()
+        } else {
+            let unchanged = self
+                .servers
+                .get(&row.endpoint)
+                .is_some_and(|(icao, tokens)| *icao == row.icao && *tokens == row.tokens);
+            if !unchanged {
+                self.servers
+                    .insert(row.endpoint.clone(), (row.icao, row.tokens.clone()));
+            }
+            !unchanged
+        };
+
+        if self.dedup && !changed {
+            return Ok(None);
+        }
+
+        Ok(Some(match kind {
+            ChangeType::Insert => ServerEvent::Insert(row),
+            ChangeType::Update => ServerEvent::Update(row),
+            ChangeType::Delete => ServerEvent::Delete(row),
+        }))
+    }
+}
+
 pub fn deserialize_token_set(s: &str) -> eyre::Result<TokenSet> {
-    let mut ts = BTreeSet::default();
+    let mut ts = TokenSet::default();
+    decode_tokens_into(s, &mut Vec::new(), &mut ts)?;
+    Ok(ts)
+}
 
-    let mut tokens = data_encoding::BASE64_NOPAD.decode(s.as_bytes())?;
+/// Deserializes a token set stored as a blob, see [`super::write::ToSqlParam::to_sql_blob`]
+pub fn deserialize_token_blob(blob: &[u8]) -> eyre::Result<TokenSet> {
+    let mut ts = TokenSet::default();
+    decode_token_blob_into(blob, &mut ts)?;
+    Ok(ts)
+}
 
-    if tokens.is_empty() {
-        return Ok(TokenSet(ts));
+/// Reads a `tokens` column, which is base64 text if it was written for a
+/// corrosion agent, or a blob if it was written directly
+fn token_set_value(value: &SqliteValue) -> eyre::Result<TokenSet> {
+    match value {
+        SqliteValue::Null => Ok(TokenSet::default()),
+        SqliteValue::Text(text) => deserialize_token_set(text),
+        SqliteValue::Blob(blob) => deserialize_token_blob(blob),
+        other => eyre::bail!("column 'tokens' is not a string or blob: {other:?}"),
     }
+}
 
-    if tokens[0] & 0x80u8 != 0 {
-        let len = (tokens[0] & !0x80) as usize;
-        for tok in tokens[1..].chunks_exact(len) {
-            ts.insert(tok.to_vec());
+/// Reads a `tokens` column directly from SQLite, like [`token_set_value`]
+fn token_set_ref(value: rusqlite::types::ValueRef<'_>) -> eyre::Result<TokenSet> {
+    use rusqlite::types::ValueRef;
+
+    match value {
+        ValueRef::Null => Ok(TokenSet::default()),
+        ValueRef::Text(text) => deserialize_token_set(std::str::from_utf8(text)?),
+        ValueRef::Blob(blob) => deserialize_token_blob(blob),
+        other => eyre::bail!("column 'tokens' is not a string or blob: {other:?}"),
+    }
+}
+
+/// Decodes a token set into `out`, replacing its tokens, without allocating
+/// other than for the tokens themselves, eg. for subscriptions that decode
+/// many rows
+///
+/// The blob is decoded into `scratch`, and the allocations of the tokens that
+/// were in `out` are reused for the decoded tokens, so both should be kept
+/// between calls. If decoding fails, `out` contains the tokens that were
+/// decoded before the error
+pub fn decode_tokens_into(s: &str, scratch: &mut Vec<u8>, out: &mut TokenSet) -> eyre::Result<()> {
+    let encoding = &data_encoding::BASE64_NOPAD;
+    scratch.resize(encoding.decode_len(s.len())?, 0);
+    let len = encoding
+        .decode_mut(s.as_bytes(), scratch)
+        .map_err(|partial| partial.error)?;
+    decode_token_blob_into(&scratch[..len], out)
+}
+
+/// Decodes a token set stored as a blob into `out`, like [`decode_tokens_into`]
+pub fn decode_token_blob_into(tokens: &[u8], out: &mut TokenSet) -> eyre::Result<()> {
+    use super::write::{MAX_ENCODED_TOKENS, MAX_TOKEN_LEN, VARINT_FORMAT};
+
+    let mut spare = std::mem::take(&mut out.0);
+    let mut insert = |tok: &[u8]| {
+        let mut v = spare.pop_first().unwrap_or_default();
+        v.clear();
+        v.extend_from_slice(tok);
+        out.0.insert(v);
+    };
+
+    let Some((&first, mut toks)) = tokens.split_first() else {
+        return Ok(());
+    };
+
+    if first & 0x80u8 != 0 && first != VARINT_FORMAT {
+        let len = (first & !0x80) as usize;
+        for tok in toks.chunks_exact(len) {
+            insert(tok);
         }
-    } else if tokens[0] > 1 {
-        let mut toks = &tokens[1..];
-        for _ in 0..tokens[0] as usize {
-            let len = toks[0] as usize;
+    } else if first != 1 {
+        let varint_lens = first == VARINT_FORMAT;
+
+        let count = if first == 0 || varint_lens {
+            let count = read_varint(&mut toks).context("token count is invalid")?;
             eyre::ensure!(
-                len <= toks.len() - 1,
+                count <= MAX_ENCODED_TOKENS,
+                "token count {count} is too large"
+            );
+            count
+        } else {
+            first as usize
+        };
+
+        for _ in 0..count {
+            eyre::ensure!(
+                !toks.is_empty(),
+                "token set has fewer tokens than its count"
+            );
+
+            let len = if varint_lens {
+                let len = read_varint(&mut toks).context("token length is invalid")?;
+                eyre::ensure!(len <= MAX_TOKEN_LEN, "token length {len} is too large");
+                len
+            } else {
+                let (&len, rest) = toks.split_first().unwrap();
+                toks = rest;
+                len as usize
+            };
+
+            eyre::ensure!(
+                len <= toks.len(),
                 "token length {len} is longer than remaining binary slice"
             );
 
-            ts.insert(toks[1..1 + len].to_vec());
-            toks = &toks[1 + len..];
+            insert(&toks[..len]);
+            toks = &toks[len..];
         }
     } else {
-        tokens.remove(0);
-        ts.insert(tokens);
+        insert(toks);
     }
 
-    Ok(TokenSet(ts))
+    Ok(())
 }
 
+/// Reads a LEB128 varint, advancing past it
+#[inline]
+fn read_varint(buf: &mut &[u8]) -> eyre::Result<usize> {
+    let mut value = 0usize;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = buf.split_first().context("varint is truncated")?;
+        eyre::ensure!(shift < usize::BITS, "varint is too large");
+        value |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        *buf = rest;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Parses an endpoint written by [`crate::client::write`], ie. `|<ip>:<port>`
+/// or `<hostname>:<port>`
+///
+/// This is called for every row and change of a subscription
 #[inline]
 pub fn parse_endpoint(addr: &str) -> eyre::Result<Endpoint> {
     let (addr, port) = addr.rsplit_once(':').context("missing ':'")?;
     let port = port.parse()?;
-    if let Some(ip) = addr.strip_prefix('|') {
-        let ip = ip.parse()?;
-        Ok(Endpoint::new(AddressKind::Ip(ip), port))
+    let Some(ip) = addr.strip_prefix('|') else {
+        return Ok(Endpoint::new(AddressKind::Name(addr.to_owned()), port));
+    };
+
+    if let Some((ip, scope_id)) = ip.split_once('%') {
+        let scope_id = scope_id
+            .parse()
+            .wrap_err_with(|| format!("invalid scope id '{scope_id}'"))?;
+        return Ok(Endpoint::new(
+            AddressKind::scoped(ip.parse()?, scope_id),
+            port,
+        ));
+    }
+
+    // Only IPv6 addresses contain a ':', so unlike parsing an `IpAddr`, which
+    // tries IPv4 first, only the one parser that can succeed is run
+    let parsed = if ip.contains(':') {
+        ip.parse::<Ipv6Addr>().map(IpAddr::V6)
     } else {
-        Ok(Endpoint::new(AddressKind::Name(addr.to_owned()), port))
+        ip.parse::<Ipv4Addr>().map(IpAddr::V4)
+    };
+
+    let ip = match parsed {
+        Ok(ip) => ip,
+        // Parse it again as an `IpAddr` so the error is the same as it would
+        // be for any other IP
+        Err(_) => ip.parse::<IpAddr>()?,
+    };
+    Ok(Endpoint::new(AddressKind::Ip(ip), port))
+}
+
+/// Parses an endpoint written by a human, eg. in a config file, as well as the
+/// form accepted by [`parse_endpoint`]
+///
+/// IPv6 addresses must be enclosed in brackets, ie. `[<ipv6>]:<port>`, IPv4
+/// addresses are `<ipv4>:<port>`, and anything else is treated as a hostname
+pub fn parse_endpoint_lenient(addr: &str) -> eyre::Result<Endpoint> {
+    if addr.starts_with('|') {
+        return parse_endpoint(addr).wrap_err_with(|| format!("invalid endpoint '{addr}'"));
+    }
+
+    let parse_port = |port: &str| {
+        port.parse::<u16>()
+            .wrap_err_with(|| format!("invalid port '{port}' in endpoint '{addr}'"))
+    };
+
+    if let Some(rest) = addr.strip_prefix('[') {
+        let (ip, port) = rest.split_once("]:").with_context(|| {
+            format!("missing ']:<port>' after the IPv6 address in endpoint '{addr}'")
+        })?;
+        let (ip, scope_id) = match ip.split_once('%') {
+            Some((ip, scope_id)) => {
+                let scope_id = scope_id.parse::<u32>().wrap_err_with(|| {
+                    format!("invalid scope id '{scope_id}' in endpoint '{addr}'")
+                })?;
+                (ip, scope_id)
+            }
+            None => (ip, 0),
+        };
+        let ip = ip
+            .parse::<Ipv6Addr>()
+            .wrap_err_with(|| format!("invalid IPv6 address '{ip}' in endpoint '{addr}'"))?;
+        return Ok(Endpoint::new(
+            AddressKind::scoped(ip, scope_id),
+            parse_port(port)?,
+        ));
+    }
+
+    let (host, port) = addr
+        .rsplit_once(':')
+        .with_context(|| format!("missing ':<port>' in endpoint '{addr}'"))?;
+    eyre::ensure!(
+        !host.contains(':'),
+        "the IPv6 address in endpoint '{addr}' must be enclosed in brackets, eg. '[{host}]:{port}'"
+    );
+    eyre::ensure!(!host.is_empty(), "missing host in endpoint '{addr}'");
+    let port = parse_port(port)?;
+
+    let address = match host.parse::<Ipv4Addr>() {
+        Ok(ip) => AddressKind::Ip(ip.into()),
+        Err(_) => AddressKind::Name(host.to_owned()),
+    };
+    Ok(Endpoint::new(address, port))
+}
+
+/// The set of servers contributed by a datacenter, ie. its `dc.servers` column
+///
+/// The column is a JSONB object keyed by the endpoint of each server, so it
+/// needs to be selected as `json(servers)` to be read
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContributedServers(pub BTreeSet<Endpoint>);
+
+impl FromSqlValue for ContributedServers {
+    const COLUMNS: usize = 1;
+
+    fn from_sql(values: &[SqliteValue]) -> eyre::Result<Self> {
+        let servers = match values.first().context("missing column 'servers'")? {
+            // A datacenter that was never given any servers
+            SqliteValue::Null => return Ok(Self::default()),
+            SqliteValue::Text(json) => json,
+            other => eyre::bail!("column 'servers' is not a JSON string: {other:?}"),
+        };
+
+        let servers: BTreeMap<String, de::IgnoredAny> =
+            serde_json::from_str(servers).wrap_err("column 'servers' is not a JSON object")?;
+        servers
+            .keys()
+            .map(|server| {
+                // Keys written before servers were keyed by their compact
+                // endpoint are only an address, which either fails to parse,
+                // or for IPv6 addresses, parses as a hostname with a port
+                eyre::ensure!(
+                    server.starts_with('|') || server.matches(':').count() == 1,
+                    "column 'servers' has a legacy key '{server}' without a port, see `Datacenter::migrate_server_keys`"
+                );
+                parse_endpoint(server).wrap_err_with(|| {
+                    format!("column 'servers' has an invalid endpoint '{server}'")
+                })
+            })
+            .collect::<eyre::Result<_>>()
+            .map(Self)
+    }
+}
+
+/// The line format used by [`export_ndjson`]
+///
+/// The ICAO and tokens use the same encodings as the protocol types, but the
+/// endpoint is written in its `<address>:<port>` display form, as the JSON
+/// encoding of [`AddressKind`] doesn't distinguish IPs from names when it is
+/// read back
+#[derive(serde::Serialize)]
+struct ExportRow<'s> {
+    #[serde(serialize_with = "serialize_display")]
+    endpoint: &'s Endpoint,
+    icao: IcaoCode,
+    tokens: &'s TokenSet,
+}
+
+#[inline]
+fn serialize_display<S: serde::Serializer>(
+    endpoint: &&Endpoint,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(endpoint)
+}
+
+/// Writes every row of the `servers` table to `writer` as newline delimited
+/// JSON, returning the number of rows written
+///
+/// Rows are streamed one at a time, so the table is never loaded into memory
+/// all at once
+pub fn export_ndjson(
+    conn: &rusqlite::Connection,
+    mut writer: impl std::io::Write,
+) -> eyre::Result<usize> {
+    let mut statement = conn.prepare("SELECT endpoint,icao,tokens FROM servers ORDER BY rowid")?;
+    let mut rows = statement.query([])?;
+    let mut count = 0;
+
+    while let Some(row) = rows.next()? {
+        let endpoint = parse_endpoint(&row.get::<_, String>(0)?)?;
+        let icao = row.get::<_, String>(1)?.parse()?;
+        let tokens = token_set_ref(row.get_ref(2)?)?;
+
+        serde_json::to_writer(
+            &mut writer,
+            &ExportRow {
+                endpoint: &endpoint,
+                icao,
+                tokens: &tokens,
+            },
+        )?;
+        writer.write_all(b"\n")?;
+        count += 1;
+    }
+
+    writer.flush()?;
+    Ok(count)
+}
+
+/// The number of peers that contribute the server, `0` if the server doesn't
+/// exist
+///
+/// The contributors are counted by SQLite, so the JSONB set doesn't need to be
+/// read and parsed
+pub fn contributor_count(conn: &rusqlite::Connection, endpoint: &Endpoint) -> eyre::Result<usize> {
+    use crate::client::write::ToSqlParam as _;
+    use rusqlite::OptionalExtension as _;
+
+    let count = conn
+        .query_row(
+            "SELECT (SELECT count(*) FROM json_each(contributors)) FROM servers WHERE endpoint = ?",
+            [endpoint.to_sql()],
+            |row| row.get::<_, usize>(0),
+        )
+        .optional()?;
+    Ok(count.unwrap_or_default())
+}
+
+/// Registers the `has_token_prefix(tokens, prefix)` SQLite function on `conn`,
+/// which is true if any token in a `tokens` column starts with `prefix`
+///
+/// Tokens are stored in an encoding SQLite can't see into, so this is the
+/// only way to filter servers by their tokens in a query, eg.
+/// `SELECT endpoint FROM servers WHERE has_token_prefix(tokens, ?)`
+pub fn register_has_token_prefix(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    use rusqlite::functions::FunctionFlags;
+
+    conn.create_scalar_function(
+        "has_token_prefix",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let prefix = ctx
+                .get_raw(1)
+                .as_bytes()
+                .map_err(|err| rusqlite::Error::UserFunctionError(err.into()))?;
+            let tokens = token_set_ref(ctx.get_raw(0))
+                .map_err(|err| rusqlite::Error::UserFunctionError(err.into()))?;
+
+            Ok(tokens.0.iter().any(|token| token.starts_with(prefix)))
+        },
+    )
+}
+
+/// The servers with at least one token that starts with `prefix`, eg. the
+/// servers of a tenant whose id prefixes its tokens
+///
+/// `has_token_prefix` is (re)registered on `conn` first, so any connection can
+/// be used, see [`register_has_token_prefix`]
+pub fn servers_with_token_prefix(
+    conn: &rusqlite::Connection,
+    prefix: &[u8],
+) -> eyre::Result<Vec<Endpoint>> {
+    register_has_token_prefix(conn)?;
+
+    let mut statement = conn.prepare_cached(
+        "SELECT endpoint FROM servers WHERE has_token_prefix(tokens, ?) ORDER BY rowid",
+    )?;
+    let mut rows = statement.query([prefix])?;
+    let mut servers = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        servers.push(parse_endpoint(row.get_ref(0)?.as_str()?)?);
     }
+
+    Ok(servers)
+}
+
+/// The number of servers with each ICAO, eg. for dashboards
+///
+/// Servers without a known ICAO are counted under [`IcaoCode::default`]
+pub fn counts_by_icao(
+    conn: &rusqlite::Connection,
+) -> eyre::Result<std::collections::BTreeMap<IcaoCode, usize>> {
+    let mut statement = conn.prepare_cached("SELECT icao,count(*) FROM servers GROUP BY icao")?;
+    let mut rows = statement.query([])?;
+    let mut counts = std::collections::BTreeMap::new();
+
+    while let Some(row) = rows.next()? {
+        let icao = row.get::<_, String>(0)?;
+        let icao = icao
+            .parse()
+            .wrap_err_with(|| format!("column 'icao' holds an invalid ICAO code '{icao}'"))?;
+        counts.insert(icao, row.get::<_, usize>(1)?);
+    }
+
+    Ok(counts)
 }
 
 macro_rules! get_column {
@@ -75,6 +649,21 @@ macro_rules! get_column {
     };
 }
 
+/// Reads and validates an ICAO column
+///
+/// The column is a blob rather than a string if it was written directly rather
+/// than via [`crate::client::write`], in which case it may not even be UTF-8
+fn icao_column(values: &[SqliteValue], index: usize) -> eyre::Result<IcaoCode> {
+    let value = values.get(index).context("missing column 'icao'")?;
+    let icao = match value {
+        SqliteValue::Text(text) => text.parse(),
+        SqliteValue::Blob(blob) => IcaoCode::try_from(&blob[..]),
+        other => eyre::bail!("column 'icao' is not a string or blob: {other:?}"),
+    };
+
+    icao.wrap_err_with(|| format!("column 'icao' holds an invalid ICAO code {value:?}"))
+}
+
 macro_rules! get_json {
     ($name:literal, $conv:expr, $seq:expr) => {{
         let v = $seq
@@ -85,10 +674,12 @@ macro_rules! get_json {
 }
 
 impl FromSqlValue for ServerRow {
+    const COLUMNS: usize = 3;
+
     fn from_sql(values: &[SqliteValue]) -> eyre::Result<Self> {
         let endpoint = parse_endpoint(get_column!(0, "endpoint", values))?;
-        let icao = get_column!(1, "icao", values).parse()?;
-        let tokens = deserialize_token_set(get_column!(2, "tokens", values))?;
+        let icao = icao_column(values, 1)?;
+        let tokens = token_set_value(values.get(2).context("missing column 'tokens'")?)?;
 
         Ok(Self {
             endpoint,
@@ -98,6 +689,54 @@ impl FromSqlValue for ServerRow {
     }
 }
 
+impl FromSqlValue for Endpoint {
+    const COLUMNS: usize = 1;
+
+    fn from_sql(values: &[SqliteValue]) -> eyre::Result<Self> {
+        parse_endpoint(get_column!(0, "endpoint", values))
+    }
+}
+
+impl FromSqlValue for IcaoCode {
+    const COLUMNS: usize = 1;
+
+    fn from_sql(values: &[SqliteValue]) -> eyre::Result<Self> {
+        icao_column(values, 0)
+    }
+}
+
+impl FromSqlValue for TokenSet {
+    const COLUMNS: usize = 1;
+
+    fn from_sql(values: &[SqliteValue]) -> eyre::Result<Self> {
+        token_set_value(values.first().context("missing column 'tokens'")?)
+    }
+}
+
+/// Implements [`FromSqlValue`] for a tuple, where each element is read from
+/// the columns following the previous element's
+macro_rules! tuple_from_sql {
+    ($($name:ident),+) => {
+        impl<$($name: FromSqlValue),+> FromSqlValue for ($($name,)+) {
+            const COLUMNS: usize = 0 $(+ $name::COLUMNS)+;
+
+            // The offset after the last element is never read
+            #[allow(unused_assignments)]
+            fn from_sql(values: &[SqliteValue]) -> eyre::Result<Self> {
+                let mut offset = 0;
+                Ok(($({
+                    let value = $name::from_sql(values.get(offset..).unwrap_or_default())?;
+                    offset += $name::COLUMNS;
+                    value
+                },)+))
+            }
+        }
+    };
+}
+
+tuple_from_sql!(A, B);
+tuple_from_sql!(A, B, C);
+
 impl<'de> Deserialize<'de> for ServerRow {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -118,7 +757,7 @@ impl<'de> Deserialize<'de> for ServerRow {
             {
                 let endpoint = get_json!("endpoint", parse_endpoint, seq);
                 let icao = get_json!("icao", IcaoCode::from_str, seq);
-                let tokens = get_json!("tokens", deserialize_token_set, seq);
+                let tokens = get_json!("tokens", |v: SqliteValue| token_set_value(&v), seq);
 
                 // Ignore the rest of the elements, if we don't we'll leave
                 // the deserializer with tokens that will cause an error
@@ -8,66 +8,215 @@ use quilkin_types::{AddressKind, Endpoint, IcaoCode, TokenSet};
 
 pub trait ToSqlParam {
     fn to_sql(&self) -> SqliteParam;
+
+    /// Converts to a SQL parameter for statements that are executed directly
+    /// against SQLite rather than sent as JSON, so binary values don't need
+    /// to be encoded as text
+    ///
+    /// The default is the same as [`Self::to_sql`]
+    #[inline]
+    fn to_sql_blob(&self) -> SqliteParam {
+        self.to_sql()
+    }
 }
 
 pub type Statements<const N: usize> = smallvec::SmallVec<[Statement; N]>;
 
+/// The maximum length of the tokens in a set whose tokens all have the same
+/// length for it to be encoded in the low 7 bits of the first byte of the
+/// token set blob, other sets are written in the [`VARINT_FORMAT`]
+pub const MAX_SAME_LEN_TOKEN_LEN: usize = u8::MAX as usize >> 1;
+
+/// The first byte of token set blobs whose count, and the length of each
+/// token, are written as LEB128 varints
+///
+/// Otherwise this would mark a set of tokens that are all empty, which can't
+/// be written as a set can only contain one empty token. Blobs written before
+/// this format have a single byte count, or a `0` byte followed by a varint
+/// count, and single byte lengths, which are still read
+pub const VARINT_FORMAT: u8 = 0x80;
+
+/// The maximum number of tokens that can be encoded in a token set blob
+///
+/// This is far more than a game server registers in practice, but bounds how
+/// much a corrupt blob can make [`decode_token_blob_into`](super::read::decode_token_blob_into)
+/// allocate. [`Server`] never writes more than this, regardless of
+/// [`Server::max_tokens_per_server`]
+pub const MAX_ENCODED_TOKENS: usize = 1 << 16;
+
+/// The maximum length in bytes of a single token that can be encoded in a
+/// token set blob
+pub const MAX_TOKEN_LEN: usize = 1 << 16;
+
+/// The maximum number of servers inserted by a single statement created by
+/// [`Server::upsert_many`], keeping the number of parameters well under
+/// SQLite's default limit of 32766
+pub const MAX_SERVERS_PER_INSERT: usize = 1000;
+
+/// A token set that can't be encoded, see [`try_token_set_to_sql`]
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum TokenEncodeError {
+    #[error("token set has {count} tokens, more than the maximum of {MAX_ENCODED_TOKENS}")]
+    TooManyTokens { count: usize },
+    #[error("token {index} is {len} bytes, more than the maximum of {MAX_TOKEN_LEN}")]
+    TokenTooLong { index: usize, len: usize },
+}
+
+/// A server has more tokens than the limit, see [`Server::max_tokens_per_server`]
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error(
+    "server {} has {} tokens, more than the maximum of {}",
+    endpoint,
+    count,
+    max
+)]
+pub struct TooManyTokens {
+    pub endpoint: Endpoint,
+    pub count: usize,
+    pub max: usize,
+}
+
+impl TooManyTokens {
+    /// Checks the number of tokens against the limit
+    #[inline]
+    pub fn check(endpoint: &Endpoint, tokens: &TokenSet, max: usize) -> Result<(), Self> {
+        if tokens.0.len() <= max {
+            return Ok(());
+        }
+
+        Err(Self {
+            endpoint: endpoint.clone(),
+            count: tokens.0.len(),
+            max,
+        })
+    }
+}
+
+/// A server's tokens can't be written by [`Server`]
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum InvalidTokens {
+    #[error(transparent)]
+    TooMany(#[from] TooManyTokens),
+    #[error("the tokens of server {endpoint} can't be encoded: {error}")]
+    Unencodable {
+        endpoint: Endpoint,
+        #[source]
+        error: TokenEncodeError,
+    },
+}
+
 impl ToSqlParam for TokenSet {
     /// Converts a token set to a SQL parameter
     ///
-    /// Due to the limitations imposed on us via JSON (binary data is cumbersome) and SQLite (no arrays)
-    /// we base64 a custom encoding for token sets
+    /// # Panics
+    ///
+    /// If the token set can't be encoded, use [`try_token_set_to_sql`] for
+    /// token sets that haven't already been validated
     fn to_sql(&self) -> SqliteParam {
-        const MAX_TOKENS: usize = u8::MAX as usize >> 1;
-        let tokens = &self.0;
-        if tokens.is_empty() {
-            return SqliteParam::Null;
+        match try_token_set_to_sql(self) {
+            Ok(param) => param,
+            Err(error) => panic!("{error}"),
         }
+    }
 
-        let mut blob = smallvec::SmallVec::<[u8; 512]>::new();
-
-        // We could varint encode this instead, but for now just fail
-        debug_assert!(
-            tokens.len() <= MAX_TOKENS,
-            "number of tokens ({}) is more than {MAX_TOKENS}",
-            tokens.len()
-        );
-
-        let len_prefix = if tokens.len() > 1 {
-            // If all the tokens have the same length, and that length is less than
-            // MAX_TOKENS, we can skip length prefixing each token
-            let len = tokens.first().unwrap().len();
-            let same_len = tokens.iter().all(|tok| tok.len() == len);
-
-            if same_len && len <= MAX_TOKENS {
-                blob.push(0x80 | len as u8);
-            } else {
-                blob.push(tokens.len() as u8);
-            }
+    /// Converts a token set to a blob SQL parameter
+    ///
+    /// # Panics
+    ///
+    /// If the token set can't be encoded, use [`try_token_set_to_sql_blob`]
+    /// for token sets that haven't already been validated
+    fn to_sql_blob(&self) -> SqliteParam {
+        match try_token_set_to_sql_blob(self) {
+            Ok(param) => param,
+            Err(error) => panic!("{error}"),
+        }
+    }
+}
 
-            !same_len
-        } else {
-            blob.push(1);
-            false
-        };
+/// Converts a token set to a SQL parameter, failing if it has more than
+/// [`MAX_ENCODED_TOKENS`] tokens, or any token is longer than [`MAX_TOKEN_LEN`]
+///
+/// Due to the limitations imposed on us via JSON (binary data is cumbersome) and SQLite (no arrays)
+/// we base64 a custom encoding for token sets
+pub fn try_token_set_to_sql(ts: &TokenSet) -> Result<SqliteParam, TokenEncodeError> {
+    Ok(match encode_token_set(ts)? {
+        Some(blob) => SqliteParam::Text(data_encoding::BASE64_NOPAD.encode(&blob).into()),
+        None => SqliteParam::Null,
+    })
+}
 
-        for tok in tokens {
-            if len_prefix {
-                debug_assert!(
-                    tok.len() <= u8::MAX as usize,
-                    "token length {} is more than {}",
-                    tok.len(),
-                    u8::MAX
-                );
+/// Converts a token set to a blob SQL parameter, with the same encoding as
+/// [`try_token_set_to_sql`] but without the base64, for statements that are
+/// executed directly against SQLite
+pub fn try_token_set_to_sql_blob(ts: &TokenSet) -> Result<SqliteParam, TokenEncodeError> {
+    Ok(match encode_token_set(ts)? {
+        Some(blob) => SqliteParam::Blob(blob),
+        None => SqliteParam::Null,
+    })
+}
 
-                blob.push(tok.len() as u8);
-            }
+/// Encodes a token set, or `None` if it's empty
+fn encode_token_set(
+    ts: &TokenSet,
+) -> Result<Option<smallvec::SmallVec<[u8; 512]>>, TokenEncodeError> {
+    let tokens = &ts.0;
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    if tokens.len() > MAX_ENCODED_TOKENS {
+        return Err(TokenEncodeError::TooManyTokens {
+            count: tokens.len(),
+        });
+    }
+
+    let mut blob = smallvec::SmallVec::<[u8; 512]>::new();
+
+    let len_prefix = if tokens.len() > 1 {
+        // If all the tokens have the same length, and that length is at most
+        // MAX_SAME_LEN_TOKEN_LEN, we can skip length prefixing each token
+        let len = tokens.first().unwrap().len();
+        let same_len = tokens.iter().all(|tok| tok.len() == len);
+
+        if same_len && (1..=MAX_SAME_LEN_TOKEN_LEN).contains(&len) {
+            blob.push(0x80 | len as u8);
+            false
+        } else {
+            blob.push(VARINT_FORMAT);
+            push_varint(&mut blob, tokens.len());
+            true
+        }
+    } else {
+        blob.push(1);
+        false
+    };
+
+    for (index, tok) in tokens.iter().enumerate() {
+        if tok.len() > MAX_TOKEN_LEN {
+            return Err(TokenEncodeError::TokenTooLong {
+                index,
+                len: tok.len(),
+            });
+        }
 
-            blob.extend_from_slice(&tok);
+        if len_prefix {
+            push_varint(&mut blob, tok.len());
         }
 
-        SqliteParam::Text(data_encoding::BASE64_NOPAD.encode(&blob).into())
+        blob.extend_from_slice(&tok);
+    }
+
+    Ok(Some(blob))
+}
+
+/// Writes a LEB128 varint
+#[inline]
+fn push_varint(blob: &mut smallvec::SmallVec<[u8; 512]>, mut value: usize) {
+    while value >= 0x80 {
+        blob.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
     }
+    blob.push(value as u8);
 }
 
 impl ToSqlParam for IcaoCode {
@@ -103,54 +252,372 @@ fn to_compact_str(ep: &Endpoint) -> compact_str::CompactString {
             // when parsing we can easily distinguish IPs from hostnames
             write!(&mut cs, "|{ip}").unwrap();
         }
+        AddressKind::ScopedIp(ip, scope_id) => {
+            // The scope follows the address in the same form as a scoped
+            // IPv6 address is written, eg. fe80::1%2
+            write!(&mut cs, "|{ip}%{scope_id}").unwrap();
+        }
     }
 
     write!(&mut cs, ":{}", ep.port).unwrap();
     cs
 }
 
+/// The key a peer's contributions to servers are recorded under
+///
+/// By default this is the IP of the peer, but an agent whose IP can change
+/// can instead use a stable id so that it isn't treated as a new contributor
+/// when it reconnects from a different address
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ContributorId(compact_str::CompactString);
+
+impl ContributorId {
+    /// Creates a contributor id, returning `None` if the id is empty or contains
+    /// characters other than ASCII alphanumerics, `-`, `_`, `.`, or `:`
+    #[inline]
+    pub fn new(id: &str) -> Option<Self> {
+        let valid = !id.is_empty()
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'));
+        valid.then(|| Self(id.into()))
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<Peer> for ContributorId {
+    #[inline]
+    fn from(peer: Peer) -> Self {
+        Self(compact_str::format_compact!("{}", peer.ip()))
+    }
+}
+
 pub struct Server<'s, const N: usize> {
     pub peer: Peer,
+    pub contributor: ContributorId,
     pub statements: &'s mut smallvec::SmallVec<[Statement; N]>,
+    /// If enabled, the `dc` patches for consecutive upserts are collapsed into
+    /// a single statement
+    collapsed: Option<CollapsedDc>,
+    /// The maximum number of tokens in an upsert or update
+    max_tokens: usize,
+    /// Whether token sets are written as blobs rather than base64 text
+    blob_tokens: bool,
+}
+
+/// The servers added by consecutive upserts with the same ICAO, that have
+/// not yet been written to the `dc` row
+struct CollapsedDc {
+    icao: Option<IcaoCode>,
+    servers: compact_str::CompactString,
+}
+
+impl CollapsedDc {
+    /// Adds a server to the pending patch, returning the previous patch if
+    /// it was for a different ICAO, as the `dc` row is only patched if the
+    /// ICAO matches
+    fn add(
+        &mut self,
+        server: &str,
+        icao: IcaoCode,
+    ) -> Option<(compact_str::CompactString, IcaoCode)> {
+        let flushed = if self.icao.is_some_and(|pending| pending != icao) {
+            self.take()
+        } else {
+            None
+        };
+
+        if self.icao.is_none() {
+            self.icao = Some(icao);
+            self.servers.push('{');
+        } else {
+            self.servers.push(',');
+        }
+
+        use std::fmt::Write as _;
+        let _ = write!(&mut self.servers, "\"{server}\":{{}}");
+
+        flushed
+    }
+
+    /// Takes the pending patch, if any
+    fn take(&mut self) -> Option<(compact_str::CompactString, IcaoCode)> {
+        let icao = self.icao.take()?;
+        self.servers.push('}');
+        Some((std::mem::take(&mut self.servers), icao))
+    }
+}
+
+// The statement counts are on an impl without a generic `N` so that they can
+// be used as eg. `Server::STATEMENTS_PER_UPSERT` to choose `N`
+impl Server<'_, 0> {
+    /// The number of statements created by [`Self::upsert`], unless
+    /// [`Self::collapse_dc_patches`] is enabled
+    pub const STATEMENTS_PER_UPSERT: usize = 2;
+    /// The number of statements created by [`Self::remove_immediate`] and
+    /// [`Self::remove_deferred`]
+    pub const STATEMENTS_PER_REMOVE: usize = 2;
+    /// The number of statements created by [`Self::update`], if it updates
+    /// any columns
+    pub const STATEMENTS_PER_UPDATE: usize = 1;
+    /// The number of statements created by [`Self::touch`]
+    pub const STATEMENTS_PER_TOUCH: usize = 1;
+    /// The number of statements created by [`Self::rename`]
+    pub const STATEMENTS_PER_RENAME: usize = 2;
 }
 
 impl<'s, const N: usize> Server<'s, N> {
     #[inline]
     pub fn for_peer(peer: Peer, statements: &'s mut smallvec::SmallVec<[Statement; N]>) -> Self {
-        Self { peer, statements }
+        Self::for_peer_with_id(peer, peer.into(), statements)
+    }
+
+    /// Creates a server writer where the peer's contributions are recorded
+    /// under the specified id rather than the peer's IP
+    #[inline]
+    pub fn for_peer_with_id(
+        peer: Peer,
+        contributor: ContributorId,
+        statements: &'s mut smallvec::SmallVec<[Statement; N]>,
+    ) -> Self {
+        Self {
+            peer,
+            contributor,
+            statements,
+            collapsed: None,
+            max_tokens: MAX_ENCODED_TOKENS,
+            blob_tokens: false,
+        }
+    }
+
+    /// Sets the maximum number of tokens a server can be upserted or updated
+    /// with, by default, and at most, [`MAX_ENCODED_TOKENS`]
+    ///
+    /// Upserts and updates over the limit fail with [`InvalidTokens::TooMany`]
+    #[inline]
+    pub fn max_tokens_per_server(mut self, max: usize) -> Self {
+        self.max_tokens = max.min(MAX_ENCODED_TOKENS);
+        self
+    }
+
+    /// Writes token sets as blobs rather than base64 text, see
+    /// [`ToSqlParam::to_sql_blob`]
+    ///
+    /// Only use this if the statements are executed directly against SQLite,
+    /// eg. with [`execute_all_conn`], as binary parameters can't be sent to a
+    /// corrosion agent as JSON
+    #[inline]
+    pub fn blob_tokens(mut self) -> Self {
+        self.blob_tokens = true;
+        self
+    }
+
+    /// Checks the server's tokens against the limit, and encodes them
+    #[inline]
+    fn tokens_param(
+        &self,
+        endpoint: &Endpoint,
+        tokens: &TokenSet,
+    ) -> Result<SqliteParam, InvalidTokens> {
+        TooManyTokens::check(endpoint, tokens, self.max_tokens)?;
+
+        let param = if self.blob_tokens {
+            try_token_set_to_sql_blob(tokens)
+        } else {
+            try_token_set_to_sql(tokens)
+        };
+        param.map_err(|error| InvalidTokens::Unencodable {
+            endpoint: endpoint.clone(),
+            error,
+        })
+    }
+
+    /// Collapses the `dc` patches for consecutive upserts into a single
+    /// statement, rather than rewriting the `dc` row once per server
+    ///
+    /// [`Self::flush`] must be called once all of the upserts have been
+    /// made to write the collapsed patch
+    #[inline]
+    pub fn collapse_dc_patches(mut self) -> Self {
+        self.collapsed = Some(CollapsedDc {
+            icao: None,
+            servers: compact_str::CompactString::default(),
+        });
+        self
+    }
+
+    /// Writes the collapsed `dc` patch for any pending upserts
+    ///
+    /// This is a no-op if [`Self::collapse_dc_patches`] is not enabled
+    pub fn flush(&mut self) {
+        if let Some((servers, icao)) = self.collapsed.as_mut().and_then(CollapsedDc::take) {
+            self.push_dc_patch(&servers, icao);
+        }
+    }
+
+    fn push_dc_patch(&mut self, servers: &str, icao: IcaoCode) {
+        self.statements.push(Statement::WithParams(
+            format!(
+                "INSERT INTO dc (ip,port,icao,servers) VALUES (?,?,?,jsonb('{servers}'))
+            ON CONFLICT(ip) DO UPDATE SET
+                servers = jsonb_patch(servers,'{servers}')
+            WHERE excluded.icao = dc.icao"
+            ),
+            vec![
+                self.peer.ip().to_string().into(),
+                self.peer.port().into(),
+                icao.to_sql(),
+            ],
+        ));
     }
 
     /// Create a statement to insert a new server
+    ///
+    /// Fails if the server has more tokens than the [limit](Self::max_tokens_per_server),
+    /// or its tokens can't be encoded, in which case no statements are created
     #[inline]
-    pub fn upsert(&mut self, endpoint: &Endpoint, icao: IcaoCode, tokens: &TokenSet) {
+    pub fn upsert(
+        &mut self,
+        endpoint: &Endpoint,
+        icao: IcaoCode,
+        tokens: &TokenSet,
+    ) -> Result<(), InvalidTokens> {
+        let tokens = self.tokens_param(endpoint, tokens)?;
+
         let mut params = Vec::with_capacity(4);
 
         params.push(endpoint.to_sql());
         params.push(icao.to_sql());
-        params.push(tokens.to_sql());
+        params.push(tokens);
 
-        let peer_ip = self.peer.ip().to_string();
+        let contributor = self.contributor.as_str();
 
         self.statements.push(Statement::WithParams(
-            format!("INSERT INTO servers (endpoint,icao,tokens,contributors,cont_update) VALUES (?,?,?,jsonb('{{\"{peer_ip}\":{{}}}}'),unixepoch('now'))
+            format!("INSERT INTO servers (endpoint,icao,tokens,contributors,cont_update) VALUES (?,?,?,jsonb('{{\"{contributor}\":{{}}}}'),unixepoch('now'))
              ON CONFLICT(endpoint) DO UPDATE SET
-                contributors = jsonb_patch(contributors,'{{\"{peer_ip}\":{{}}}}'),
+                contributors = jsonb_patch(contributors,'{{\"{contributor}\":{{}}}}'),
                 cont_update = unixepoch('now')
              WHERE excluded.icao = servers.icao"),
             params,
         ));
 
-        let server = endpoint.address.to_string();
+        let server = to_compact_str(endpoint);
 
-        self.statements.push(Statement::WithParams(
-            format!(
-                "INSERT INTO dc (ip,port,icao,servers) VALUES (?,?,?,jsonb('{{\"{server}\":{{}}}}'))
+        let Some(collapsed) = &mut self.collapsed else {
+            self.push_dc_patch(&format!("{{\"{server}\":{{}}}}"), icao);
+            return Ok(());
+        };
+
+        if let Some((servers, icao)) = collapsed.add(&server, icao) {
+            self.push_dc_patch(&servers, icao);
+        }
+        Ok(())
+    }
+
+    /// Create statements to insert many servers at once
+    ///
+    /// This is equivalent to calling [`Self::upsert`] for each row, but the
+    /// servers are inserted by a single statement per [`MAX_SERVERS_PER_INSERT`]
+    /// rows, each followed by a single statement patching the `dc` row
+    ///
+    /// Fails if any server has more tokens than the [limit](Self::max_tokens_per_server),
+    /// or its tokens can't be encoded, in which case no statements are created
+    pub fn upsert_many(
+        &mut self,
+        rows: &[(Endpoint, IcaoCode, &TokenSet)],
+    ) -> Result<(), InvalidTokens> {
+        // Every row is encoded before any statement is created
+        let mut encoded = rows
+            .iter()
+            .map(|(endpoint, _, tokens)| self.tokens_param(endpoint, tokens))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter();
+
+        self.flush();
+
+        use std::fmt::Write as _;
+        let contributor = self.contributor.as_str();
+
+        for chunk in rows.chunks(MAX_SERVERS_PER_INSERT) {
+            let mut query = String::with_capacity(256 + chunk.len() * 64);
+            query.push_str(
+                "INSERT INTO servers (endpoint,icao,tokens,contributors,cont_update) VALUES ",
+            );
+
+            let mut params = Vec::with_capacity(chunk.len() * 3);
+            // The servers for each ICAO, in the order the ICAO was first seen,
+            // as only the first ICAO can insert the `dc` row and the patches
+            // for the others are only applied if the row has the same ICAO
+            let mut dc_servers = smallvec::SmallVec::<[(IcaoCode, String); 1]>::new();
+
+            for (i, ((endpoint, icao, _), tokens)) in chunk.iter().zip(&mut encoded).enumerate() {
+                if i > 0 {
+                    query.push(',');
+                }
+
+                let _ = write!(
+                    &mut query,
+                    "(?,?,?,jsonb('{{\"{contributor}\":{{}}}}'),unixepoch('now'))"
+                );
+
+                params.push(endpoint.to_sql());
+                params.push(icao.to_sql());
+                params.push(tokens);
+
+                let server = to_compact_str(endpoint);
+                match dc_servers.iter_mut().find(|(code, _)| code == icao) {
+                    Some((_, servers)) => {
+                        let _ = write!(servers, ",\"{server}\":{{}}");
+                    }
+                    None => dc_servers.push((*icao, format!("{{\"{server}\":{{}}"))),
+                }
+            }
+
+            let _ = write!(
+                &mut query,
+                "
+             ON CONFLICT(endpoint) DO UPDATE SET
+                contributors = jsonb_patch(contributors,'{{\"{contributor}\":{{}}}}'),
+                cont_update = unixepoch('now')
+             WHERE excluded.icao = servers.icao"
+            );
+
+            self.statements.push(Statement::WithParams(query, params));
+
+            let mut query = String::with_capacity(256);
+            query.push_str("INSERT INTO dc (ip,port,icao,servers) VALUES ");
+
+            let mut params = Vec::with_capacity(dc_servers.len() * 4);
+            let ip = self.peer.ip().to_string();
+
+            for (i, (icao, mut servers)) in dc_servers.into_iter().enumerate() {
+                if i > 0 {
+                    query.push(',');
+                }
+                query.push_str("(?,?,?,jsonb(?))");
+
+                servers.push('}');
+                params.push(ip.clone().into());
+                params.push(self.peer.port().into());
+                params.push(icao.to_sql());
+                params.push(servers.into());
+            }
+
+            query.push_str(
+                "
             ON CONFLICT(ip) DO UPDATE SET
-                servers = jsonb_patch(servers,'{{\"{server}\":{{}}}}')
-            WHERE excluded.icao = dc.icao"
-            ),
-            vec![peer_ip.into(), self.peer.port().into(), icao.to_sql()],
-        ));
+                servers = jsonb_patch(dc.servers,excluded.servers)
+            WHERE excluded.icao = dc.icao",
+            );
+
+            self.statements.push(Statement::WithParams(query, params));
+        }
+
+        Ok(())
     }
 
     /// Create a statement to remove the specified server immediately
@@ -159,13 +626,17 @@ impl<'s, const N: usize> Server<'s, N> {
     /// many contributors there are to the server
     #[inline]
     pub fn remove_immediate(&mut self, endpoint: &Endpoint) {
+        // Ensure the server is added before it is removed if it was upserted
+        // in the same batch
+        self.flush();
+
         self.statements.push(Statement::WithParams(
             "DELETE FROM servers WHERE rowid = (SELECT MIN(rowid) FROM servers WHERE endpoint = ?)"
                 .into(),
             vec![endpoint.to_sql()],
         ));
 
-        let server = endpoint.address.to_string();
+        let server = to_compact_str(endpoint);
 
         self.statements.push(Statement::WithParams(
             format!("UPDATE dc SET servers = jsonb_patch(servers, '{{\"{server}\":null}}') WHERE rowid = (SELECT MIN(rowid) FROM dc WHERE ip = ?)"),
@@ -181,12 +652,15 @@ impl<'s, const N: usize> Server<'s, N> {
     /// time period.
     #[inline]
     pub fn remove_deferred(&mut self, endpoint: &Endpoint) {
+        self.flush();
+
         let peer_ip = self.peer.ip().to_string();
+        let contributor = self.contributor.as_str();
 
         self.statements.push(Statement::WithParams(
             format!(
                 "UPDATE servers SET
-                contributors = jsonb_patch(contributors,'{{\"{peer_ip}\":null}}'),
+                contributors = jsonb_patch(contributors,'{{\"{contributor}\":null}}'),
                 cont_update = unixepoch('now')
             WHERE rowid = (SELECT MIN(rowid) FROM servers WHERE endpoint = ?)"
             ),
@@ -201,8 +675,83 @@ impl<'s, const N: usize> Server<'s, N> {
         ));
     }
 
+    /// Create a statement to mark the server as still being contributed by the
+    /// peer, eg. as a heartbeat that keeps it from being [reaped](Self::reap_old)
+    ///
+    /// Only `cont_update` is changed, and only if the peer is one of the
+    /// server's contributors
+    #[inline]
+    pub fn touch(&mut self, endpoint: &Endpoint) {
+        let contributor = self.contributor.as_str();
+
+        self.statements.push(Statement::WithParams(
+            format!(
+                "UPDATE servers SET cont_update = unixepoch('now')
+            WHERE rowid = (SELECT MIN(rowid) FROM servers WHERE endpoint = ?)
+                AND json_extract(contributors,'$.\"{contributor}\"') IS NOT NULL"
+            ),
+            vec![endpoint.to_sql()],
+        ));
+    }
+
+    /// Create statements to change a server's endpoint in place, eg. when it
+    /// moves from an IP to a hostname
+    ///
+    /// Unlike removing the old endpoint and inserting the new one, the server's
+    /// contributors and the time they were last updated are preserved, and the
+    /// server is renamed in every datacenter that contributed it. Fails when
+    /// executed if a server with the new endpoint already exists
+    #[inline]
+    pub fn rename(&mut self, old: &Endpoint, new: &Endpoint) {
+        self.flush();
+
+        self.statements.push(Statement::WithParams(
+            "UPDATE servers SET endpoint = ? WHERE rowid = (SELECT MIN(rowid) FROM servers WHERE endpoint = ?)"
+                .into(),
+            vec![new.to_sql(), old.to_sql()],
+        ));
+
+        let old = to_compact_str(old);
+        let new = to_compact_str(new);
+
+        self.statements.push(Statement::Simple(format!(
+            "UPDATE dc SET servers = jsonb_patch(servers, '{{\"{old}\":null,\"{new}\":{{}}}}')
+            WHERE json_extract(servers, '$.\"{old}\"') IS NOT NULL"
+        )));
+    }
+
+    /// Create a statement to move all of the contributions recorded under
+    /// `from` to this writer's contributor
+    ///
+    /// This is used to migrate contributions recorded under a peer's IP to
+    /// the stable id it now identifies itself with
+    #[inline]
+    pub fn migrate_contributor(&mut self, from: &ContributorId) {
+        let from = from.as_str();
+        let to = self.contributor.as_str();
+
+        self.statements.push(Statement::Simple(format!(
+            "UPDATE servers SET
+                contributors = jsonb_patch(contributors,'{{\"{from}\":null,\"{to}\":{{}}}}')
+            WHERE json_extract(contributors,'$.\"{from}\"') IS NOT NULL"
+        )));
+    }
+
     /// Create a statement to update one or more server columns
-    pub fn update(&mut self, update: UpdateBuilder<'_>) {
+    ///
+    /// Fails if the tokens are being updated to more than the [limit](Self::max_tokens_per_server),
+    /// or to tokens that can't be encoded, in which case no statement is
+    /// created. If no columns are being updated this is a no-op
+    pub fn update(&mut self, update: UpdateBuilder<'_>) -> Result<(), InvalidTokens> {
+        if update.params() == 0 {
+            return Ok(());
+        }
+
+        let tokens = update
+            .tokens
+            .map(|ts| self.tokens_param(update.ep, ts))
+            .transpose()?;
+
         let mut query = String::with_capacity(128);
         query.push_str("UPDATE servers SET ");
 
@@ -213,13 +762,13 @@ impl<'s, const N: usize> Server<'s, N> {
             params.push(SqliteParam::Text(icao.as_ref().into()));
         }
 
-        if let Some(ts) = update.tokens {
+        if let Some(tokens) = tokens {
             if !params.is_empty() {
                 query.push_str(", ");
             }
 
             query.push_str("tokens = ?");
-            params.push(ts.to_sql());
+            params.push(tokens);
         }
 
         // We know we are only updating one row, so ideally we would just stick
@@ -230,20 +779,41 @@ impl<'s, const N: usize> Server<'s, N> {
         params.push(update.ep.to_sql());
 
         self.statements.push(Statement::WithParams(query, params));
+        Ok(())
     }
 
     /// Create a statement to remove servers with no contributors whose last
     /// update was older
     ///
+    /// The age is measured from `now`, or the time the statement is executed
+    /// by SQLite if not specified
+    ///
     /// Note that unlike the other methods, the peer for this does not matter
     #[inline]
-    pub fn reap_old(&mut self, max_age: std::time::Duration) {
+    pub fn reap_old(&mut self, max_age: std::time::Duration, now: Option<time::UtcDateTime>) {
+        let now = match now {
+            Some(now) => compact_str::format_compact!("{}", now.unix_timestamp()),
+            None => "unixepoch('now')".into(),
+        };
+
         self.statements.push(Statement::Simple(format!(
-            "DELETE FROM servers WHERE length(contributors) <= 1 AND unixepoch('now') - cont_update > {}", max_age.as_secs()
+            "DELETE FROM servers WHERE length(contributors) <= 1 AND {now} - cont_update > {}",
+            max_age.as_secs()
         )));
     }
 }
 
+impl<const N: usize> Drop for Server<'_, N> {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            debug_assert!(
+                self.collapsed.as_ref().is_none_or(|c| c.icao.is_none()),
+                "collapsed dc patches were not flushed"
+            );
+        }
+    }
+}
+
 pub struct UpdateBuilder<'s> {
     ep: &'s Endpoint,
     icao: Option<IcaoCode>,
@@ -296,6 +866,16 @@ impl ToSqlParam for Peer {
 
 pub struct Datacenter<'s, const N: usize>(pub &'s mut smallvec::SmallVec<[Statement; N]>);
 
+impl Datacenter<'_, 0> {
+    /// The number of statements created by [`Self::insert`]
+    pub const STATEMENTS_PER_INSERT: usize = 1;
+    /// The number of statements created by [`Self::remove`] and [`Self::remove_with_id`]
+    pub const STATEMENTS_PER_REMOVE: usize = 2;
+    /// The number of statements created by [`Self::update`], if it updates
+    /// any columns
+    pub const STATEMENTS_PER_UPDATE: usize = 1;
+}
+
 impl<'s, const N: usize> Datacenter<'s, N> {
     #[inline]
     pub fn insert(&mut self, peer: Peer, qcmp: u16, icao: IcaoCode) {
@@ -320,15 +900,28 @@ impl<'s, const N: usize> Datacenter<'s, N> {
     /// if not specified
     #[inline]
     pub fn remove(&mut self, peer: Peer, update_time: Option<time::UtcDateTime>) {
+        self.remove_with_id(peer, &peer.into(), update_time);
+    }
+
+    /// Create a statement to remove the specified peer, whose contributions
+    /// were recorded under the specified id
+    ///
+    /// See [`Self::remove`]
+    #[inline]
+    pub fn remove_with_id(
+        &mut self,
+        peer: Peer,
+        contributor: &ContributorId,
+        update_time: Option<time::UtcDateTime>,
+    ) {
         let time = update_time.unwrap_or(time::UtcDateTime::now());
 
         self.0.push(Statement::Simple(format!(
-            "WITH sj AS (SELECT server.key FROM dc JOIN json_each(dc.servers) AS server WHERE ip = '{0}' LIMIT 1)
+            "WITH sj AS (SELECT server.key FROM dc JOIN json_each(dc.servers) AS server WHERE ip = '{0}')
             UPDATE servers SET
-                contributors = jsonb_patch(s.contributors,'{{\"{0}\":null}}'),
-                cont_update = {1}
-            FROM servers s
-            LEFT JOIN sj ON s.endpoint = sj.key", peer.ip(), time.unix_timestamp()
+                contributors = jsonb_patch(contributors,'{{\"{1}\":null}}'),
+                cont_update = {2}
+            WHERE endpoint IN (SELECT key FROM sj)", peer.ip(), contributor.as_str(), time.unix_timestamp()
         )));
 
         self.0.push(Statement::WithParams(
@@ -337,9 +930,97 @@ impl<'s, const N: usize> Datacenter<'s, N> {
         ));
     }
 
+    /// Create a statement that rewrites the keys of every `dc.servers` set that
+    /// were written before servers were keyed by their compact endpoint, ie.
+    /// `|<ip>:<port>` or `<hostname>:<port>`, rather than their address alone
+    ///
+    /// Legacy keys can't be read by [`ContributedServers`](crate::client::read::ContributedServers),
+    /// and aren't removed by [`Server::remove_immediate`] or [`Server::rename`].
+    /// As the port wasn't recorded, each legacy key is replaced by the key of
+    /// every server with that address, and dropped if there are none. This
+    /// only needs to be executed once, but is a no-op if there are no legacy
+    /// keys
+    #[inline]
+    pub fn migrate_server_keys(&mut self) {
+        // Only IPs start with '|', and hostnames can't contain ':', so a key
+        // with no ':', or more than one without a '|', is only an address
+        const LEGACY: &str =
+            "(server.key NOT LIKE '|%' AND (server.key NOT LIKE '%:%' OR server.key LIKE '%:%:%'))";
+
+        self.0.push(Statement::Simple(format!(
+            "UPDATE dc SET servers = (
+                SELECT jsonb_group_object(key, json('{{}}')) FROM (
+                    SELECT server.key AS key FROM json_each(dc.servers) AS server WHERE NOT {LEGACY}
+                    UNION
+                    SELECT servers.endpoint AS key FROM json_each(dc.servers) AS server JOIN servers
+                    WHERE {LEGACY} AND (
+                        (substr(servers.endpoint, 1, length(server.key) + 2) = '|' || server.key || ':'
+                            AND rtrim(substr(servers.endpoint, length(server.key) + 3), '0123456789') = '')
+                        OR (substr(servers.endpoint, 1, length(server.key) + 1) = server.key || ':'
+                            AND rtrim(substr(servers.endpoint, length(server.key) + 2), '0123456789') = '')
+                    )
+                )
+            )
+            WHERE EXISTS (SELECT 1 FROM json_each(dc.servers) AS server WHERE {LEGACY})"
+        )));
+    }
+
+    /// Create statements to remove many peers at once, eg. when a batch of
+    /// peers is lost at the same time
+    ///
+    /// This is equivalent to calling [`Self::remove`] for each peer, but the
+    /// peers are removed as contributors by a single statement, and their rows
+    /// deleted by another
+    #[inline]
+    pub fn remove_many(&mut self, peers: &[Peer], update_time: Option<time::UtcDateTime>) {
+        if peers.is_empty() {
+            return;
+        }
+
+        let time = update_time.unwrap_or(time::UtcDateTime::now());
+
+        use std::fmt::Write as _;
+        let mut ips = String::with_capacity(peers.len() * 24);
+        for (i, peer) in peers.iter().enumerate() {
+            if i > 0 {
+                ips.push(',');
+            }
+            let _ = write!(&mut ips, "'{}'", peer.ip());
+        }
+
+        // Each server is patched to remove only the peers that know of it,
+        // the same as if each peer was removed individually
+        self.0.push(Statement::Simple(format!(
+            "WITH sj AS (SELECT server.key, dc.ip FROM dc JOIN json_each(dc.servers) AS server WHERE ip IN ({ips}))
+            UPDATE servers SET
+                contributors = jsonb_patch(contributors,(SELECT json_group_object(sj.ip, NULL) FROM sj WHERE sj.key = servers.endpoint)),
+                cont_update = {}
+            WHERE endpoint IN (SELECT key FROM sj)", time.unix_timestamp()
+        )));
+
+        let mut query = String::with_capacity(32 + peers.len() * 2);
+        query.push_str("DELETE FROM dc WHERE ip IN (");
+        for i in 0..peers.len() {
+            if i > 0 {
+                query.push(',');
+            }
+            query.push('?');
+        }
+        query.push(')');
+
+        self.0.push(Statement::WithParams(
+            query,
+            peers.iter().map(ToSqlParam::to_sql).collect(),
+        ));
+    }
+
     /// Create a statement to update one or more datacenter columns
+    ///
+    /// If neither column is being updated this is a no-op
     pub fn update(&mut self, peer: Peer, port: Option<u16>, icao: Option<IcaoCode>) {
-        debug_assert!(port.is_some() || icao.is_some());
+        if port.is_none() && icao.is_none() {
+            return;
+        }
 
         let mut query = String::with_capacity(128);
         query.push_str("UPDATE dc SET ");
@@ -377,3 +1058,127 @@ impl<'s, const N: usize> Filter<'s, N> {
         ));
     }
 }
+
+/// Executes statements built by the writers in this module against a plain
+/// SQLite connection instead of a corrosion agent, eg. to process a database
+/// file offline, returning the total number of rows changed
+///
+/// The statements are executed one after another, pass a
+/// [`rusqlite::Transaction`] to apply them atomically
+pub fn execute_all_conn(
+    conn: &rusqlite::Connection,
+    statements: &[Statement],
+) -> rusqlite::Result<usize> {
+    let mut rows = 0;
+    for statement in statements {
+        let mut prepared = conn.prepare_cached(statement.query())?;
+        rows += match statement {
+            Statement::Simple(_)
+            | Statement::Verbose {
+                params: None,
+                named_params: None,
+                ..
+            } => prepared.execute([])?,
+            Statement::WithParams(_, params)
+            | Statement::Verbose {
+                params: Some(params),
+                ..
+            } => prepared.execute(rusqlite::params_from_iter(params))?,
+            Statement::WithNamedParams(_, params)
+            | Statement::Verbose {
+                named_params: Some(params),
+                ..
+            } => {
+                let params: Vec<(&str, &dyn rusqlite::ToSql)> = params
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value as &dyn rusqlite::ToSql))
+                    .collect();
+                prepared.execute(params.as_slice())?
+            }
+        };
+    }
+
+    Ok(rows)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ImportError {
+    #[error("failed to read line {}: {}", line, error)]
+    Io {
+        line: usize,
+        #[source]
+        error: std::io::Error,
+    },
+    #[error("line {} is not a valid server: {}", line, error)]
+    Json {
+        line: usize,
+        #[source]
+        error: serde_json::Error,
+    },
+    #[error("line {} has an invalid endpoint '{}'", line, endpoint)]
+    InvalidEndpoint { line: usize, endpoint: String },
+}
+
+/// The line format written by [`super::read::export_ndjson`]
+#[derive(serde::Deserialize)]
+struct ImportRow {
+    endpoint: String,
+    icao: IcaoCode,
+    tokens: TokenSet,
+}
+
+/// Reads servers written by [`super::read::export_ndjson`], yielding one
+/// upsert per non-empty line
+///
+/// Errors report the 1-based line number they occurred on, and don't stop
+/// iteration, so callers can choose to skip malformed lines
+pub fn import_ndjson(
+    reader: impl std::io::BufRead,
+) -> impl Iterator<Item = Result<crate::persistent::ServerUpsert, ImportError>> {
+    reader.lines().enumerate().filter_map(|(i, line)| {
+        let line_num = i + 1;
+        let line = match line {
+            Ok(line) => line,
+            Err(error) => {
+                return Some(Err(ImportError::Io {
+                    line: line_num,
+                    error,
+                }));
+            }
+        };
+
+        if line.trim().is_empty() {
+            return None;
+        }
+
+        Some(parse_import_row(&line, line_num))
+    })
+}
+
+fn parse_import_row(
+    line: &str,
+    line_num: usize,
+) -> Result<crate::persistent::ServerUpsert, ImportError> {
+    let row: ImportRow = serde_json::from_str(line).map_err(|error| ImportError::Json {
+        line: line_num,
+        error,
+    })?;
+
+    let endpoint = row
+        .endpoint
+        .rsplit_once(':')
+        .and_then(|(address, port)| {
+            let address = address.parse::<AddressKind>().ok()?;
+            Some(Endpoint::new(address, port.parse().ok()?))
+        })
+        .ok_or_else(|| ImportError::InvalidEndpoint {
+            line: line_num,
+            endpoint: row.endpoint.clone(),
+        })?;
+
+    Ok(crate::persistent::ServerUpsert {
+        endpoint,
+        icao: row.icao,
+        tokens: row.tokens,
+    })
+}
@@ -8,3 +8,18 @@ pub mod schema;
 pub mod server;
 
 pub type Peer = std::net::SocketAddrV6;
+
+/// Converts the address of a connection to its canonical [`Peer`] form, IPv4
+/// addresses are mapped to IPv6 and the port is preserved
+///
+/// The flow info and scope id of IPv6 addresses are discarded, so that they
+/// can't make the same peer appear different
+#[inline]
+pub fn peer_from_socket_addr(addr: std::net::SocketAddr) -> Peer {
+    let ip = match addr.ip() {
+        std::net::IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        std::net::IpAddr::V6(v6) => v6,
+    };
+
+    Peer::new(ip, addr.port(), 0, 0)
+}
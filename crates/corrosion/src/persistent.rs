@@ -7,11 +7,19 @@ pub mod server;
 
 use bytes::{BufMut, BytesMut};
 pub use corro_api_types::ExecResult;
+pub use error::ErrorCode;
 use quilkin_types::{Endpoint, IcaoCode, TokenSet};
 use serde::{Deserialize, Serialize};
 
 pub const MAGIC: [u8; 4] = 0xf0cacc1au32.to_ne_bytes();
 
+/// The ALPN protocol offered by clients, and accepted by servers, on TLS
+/// sessions, so that the protocol can share a port, or be routed by a
+/// QUIC-aware proxy
+///
+/// Unencrypted sessions have no TLS handshake, so don't negotiate a protocol
+pub const ALPN: &[u8] = b"quilkin-corrosion/1";
+
 #[derive(thiserror::Error, Debug)]
 pub enum HandshakeError {
     #[error("handshake response from peer was invalid")]
@@ -51,6 +59,42 @@ fn explicit_size<const N: usize>(buf: &[u8]) -> Result<[u8; N], HandshakeError>
     Ok(es)
 }
 
+/// Optional protocol behaviours, exchanged in version 3 handshakes
+///
+/// The client sends the features it supports, and the server responds with
+/// the ones it also supports, which are the features used on the connection.
+/// Bits that a peer doesn't know are ignored, so new features can be added
+/// without a new version
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Features(pub u32);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// Frames are numbered in the order they are sent on the stream, starting
+    /// at 0, and each response is prefixed with the number of the frame it is
+    /// for, see [`write_request_id_prefixed_jsonb`]
+    ///
+    /// This lets the server execute frames concurrently and respond to them
+    /// as they complete, so a slow frame doesn't delay the responses to the
+    /// frames sent after it. Frames that change the same endpoints are still
+    /// executed in the order they were sent
+    pub const REQUEST_IDS: Self = Self(1 << 0);
+    /// The features implemented by this crate
+    pub const SUPPORTED: Self = Self::REQUEST_IDS;
+
+    /// Whether all of the features in `other` are in `self`
+    #[inline]
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The features that are in both `self` and `other`
+    #[inline]
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
 pub struct ClientHandshakeRequestV1 {
     pub qcmp_port: u16,
     pub icao: IcaoCode,
@@ -59,8 +103,17 @@ pub struct ClientHandshakeRequestV1 {
 impl ClientHandshakeRequestV1 {
     #[inline]
     pub fn write(self) -> [u8; 12] {
+        self.write_as(1)
+    }
+
+    /// Writes the request with the specified version
+    ///
+    /// Version 2 requests have the same layout as version 1, but the server
+    /// responds with a [`ServerHandshakeResponseV2`]
+    #[inline]
+    pub fn write_as(self, version: u16) -> [u8; 12] {
         let mut req = [0u8; 12];
-        write_magic_and_version(&mut req, 1);
+        write_magic_and_version(&mut req, version);
 
         req[6..8].copy_from_slice(&self.qcmp_port.to_ne_bytes());
         req[8..12].copy_from_slice(self.icao.as_bytes());
@@ -70,13 +123,50 @@ impl ClientHandshakeRequestV1 {
     #[inline]
     pub fn read(buf: [u8; 6]) -> Result<Self, HandshakeError> {
         let qcmp_port = buf[0] as u16 | (buf[1] as u16) << 8;
-        let icao = buf[2..].try_into()?;
+        let icao = IcaoCode::new([buf[2], buf[3], buf[4], buf[5]])?;
         Ok(Self { qcmp_port, icao })
     }
 }
 
+/// The version 3 handshake request, which adds the [`Features`] the client
+/// supports
+pub struct ClientHandshakeRequestV3 {
+    pub qcmp_port: u16,
+    pub icao: IcaoCode,
+    pub features: Features,
+}
+
+impl ClientHandshakeRequestV3 {
+    #[inline]
+    pub fn write(self) -> [u8; 16] {
+        let mut req = [0u8; 16];
+        req[..12].copy_from_slice(
+            &ClientHandshakeRequestV1 {
+                qcmp_port: self.qcmp_port,
+                icao: self.icao,
+            }
+            .write_as(3),
+        );
+        req[12..16].copy_from_slice(&self.features.0.to_ne_bytes());
+        req
+    }
+
+    #[inline]
+    pub fn read(buf: [u8; 10]) -> Result<Self, HandshakeError> {
+        let ClientHandshakeRequestV1 { qcmp_port, icao } =
+            ClientHandshakeRequestV1::read([buf[0], buf[1], buf[2], buf[3], buf[4], buf[5]])?;
+        let features = Features(u32::from_ne_bytes([buf[6], buf[7], buf[8], buf[9]]));
+        Ok(Self {
+            qcmp_port,
+            icao,
+            features,
+        })
+    }
+}
+
 pub enum ClientHandshake {
     V1(ClientHandshakeRequestV1),
+    V3(ClientHandshakeRequestV3),
 }
 
 impl ClientHandshake {
@@ -89,10 +179,15 @@ impl ClientHandshake {
         buf = &buf[6..];
 
         let this = match version {
-            1 => {
+            // Version 2 only changes the response
+            1 | 2 => {
                 let fixed = explicit_size(buf)?;
                 Self::V1(ClientHandshakeRequestV1::read(fixed)?)
             }
+            3 => {
+                let fixed = explicit_size(buf)?;
+                Self::V3(ClientHandshakeRequestV3::read(fixed)?)
+            }
             theirs => {
                 return Err(HandshakeError::UnsupportedVersion {
                     ours: server_version,
@@ -103,9 +198,61 @@ impl ClientHandshake {
 
         Ok((version, this))
     }
-    pub fn client_details(self) -> (u16, IcaoCode) {
-        let Self::V1(req) = self;
-        (req.qcmp_port, req.icao)
+    pub fn client_details(&self) -> (u16, IcaoCode) {
+        match self {
+            Self::V1(req) => (req.qcmp_port, req.icao),
+            Self::V3(req) => (req.qcmp_port, req.icao),
+        }
+    }
+
+    /// The features the client supports, handshakes before version 3 don't
+    /// have any
+    #[inline]
+    pub fn features(&self) -> Features {
+        match self {
+            Self::V1(_) => Features::NONE,
+            Self::V3(req) => req.features,
+        }
+    }
+}
+
+/// Why a server declined a connection during the handshake
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The server is at its maximum number of connections
+    AtCapacity,
+    /// The client's ICAO is not allowed to connect to the server
+    Unauthorized,
+}
+
+impl RejectReason {
+    /// The byte used for the reason in a [`ServerHandshakeResponseV2`]
+    #[inline]
+    fn to_byte(reason: Option<Self>) -> u8 {
+        match reason {
+            None => 0,
+            Some(Self::AtCapacity) => 1,
+            Some(Self::Unauthorized) => 2,
+        }
+    }
+
+    #[inline]
+    fn from_byte(byte: u8) -> Result<Option<Self>, HandshakeError> {
+        match byte {
+            0 => Ok(None),
+            1 => Ok(Some(Self::AtCapacity)),
+            2 => Ok(Some(Self::Unauthorized)),
+            _ => Err(HandshakeError::InvalidResponse),
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::AtCapacity => "the server is at capacity",
+            Self::Unauthorized => "the client's ICAO is not allowed",
+        })
     }
 }
 
@@ -132,8 +279,102 @@ impl ServerHandshakeResponseV1 {
     }
 }
 
+/// The response to a version 2 handshake, which can also report the load of
+/// the server so that clients can choose between servers, and why the
+/// connection was declined
+pub struct ServerHandshakeResponseV2 {
+    pub accept: bool,
+    /// The number of connections to the server as a percentage of its
+    /// maximum, if the server reports it
+    pub load: Option<u8>,
+    /// Why the connection was declined, if it was
+    pub reason: Option<RejectReason>,
+}
+
+impl ServerHandshakeResponseV2 {
+    /// The value of the load byte when the server doesn't report its load
+    const NO_LOAD: u8 = u8::MAX;
+
+    #[inline]
+    pub fn write(self) -> [u8; 9] {
+        let mut res = [0u8; 9];
+        write_magic_and_version(&mut res, 2);
+        res[6] = if self.accept { 1 } else { 0 };
+        res[7] = self.load.map_or(Self::NO_LOAD, |load| load.min(100));
+        res[8] = RejectReason::to_byte(self.reason);
+        res
+    }
+
+    #[inline]
+    pub fn read(buf: [u8; 3]) -> Result<Self, HandshakeError> {
+        let ServerHandshakeResponseV1 { accept } = ServerHandshakeResponseV1::read([buf[0]])?;
+        let load = match buf[1] {
+            Self::NO_LOAD => None,
+            load @ 0..=100 => Some(load),
+            _ => return Err(HandshakeError::InvalidResponse),
+        };
+        let reason = RejectReason::from_byte(buf[2])?;
+
+        Ok(Self {
+            accept,
+            load,
+            reason,
+        })
+    }
+}
+
+/// The response to a version 3 handshake, which adds the [`Features`] used
+/// on the connection to the version 2 response
+pub struct ServerHandshakeResponseV3 {
+    pub accept: bool,
+    /// The number of connections to the server as a percentage of its
+    /// maximum, if the server reports it
+    pub load: Option<u8>,
+    /// Why the connection was declined, if it was
+    pub reason: Option<RejectReason>,
+    /// The features that both the client and server support
+    pub features: Features,
+}
+
+impl ServerHandshakeResponseV3 {
+    #[inline]
+    pub fn write(self) -> [u8; 13] {
+        let mut res = [0u8; 13];
+        res[..9].copy_from_slice(
+            &ServerHandshakeResponseV2 {
+                accept: self.accept,
+                load: self.load,
+                reason: self.reason,
+            }
+            .write(),
+        );
+        write_magic_and_version(&mut res, 3);
+        res[9..13].copy_from_slice(&self.features.0.to_ne_bytes());
+        res
+    }
+
+    #[inline]
+    pub fn read(buf: [u8; 7]) -> Result<Self, HandshakeError> {
+        let ServerHandshakeResponseV2 {
+            accept,
+            load,
+            reason,
+        } = ServerHandshakeResponseV2::read([buf[0], buf[1], buf[2]])?;
+        let features = Features(u32::from_ne_bytes([buf[3], buf[4], buf[5], buf[6]]));
+
+        Ok(Self {
+            accept,
+            load,
+            reason,
+            features,
+        })
+    }
+}
+
 pub enum ServerHandshake {
     V1(ServerHandshakeResponseV1),
+    V2(ServerHandshakeResponseV2),
+    V3(ServerHandshakeResponseV3),
 }
 
 impl ServerHandshake {
@@ -150,6 +391,14 @@ impl ServerHandshake {
                 let fixed = explicit_size(buf)?;
                 Ok(Self::V1(ServerHandshakeResponseV1::read(fixed)?))
             }
+            2 => {
+                let fixed = explicit_size(buf)?;
+                Ok(Self::V2(ServerHandshakeResponseV2::read(fixed)?))
+            }
+            3 => {
+                let fixed = explicit_size(buf)?;
+                Ok(Self::V3(ServerHandshakeResponseV3::read(fixed)?))
+            }
             theirs => Err(HandshakeError::UnsupportedVersion {
                 ours: client_version,
                 theirs,
@@ -172,9 +421,42 @@ fn update_length_prefix(buf: &mut bytes::BytesMut) {
 #[inline]
 pub fn write_length_prefixed_jsonb<T: serde::Serialize>(
     item: &T,
+) -> Result<BytesMut, serde_json::Error> {
+    write_prefixed_jsonb(&[], item)
+}
+
+/// Writes a response to the frame with the id, for streams that use
+/// [`Features::REQUEST_IDS`]
+///
+/// The id is included in the length of the frame
+#[inline]
+pub fn write_request_id_prefixed_jsonb<T: serde::Serialize>(
+    id: u32,
+    item: &T,
+) -> Result<BytesMut, serde_json::Error> {
+    write_prefixed_jsonb(&id.to_ne_bytes(), item)
+}
+
+/// Splits the id written by [`write_request_id_prefixed_jsonb`] from the rest
+/// of the frame, or returns `None` if the frame is too short to contain one
+#[inline]
+pub fn split_request_id(mut frame: bytes::Bytes) -> Option<(u32, bytes::Bytes)> {
+    if frame.len() < 4 {
+        return None;
+    }
+
+    let id = frame.split_to(4);
+    Some((u32::from_ne_bytes([id[0], id[1], id[2], id[3]]), frame))
+}
+
+#[inline]
+fn write_prefixed_jsonb<T: serde::Serialize>(
+    prefix: &[u8],
+    item: &T,
 ) -> Result<BytesMut, serde_json::Error> {
     let mut buf = bytes::BytesMut::new();
     buf.put_u16(0);
+    buf.extend_from_slice(prefix);
     {
         let mut w = buf.writer();
         serde_json::to_writer(&mut w, item)?;
@@ -211,6 +493,8 @@ pub enum LengthReadError {
         received
     )]
     LengthMismatch { expected: usize, received: usize },
+    #[error("the frame length {} is more than the maximum of {}", len, max)]
+    TooLarge { len: usize, max: usize },
     #[error(transparent)]
     Json(#[from] serde_json::Error),
 }
@@ -233,6 +517,7 @@ impl<'s> From<&'s LengthReadError> for Ec {
                     Ec::PayloadInsufficient
                 }
             }
+            LengthReadError::TooLarge { .. } => Ec::PayloadTooLarge,
             LengthReadError::Json(_) => Ec::BadRequest,
         }
     }
@@ -241,23 +526,58 @@ impl<'s> From<&'s LengthReadError> for Ec {
 #[inline]
 pub async fn read_length_prefixed(
     recv: &mut quinn::RecvStream,
+) -> Result<bytes::Bytes, LengthReadError> {
+    read_length_prefixed_limited(recv, usize::MAX).await
+}
+
+/// Reads a length prefixed frame, failing with [`LengthReadError::TooLarge`]
+/// if the length is more than `max`, before any of the frame is read or
+/// memory is allocated for it
+pub async fn read_length_prefixed_limited(
+    recv: &mut quinn::RecvStream,
+    max: usize,
 ) -> Result<bytes::Bytes, LengthReadError> {
     let mut len = [0u8; 2];
     recv.read_exact(&mut len).await?;
     let len = u16::from_ne_bytes(len) as usize;
 
+    if len > max {
+        return Err(LengthReadError::TooLarge { len, max });
+    }
+
+    // An empty frame is valid, and there's nothing more to read for it, so
+    // don't wait on the stream, where the end of it could be mistaken for
+    // the end of the frame
+    if len == 0 {
+        return Ok(bytes::Bytes::new());
+    }
+
     let Some(chunk) = recv.read_chunk(len, true).await? else {
         return Err(LengthReadError::StreamEnded);
     };
 
-    if chunk.bytes.len() != len {
-        return Err(LengthReadError::LengthMismatch {
-            expected: len,
-            received: chunk.bytes.len(),
-        });
+    if chunk.bytes.len() == len {
+        return Ok(chunk.bytes);
+    }
+
+    // The payload was split across multiple chunks, eg. the peer wrote it
+    // in several writes, so reassemble it. Each chunk is bounded by the
+    // remaining length so this never reads past the end of the frame
+    let mut buf = BytesMut::with_capacity(len);
+    buf.extend_from_slice(&chunk.bytes);
+
+    while buf.len() < len {
+        let Some(chunk) = recv.read_chunk(len - buf.len(), true).await? else {
+            return Err(LengthReadError::LengthMismatch {
+                expected: len,
+                received: buf.len(),
+            });
+        };
+
+        buf.extend_from_slice(&chunk.bytes);
     }
 
-    Ok(chunk.bytes)
+    Ok(buf.freeze())
 }
 
 #[inline]
@@ -268,7 +588,55 @@ pub async fn read_length_prefixed_jsonb<T: serde::de::DeserializeOwned>(
     Ok(serde_json::from_slice(&bytes)?)
 }
 
-#[derive(Deserialize, Serialize)]
+/// The response to a frame of changes
+///
+/// This is the JSON of an [`ExecResult`], clients that only read an
+/// [`ExecResult`] ignore the extra fields of an [`ErrorFrame`]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Response {
+    Error(ErrorFrame),
+    Result(ExecResult),
+}
+
+impl Response {
+    /// The result, or the error, with an [`ExecResult::Error`] being an error
+    /// without a code
+    #[inline]
+    pub fn into_result(self) -> Result<ExecResult, ErrorFrame> {
+        match self {
+            Self::Error(error) => Err(error),
+            Self::Result(ExecResult::Error { error }) => Err(ErrorFrame {
+                error,
+                code: None,
+                retryable: false,
+            }),
+            Self::Result(result) => Ok(result),
+        }
+    }
+}
+
+impl From<ExecResult> for Response {
+    #[inline]
+    fn from(result: ExecResult) -> Self {
+        Self::Result(result)
+    }
+}
+
+/// A frame of changes that failed to execute, see [`server::ExecError`]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ErrorFrame {
+    /// The message of the error, the same field as [`ExecResult::Error`]
+    pub error: String,
+    /// The kind of error, if the server reported one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    /// Whether the same changes could succeed if they were sent again
+    #[serde(default)]
+    pub retryable: bool,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct ServerUpsert {
     #[serde(rename = "a")]
     pub endpoint: Endpoint,
@@ -278,7 +646,7 @@ pub struct ServerUpsert {
     pub tokens: TokenSet,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct ServerUpdate {
     #[serde(rename = "a")]
     pub endpoint: Endpoint,
@@ -288,7 +656,7 @@ pub struct ServerUpdate {
     pub tokens: Option<TokenSet>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 #[serde(tag = "ty", content = "a")]
 pub enum ServerChange {
     #[serde(rename = "i")]
@@ -298,3 +666,406 @@ pub enum ServerChange {
     #[serde(rename = "u")]
     Update(Vec<ServerUpdate>),
 }
+
+impl ServerChange {
+    /// The number of servers the change applies to
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Insert(upserts) => upserts.len(),
+            Self::Remove(endpoints) => endpoints.len(),
+            Self::Update(updates) => updates.len(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The endpoints of the servers the change applies to
+    pub fn endpoints(&self) -> Box<dyn Iterator<Item = &Endpoint> + '_> {
+        match self {
+            Self::Insert(upserts) => Box::new(upserts.iter().map(|upsert| &upsert.endpoint)),
+            Self::Remove(endpoints) => Box::new(endpoints.iter()),
+            Self::Update(updates) => Box::new(updates.iter().map(|update| &update.endpoint)),
+        }
+    }
+
+    /// Returns true if the changes can be safely applied more than once
+    ///
+    /// Removes are not, as the server may have been inserted again, eg. by
+    /// another contributor, between the original remove and the retry
+    pub fn is_idempotent(changes: &[ServerChange]) -> bool {
+        !changes
+            .iter()
+            .any(|change| matches!(change, Self::Remove(_)))
+    }
+
+    /// Computes the size of the frame [`write_length_prefixed_jsonb`] would
+    /// produce for the batch, including the 2 byte length prefix, without
+    /// actually buffering the serialized JSON
+    pub fn wire_len(changes: &[ServerChange]) -> Result<usize, serde_json::Error> {
+        struct Counter(usize);
+
+        impl std::io::Write for Counter {
+            #[inline]
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0 += buf.len();
+                Ok(buf.len())
+            }
+
+            #[inline]
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut counter = Counter(2);
+        serde_json::to_writer(&mut counter, changes)?;
+        Ok(counter.0)
+    }
+}
+
+impl ServerChange {
+    /// Normalises a batch so that each endpoint is removed, inserted, and
+    /// updated at most once, in that order
+    ///
+    /// - A remove replaces any earlier operation on the endpoint
+    /// - An insert replaces an earlier insert, but is kept alongside an
+    ///   earlier remove or update, as an upsert of an existing server doesn't
+    ///   change its ICAO or tokens
+    /// - An update is merged into an earlier update, the fields it sets take
+    ///   precedence, and is kept alongside an earlier remove or insert
+    ///
+    /// Batches without conflicting operations are returned as is, otherwise
+    /// the changes are grouped into a remove, an insert, and an update, in that
+    /// order, with endpoints in the order they first appeared in the batch
+    pub fn normalize(changes: Vec<ServerChange>) -> Vec<ServerChange> {
+        let unique = {
+            let mut seen = std::collections::BTreeSet::new();
+            changes.iter().all(|change| match change {
+                Self::Insert(upserts) => upserts.iter().all(|upsert| seen.insert(&upsert.endpoint)),
+                Self::Remove(endpoints) => endpoints.iter().all(|endpoint| seen.insert(endpoint)),
+                Self::Update(updates) => updates.iter().all(|update| seen.insert(&update.endpoint)),
+            })
+        };
+
+        if unique {
+            return changes;
+        }
+
+        enum Op {
+            Insert(ServerUpsert),
+            Remove(Endpoint),
+            Update(ServerUpdate),
+        }
+
+        impl Op {
+            fn endpoint(&self) -> &Endpoint {
+                match self {
+                    Self::Insert(upsert) => &upsert.endpoint,
+                    Self::Remove(endpoint) => endpoint,
+                    Self::Update(update) => &update.endpoint,
+                }
+            }
+        }
+
+        /// The operations on a single endpoint, which are applied in the
+        /// order of the fields
+        #[derive(Default)]
+        struct Ops {
+            remove: Option<Endpoint>,
+            insert: Option<ServerUpsert>,
+            update: Option<ServerUpdate>,
+        }
+
+        impl Ops {
+            fn then(&mut self, next: Op) {
+                match next {
+                    Op::Remove(endpoint) => {
+                        *self = Self {
+                            remove: Some(endpoint),
+                            ..Default::default()
+                        };
+                    }
+                    Op::Insert(upsert) => self.insert = Some(upsert),
+                    Op::Update(update) => {
+                        self.update = Some(match self.update.take() {
+                            Some(prev) => ServerUpdate {
+                                endpoint: update.endpoint,
+                                icao: update.icao.or(prev.icao),
+                                tokens: update.tokens.or(prev.tokens),
+                            },
+                            None => update,
+                        });
+                    }
+                }
+            }
+        }
+
+        let ops = changes
+            .into_iter()
+            .flat_map(|change| -> Box<dyn Iterator<Item = Op>> {
+                match change {
+                    Self::Insert(upserts) => Box::new(upserts.into_iter().map(Op::Insert)),
+                    Self::Remove(endpoints) => Box::new(endpoints.into_iter().map(Op::Remove)),
+                    Self::Update(updates) => Box::new(updates.into_iter().map(Op::Update)),
+                }
+            });
+
+        let mut normalized: Vec<Ops> = Vec::new();
+        let mut index = std::collections::BTreeMap::new();
+        for op in ops {
+            let i = *index.entry(op.endpoint().clone()).or_insert_with(|| {
+                normalized.push(Ops::default());
+                normalized.len() - 1
+            });
+            normalized[i].then(op);
+        }
+
+        let mut removes = Vec::new();
+        let mut inserts = Vec::new();
+        let mut updates = Vec::new();
+        for ops in normalized {
+            removes.extend(ops.remove);
+            inserts.extend(ops.insert);
+            updates.extend(ops.update);
+        }
+
+        let mut changes = Vec::with_capacity(3);
+        if !removes.is_empty() {
+            changes.push(Self::Remove(removes));
+        }
+        if !inserts.is_empty() {
+            changes.push(Self::Insert(inserts));
+        }
+        if !updates.is_empty() {
+            changes.push(Self::Update(updates));
+        }
+        changes
+    }
+}
+
+/// How an executor handles a change that sets a server's ICAO to something
+/// other than the ICAO of the datacenter contributing it
+///
+/// Since `upsert` only updates an existing server if the ICAO matches, such
+/// changes are usually a misconfigured agent rather than intentional
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum IcaoMismatchPolicy {
+    /// The changes are applied as is
+    Allow,
+    /// The changes are applied, but each mismatch is logged
+    #[default]
+    Warn,
+    /// The changes are rejected if any of them mismatch
+    Reject,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "server {} has ICAO {}, which differs from the ICAO {} of the datacenter contributing it",
+    endpoint,
+    server,
+    dc
+)]
+pub struct IcaoMismatch {
+    pub endpoint: Endpoint,
+    pub server: IcaoCode,
+    pub dc: IcaoCode,
+}
+
+impl ServerChange {
+    /// Checks the ICAO of every upsert and update in the changes against
+    /// the ICAO of the contributing peer's datacenter, ie. its `dc.icao`
+    ///
+    /// Returns the first mismatch if the policy is [`IcaoMismatchPolicy::Reject`]
+    pub fn check_icao(
+        changes: &[ServerChange],
+        dc: IcaoCode,
+        policy: IcaoMismatchPolicy,
+    ) -> Result<(), IcaoMismatch> {
+        if policy == IcaoMismatchPolicy::Allow {
+            return Ok(());
+        }
+
+        let servers = changes.iter().flat_map(|change| {
+            let (inserts, updates) = match change {
+                Self::Insert(upserts) => (upserts.as_slice(), [].as_slice()),
+                Self::Update(updates) => ([].as_slice(), updates.as_slice()),
+                Self::Remove(_) => ([].as_slice(), [].as_slice()),
+            };
+
+            inserts
+                .iter()
+                .map(|upsert| (&upsert.endpoint, upsert.icao))
+                .chain(
+                    updates
+                        .iter()
+                        .filter_map(|update| Some((&update.endpoint, update.icao?))),
+                )
+        });
+
+        for (endpoint, server) in servers {
+            if server == dc {
+                continue;
+            }
+
+            if policy == IcaoMismatchPolicy::Reject {
+                return Err(IcaoMismatch {
+                    endpoint: endpoint.clone(),
+                    server,
+                    dc,
+                });
+            }
+
+            tracing::warn!(%endpoint, %server, %dc, "server ICAO differs from its datacenter's ICAO");
+        }
+
+        Ok(())
+    }
+
+    /// Checks the number of tokens of every upsert and update in the changes,
+    /// returning the first server with more than `max` tokens
+    ///
+    /// [`crate::client::write::Server`] enforces its own limit when creating
+    /// the statements, this allows executors to reject the whole frame first
+    pub fn check_tokens(
+        changes: &[ServerChange],
+        max: usize,
+    ) -> Result<(), crate::client::write::TooManyTokens> {
+        use crate::client::write::TooManyTokens;
+
+        for change in changes {
+            match change {
+                Self::Insert(upserts) => {
+                    for upsert in upserts {
+                        TooManyTokens::check(&upsert.endpoint, &upsert.tokens, max)?;
+                    }
+                }
+                Self::Update(updates) => {
+                    for update in updates {
+                        if let Some(tokens) = &update.tokens {
+                            TooManyTokens::check(&update.endpoint, tokens, max)?;
+                        }
+                    }
+                }
+                Self::Remove(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The kind of operation applied to an endpoint in a [`ChangeBatch`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Insert,
+    Remove,
+    Update,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "endpoint {} is already in the batch as an {:?}, and can't also be an {:?}",
+    endpoint,
+    existing,
+    new
+)]
+pub struct BatchConflict {
+    pub endpoint: Endpoint,
+    pub existing: ChangeKind,
+    pub new: ChangeKind,
+}
+
+/// Builds a batch of changes where each endpoint is the subject of at most
+/// one operation
+///
+/// The server applies each change in order, so eg. an insert and an update
+/// of the same endpoint in the same batch would depend on the order they were
+/// added, which is almost certainly a bug in the caller
+#[derive(Default)]
+pub struct ChangeBatch {
+    inserts: Vec<ServerUpsert>,
+    removes: Vec<Endpoint>,
+    updates: Vec<ServerUpdate>,
+    seen: std::collections::BTreeMap<Endpoint, ChangeKind>,
+}
+
+impl ChangeBatch {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn claim(&mut self, endpoint: &Endpoint, new: ChangeKind) -> Result<(), BatchConflict> {
+        if let Some(existing) = self.seen.get(endpoint) {
+            return Err(BatchConflict {
+                endpoint: endpoint.clone(),
+                existing: *existing,
+                new,
+            });
+        }
+
+        self.seen.insert(endpoint.clone(), new);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn upsert(&mut self, upsert: ServerUpsert) -> Result<&mut Self, BatchConflict> {
+        self.claim(&upsert.endpoint, ChangeKind::Insert)?;
+        self.inserts.push(upsert);
+        Ok(self)
+    }
+
+    #[inline]
+    pub fn remove(&mut self, endpoint: Endpoint) -> Result<&mut Self, BatchConflict> {
+        self.claim(&endpoint, ChangeKind::Remove)?;
+        self.removes.push(endpoint);
+        Ok(self)
+    }
+
+    #[inline]
+    pub fn update(&mut self, update: ServerUpdate) -> Result<&mut Self, BatchConflict> {
+        self.claim(&update.endpoint, ChangeKind::Update)?;
+        self.updates.push(update);
+        Ok(self)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// Converts the batch into the changes to send, omitting empty changes
+    pub fn into_changes(self) -> Vec<ServerChange> {
+        let mut changes = Vec::with_capacity(3);
+
+        if !self.inserts.is_empty() {
+            changes.push(ServerChange::Insert(self.inserts));
+        }
+        if !self.removes.is_empty() {
+            changes.push(ServerChange::Remove(self.removes));
+        }
+        if !self.updates.is_empty() {
+            changes.push(ServerChange::Update(self.updates));
+        }
+
+        changes
+    }
+}
+
+/// A borrowed version of [`ServerChange`] that serializes identically, so
+/// that callers don't need to give up ownership to send a single change
+#[derive(Serialize)]
+#[serde(tag = "ty", content = "a")]
+enum ServerChangeRef<'s> {
+    #[serde(rename = "i")]
+    Insert(&'s [ServerUpsert]),
+    #[serde(rename = "r")]
+    Remove(&'s [Endpoint]),
+    #[serde(rename = "u")]
+    Update(&'s [ServerUpdate]),
+}
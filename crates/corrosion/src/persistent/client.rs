@@ -1,10 +1,161 @@
 use bytes::Bytes;
 use corro_api_types::ExecResult;
 use quilkin_types::IcaoCode;
-use std::net::SocketAddr;
-use tokio::sync::{mpsc, oneshot};
+use std::{
+    collections::VecDeque,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{Notify, mpsc, oneshot};
 
-type ResponseTx = oneshot::Sender<Result<ExecResult, StreamError>>;
+mod stats;
+pub use stats::{ClientStats, ClientStatsSnapshot, LatencyHistogram, LatencySnapshot, PathStats};
+
+type ResponseTx = oneshot::Sender<Result<super::Response, StreamError>>;
+type ResponseRx = oneshot::Receiver<Result<super::Response, StreamError>>;
+type SubmitTx = oneshot::Sender<Result<(), TransactionError>>;
+
+/// A message to the task batching changes queued via [`Client::submit`]
+enum Submission {
+    Change(super::ServerChange, SubmitTx),
+    /// Sends the pending batch immediately
+    Flush,
+}
+/// Supplies the full set of servers a client should have contributed
+type Supplier = Arc<dyn Fn() -> Vec<super::ServerUpsert> + Send + Sync>;
+
+/// A transaction waiting to be sent by the I/O task
+struct Request {
+    msg: Bytes,
+    /// Whether the transaction can be safely sent again if the stream fails
+    idempotent: bool,
+    queued: Instant,
+    comp: ResponseTx,
+    unsent: UnsentGuard,
+}
+
+impl Request {
+    fn new(msg: Bytes, idempotent: bool, comp: ResponseTx, unsent: &Unsent) -> Self {
+        let queued = Instant::now();
+        Self {
+            unsent: UnsentGuard {
+                sink: unsent.clone(),
+                msg: msg.clone(),
+                queued,
+                written: false,
+            },
+            msg,
+            idempotent,
+            queued,
+            comp,
+        }
+    }
+}
+
+/// The changes that were never sent to the server, collected when the tasks of
+/// a client are aborted once the deadline of [`Client::shutdown_graceful`]
+/// elapses
+///
+/// Nothing is collected before then, eg. requests that are dropped because the
+/// stream failed are answered with an error instead
+#[derive(Clone, Default)]
+struct Unsent(Arc<Mutex<Option<UnsentChanges>>>);
+
+#[derive(Default)]
+struct UnsentChanges {
+    /// The changes of each request that was never written, with when it was
+    /// queued
+    requests: Vec<(Instant, Vec<super::ServerChange>)>,
+    /// The changes submitted via [`Client::submit`] that were never queued
+    submitted: Vec<super::ServerChange>,
+}
+
+impl Unsent {
+    fn collect(&self) {
+        *self.0.lock().unwrap() = Some(UnsentChanges::default());
+    }
+
+    #[inline]
+    fn is_collecting(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    /// Takes the collected changes, returning them in the order they were
+    /// queued, along with the number of requests they came from
+    fn take(&self) -> (Vec<super::ServerChange>, u64) {
+        let Some(mut unsent) = self.0.lock().unwrap().take() else {
+            return (Vec::new(), 0);
+        };
+
+        unsent.requests.sort_by_key(|(queued, _)| *queued);
+        let requests = unsent.requests.len() as u64;
+        let changes = unsent
+            .requests
+            .into_iter()
+            .flat_map(|(_, changes)| changes)
+            .chain(unsent.submitted)
+            .collect();
+        (changes, requests)
+    }
+}
+
+/// Returns the changes of a request to the [`Unsent`] sink if it is dropped
+/// without being written to the stream
+struct UnsentGuard {
+    sink: Unsent,
+    msg: Bytes,
+    queued: Instant,
+    written: bool,
+}
+
+impl Drop for UnsentGuard {
+    fn drop(&mut self) {
+        if self.written {
+            return;
+        }
+
+        let mut sink = self.sink.0.lock().unwrap();
+        let Some(unsent) = sink.as_mut() else {
+            return;
+        };
+
+        // Skip the length prefix
+        match serde_json::from_slice(&self.msg[2..]) {
+            Ok(changes) => unsent.requests.push((self.queued, changes)),
+            Err(error) => tracing::warn!(%error, "failed to decode unsent transaction"),
+        }
+    }
+}
+
+/// The queue of transactions sent by the I/O task
+#[derive(Clone)]
+struct Queue {
+    tx: mpsc::UnboundedSender<Request>,
+    stats: Arc<ClientStats>,
+    unsent: Unsent,
+}
+
+impl Queue {
+    fn enqueue(&self, msg: Bytes, idempotent: bool) -> Result<ResponseRx, TransactionError> {
+        let (comp, rx) = oneshot::channel();
+
+        self.stats.queued();
+        if self
+            .tx
+            .send(Request::new(msg, idempotent, comp, &self.unsent))
+            .is_err()
+        {
+            self.stats.dequeued(1);
+            return Err(TransactionError::TaskShutdown);
+        }
+
+        Ok(rx)
+    }
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum StreamError {
@@ -28,6 +179,58 @@ pub enum StreamError {
     LengthMismatch { expected: usize, received: usize },
     #[error("stream ended")]
     StreamEnded,
+    #[error("the coalesced frame containing this transaction failed: {}", .0)]
+    Coalesced(Arc<StreamError>),
+    #[error("failed to reopen the stream: {}", .0)]
+    Reconnect(Box<ConnectError>),
+}
+
+impl StreamError {
+    /// Returns true if the error is likely transient, eg. the connection was
+    /// lost, such that the transaction could succeed if sent again on a new
+    /// stream
+    pub fn is_retryable(&self) -> bool {
+        use quinn::{ConnectionError as Ce, ReadError, ReadExactError, ResetError, WriteError};
+
+        let connection = |error: &Ce| {
+            matches!(
+                error,
+                Ce::Reset | Ce::TimedOut | Ce::ConnectionClosed(_) | Ce::ApplicationClosed(_)
+            )
+        };
+        let read = |error: &ReadError| match error {
+            ReadError::Reset(_) => true,
+            ReadError::ConnectionLost(error) => connection(error),
+            _ => false,
+        };
+
+        match self {
+            Self::Connect(error)
+            | Self::Write(WriteError::ConnectionLost(error))
+            | Self::Reset(ResetError::ConnectionLost(error)) => connection(error),
+            Self::Write(WriteError::Stopped(_)) => true,
+            Self::Read(error) | Self::ReadExact(ReadExactError::ReadError(error)) => read(error),
+            Self::ReadExact(ReadExactError::FinishedEarly(_)) | Self::StreamEnded => true,
+            Self::Coalesced(error) => error.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// The error of the connection, if the stream failed because the
+    /// connection was lost
+    fn connection_error(&self) -> Option<&quinn::ConnectionError> {
+        use quinn::{ReadError, ReadExactError, ResetError, WriteError};
+
+        match self {
+            Self::Connect(error)
+            | Self::Write(WriteError::ConnectionLost(error))
+            | Self::Read(ReadError::ConnectionLost(error))
+            | Self::ReadExact(ReadExactError::ReadError(ReadError::ConnectionLost(error)))
+            | Self::Reset(ResetError::ConnectionLost(error)) => Some(error),
+            Self::Coalesced(error) => error.connection_error(),
+            _ => None,
+        }
+    }
 }
 
 use super::LengthReadError as Lre;
@@ -39,6 +242,11 @@ impl From<Lre> for StreamError {
             Lre::LengthMismatch { expected, received } => {
                 Self::LengthMismatch { expected, received }
             }
+            // The client doesn't limit the length of responses
+            Lre::TooLarge { len, max } => Self::LengthMismatch {
+                expected: max,
+                received: len,
+            },
             Lre::ReadExact(re) => Self::ReadExact(re),
             Lre::Read(r) => Self::Read(r),
             Lre::StreamEnded => Self::StreamEnded,
@@ -58,6 +266,108 @@ pub enum ConnectError {
     Handshake(#[from] super::HandshakeError),
     #[error(transparent)]
     Write(#[from] StreamError),
+    #[error(
+        "stream {} was opened instead of the first bidirectional stream",
+        stream_id
+    )]
+    StreamReused { stream_id: quinn::StreamId },
+    #[error(
+        "unable to connect to {} from {}, the address families are incompatible",
+        remote,
+        local
+    )]
+    AddressFamilyMismatch {
+        local: SocketAddr,
+        remote: SocketAddr,
+    },
+    #[error(transparent)]
+    Tls(#[from] quinn::rustls::client::VerifierBuilderError),
+    #[error("'{}' did not resolve to any addresses", host)]
+    NoAddresses { host: String },
+    #[error("failed to connect to any of the {} addresses", .0.len())]
+    AllAddressesFailed(Vec<(SocketAddr, ConnectError)>),
+    /// The server declined the connection in its handshake response
+    #[error(
+        "the server declined the connection{}",
+        reason.map(|reason| format!(": {reason}")).unwrap_or_default()
+    )]
+    Rejected {
+        /// Why the connection was declined, if the server's handshake
+        /// response carries a reason
+        reason: Option<super::RejectReason>,
+    },
+    /// The server responded to the handshake with a version of the protocol
+    /// that this client doesn't implement
+    #[error(
+        "the server responded with protocol version {}, but this client only implements version {}",
+        theirs,
+        ours
+    )]
+    UnsupportedVersion { ours: u16, theirs: u16 },
+}
+
+impl ConnectError {
+    /// The error of the connection, if the connection was established but
+    /// then lost, or couldn't be established
+    fn connection_error(&self) -> Option<&quinn::ConnectionError> {
+        match self {
+            Self::Connection(error) => Some(error),
+            Self::Write(error) => error.connection_error(),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the server didn't respond in time, eg. because it is
+    /// unreachable or not running
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Self::AllAddressesFailed(errors) => {
+                !errors.is_empty() && errors.iter().all(|(_, error)| error.is_timeout())
+            }
+            _ => matches!(
+                self.connection_error(),
+                Some(quinn::ConnectionError::TimedOut)
+            ),
+        }
+    }
+
+    /// Returns true if the server was reached, but refused the connection,
+    /// either at the QUIC level or by declining the handshake
+    pub fn is_refused(&self) -> bool {
+        match self {
+            Self::Rejected { .. } => true,
+            Self::AllAddressesFailed(errors) => {
+                !errors.is_empty() && errors.iter().all(|(_, error)| error.is_refused())
+            }
+            _ => matches!(
+                self.connection_error(),
+                Some(quinn::ConnectionError::ConnectionClosed(close))
+                    if close.error_code == quinn::TransportErrorCode::CONNECTION_REFUSED
+            ),
+        }
+    }
+
+    /// Returns true if the error is likely transient, such that connecting
+    /// again later could succeed, eg. the server timed out, is restarting, or
+    /// is at capacity
+    ///
+    /// Errors caused by the client's configuration, such as an unresolvable
+    /// host, a TLS failure, or an ICAO the server doesn't allow, are not
+    pub fn is_retryable(&self) -> bool {
+        use quinn::ConnectionError as Ce;
+
+        match self {
+            Self::Rejected { reason } => !matches!(reason, Some(super::RejectReason::Unauthorized)),
+            Self::AllAddressesFailed(errors) => {
+                errors.iter().any(|(_, error)| error.is_retryable())
+            }
+            Self::Write(error) if error.is_retryable() => true,
+            _ => matches!(
+                self.connection_error(),
+                Some(Ce::Reset | Ce::TimedOut | Ce::ConnectionClosed(_) | Ce::ApplicationClosed(_))
+            ),
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -68,6 +378,392 @@ pub enum TransactionError {
     Stream(#[from] StreamError),
     #[error("the I/O task for this client was shutdown")]
     TaskShutdown,
+    #[error("timed out after {:?} waiting for the transaction response", timeout)]
+    Timeout { timeout: Duration },
+    #[error("the server failed to execute the transaction: {}", message)]
+    Rejected {
+        message: String,
+        /// The kind of error, if the server reported one
+        code: Option<super::ErrorCode>,
+        /// Whether the server reported that the transaction could succeed if
+        /// it was sent again
+        retryable: bool,
+    },
+    #[error("the change is {} bytes, larger than the maximum frame size", len)]
+    TooLarge { len: usize },
+    #[error("the batch containing this change failed: {}", .0)]
+    BatchFailed(Arc<TransactionError>),
+}
+
+impl From<super::ErrorFrame> for TransactionError {
+    fn from(error: super::ErrorFrame) -> Self {
+        Self::Rejected {
+            message: error.error,
+            code: error.code,
+            retryable: error.retryable,
+        }
+    }
+}
+
+/// Configuration for a [`Client`]
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// The timeout applied to [`Client::transactions`], if not set, transactions
+    /// will wait indefinitely for a response
+    pub transaction_timeout: Option<Duration>,
+    /// The local address to bind the client's socket to, if not set, the
+    /// unspecified address of the same family as the server is used
+    pub bind_addr: Option<SocketAddr>,
+    /// If the bind address is IPv6, allows the socket to also connect to IPv4
+    /// addresses
+    pub dual_stack: bool,
+    /// Overrides for the QUIC transport, if set, [`Self::keep_alive_interval`]
+    /// and [`Self::idle_timeout`] are ignored
+    pub transport: Option<Arc<quinn::TransportConfig>>,
+    /// The interval keep-alive packets are sent at, so that NAT mappings don't
+    /// expire while there are no transactions, `None` disables keep-alives
+    ///
+    /// Defaults to [`DEFAULT_KEEP_ALIVE_INTERVAL`]
+    pub keep_alive_interval: Option<Duration>,
+    /// The connection is closed if nothing is received for this long, the
+    /// effective timeout is the lower of this and the server's idle timeout
+    ///
+    /// Defaults to [`DEFAULT_IDLE_TIMEOUT`]
+    pub idle_timeout: Option<Duration>,
+    /// If set, up to this many transactions that are queued while waiting
+    /// for a response are coalesced into a single frame
+    ///
+    /// Every transaction in a coalesced frame receives the same response, so
+    /// eg. `rows_affected` is the total for the frame rather than for each
+    /// individual transaction
+    pub coalesce: Option<usize>,
+    /// If set, up to this many frames are written to the stream before the
+    /// response to the first is received, rather than waiting for each
+    /// response before sending the next frame
+    ///
+    /// If the server supports [`Features::REQUEST_IDS`](super::Features::REQUEST_IDS)
+    /// it executes the frames concurrently and responds to each as soon as it
+    /// completes, so a slow frame doesn't delay the responses to the frames
+    /// sent after it. Frames that change the same endpoints are still executed
+    /// in the order they were sent.
+    ///
+    /// Older servers execute a stream's frames one at a time and respond in
+    /// the order they were sent, so only the latency of sending them is hidden
+    pub max_in_flight: Option<usize>,
+    /// The maximum number of times a transaction is sent again, on a new stream,
+    /// if it fails with a [retryable](StreamError::is_retryable) error
+    ///
+    /// Only transactions that don't contain removes are retried, others fail
+    /// with the original error, though the stream is still reopened. If
+    /// reopening the stream fails, it is attempted again up to the same number
+    /// of times, with an exponential backoff, unless [`Self::reconnect`] is
+    /// set. If 0, the default, transactions are never retried, and unless
+    /// [`Self::reconnect`] is set, the client is closed if the stream fails
+    pub max_retries: u32,
+    /// If set, the stream is reopened whenever it fails with a retryable
+    /// error, including while there are no transactions in flight, eg. if
+    /// the server restarts, with the attempts and backoff of the policy
+    ///
+    /// Transactions queued while the stream is being reopened are sent once
+    /// it has been, transactions in flight when it failed are only sent again
+    /// as per [`Self::max_retries`]. The client is closed if every attempt
+    /// to reopen the stream fails
+    pub reconnect: Option<ReconnectPolicy>,
+    /// The roots used to validate the server's certificate when connecting
+    /// with [`Client::connect`], if not set, the session is not encrypted
+    pub tls_roots: Option<Arc<quinn::rustls::RootCertStore>>,
+    /// If set, the connection's [path statistics](Client::path_stats) are
+    /// sampled at this interval and recorded in the client's [`ClientStats`]
+    pub path_stats_interval: Option<Duration>,
+    /// If set, changes queued via [`Client::submit`] are held until one of the
+    /// policy's limits is reached, so that they are sent in fewer, larger
+    /// transactions
+    pub flush: Option<FlushPolicy>,
+}
+
+/// Limits on how long changes [submitted](Client::submit) to a [`Client`] are
+/// held before they are sent, whichever is reached first
+#[derive(Copy, Clone, Debug)]
+pub struct FlushPolicy {
+    /// The maximum amount of time to hold changes after the first change of a
+    /// batch is submitted
+    pub max_delay: Duration,
+    /// The batch is sent as soon as it contains this many changes
+    pub max_changes: usize,
+    /// The batch is sent as soon as its frame is at least this many bytes,
+    /// frames are never larger than the maximum frame size regardless
+    pub max_bytes: usize,
+}
+
+/// The default keep-alive interval, well under the ~30s that UDP mappings
+/// typically last in NATs
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// The default idle timeout, which matches the server's default
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            transaction_timeout: None,
+            bind_addr: None,
+            dual_stack: false,
+            transport: None,
+            keep_alive_interval: Some(DEFAULT_KEEP_ALIVE_INTERVAL),
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            coalesce: None,
+            max_in_flight: None,
+            max_retries: 0,
+            reconnect: None,
+            tls_roots: None,
+            path_stats_interval: None,
+            flush: None,
+        }
+    }
+}
+
+/// How the stream is reopened after it fails, see [`ClientConfig::reconnect`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// The maximum number of attempts to reopen the stream, including the
+    /// first, at least one attempt is always made
+    pub max_attempts: u32,
+    /// The delay after the first failed attempt, doubled after each
+    /// subsequent failed attempt, up to 64 times this delay
+    pub base_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            base_delay: REOPEN_DELAY,
+        }
+    }
+}
+
+/// The keep-alive and idle timeout applied to a [`Client`]'s connection
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TransportTimeouts {
+    pub keep_alive_interval: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+}
+
+/// The delay between starting connection attempts to each of the addresses
+/// a host resolves to
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// The delay before the first attempt to reopen the stream again after failing
+/// to reopen it, doubled for each subsequent attempt
+const REOPEN_DELAY: Duration = Duration::from_millis(100);
+
+impl ClientConfig {
+    /// Creates the QUIC client configuration, encrypted if there are TLS roots
+    fn quic_config(&self, encrypted: bool) -> Result<quinn::ClientConfig, ConnectError> {
+        let mut client_config = match &self.tls_roots {
+            Some(roots) if encrypted => Self::tls_config(roots.clone())?,
+            _ => quinn_plaintext::client_config(),
+        };
+
+        let transport = match &self.transport {
+            Some(transport) => transport.clone(),
+            None => {
+                let mut transport = quinn::TransportConfig::default();
+                transport.keep_alive_interval(self.keep_alive_interval);
+                transport.max_idle_timeout(self.idle_timeout.map(|timeout| {
+                    quinn::IdleTimeout::try_from(timeout)
+                        .unwrap_or_else(|_| quinn::VarInt::MAX.into())
+                }));
+                Arc::new(transport)
+            }
+        };
+        client_config.transport_config(transport);
+
+        Ok(client_config)
+    }
+
+    /// Creates a QUIC configuration that uses TLS and offers the [`ALPN`](super::ALPN)
+    /// protocol
+    ///
+    /// This is the same as [`quinn::ClientConfig::with_root_certificates`],
+    /// other than the ALPN protocol
+    fn tls_config(
+        roots: Arc<quinn::rustls::RootCertStore>,
+    ) -> Result<quinn::ClientConfig, ConnectError> {
+        let provider = Arc::new(quinn::rustls::crypto::ring::default_provider());
+        let verifier = quinn::rustls::client::WebPkiServerVerifier::builder_with_provider(
+            roots,
+            provider.clone(),
+        )
+        .build()?;
+
+        let mut crypto = quinn::rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&quinn::rustls::version::TLS13])
+            .expect("the ring provider supports TLS 1.3")
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_no_client_auth();
+        crypto.enable_early_data = true;
+        crypto.alpn_protocols = vec![super::ALPN.to_vec()];
+
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(crypto)
+            .expect("the ring provider supports the initial cipher suite");
+        Ok(quinn::ClientConfig::new(Arc::new(crypto)))
+    }
+
+    /// The timeouts applied to the transport, `None` if the transport is overridden
+    fn timeouts(&self) -> Option<TransportTimeouts> {
+        self.transport.is_none().then_some(TransportTimeouts {
+            keep_alive_interval: self.keep_alive_interval,
+            idle_timeout: self.idle_timeout,
+        })
+    }
+
+    /// Creates the endpoint used to connect to `remote`, returning the address
+    /// that should actually be used to connect, as IPv4 addresses need to be
+    /// mapped when using a dual stack socket
+    fn bind(&self, remote: SocketAddr) -> Result<(quinn::Endpoint, SocketAddr), ConnectError> {
+        let local = self.bind_addr.unwrap_or_else(|| match remote {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        });
+
+        let remote = self.map_remote(local, remote)?;
+        let ep = EndpointBuilder::new(local)
+            .dual_stack(self.dual_stack)
+            .build()?;
+
+        Ok((ep, remote))
+    }
+
+    /// Maps `remote` to an address reachable from a socket bound to `local`
+    fn map_remote(
+        &self,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Result<SocketAddr, ConnectError> {
+        match (local, remote) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+                Ok(remote)
+            }
+            (SocketAddr::V6(_), SocketAddr::V4(v4)) if self.dual_stack => {
+                Ok((v4.ip().to_ipv6_mapped(), v4.port()).into())
+            }
+            _ => Err(ConnectError::AddressFamilyMismatch { local, remote }),
+        }
+    }
+}
+
+/// Builds a [`quinn::Endpoint`] suitable for sharing between multiple
+/// [`Client`]s via [`Client::connect_with_endpoint`]
+#[derive(Clone, Debug)]
+pub struct EndpointBuilder {
+    bind_addr: SocketAddr,
+    dual_stack: bool,
+    config: quinn::EndpointConfig,
+}
+
+impl EndpointBuilder {
+    /// Creates a builder for an endpoint bound to `bind_addr`
+    pub fn new(bind_addr: SocketAddr) -> Self {
+        Self {
+            bind_addr,
+            dual_stack: false,
+            config: quinn::EndpointConfig::default(),
+        }
+    }
+
+    /// If the bind address is IPv6, allows the socket to also connect to IPv4
+    /// addresses
+    pub fn dual_stack(mut self, dual_stack: bool) -> Self {
+        self.dual_stack = dual_stack;
+        self
+    }
+
+    /// Overrides the endpoint configuration
+    pub fn config(mut self, config: quinn::EndpointConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Binds the socket and creates the endpoint, this must be called within
+    /// a tokio runtime
+    pub fn build(self) -> Result<quinn::Endpoint, ConnectError> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(self.bind_addr),
+            socket2::Type::DGRAM,
+            Some(socket2::Protocol::UDP),
+        )?;
+        if self.bind_addr.is_ipv6() {
+            socket.set_only_v6(!self.dual_stack)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&self.bind_addr.into())?;
+
+        let runtime = quinn::default_runtime().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::Other, "no async runtime found")
+        })?;
+
+        Ok(quinn::Endpoint::new(
+            self.config,
+            None,
+            socket.into(),
+            runtime,
+        )?)
+    }
+}
+
+/// Why the I/O task for a [`Client`] ended
+#[derive(Clone, Debug)]
+pub enum CloseReason {
+    /// The client was shutdown
+    Shutdown,
+    /// The server reset the stream
+    Reset(super::ErrorCode),
+    /// The stream failed
+    Error(Arc<StreamError>),
+}
+
+impl From<Result<Option<quinn::VarInt>, StreamError>> for CloseReason {
+    fn from(res: Result<Option<quinn::VarInt>, StreamError>) -> Self {
+        use quinn::{ReadError, ReadExactError};
+
+        match res {
+            Ok(None) => Self::Shutdown,
+            Ok(Some(code))
+            | Err(
+                StreamError::Read(ReadError::Reset(code))
+                | StreamError::ReadExact(ReadExactError::ReadError(ReadError::Reset(code))),
+            ) => Self::Reset(code.into()),
+            Err(error) => Self::Error(Arc::new(error)),
+        }
+    }
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Shutdown => f.write_str("the client was shutdown"),
+            Self::Reset(code) => write!(f, "the server reset the stream with {code}"),
+            Self::Error(error) => write!(f, "the stream failed: {error}"),
+        }
+    }
+}
+
+/// The outcome of [`Client::shutdown_graceful`]
+///
+/// Changes queued via [`Client::submit`] are counted once per batch they were
+/// sent in
+#[derive(Debug, PartialEq)]
+pub struct ShutdownSummary {
+    /// The number of transactions that finished before the deadline
+    pub completed: u64,
+    /// The number of transactions that were sent but were still awaiting a
+    /// response when the deadline elapsed, these may or may not have been
+    /// applied by the server
+    pub abandoned: u64,
+    /// The changes that were never sent to the server before the deadline
+    /// elapsed, in the order they were queued, so that the caller can send
+    /// them later
+    pub unsent: Vec<super::ServerChange>,
 }
 
 /// The current version of the client stream
@@ -75,126 +771,371 @@ pub enum TransactionError {
 /// - 0: Invalid
 /// - 1: The initial version
 ///   Requests are 16-bit length-prefixed JSON, where the JSON is [`ServerChange`]
-///   Responses are the JSON of [`ExecResult`]
-pub const VERSION: u16 = 1;
+///   Responses are the JSON of [`ExecResult`], errors may also have the fields
+///   of an [`ErrorFrame`](super::ErrorFrame)
+/// - 2: The same framing as version 1, the handshake response can report the
+///   load of the server
+/// - 3: The same framing as version 1, the handshake request and response
+///   exchange [`Features`](super::Features). If [`Features::REQUEST_IDS`](super::Features::REQUEST_IDS)
+///   is negotiated, each response is prefixed with the id of its request
+pub const VERSION: u16 = 3;
+
+/// The framing used on the stream, selected by the version of the handshake
+/// response from the server
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Protocol {
+    /// Length-prefixed JSON requests and responses
+    V1,
+    /// The same framing as [`Self::V1`]
+    V2,
+    /// The same framing as [`Self::V1`]
+    V3,
+}
+
+impl Protocol {
+    #[inline]
+    fn version(self) -> u16 {
+        match self {
+            Self::V1 => 1,
+            Self::V2 => 2,
+            Self::V3 => 3,
+        }
+    }
+}
+
+/// The protocol and features negotiated on the current stream, shared between
+/// a [`Client`] and its I/O task, which replaces them if the stream is reopened
+#[derive(Copy, Clone)]
+struct Negotiated {
+    protocol: Protocol,
+    features: super::Features,
+}
+
+/// When the stream to the server was last used, shared between a [`Client`]
+/// and its I/O task
+struct Activity {
+    connected: Instant,
+    /// The microseconds between `connected` and the last frame written or
+    /// response received
+    elapsed_us: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            connected: Instant::now(),
+            elapsed_us: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    fn touch(&self) {
+        self.elapsed_us.store(
+            self.connected.elapsed().as_micros() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    #[inline]
+    fn last(&self) -> Instant {
+        self.connected + Duration::from_micros(self.elapsed_us.load(Ordering::Relaxed))
+    }
+}
 
 /// A persistent connection to a corrosion agent
+///
+/// Each client uses exactly one bidirectional stream for all of its requests
+/// and responses. Responses are matched to requests by their request id if the
+/// server supports [`Features::REQUEST_IDS`](super::Features::REQUEST_IDS),
+/// otherwise by the order they were sent in. Either is only valid as long as
+/// nothing else is sent or received on that stream.
 pub struct Client {
-    inner: quinn::Connection,
+    /// The current connection, replaced if the stream is reopened
+    inner: Arc<Mutex<quinn::Connection>>,
     local_addr: SocketAddr,
-    tx: mpsc::UnboundedSender<(Bytes, ResponseTx)>,
-    task: tokio::task::JoinHandle<Result<Option<quinn::VarInt>, StreamError>>,
+    stream_id: quinn::StreamId,
+    negotiated: Arc<Mutex<Negotiated>>,
+    load: Option<u8>,
+    activity: Arc<Activity>,
+    queue: Queue,
+    task: tokio::task::JoinHandle<CloseReason>,
+    close_reason: Arc<OnceLock<CloseReason>>,
+    submit_tx: mpsc::UnboundedSender<Submission>,
+    coalescer: tokio::task::JoinHandle<()>,
+    transaction_timeout: Option<Duration>,
+    timeouts: Option<TransportTimeouts>,
+    /// Notified by the I/O task each time the stream is reopened
+    reconnected: Arc<Notify>,
+    /// The periodic resync task, stopped when the sender is dropped
+    resync: Option<(oneshot::Sender<()>, tokio::task::JoinHandle<()>)>,
+    /// The resync supplier, replayed by the I/O task when the stream is reopened
+    replay: Arc<Mutex<Option<Supplier>>>,
+    #[cfg(feature = "tokio-metrics")]
+    monitor: tokio_metrics::TaskMonitor,
 }
 
 impl Client {
     /// Connects using a non-encrypted session
+    #[inline]
     pub async fn connect_insecure(
         addr: SocketAddr,
         qcmp_port: u16,
         icao: IcaoCode,
     ) -> Result<Self, ConnectError> {
-        let ep = quinn::Endpoint::client((std::net::Ipv6Addr::LOCALHOST, 0).into())?;
-
-        let inner = ep
-            .connect_with(
-                quinn_plaintext::client_config(),
-                addr,
-                &addr.ip().to_string(),
-            )?
-            .await?;
+        Self::connect_insecure_with_config(addr, qcmp_port, icao, ClientConfig::default()).await
+    }
 
-        // This is really infallible
-        let local_addr = ep.local_addr()?;
+    /// Connects using a non-encrypted session with the specified configuration
+    #[inline]
+    pub async fn connect_insecure_with_config(
+        addr: SocketAddr,
+        qcmp_port: u16,
+        icao: IcaoCode,
+        config: ClientConfig,
+    ) -> Result<Self, ConnectError> {
+        let client_config = config.quic_config(false)?;
+        let (ep, remote) = config.bind(addr)?;
+        Self::establish(
+            &ep,
+            remote,
+            &addr.ip().to_string(),
+            client_config,
+            qcmp_port,
+            icao,
+            config,
+        )
+        .await
+    }
 
-        let client = inner.clone();
-        let (mut send, mut recv) = client.open_bi().await?;
+    /// Connects using a non-encrypted session over an existing endpoint
+    ///
+    /// The caller controls how the endpoint is bound, eg. via [`EndpointBuilder`],
+    /// so [`ClientConfig::bind_addr`] is ignored, and [`ClientConfig::dual_stack`]
+    /// should match how the endpoint was bound. Shutting down the client only
+    /// closes its connection, the endpoint can continue to be used by others
+    pub async fn connect_with_endpoint(
+        ep: &quinn::Endpoint,
+        addr: SocketAddr,
+        qcmp_port: u16,
+        icao: IcaoCode,
+        config: ClientConfig,
+    ) -> Result<Self, ConnectError> {
+        let client_config = config.quic_config(false)?;
+        let remote = config.map_remote(ep.local_addr()?, addr)?;
+        Self::establish(
+            ep,
+            remote,
+            &addr.ip().to_string(),
+            client_config,
+            qcmp_port,
+            icao,
+            config,
+        )
+        .await
+    }
 
-        // Handshake
-        // We need to actually send something for the connection to be fully established
-        let peer_version = {
-            let req = super::ClientHandshakeRequestV1 { qcmp_port, icao }.write();
+    /// Resolves `host` and connects to the first address that accepts the
+    /// connection
+    ///
+    /// If [`ClientConfig::tls_roots`] is set the session is encrypted, and the
+    /// server's certificate must be valid for `host`
+    pub async fn connect(
+        host: &str,
+        port: u16,
+        qcmp_port: u16,
+        icao: IcaoCode,
+        config: ClientConfig,
+    ) -> Result<Self, ConnectError> {
+        let addrs: Vec<_> = tokio::net::lookup_host((host, port)).await?.collect();
+        Self::connect_to_any(&addrs, host, qcmp_port, icao, config).await
+    }
 
-            send.write_chunk(super::write_length_prefixed(&req).freeze())
-                .await
-                .map_err(StreamError::from)?;
+    /// Connects to the first of the addresses that accepts the connection,
+    /// using `server_name` to validate the server's certificate
+    ///
+    /// Attempts are started 250ms apart, alternating between
+    /// address families, in the style of Happy Eyeballs (RFC 8305), so that a
+    /// dead address doesn't prevent connecting to a live one
+    pub async fn connect_to_any(
+        addrs: &[SocketAddr],
+        server_name: &str,
+        qcmp_port: u16,
+        icao: IcaoCode,
+        config: ClientConfig,
+    ) -> Result<Self, ConnectError> {
+        if addrs.is_empty() {
+            return Err(ConnectError::NoAddresses {
+                host: server_name.to_owned(),
+            });
+        }
 
-            let res = super::read_length_prefixed(&mut recv)
-                .await
-                .map_err(StreamError::from)?;
-            match super::ServerHandshake::read(VERSION, &res[..])? {
-                super::ServerHandshake::V1(shs) => {
-                    if !shs.accept {
-                        return Err(ConnectError::Handshake(
-                            crate::persistent::HandshakeError::UnsupportedVersion {
-                                ours: VERSION,
-                                theirs: 1,
-                            },
-                        ));
+        let client_config = config.quic_config(true)?;
+
+        let mut attempts = tokio::task::JoinSet::new();
+        for (i, addr) in interleave_families(addrs).into_iter().enumerate() {
+            let client_config = client_config.clone();
+            let config = config.clone();
+            let server_name = server_name.to_owned();
+
+            attempts.spawn(async move {
+                tokio::time::sleep(ATTEMPT_DELAY * i as u32).await;
+                let res = match config.bind(addr) {
+                    Ok((ep, remote)) => {
+                        Self::establish(
+                            &ep,
+                            remote,
+                            &server_name,
+                            client_config,
+                            qcmp_port,
+                            icao,
+                            config,
+                        )
+                        .await
                     }
+                    Err(error) => Err(error),
+                };
+                (addr, res)
+            });
+        }
 
-                    1
+        let mut errors = Vec::new();
+        while let Some(res) = attempts.join_next().await {
+            match res {
+                // Dropping the set aborts the remaining attempts
+                Ok((_, Ok(client))) => return Ok(client),
+                Ok((addr, Err(error))) => {
+                    tracing::debug!(%addr, %error, "connection attempt failed");
+                    errors.push((addr, error));
+                }
+                Err(error) => {
+                    tracing::warn!(%error, "connection attempt task failed");
                 }
             }
+        }
+
+        Err(ConnectError::AllAddressesFailed(errors))
+    }
+
+    async fn establish(
+        ep: &quinn::Endpoint,
+        addr: SocketAddr,
+        server_name: &str,
+        client_config: quinn::ClientConfig,
+        qcmp_port: u16,
+        icao: IcaoCode,
+        config: ClientConfig,
+    ) -> Result<Self, ConnectError> {
+        let link = Link {
+            ep: ep.clone(),
+            addr,
+            server_name: server_name.to_owned(),
+            client_config,
+            qcmp_port,
+            icao,
         };
 
-        let (tx, mut reqrx) = mpsc::unbounded_channel();
+        let stream = link.open().await?;
 
-        let task = tokio::task::spawn(async move {
-            let func = async || -> Result<Option<quinn::VarInt>, StreamError> {
-                match peer_version {
-                    1 => loop {
-                        let (msg, comp): (_, ResponseTx) = tokio::select! {
-                            res = recv.received_reset() => {
-                                return res.map_err(StreamError::Reset);
-                            }
-                            req = reqrx.recv() => {
-                                let Some(req) = req else {
-                                    let _ = send.reset(quinn::VarInt::from_u32(1));
-                                    let _ = send.finish();
-                                    // We need to drop the recv stream so that the server
-                                    // knows we don't care and it can finish closing the connection
-                                    drop(recv);
-                                    tracing::debug!("waiting for server to received buffered stream...");
-                                    drop(send.stopped().await);
-                                    tracing::debug!("client finished");
-                                    break;
-                                };
-
-                                req
-                            }
-                        };
+        // The endpoint may be bound to the unspecified address, in which case
+        // ask the OS which local address it routes the server's address through
+        let local_addr = {
+            let bound = ep.local_addr()?;
+            if bound.ip().is_unspecified() {
+                let probe = std::net::UdpSocket::bind(SocketAddr::new(bound.ip(), 0))?;
+                probe.connect(addr)?;
+                SocketAddr::new(probe.local_addr()?.ip(), bound.port())
+            } else {
+                bound
+            }
+        };
 
-                        send.write_chunk(msg).await?;
-                        let res = super::read_length_prefixed_jsonb::<ExecResult>(&mut recv)
-                            .await
-                            .map_err(StreamError::from);
+        let stream_id = stream.send.id();
+        let load = stream.load;
+        let negotiated = Arc::new(Mutex::new(Negotiated {
+            protocol: stream.protocol,
+            features: stream.features,
+        }));
+        let inner = Arc::new(Mutex::new(stream.conn.clone()));
+        let activity = Arc::new(Activity::new());
 
-                        if let Err(error) = &res {
-                            tracing::error!(%error, "error occurred reading response to transaction");
-                        }
+        let (tx, reqrx) = mpsc::unbounded_channel();
+        let queue = Queue {
+            tx,
+            stats: Default::default(),
+            unsent: Unsent::default(),
+        };
+        let close_reason = Arc::new(OnceLock::new());
+        let reconnected = Arc::new(Notify::new());
+        let replay = Arc::new(Mutex::new(None));
 
-                        if comp.send(res).is_err() {
-                            tracing::warn!("transaction response could not be sent to queuer");
-                        }
-                    },
-                    _invalid => {
-                        return Err(StreamError::Connect(
-                            quinn::ConnectionError::VersionMismatch,
-                        ));
-                    }
-                }
+        let max_in_flight = config.max_in_flight.unwrap_or(1).max(1);
+        let io = IoLoop {
+            protocol: stream.protocol,
+            session: Session::new(stream, max_in_flight),
+            reqrx,
+            stats: queue.stats.clone(),
+            max_coalesced: config.coalesce.unwrap_or(1).max(1),
+            max_in_flight,
+            max_retries: config.max_retries,
+            reconnect: config
+                .reconnect
+                .or((config.max_retries > 0).then(|| ReconnectPolicy {
+                    max_attempts: config.max_retries.saturating_add(1),
+                    base_delay: REOPEN_DELAY,
+                })),
+            reconnect_idle: config.reconnect.is_some(),
+            link,
+            inner: inner.clone(),
+            negotiated: negotiated.clone(),
+            activity: activity.clone(),
+            reconnected: reconnected.clone(),
+            replay: replay.clone(),
+            unsent: queue.unsent.clone(),
+        };
 
-                Ok(None)
-            };
+        let task_close_reason = close_reason.clone();
+        let io_task = async move {
+            let reason = CloseReason::from(io.run().await);
+            let _ = task_close_reason.set(reason.clone());
+            reason
+        };
 
-            func().await
-        });
+        #[cfg(feature = "tokio-metrics")]
+        let monitor = tokio_metrics::TaskMonitor::new();
+        #[cfg(feature = "tokio-metrics")]
+        let io_task = monitor.instrument(io_task);
+        let task = tokio::task::spawn(io_task);
+
+        let (submit_tx, submit_rx) = mpsc::unbounded_channel();
+        let coalescer = tokio::task::spawn(coalesce(submit_rx, queue.clone(), config.flush));
+
+        if let Some(interval) = config.path_stats_interval {
+            tokio::task::spawn(sample_path_stats(
+                Arc::downgrade(&inner),
+                queue.stats.clone(),
+                interval,
+            ));
+        }
 
         Ok(Self {
             inner,
-            tx,
+            queue,
             task,
+            close_reason,
+            submit_tx,
+            coalescer,
             local_addr,
+            stream_id,
+            negotiated,
+            load,
+            activity,
+            transaction_timeout: config.transaction_timeout,
+            timeouts: config.timeouts(),
+            reconnected,
+            resync: None,
+            replay,
+            #[cfg(feature = "tokio-metrics")]
+            monitor,
         })
     }
 
@@ -202,29 +1143,1550 @@ impl Client {
         self.local_addr
     }
 
+    /// The address of the server the client is currently connected to, which
+    /// follows the connection if the stream is reopened
     pub fn remote_addr(&self) -> SocketAddr {
-        self.inner.remote_address()
+        self.inner.lock().unwrap().remote_address()
     }
 
-    pub async fn transactions(
-        &self,
-        change: &[super::ServerChange],
-    ) -> Result<ExecResult, TransactionError> {
-        let buf = super::write_length_prefixed_jsonb(&change)?;
+    /// Whether the client can still send transactions, ie. its I/O task is
+    /// running and the current connection hasn't been closed
+    ///
+    /// Nothing is sent to the server, so a connection that is lost without
+    /// being closed is only detected once its idle timeout elapses
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        !self.task.is_finished() && self.inner.lock().unwrap().close_reason().is_none()
+    }
 
-        let (tx, rx) = oneshot::channel();
-        self.tx
-            .send((buf.freeze(), tx))
-            .map_err(|_| TransactionError::TaskShutdown)?;
-        Ok(rx.await.map_err(|_| TransactionError::TaskShutdown)??)
+    /// The protocol version negotiated with the server during the handshake,
+    /// which follows the connection if the stream is reopened
+    #[inline]
+    pub fn peer_version(&self) -> u16 {
+        self.negotiated.lock().unwrap().protocol.version()
     }
 
-    /// Closes the connection to the upstream server
-    pub async fn shutdown(self) {
-        drop(self.tx);
-        if let Ok(Err(error)) = self.task.await {
-            tracing::warn!(%error, "stream exited with error");
-        }
-        drop(self.inner);
+    /// The load the server reported when the client connected, ie. its number
+    /// of connections as a percentage of its maximum, if it reports it
+    ///
+    /// This isn't updated if the stream is reopened
+    #[inline]
+    pub fn server_load(&self) -> Option<u8> {
+        self.load
+    }
+
+    /// The features negotiated with the server during the handshake, which
+    /// are none if the server is older than version 3
+    ///
+    /// Like [`Self::peer_version`], these follow the connection if the stream
+    /// is reopened, eg. on a server that was upgraded in the meantime
+    #[inline]
+    pub fn features(&self) -> super::Features {
+        self.negotiated.lock().unwrap().features
+    }
+
+    /// When a frame was last written to, or a response last received from,
+    /// the server, or when the client connected if neither has happened yet
+    #[inline]
+    pub fn last_activity(&self) -> Instant {
+        self.activity.last()
+    }
+
+    /// The QUIC connection to the server, eg. to open additional streams for
+    /// a custom control channel
+    ///
+    /// The client's own request/response stream must not be disturbed, and
+    /// no other stream may be opened before it, which is guaranteed since it
+    /// is opened when connecting. If the client reopens its stream it does so
+    /// on a new connection, so the returned connection may become stale
+    #[inline]
+    pub fn connection(&self) -> quinn::Connection {
+        self.inner.lock().unwrap().clone()
+    }
+
+    /// The statistics for the transactions sent by this client
+    #[inline]
+    pub fn stats(&self) -> Arc<ClientStats> {
+        self.queue.stats.clone()
+    }
+
+    /// The current statistics for the network path to the server
+    ///
+    /// The counters are for the current connection, so are reset if the
+    /// stream is reopened on a new connection
+    #[inline]
+    pub fn path_stats(&self) -> PathStats {
+        PathStats::sample(&self.inner.lock().unwrap())
     }
+
+    /// Why the stream to the server ended, `None` if it is still open
+    #[inline]
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason.get().cloned()
+    }
+
+    /// The keep-alive and idle timeout applied to the connection, `None` if
+    /// they were set by a [transport override](ClientConfig::transport)
+    #[inline]
+    pub fn transport_timeouts(&self) -> Option<TransportTimeouts> {
+        self.timeouts
+    }
+
+    /// The monitor for the I/O task that sends transactions and reads their
+    /// responses
+    #[cfg(feature = "tokio-metrics")]
+    #[inline]
+    pub fn task_monitor(&self) -> tokio_metrics::TaskMonitor {
+        self.monitor.clone()
+    }
+
+    /// The id of the single stream used for requests and responses
+    #[inline]
+    pub fn stream_id(&self) -> quinn::StreamId {
+        self.stream_id
+    }
+
+    /// Sends the changes to the server, waiting for the response
+    ///
+    /// If the client was configured with [`ClientConfig::transaction_timeout`]
+    /// it is applied to the transaction. If the server fails to execute the
+    /// changes, the error it responded with is returned as
+    /// [`TransactionError::Rejected`]
+    #[inline]
+    pub async fn transactions(
+        &self,
+        change: &[super::ServerChange],
+    ) -> Result<ExecResult, TransactionError> {
+        self.send_transaction(
+            change,
+            ChangeCounts::of(change),
+            self.transaction_timeout,
+            super::ServerChange::is_idempotent(change),
+        )
+        .await
+    }
+
+    /// Sends the changes to the server, waiting up to `timeout` for the response
+    ///
+    /// Note that a timed out transaction may still be applied by the server if
+    /// it was sent before the timeout elapsed
+    #[inline]
+    pub async fn transactions_timeout(
+        &self,
+        change: &[super::ServerChange],
+        timeout: Duration,
+    ) -> Result<ExecResult, TransactionError> {
+        self.send_transaction(
+            change,
+            ChangeCounts::of(change),
+            Some(timeout),
+            super::ServerChange::is_idempotent(change),
+        )
+        .await
+    }
+
+    /// Inserts or updates the specified servers
+    #[inline]
+    pub async fn upsert_servers(
+        &self,
+        servers: &[super::ServerUpsert],
+    ) -> Result<ExecResult, TransactionError> {
+        self.send_transaction(
+            &[super::ServerChangeRef::Insert(servers)],
+            ChangeCounts {
+                inserts: servers.len(),
+                ..Default::default()
+            },
+            self.transaction_timeout,
+            true,
+        )
+        .await
+    }
+
+    /// Inserts or updates a single server
+    #[inline]
+    pub async fn upsert_one(
+        &self,
+        endpoint: quilkin_types::Endpoint,
+        icao: IcaoCode,
+        tokens: &quilkin_types::TokenSet,
+    ) -> Result<ExecResult, TransactionError> {
+        self.upsert_servers(&[super::ServerUpsert {
+            endpoint,
+            icao,
+            tokens: tokens.clone(),
+        }])
+        .await
+    }
+
+    /// Removes the specified servers
+    #[inline]
+    pub async fn remove_servers(
+        &self,
+        endpoints: &[quilkin_types::Endpoint],
+    ) -> Result<ExecResult, TransactionError> {
+        self.send_transaction(
+            &[super::ServerChangeRef::Remove(endpoints)],
+            ChangeCounts {
+                removes: endpoints.len(),
+                ..Default::default()
+            },
+            self.transaction_timeout,
+            false,
+        )
+        .await
+    }
+
+    /// Updates one or more columns of the specified servers
+    #[inline]
+    pub async fn update_servers(
+        &self,
+        updates: &[super::ServerUpdate],
+    ) -> Result<ExecResult, TransactionError> {
+        self.send_transaction(
+            &[super::ServerChangeRef::Update(updates)],
+            ChangeCounts {
+                updates: updates.len(),
+                ..Default::default()
+            },
+            self.transaction_timeout,
+            true,
+        )
+        .await
+    }
+
+    /// Sends all of the changes in the batch as a single transaction
+    #[inline]
+    pub async fn apply_batch(
+        &self,
+        batch: super::ChangeBatch,
+    ) -> Result<ExecResult, TransactionError> {
+        self.transactions(&batch.into_changes()).await
+    }
+
+    /// Sends the transaction within a span recording the number of changes,
+    /// the size of the frame, and the outcome
+    async fn send_transaction<T: serde::Serialize + ?Sized>(
+        &self,
+        change: &T,
+        counts: ChangeCounts,
+        timeout: Option<Duration>,
+        idempotent: bool,
+    ) -> Result<ExecResult, TransactionError> {
+        use tracing::Instrument as _;
+
+        let span = tracing::debug_span!(
+            "transaction",
+            inserts = counts.inserts,
+            removes = counts.removes,
+            updates = counts.updates,
+            bytes = tracing::field::Empty,
+            outcome = tracing::field::Empty,
+        );
+
+        async {
+            let span = tracing::Span::current();
+            let res = async {
+                let buf = super::write_length_prefixed_jsonb(&change)?;
+                span.record("bytes", buf.len());
+
+                let rx = self.queue.enqueue(buf.freeze(), idempotent)?;
+
+                let res = if let Some(timeout) = timeout {
+                    tokio::time::timeout(timeout, rx)
+                        .await
+                        .map_err(|_| TransactionError::Timeout { timeout })?
+                } else {
+                    rx.await
+                };
+
+                res.map_err(|_| TransactionError::TaskShutdown)??
+                    .into_result()
+                    .map_err(TransactionError::from)
+            }
+            .await;
+
+            match &res {
+                Ok(_) => {
+                    span.record("outcome", "executed");
+                }
+                Err(TransactionError::Rejected { message, .. }) => {
+                    span.record("outcome", "rejected");
+                    tracing::error!(error = %message, "server failed to execute the transaction");
+                }
+                Err(error) => {
+                    span.record("outcome", "failed");
+                    tracing::error!(%error, "transaction failed");
+                }
+            }
+
+            res
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Queues a single change to be sent to the server
+    ///
+    /// Changes submitted while a transaction is in flight are coalesced into
+    /// a single transaction, the returned future resolves when the transaction
+    /// containing the change completes. The change is queued when this is
+    /// called, not when the future is first polled.
+    ///
+    /// If a [`FlushPolicy`] is configured, changes are also held until the
+    /// policy's limits are reached, or [`Self::flush`] is called.
+    pub fn submit(
+        &self,
+        change: super::ServerChange,
+    ) -> impl Future<Output = Result<(), TransactionError>> + 'static {
+        let (tx, rx) = oneshot::channel();
+        let queued = self.submit_tx.send(Submission::Change(change, tx)).is_ok();
+
+        async move {
+            if !queued {
+                return Err(TransactionError::TaskShutdown);
+            }
+
+            rx.await.map_err(|_| TransactionError::TaskShutdown)?
+        }
+    }
+
+    /// Sends the changes that have been [submitted](Self::submit), but are being
+    /// held by the [`FlushPolicy`], without waiting for its limits to be reached
+    #[inline]
+    pub fn flush(&self) {
+        let _ = self.submit_tx.send(Submission::Flush);
+    }
+
+    /// Periodically sends the full set of servers this client should have
+    /// contributed, as returned by `supplier`, to repair any drift between it
+    /// and the server, eg. due to a lost response or the server's database
+    /// being restored from a backup
+    ///
+    /// A resync is sent every `interval`. Every server in the set is upserted,
+    /// and any server that was in the previous resync's set but no longer is,
+    /// is removed. Servers that were never part of a resync are not removed.
+    /// Each resync is sent as one or more whole transactions in the same queue
+    /// as every other transaction, and is recorded in
+    /// [`ClientStatsSnapshot::resyncs`].
+    ///
+    /// When the stream is reopened, the server will have removed this client
+    /// as a contributor, so the set is upserted again before any other
+    /// transaction is sent on the new stream. If [retries](ClientConfig::max_retries)
+    /// are enabled, a connection that is lost while idle is also reopened
+    /// straight away, rather than when the next transaction is sent, so that
+    /// the servers are restored without waiting for the caller.
+    ///
+    /// Enabling resync again replaces the previous interval and supplier
+    pub fn enable_resync(
+        &mut self,
+        interval: Duration,
+        supplier: impl Fn() -> Vec<super::ServerUpsert> + Send + Sync + 'static,
+    ) {
+        self.disable_resync();
+
+        let supplier: Supplier = Arc::new(supplier);
+        *self.replay.lock().unwrap() = Some(supplier.clone());
+
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let task = tokio::task::spawn(resync(
+            self.queue.clone(),
+            interval,
+            supplier,
+            self.reconnected.clone(),
+            stop_rx,
+        ));
+        self.resync = Some((stop_tx, task));
+    }
+
+    /// Stops the periodic resync, if it was enabled
+    ///
+    /// A resync that is currently in flight is still completed
+    #[inline]
+    pub fn disable_resync(&mut self) {
+        // The task exits once it notices the sender was dropped
+        self.resync.take();
+        self.replay.lock().unwrap().take();
+    }
+
+    /// Stops accepting new transactions, and waits up to `deadline` for all
+    /// outstanding transactions to complete before closing the connection
+    ///
+    /// If the deadline elapses the connection is closed immediately, and any
+    /// transactions that hadn't completed are abandoned. The changes that
+    /// were never sent are returned in [`ShutdownSummary::unsent`]
+    pub async fn shutdown_graceful(self, deadline: Duration) -> ShutdownSummary {
+        let stats = self.queue.stats.clone();
+        let unsent = self.queue.unsent.clone();
+        let dequeued = stats.total_dequeued();
+
+        // Dropping the sender stops the resync task
+        let mut resync = self.resync.map(|(_stop, task)| task);
+        drop(self.submit_tx);
+        drop(self.queue);
+
+        let mut coalescer = self.coalescer;
+        let mut task = self.task;
+
+        let drained = tokio::time::timeout(deadline, async {
+            if let Some(resync) = &mut resync {
+                if let Err(error) = resync.await {
+                    tracing::warn!(%error, "resync task failed");
+                }
+            }
+            if let Err(error) = (&mut coalescer).await {
+                tracing::warn!(%error, "coalescing task failed");
+            }
+            if let Ok(CloseReason::Error(error)) = (&mut task).await {
+                tracing::warn!(%error, "stream exited with error");
+            }
+        })
+        .await
+        .is_ok();
+
+        let (abandoned, unsent) = if drained {
+            (0, Vec::new())
+        } else {
+            tracing::warn!(
+                ?deadline,
+                "shutdown deadline elapsed before all transactions completed"
+            );
+
+            // Aborting the tasks drops the changes they hold, which are then
+            // collected
+            unsent.collect();
+            if let Some(resync) = resync {
+                resync.abort();
+                let _ = resync.await;
+            }
+            coalescer.abort();
+            task.abort();
+            let _ = coalescer.await;
+            let _ = task.await;
+
+            self.inner
+                .lock()
+                .unwrap()
+                .close(quinn::VarInt::from_u32(1), b"shutdown deadline elapsed");
+
+            let (changes, requests) = unsent.take();
+            (stats.snapshot().in_flight.saturating_sub(requests), changes)
+        };
+
+        ShutdownSummary {
+            completed: stats.total_dequeued() - dequeued,
+            abandoned,
+            unsent,
+        }
+    }
+
+    /// Closes the connection to the upstream server, returning why the stream
+    /// ended, which is only `None` if the I/O task panicked
+    ///
+    /// Changes that have already been submitted are sent before the connection
+    /// is closed
+    pub async fn shutdown(self) -> Option<CloseReason> {
+        if let Some((stop, task)) = self.resync {
+            drop(stop);
+            if let Err(error) = task.await {
+                tracing::warn!(%error, "resync task failed");
+            }
+        }
+        drop(self.submit_tx);
+        if let Err(error) = self.coalescer.await {
+            tracing::warn!(%error, "coalescing task failed");
+        }
+        drop(self.queue);
+        let reason = self.task.await.ok();
+        if let Some(CloseReason::Error(error)) = &reason {
+            tracing::warn!(%error, "stream exited with error");
+        }
+        drop(self.inner);
+        reason
+    }
+}
+
+/// Everything needed to open, or reopen, the stream to the server
+struct Link {
+    ep: quinn::Endpoint,
+    addr: SocketAddr,
+    server_name: String,
+    client_config: quinn::ClientConfig,
+    qcmp_port: u16,
+    icao: IcaoCode,
+}
+
+/// An open stream to the server that has completed the handshake
+struct LinkStream {
+    conn: quinn::Connection,
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    protocol: Protocol,
+    /// The load reported in the handshake response
+    load: Option<u8>,
+    /// The features negotiated in the handshake
+    features: super::Features,
+}
+
+impl Link {
+    /// Connects to the server and completes the handshake on a new stream
+    async fn open(&self) -> Result<LinkStream, ConnectError> {
+        let conn = self
+            .ep
+            .connect_with(self.client_config.clone(), self.addr, &self.server_name)?
+            .await?;
+
+        self.handshake(conn.clone()).await.map_err(|error| {
+            // Servers close connections over their limits without completing
+            // the handshake, so the failure is only a symptom of the close
+            match conn.close_reason() {
+                Some(quinn::ConnectionError::ApplicationClosed(close))
+                    if super::ErrorCode::from(close.error_code) == super::ErrorCode::AtCapacity =>
+                {
+                    ConnectError::Rejected {
+                        reason: Some(super::RejectReason::AtCapacity),
+                    }
+                }
+                _ => error,
+            }
+        })
+    }
+
+    /// Opens the stream on a new connection and completes the handshake
+    async fn handshake(&self, conn: quinn::Connection) -> Result<LinkStream, ConnectError> {
+        let (mut send, mut recv) = conn.open_bi().await?;
+
+        // The connection was created just for this client, so the request/response
+        // stream must be the first one, if it isn't something else is using
+        // the connection and the response matching can't be trusted
+        let stream_id = send.id();
+        debug_assert_eq!(
+            stream_id.index(),
+            0,
+            "the client must only open a single bidirectional stream"
+        );
+        if stream_id.index() != 0 {
+            return Err(ConnectError::StreamReused { stream_id });
+        }
+
+        // Handshake
+        // We need to actually send something for the connection to be fully established
+        let (protocol, load, features) = {
+            let req = super::ClientHandshakeRequestV3 {
+                qcmp_port: self.qcmp_port,
+                icao: self.icao,
+                features: super::Features::SUPPORTED,
+            }
+            .write();
+
+            send.write_chunk(super::write_length_prefixed(&req).freeze())
+                .await
+                .map_err(StreamError::from)?;
+
+            let res = super::read_length_prefixed(&mut recv)
+                .await
+                .map_err(StreamError::from)?;
+            let shs = match super::ServerHandshake::read(VERSION, &res[..]) {
+                Ok(shs) => shs,
+                Err(super::HandshakeError::UnsupportedVersion { ours, theirs }) => {
+                    return Err(ConnectError::UnsupportedVersion { ours, theirs });
+                }
+                Err(error) => return Err(error.into()),
+            };
+            match shs {
+                super::ServerHandshake::V1(shs) => {
+                    if !shs.accept {
+                        // Version 1 responses don't say why the connection
+                        // was declined
+                        return Err(ConnectError::Rejected { reason: None });
+                    }
+
+                    (Protocol::V1, None, super::Features::NONE)
+                }
+                super::ServerHandshake::V2(shs) => {
+                    if !shs.accept {
+                        return Err(ConnectError::Rejected { reason: shs.reason });
+                    }
+
+                    (Protocol::V2, shs.load, super::Features::NONE)
+                }
+                super::ServerHandshake::V3(shs) => {
+                    if !shs.accept {
+                        return Err(ConnectError::Rejected { reason: shs.reason });
+                    }
+
+                    // The server can't enable features the client didn't ask for
+                    (
+                        Protocol::V3,
+                        shs.load,
+                        shs.features.intersection(super::Features::SUPPORTED),
+                    )
+                }
+            }
+        };
+
+        Ok(LinkStream {
+            conn,
+            send,
+            recv,
+            protocol,
+            load,
+            features,
+        })
+    }
+}
+
+/// The I/O task for a [`Client`], which sends queued transactions and matches
+/// them to their responses
+struct IoLoop {
+    session: Session,
+    protocol: Protocol,
+    reqrx: mpsc::UnboundedReceiver<Request>,
+    stats: Arc<ClientStats>,
+    max_coalesced: usize,
+    max_in_flight: usize,
+    max_retries: u32,
+    /// How the stream is reopened, if it is
+    reconnect: Option<ReconnectPolicy>,
+    /// Whether the stream is reopened if it fails while idle, even if there
+    /// are no servers to replay
+    reconnect_idle: bool,
+    link: Link,
+    /// The current connection, replaced when the stream is reopened
+    inner: Arc<Mutex<quinn::Connection>>,
+    /// What was negotiated on the current stream, replaced with it
+    negotiated: Arc<Mutex<Negotiated>>,
+    activity: Arc<Activity>,
+    reconnected: Arc<Notify>,
+    replay: Arc<Mutex<Option<Supplier>>>,
+    unsent: Unsent,
+}
+
+/// The number of servers changed by each kind of change in a transaction
+#[derive(Copy, Clone, Default)]
+struct ChangeCounts {
+    inserts: usize,
+    removes: usize,
+    updates: usize,
+}
+
+impl ChangeCounts {
+    fn of(changes: &[super::ServerChange]) -> Self {
+        use super::ServerChange as Sc;
+
+        let mut counts = Self::default();
+        for change in changes {
+            match change {
+                Sc::Insert(upserts) => counts.inserts += upserts.len(),
+                Sc::Remove(endpoints) => counts.removes += endpoints.len(),
+                Sc::Update(updates) => counts.updates += updates.len(),
+            }
+        }
+        counts
+    }
+}
+
+/// A frame containing one or more transactions
+struct Frame {
+    batch: Vec<Request>,
+    /// The id of the frame on the stream it was last written to
+    id: u32,
+    /// The number of times the frame has been sent again on a new stream
+    attempt: u32,
+    written: Instant,
+}
+
+/// The halves of an open stream
+///
+/// The receive half is read by a separate task, so that waiting for a
+/// response can be interrupted to write more frames without losing a
+/// partially read response
+struct Session {
+    send: quinn::SendStream,
+    responses: mpsc::Receiver<Result<Bytes, StreamError>>,
+    /// Resolves to how the stream ended once a read fails
+    reader: tokio::task::JoinHandle<Result<Option<quinn::VarInt>, StreamError>>,
+    /// Whether responses are matched to frames by id, rather than in the order
+    /// the frames were written
+    request_ids: bool,
+    /// The id of the next frame written to the stream
+    next_id: u32,
+}
+
+impl Session {
+    fn new(stream: LinkStream, max_in_flight: usize) -> Self {
+        let (tx, responses) = mpsc::channel(max_in_flight);
+
+        Self {
+            send: stream.send,
+            responses,
+            reader: tokio::task::spawn(read_responses(stream.recv, tx)),
+            request_ids: stream.features.contains(super::Features::REQUEST_IDS),
+            next_id: 0,
+        }
+    }
+
+    /// How the stream ended, after the reader reported an error
+    async fn ended(&mut self) -> Result<Option<quinn::VarInt>, StreamError> {
+        (&mut self.reader)
+            .await
+            .unwrap_or(Err(StreamError::StreamEnded))
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl IoLoop {
+    async fn run(mut self) -> Result<Option<quinn::VarInt>, StreamError> {
+        // A request that didn't fit in the previous coalesced frame
+        let mut held = None;
+        // Frames that are sent again on a new stream before any new frames
+        let mut retry = VecDeque::new();
+        // Frames that have been written, in the order they were written
+        let mut in_flight = VecDeque::new();
+        let mut closed = false;
+
+        match self.protocol {
+            Protocol::V1 | Protocol::V2 | Protocol::V3 => loop {
+                if in_flight.len() < self.max_in_flight {
+                    let frame = match retry.pop_front() {
+                        Some(frame) => Some(frame),
+                        None => held.take().map(|req| self.coalesce(req, &mut held)),
+                    };
+
+                    if let Some(frame) = frame {
+                        self.write(frame, &mut in_flight, &mut retry).await?;
+                        continue;
+                    }
+                }
+
+                if closed && in_flight.is_empty() {
+                    break;
+                }
+
+                let accept = !closed && in_flight.len() < self.max_in_flight;
+                tokio::select! {
+                    res = self.session.responses.recv() => {
+                        match res {
+                            Some(res) if !in_flight.is_empty() => {
+                                if let Some(ended) = self.response(res, &mut in_flight, &mut retry).await? {
+                                    return ended;
+                                }
+                            }
+                            Some(Ok(_)) => {
+                                tracing::warn!("ignoring response received while no transactions were in flight");
+                            }
+                            // The stream was reset or failed while idle
+                            Some(Err(_)) | None => {
+                                match self.session.ended().await {
+                                    // Reopen the stream now if there are servers
+                                    // to restore, rather than when the next
+                                    // transaction is sent
+                                    Err(error)
+                                        if self.reconnect.is_some()
+                                            && error.is_retryable()
+                                            && (self.reconnect_idle
+                                                || self.replay.lock().unwrap().is_some()) =>
+                                    {
+                                        self.recover(error, Vec::new(), &mut retry).await?;
+                                    }
+                                    ended => return ended,
+                                }
+                            }
+                        }
+                    }
+                    req = self.reqrx.recv(), if accept => {
+                        let Some(req) = req else {
+                            // Wait for the responses to every transaction
+                            // that was already sent before closing the stream
+                            closed = true;
+                            continue;
+                        };
+
+                        // If the caller has already given up on the transaction
+                        // there is no point sending it
+                        if req.comp.is_closed() {
+                            tracing::debug!("skipping transaction abandoned before it was sent");
+                            self.stats.dequeued(1);
+                            continue;
+                        }
+
+                        held = Some(req);
+                    }
+                }
+            },
+        }
+
+        let _ = self.session.send.reset(quinn::VarInt::from_u32(1));
+        let _ = self.session.send.finish();
+        // We need to drop the recv stream so that the server
+        // knows we don't care and it can finish closing the connection
+        self.session.reader.abort();
+        let _ = (&mut self.session.reader).await;
+        tracing::debug!("waiting for server to received buffered stream...");
+        drop(self.session.send.stopped().await);
+        tracing::debug!("client finished");
+
+        Ok(None)
+    }
+
+    /// Creates a frame starting with `req`, coalescing it with requests that
+    /// are already queued, up to the maximum
+    fn coalesce(&mut self, req: Request, held: &mut Option<Request>) -> Frame {
+        let mut len = req.msg.len();
+        let mut batch = vec![req];
+
+        while batch.len() < self.max_coalesced {
+            let Ok(req) = self.reqrx.try_recv() else {
+                break;
+            };
+
+            if req.comp.is_closed() {
+                self.stats.dequeued(1);
+                continue;
+            }
+
+            // Joining drops the length prefix and brackets of
+            // the request, and adds a `,`
+            let joined = len + req.msg.len() - 3;
+            if joined > MAX_FRAME_LEN {
+                *held = Some(req);
+                break;
+            }
+
+            len = joined;
+            batch.push(req);
+        }
+
+        Frame {
+            batch,
+            id: 0,
+            attempt: 0,
+            written: Instant::now(),
+        }
+    }
+
+    /// Writes the frame to the stream
+    ///
+    /// If the write fails with a retryable error, the stream is reopened, see
+    /// [`Self::recover`]
+    async fn write(
+        &mut self,
+        mut frame: Frame,
+        in_flight: &mut VecDeque<Frame>,
+        retry: &mut VecDeque<Frame>,
+    ) -> Result<(), StreamError> {
+        let msg = if frame.batch.len() == 1 {
+            frame.batch[0].msg.clone()
+        } else {
+            join_frames(&frame.batch)
+        };
+
+        let len = msg.len();
+        frame.written = Instant::now();
+
+        match self.session.send.write_chunk(msg).await {
+            Ok(()) => {
+                // The server numbers frames in the order it receives them
+                frame.id = self.session.next_id;
+                self.session.next_id = self.session.next_id.wrapping_add(1);
+                for req in &mut frame.batch {
+                    req.unsent.written = true;
+                }
+                self.activity.touch();
+                self.stats.sent(len);
+                self.stats.coalesced(frame.batch.len() - 1);
+                in_flight.push_back(frame);
+                Ok(())
+            }
+            Err(error) => {
+                let error = StreamError::from(error);
+                if self.reconnect.is_none() || !error.is_retryable() {
+                    // The stream can't be used any more, the queued requests
+                    // are failed when they are dropped with the task
+                    return Err(error);
+                }
+
+                // A frame that couldn't be written was never sent
+                self.lost(in_flight);
+                let lost = in_flight.drain(..).chain(std::iter::once(frame)).collect();
+                self.recover(error, lost, retry).await
+            }
+        }
+    }
+
+    /// Responds to the frame in flight that the result is for, which is the
+    /// frame with the response's id if the stream uses request ids, otherwise
+    /// the oldest frame
+    ///
+    /// Returns how the stream ended if the response couldn't be read and the
+    /// frames in flight couldn't be retried
+    async fn response(
+        &mut self,
+        res: Result<Bytes, StreamError>,
+        in_flight: &mut VecDeque<Frame>,
+        retry: &mut VecDeque<Frame>,
+    ) -> Result<Option<Result<Option<quinn::VarInt>, StreamError>>, StreamError> {
+        if let Ok(buf) = &res {
+            self.activity.touch();
+            self.stats.received(buf.len() + 2);
+        }
+
+        let (index, res) = match res {
+            Ok(buf) if self.session.request_ids => {
+                let Some((id, buf)) = super::split_request_id(buf) else {
+                    tracing::warn!("ignoring response without a request id");
+                    return Ok(None);
+                };
+                let Some(index) = in_flight.iter().position(|frame| frame.id == id) else {
+                    tracing::warn!(id, "ignoring response to a request that isn't in flight");
+                    return Ok(None);
+                };
+                (index, Ok(buf))
+            }
+            res => (0, res),
+        };
+
+        let read_failed = res.is_err();
+        let res = res.and_then(|buf| {
+            serde_json::from_slice::<super::Response>(&buf).map_err(StreamError::Json)
+        });
+
+        let mut frame = in_flight.remove(index).unwrap();
+
+        self.stats.finished(
+            matches!(res, Ok(super::Response::Result(ExecResult::Execute { .. }))),
+            frame.batch[0].queued.elapsed(),
+            frame.written.elapsed(),
+        );
+
+        match res {
+            Err(error) if self.reconnect.is_some() && error.is_retryable() => {
+                self.lost(in_flight);
+                let lost = std::iter::once(frame).chain(in_flight.drain(..)).collect();
+                self.recover(error, lost, retry).await?;
+                Ok(None)
+            }
+            Err(error) if read_failed => {
+                tracing::error!(%error, "error occurred reading response to transaction");
+
+                // Nothing more can be read from the stream, so none of the
+                // other frames in flight will receive a response either
+                self.lost(in_flight);
+                let remaining = in_flight
+                    .iter()
+                    .map(|frame| frame.batch.len())
+                    .sum::<usize>();
+                self.stats.dequeued(frame.batch.len() + remaining);
+
+                if in_flight.is_empty() {
+                    respond_all(&mut frame.batch, Err(error));
+                } else {
+                    let error = Arc::new(error);
+                    for frame in std::iter::once(frame).chain(in_flight.drain(..)) {
+                        for req in frame.batch {
+                            respond(req.comp, Err(StreamError::Coalesced(error.clone())));
+                        }
+                    }
+                }
+
+                Ok(Some(self.session.ended().await))
+            }
+            res => {
+                if let Err(error) = &res {
+                    tracing::error!(%error, "error occurred reading response to transaction");
+                }
+
+                // The response is always read, even if the caller timed
+                // out, so that the next response is matched to the
+                // correct request
+                self.stats.dequeued(frame.batch.len());
+                respond_all(&mut frame.batch, res);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Records the frames in flight as failed, as the stream they were sent on
+    /// failed before their responses were received
+    fn lost(&self, in_flight: &VecDeque<Frame>) {
+        for frame in in_flight {
+            self.stats.finished(
+                false,
+                frame.batch[0].queued.elapsed(),
+                frame.written.elapsed(),
+            );
+        }
+    }
+
+    /// Reopens the stream after it failed with a retryable error
+    ///
+    /// The requests in the frames lost with the stream that are idempotent are
+    /// sent again on the new stream, before any new requests, up to the
+    /// maximum number of retries. The others fail with the error
+    async fn recover(
+        &mut self,
+        error: StreamError,
+        lost: Vec<Frame>,
+        retry: &mut VecDeque<Frame>,
+    ) -> Result<(), StreamError> {
+        tracing::warn!(%error, "stream failed with a retryable error, reopening stream");
+
+        // Requests that may not be safe to apply more than once, eg. removes,
+        // fail with the original error
+        let mut failed = Vec::new();
+        let mut resend = Vec::with_capacity(lost.len());
+        for mut frame in lost {
+            let attempt = frame.attempt;
+            let (mut fail, keep): (Vec<_>, Vec<_>) = frame
+                .batch
+                .drain(..)
+                .partition(|req| !req.idempotent || attempt >= self.max_retries);
+            failed.append(&mut fail);
+
+            if !keep.is_empty() {
+                frame.batch = keep;
+                frame.attempt += 1;
+                resend.push(frame);
+            }
+        }
+        self.stats.dequeued(failed.len());
+
+        let error = if resend.is_empty() && retry.is_empty() {
+            respond_all(&mut failed, Err(error));
+            None
+        } else {
+            let error = Arc::new(error);
+            for req in failed {
+                respond(req.comp, Err(StreamError::Coalesced(error.clone())));
+            }
+            Some(error)
+        };
+
+        match self.reopen().await {
+            Ok(stream) => {
+                // The server may have been upgraded or downgraded while the
+                // stream was down. Every version frames requests the same way,
+                // so the frames waiting to be sent again are still valid
+                self.protocol = stream.protocol;
+                *self.negotiated.lock().unwrap() = Negotiated {
+                    protocol: stream.protocol,
+                    features: stream.features,
+                };
+                *self.inner.lock().unwrap() = stream.conn.clone();
+                self.session = Session::new(stream, self.max_in_flight);
+                self.stats.reconnected();
+                self.reconnected.notify_one();
+            }
+            Err(reconnect) => {
+                tracing::error!(error = %reconnect, "failed to reopen stream");
+                if let Some(error) = error {
+                    for frame in resend.into_iter().chain(retry.drain(..)) {
+                        self.stats.dequeued(frame.batch.len());
+                        for req in frame.batch {
+                            respond(req.comp, Err(StreamError::Coalesced(error.clone())));
+                        }
+                    }
+                }
+                return Err(StreamError::Reconnect(Box::new(reconnect)));
+            }
+        }
+
+        // The lost frames were written before the frames that were already
+        // waiting to be sent again
+        for frame in resend.into_iter().rev() {
+            self.stats.retried(frame.batch.len());
+            retry.push_front(frame);
+        }
+
+        self.replay(retry);
+        Ok(())
+    }
+
+    /// Opens a new stream, trying again with an exponential backoff up to the
+    /// maximum number of attempts, eg. while the server is restarting
+    ///
+    /// Errors that aren't [retryable](ConnectError::is_retryable) are returned
+    /// immediately
+    async fn reopen(&self) -> Result<LinkStream, ConnectError> {
+        let policy = self.reconnect.unwrap_or(ReconnectPolicy {
+            max_attempts: 1,
+            base_delay: REOPEN_DELAY,
+        });
+
+        let mut attempt = 1;
+        loop {
+            match self.link.open().await {
+                Err(error) if attempt < policy.max_attempts && error.is_retryable() => {
+                    let delay = policy.base_delay * 2u32.pow((attempt - 1).min(6));
+                    tracing::warn!(%error, ?delay, "failed to reopen stream, trying again");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Queues the full set of servers from the resync supplier, if resync is
+    /// enabled, to be sent on the new stream before any other frames
+    fn replay(&self, retry: &mut VecDeque<Frame>) {
+        let Some(supplier) = self.replay.lock().unwrap().clone() else {
+            return;
+        };
+
+        let desired = supplier();
+        if desired.is_empty() {
+            return;
+        }
+
+        let mut frames = Vec::new();
+        if let Err(error) = split_frames(super::ServerChange::Insert(desired), &mut frames) {
+            tracing::warn!(%error, "failed to replay servers after reopening stream");
+            self.stats.resynced(false);
+            return;
+        }
+
+        let mut pending = Vec::with_capacity(frames.len());
+        for (msg, idempotent) in frames.into_iter().rev() {
+            let (comp, rx) = oneshot::channel();
+            self.stats.queued();
+            retry.push_front(Frame {
+                batch: vec![Request::new(msg, idempotent, comp, &self.unsent)],
+                attempt: 0,
+                written: Instant::now(),
+            });
+            pending.push(rx);
+        }
+
+        let stats = self.stats.clone();
+        tokio::task::spawn(async move {
+            let mut replayed = true;
+            for rx in pending {
+                match rx.await {
+                    Ok(Ok(response)) => {
+                        if let Err(error) = response.into_result() {
+                            tracing::warn!(error = %error.error, "server rejected replayed servers");
+                            replayed = false;
+                        }
+                    }
+                    Ok(Err(error)) => {
+                        tracing::warn!(%error, "failed to replay servers");
+                        replayed = false;
+                    }
+                    Err(_) => replayed = false,
+                }
+            }
+            stats.resynced(replayed);
+        });
+    }
+}
+
+/// Reads responses from the stream until a read fails, returning how the
+/// stream ended
+async fn read_responses(
+    mut recv: quinn::RecvStream,
+    tx: mpsc::Sender<Result<Bytes, StreamError>>,
+) -> Result<Option<quinn::VarInt>, StreamError> {
+    use quinn::{ReadError, ReadExactError};
+
+    let error = loop {
+        match super::read_length_prefixed(&mut recv).await {
+            Ok(buf) => {
+                if tx.send(Ok(buf)).await.is_err() {
+                    return Ok(None);
+                }
+            }
+            Err(error) => break StreamError::from(error),
+        }
+    };
+
+    let ended = match &error {
+        StreamError::Read(ReadError::Reset(code))
+        | StreamError::ReadExact(ReadExactError::ReadError(ReadError::Reset(code))) => {
+            Ok(Some(*code))
+        }
+        StreamError::ReadExact(ReadExactError::FinishedEarly(_)) | StreamError::StreamEnded => {
+            Ok(None)
+        }
+        StreamError::Read(error) => Err(StreamError::Read(error.clone())),
+        StreamError::ReadExact(error) => Err(StreamError::ReadExact(error.clone())),
+        StreamError::LengthMismatch { expected, received } => Err(StreamError::LengthMismatch {
+            expected: *expected,
+            received: *received,
+        }),
+        _ => Err(StreamError::StreamEnded),
+    };
+
+    let _ = tx.send(Err(error)).await;
+    ended
+}
+
+/// Orders the addresses so that address families alternate, starting with the
+/// family of the first address
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(|addr| addr.is_ipv6());
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.iter().partition(|addr| addr.is_ipv6() == first_v6);
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    for i in 0..preferred.len().max(other.len()) {
+        ordered.extend(preferred.get(i));
+        ordered.extend(other.get(i));
+    }
+
+    ordered
+}
+
+/// Sends the response for a transaction back to the queuer
+#[inline]
+fn respond(comp: ResponseTx, res: Result<super::Response, StreamError>) {
+    if comp.send(res).is_err() {
+        tracing::debug!("transaction response arrived after the queuer stopped waiting");
+    }
+}
+
+/// Responds to every request in the batch with the same result
+fn respond_all(batch: &mut Vec<Request>, res: Result<super::Response, StreamError>) {
+    if batch.len() == 1 {
+        respond(batch.pop().unwrap().comp, res);
+    } else {
+        let res = res.map_err(Arc::new);
+        for req in batch.drain(..) {
+            let res = match &res {
+                Ok(res) => Ok(res.clone()),
+                Err(error) => Err(StreamError::Coalesced(error.clone())),
+            };
+            respond(req.comp, res);
+        }
+    }
+}
+
+/// Joins the JSON arrays of multiple requests into a single frame, preserving
+/// the order they were queued in
+fn join_frames(batch: &[Request]) -> Bytes {
+    let len = batch.iter().map(|req| req.msg.len() - 3).sum::<usize>() + 3;
+    let mut buf = bytes::BytesMut::with_capacity(len);
+    buf.extend_from_slice(&[0, 0, b'[']);
+
+    let mut first = true;
+    for req in batch {
+        // Strip the length prefix and the array brackets
+        let inner = &req.msg[3..req.msg.len() - 1];
+        if inner.is_empty() {
+            continue;
+        }
+
+        if !first {
+            buf.extend_from_slice(b",");
+        }
+        buf.extend_from_slice(inner);
+        first = false;
+    }
+
+    buf.extend_from_slice(b"]");
+    super::update_length_prefix(&mut buf);
+    buf.freeze()
+}
+
+/// The maximum size of a frame, including the length prefix
+const MAX_FRAME_LEN: usize = u16::MAX as usize + 2;
+
+/// The changes held by the coalescing task that haven't been queued yet,
+/// returned to the [`Unsent`] sink if the task is dropped
+struct Pending {
+    rx: mpsc::UnboundedReceiver<Submission>,
+    held: Option<(super::ServerChange, SubmitTx)>,
+    changes: Vec<super::ServerChange>,
+    unsent: Unsent,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if !self.unsent.is_collecting() {
+            return;
+        }
+
+        let mut submitted: Vec<_> = self.changes.drain(..).collect();
+        submitted.extend(self.held.take().map(|(change, _)| change));
+        while let Ok(submission) = self.rx.try_recv() {
+            if let Submission::Change(change, _) = submission {
+                submitted.push(change);
+            }
+        }
+
+        if let Some(unsent) = self.unsent.0.lock().unwrap().as_mut() {
+            unsent.submitted.extend(submitted);
+        }
+    }
+}
+
+/// Batches changes queued via [`Client::submit`] into transactions
+///
+/// Only one transaction is in flight at a time, any changes queued while
+/// waiting for its response are sent together in the next transaction, up to
+/// the maximum frame size. If there is a flush policy, the batch also waits
+/// for more changes until one of its limits is reached
+async fn coalesce(
+    rx: mpsc::UnboundedReceiver<Submission>,
+    queue: Queue,
+    policy: Option<FlushPolicy>,
+) {
+    // The number of bytes the change adds to a batch, ie. its JSON plus the
+    // trailing `,` or `]`
+    let change_len = |change: &super::ServerChange| {
+        super::ServerChange::wire_len(std::slice::from_ref(change)).map(|len| len - 3)
+    };
+
+    let mut pending = Pending {
+        rx,
+        held: None,
+        changes: Vec::new(),
+        unsent: queue.unsent.clone(),
+    };
+    let mut waiters = Vec::new();
+
+    loop {
+        let next = match pending.held.take() {
+            Some(next) => Some(next),
+            None => loop {
+                match pending.rx.recv().await {
+                    Some(Submission::Change(change, comp)) => break Some((change, comp)),
+                    // Nothing is pending, so there is nothing to flush
+                    Some(Submission::Flush) => {}
+                    None => break None,
+                }
+            },
+        };
+        let Some(first) = next else {
+            break;
+        };
+
+        // The length prefix and the opening `[`
+        let mut len = 3;
+        let deadline = policy.map(|policy| tokio::time::Instant::now() + policy.max_delay);
+        let mut next = Some(first);
+
+        loop {
+            let (change, comp) = match next.take() {
+                Some(next) => next,
+                None => match pending.rx.try_recv() {
+                    Ok(Submission::Change(change, comp)) => (change, comp),
+                    Ok(Submission::Flush) | Err(mpsc::error::TryRecvError::Disconnected) => break,
+                    Err(mpsc::error::TryRecvError::Empty) => {
+                        let Some(deadline) = deadline else {
+                            break;
+                        };
+
+                        match tokio::time::timeout_at(deadline, pending.rx.recv()).await {
+                            Ok(Some(Submission::Change(change, comp))) => (change, comp),
+                            Ok(Some(Submission::Flush) | None) | Err(_) => break,
+                        }
+                    }
+                },
+            };
+
+            let clen = match change_len(&change) {
+                Ok(clen) => clen,
+                Err(error) => {
+                    let _ = comp.send(Err(error.into()));
+                    continue;
+                }
+            };
+
+            if 3 + clen > MAX_FRAME_LEN {
+                let _ = comp.send(Err(TransactionError::TooLarge { len: clen - 1 }));
+                continue;
+            }
+
+            if len + clen > MAX_FRAME_LEN {
+                pending.held = Some((change, comp));
+                break;
+            }
+
+            len += clen;
+            pending.changes.push(change);
+            waiters.push(comp);
+
+            if policy.is_some_and(|policy| {
+                pending.changes.len() >= policy.max_changes || len >= policy.max_bytes
+            }) {
+                break;
+            }
+        }
+
+        if pending.changes.is_empty() {
+            continue;
+        }
+
+        let res = async {
+            let buf = super::write_length_prefixed_jsonb(&pending.changes);
+            let idempotent = super::ServerChange::is_idempotent(&pending.changes);
+            // The changes are now owned by the queued request
+            pending.changes.clear();
+
+            let rx = queue.enqueue(buf?.freeze(), idempotent)?;
+            Ok::<_, TransactionError>(rx.await.map_err(|_| TransactionError::TaskShutdown)??)
+        }
+        .await;
+
+        match res.map(super::Response::into_result) {
+            Ok(Ok(_)) => {
+                for comp in waiters.drain(..) {
+                    let _ = comp.send(Ok(()));
+                }
+            }
+            Ok(Err(error)) => {
+                for comp in waiters.drain(..) {
+                    let _ = comp.send(Err(error.clone().into()));
+                }
+            }
+            Err(error) => {
+                let error = Arc::new(error);
+                for comp in waiters.drain(..) {
+                    let _ = comp.send(Err(TransactionError::BatchFailed(error.clone())));
+                }
+            }
+        }
+    }
+}
+
+/// Records the path statistics of the connection every `interval`, until the
+/// client and its I/O task are dropped
+async fn sample_path_stats(
+    conn: std::sync::Weak<Mutex<quinn::Connection>>,
+    stats: Arc<ClientStats>,
+    interval: Duration,
+) {
+    let mut timer = tokio::time::interval(interval);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        timer.tick().await;
+
+        let Some(conn) = conn.upgrade() else {
+            break;
+        };
+        let path = PathStats::sample(&conn.lock().unwrap());
+        stats.sampled_path(path);
+    }
+}
+
+/// Periodically sends the full set of servers returned by the supplier, see
+/// [`Client::enable_resync`]
+async fn resync(
+    queue: Queue,
+    interval: Duration,
+    supplier: Supplier,
+    reconnected: Arc<Notify>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    // The servers sent in the last resync, any that are no longer desired
+    // need to be removed
+    let mut synced = std::collections::BTreeSet::new();
+
+    loop {
+        tokio::select! {
+            _ = &mut stop => break,
+            _ = timer.tick() => {}
+            // The I/O task replays the servers itself when the stream is
+            // reopened, so just wait for the full interval from now
+            () = reconnected.notified() => {
+                timer.reset();
+                continue;
+            }
+        }
+
+        let desired = supplier();
+        let current: std::collections::BTreeSet<_> = desired
+            .iter()
+            .map(|upsert| upsert.endpoint.clone())
+            .collect();
+        let removed: Vec<_> = synced.difference(&current).cloned().collect();
+
+        let res = async {
+            let mut frames = Vec::new();
+            if !removed.is_empty() {
+                split_frames(super::ServerChange::Remove(removed), &mut frames)?;
+            }
+            if !desired.is_empty() {
+                split_frames(super::ServerChange::Insert(desired), &mut frames)?;
+            }
+
+            for (frame, idempotent) in frames {
+                let rx = queue.enqueue(frame, idempotent)?;
+                rx.await
+                    .map_err(|_| TransactionError::TaskShutdown)??
+                    .into_result()?;
+            }
+
+            Ok::<_, TransactionError>(())
+        }
+        .await;
+
+        match res {
+            Ok(()) => {
+                queue.stats.resynced(true);
+                synced = current;
+            }
+            Err(TransactionError::TaskShutdown) => break,
+            Err(error) => {
+                tracing::warn!(%error, "failed to resync servers");
+                queue.stats.resynced(false);
+                // Some of the removes may not have been applied, so keep
+                // them until a resync succeeds
+                synced.extend(current);
+            }
+        }
+    }
+}
+
+/// Serializes the change into one or more frames, splitting it in half until
+/// each part fits in a single frame
+fn split_frames(
+    change: super::ServerChange,
+    frames: &mut Vec<(Bytes, bool)>,
+) -> Result<(), TransactionError> {
+    use super::ServerChange as Sc;
+
+    let changes = [change];
+    let len = Sc::wire_len(&changes)?;
+    if len <= MAX_FRAME_LEN {
+        let idempotent = Sc::is_idempotent(&changes);
+        frames.push((
+            super::write_length_prefixed_jsonb(&changes)?.freeze(),
+            idempotent,
+        ));
+        return Ok(());
+    }
+
+    let [change] = changes;
+    if change.len() <= 1 {
+        return Err(TransactionError::TooLarge { len: len - 4 });
+    }
+
+    let (first, second) = match change {
+        Sc::Insert(mut upserts) => {
+            let second = upserts.split_off(upserts.len() / 2);
+            (Sc::Insert(upserts), Sc::Insert(second))
+        }
+        Sc::Remove(mut endpoints) => {
+            let second = endpoints.split_off(endpoints.len() / 2);
+            (Sc::Remove(endpoints), Sc::Remove(second))
+        }
+        Sc::Update(mut updates) => {
+            let second = updates.split_off(updates.len() / 2);
+            (Sc::Update(updates), Sc::Update(second))
+        }
+    };
+
+    split_frames(first, frames)?;
+    split_frames(second, frames)
 }
@@ -0,0 +1,282 @@
+//! Statistics for the transactions sent by a [`super::Client`]
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The number of latency buckets, the upper bound of bucket `i` is
+/// `16 << i` microseconds, the last bucket also contains everything above it
+const BUCKETS: usize = 24;
+const MIN_BUCKET_US: u64 = 16;
+
+/// A histogram of latencies with exponentially sized buckets
+#[derive(Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; BUCKETS],
+    sum_us: AtomicU64,
+}
+
+impl LatencyHistogram {
+    #[inline]
+    pub fn record(&self, latency: Duration) {
+        let us = (latency.as_micros() as u64).max(1);
+        let index = ((64 - ((us - 1) / MIN_BUCKET_US).leading_zeros()) as usize).min(BUCKETS - 1);
+
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let buckets: [u64; BUCKETS] =
+            std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed));
+        let count = buckets.iter().sum::<u64>();
+        let sum_us = self.sum_us.load(Ordering::Relaxed);
+
+        // The upper bound of the bucket containing the specified quantile
+        let quantile = |q: f64| {
+            if count == 0 {
+                return Duration::ZERO;
+            }
+
+            let target = ((count as f64 * q).ceil() as u64).max(1);
+            let mut seen = 0;
+            let index = buckets
+                .iter()
+                .position(|b| {
+                    seen += b;
+                    seen >= target
+                })
+                .unwrap_or(BUCKETS - 1);
+
+            Duration::from_micros(MIN_BUCKET_US << index)
+        };
+
+        LatencySnapshot {
+            count,
+            mean: sum_us
+                .checked_div(count)
+                .map_or(Duration::ZERO, Duration::from_micros),
+            p50: quantile(0.5),
+            p99: quantile(0.99),
+        }
+    }
+}
+
+/// A point in time view of a [`LatencyHistogram`]
+///
+/// The percentiles are the upper bound of the bucket they fall in, so are
+/// only accurate to within a factor of 2
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// The number of latencies recorded
+    pub count: u64,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+/// Statistics for the transactions sent by a client, updated by its I/O task
+#[derive(Default)]
+pub struct ClientStats {
+    in_flight: AtomicU64,
+    dequeued: AtomicU64,
+    sent: AtomicU64,
+    coalesced: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    retried: AtomicU64,
+    reconnects: AtomicU64,
+    resyncs: AtomicU64,
+    failed_resyncs: AtomicU64,
+    /// The last sampled path statistics
+    path_rtt_us: AtomicU64,
+    path_cwnd: AtomicU64,
+    path_lost_packets: AtomicU64,
+    path_sent_packets: AtomicU64,
+    path_congestion_events: AtomicU64,
+    /// The latency from when a transaction was queued until its response was received
+    queued_latency: LatencyHistogram,
+    /// The latency from when a transaction was written until its response was received
+    write_latency: LatencyHistogram,
+}
+
+impl ClientStats {
+    #[inline]
+    pub(super) fn queued(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Transactions were removed from the queue, either because they were
+    /// handled or abandoned
+    #[inline]
+    pub(super) fn dequeued(&self, count: usize) {
+        self.in_flight.fetch_sub(count as u64, Ordering::Relaxed);
+        self.dequeued.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// The total number of transactions that have been removed from the queue
+    #[inline]
+    pub(super) fn total_dequeued(&self) -> u64 {
+        self.dequeued.load(Ordering::Relaxed)
+    }
+
+    /// Transactions were sent in the same frame as another transaction
+    #[inline]
+    pub(super) fn coalesced(&self, count: usize) {
+        self.coalesced.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(super) fn sent(&self, bytes: usize) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(super) fn received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Transactions are being sent again after the stream failed
+    #[inline]
+    pub(super) fn retried(&self, count: usize) {
+        self.retried.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(super) fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(super) fn resynced(&self, success: bool) {
+        if success {
+            self.resyncs.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failed_resyncs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub(super) fn sampled_path(&self, path: PathStats) {
+        self.path_rtt_us
+            .store(path.rtt.as_micros() as u64, Ordering::Relaxed);
+        self.path_cwnd.store(path.cwnd, Ordering::Relaxed);
+        self.path_lost_packets
+            .store(path.lost_packets, Ordering::Relaxed);
+        self.path_sent_packets
+            .store(path.sent_packets, Ordering::Relaxed);
+        self.path_congestion_events
+            .store(path.congestion_events, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(super) fn finished(&self, success: bool, queued: Duration, written: Duration) {
+        if success {
+            self.completed.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.queued_latency.record(queued);
+        self.write_latency.record(written);
+    }
+
+    /// Takes a snapshot of the current statistics
+    pub fn snapshot(&self) -> ClientStatsSnapshot {
+        ClientStatsSnapshot {
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            retried: self.retried.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            resyncs: self.resyncs.load(Ordering::Relaxed),
+            failed_resyncs: self.failed_resyncs.load(Ordering::Relaxed),
+            path: PathStats {
+                rtt: Duration::from_micros(self.path_rtt_us.load(Ordering::Relaxed)),
+                cwnd: self.path_cwnd.load(Ordering::Relaxed),
+                lost_packets: self.path_lost_packets.load(Ordering::Relaxed),
+                sent_packets: self.path_sent_packets.load(Ordering::Relaxed),
+                congestion_events: self.path_congestion_events.load(Ordering::Relaxed),
+            },
+            queued_latency: self.queued_latency.snapshot(),
+            write_latency: self.write_latency.snapshot(),
+        }
+    }
+}
+
+/// A point in time view of [`ClientStats`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientStatsSnapshot {
+    /// The number of transactions that have been queued but not yet completed
+    pub in_flight: u64,
+    /// The number of frames written to the stream, each frame contains one
+    /// transaction, unless transactions were coalesced
+    pub sent: u64,
+    /// The number of transactions that were sent in the same frame as another
+    /// transaction
+    pub coalesced: u64,
+    /// The number of sent frames the server successfully executed
+    pub completed: u64,
+    /// The number of sent frames that the server failed to execute, or
+    /// whose response couldn't be read, including frames that were retried
+    pub failed: u64,
+    /// The number of bytes written to the stream, including length prefixes
+    pub bytes_sent: u64,
+    /// The number of bytes read from the stream, including length prefixes
+    pub bytes_received: u64,
+    /// The number of transactions that were sent again after the stream failed
+    pub retried: u64,
+    /// The number of times the stream was reopened after failing
+    pub reconnects: u64,
+    /// The number of [resyncs](super::Client::enable_resync) that completed
+    pub resyncs: u64,
+    /// The number of resyncs that failed, the servers they would have removed
+    /// are removed by the next resync instead
+    pub failed_resyncs: u64,
+    /// The last sampled path statistics, all zero unless
+    /// [`super::ClientConfig::path_stats_interval`] is set
+    pub path: PathStats,
+    /// The latency from when a transaction was queued until its response was received
+    pub queued_latency: LatencySnapshot,
+    /// The latency from when a transaction was written until its response was received
+    pub write_latency: LatencySnapshot,
+}
+
+/// Statistics for the network path of a client's connection
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PathStats {
+    /// The current estimate of the round trip time
+    pub rtt: Duration,
+    /// The current congestion window, in bytes
+    pub cwnd: u64,
+    /// The number of packets lost
+    pub lost_packets: u64,
+    /// The number of packets sent
+    pub sent_packets: u64,
+    /// The number of congestion events
+    pub congestion_events: u64,
+}
+
+impl PathStats {
+    #[inline]
+    pub(super) fn sample(conn: &quinn::Connection) -> Self {
+        let path = conn.stats().path;
+
+        Self {
+            rtt: conn.rtt(),
+            cwnd: path.cwnd,
+            lost_packets: path.lost_packets,
+            sent_packets: path.sent_packets,
+            congestion_events: path.congestion_events,
+        }
+    }
+}
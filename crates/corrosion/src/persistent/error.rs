@@ -3,6 +3,7 @@ use std::fmt;
 /// Error codes that can be sent as the close/reset for an HTTP/3 stream
 ///
 /// These are just integers, so they are just a subset of HTTP status codes
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(u16)]
 pub enum ErrorCode {
     Unknown = 0,
@@ -12,6 +13,11 @@ pub enum ErrorCode {
     BadRequest = 400,
     /// There was an error deserializing or otherwise handling a handshake
     BadHandshake = 402,
+    /// The client is not allowed to connect to the server, eg. because the
+    /// server doesn't serve the client's ICAO
+    Unauthorized = 403,
+    /// The client didn't send a frame within the server's idle timeout
+    IdleTimeout = 408,
     /// A length prefixed piece frame could not be read because the length could
     /// not be read, or the frame could not be read before the end of the stream
     LengthRequired = 411,
@@ -24,10 +30,32 @@ pub enum ErrorCode {
     ClientClosed = 499,
     /// Internal server error
     InternalServerError = 500,
+    /// The server is at its connection limit, either in total or for the
+    /// client's IP address
+    AtCapacity = 503,
     /// The version of the client is not supported by the server
     VersionNotSupported = 505,
 }
 
+impl ErrorCode {
+    /// Every error code, in ascending order
+    pub const ALL: [Self; 13] = [
+        Self::Unknown,
+        Self::Ok,
+        Self::BadRequest,
+        Self::BadHandshake,
+        Self::Unauthorized,
+        Self::IdleTimeout,
+        Self::LengthRequired,
+        Self::PayloadTooLarge,
+        Self::PayloadInsufficient,
+        Self::ClientClosed,
+        Self::InternalServerError,
+        Self::AtCapacity,
+        Self::VersionNotSupported,
+    ];
+}
+
 impl fmt::Display for ErrorCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -35,16 +63,28 @@ impl fmt::Display for ErrorCode {
             Self::Ok => f.write_str("200: ok"),
             Self::BadRequest => f.write_str("400: bad request"),
             Self::BadHandshake => f.write_str("402: bad handshake"),
+            Self::Unauthorized => f.write_str("403: unauthorized"),
+            Self::IdleTimeout => f.write_str("408: idle timeout"),
             Self::LengthRequired => f.write_str("411: length required"),
             Self::PayloadTooLarge => f.write_str("413: payload too large"),
             Self::PayloadInsufficient => f.write_str("414: payload insufficient"),
             Self::ClientClosed => f.write_str("499: client closed"),
             Self::InternalServerError => f.write_str("500: internal server error"),
+            Self::AtCapacity => f.write_str("503: at capacity"),
             Self::VersionNotSupported => f.write_str("505: version not supported"),
         }
     }
 }
 
+impl From<super::RejectReason> for ErrorCode {
+    fn from(value: super::RejectReason) -> Self {
+        match value {
+            super::RejectReason::AtCapacity => Self::AtCapacity,
+            super::RejectReason::Unauthorized => Self::Unauthorized,
+        }
+    }
+}
+
 impl From<ErrorCode> for quinn::VarInt {
     fn from(value: ErrorCode) -> Self {
         Self::from_u32(value as u32)
@@ -55,14 +95,32 @@ impl From<quinn::VarInt> for ErrorCode {
     fn from(value: quinn::VarInt) -> Self {
         match value.into_inner() {
             200 => Self::Ok,
+            400 => Self::BadRequest,
             402 => Self::BadHandshake,
+            403 => Self::Unauthorized,
+            408 => Self::IdleTimeout,
             411 => Self::LengthRequired,
             413 => Self::PayloadTooLarge,
             414 => Self::PayloadInsufficient,
             499 => Self::ClientClosed,
             500 => Self::InternalServerError,
+            503 => Self::AtCapacity,
             505 => Self::VersionNotSupported,
             _ => Self::Unknown,
         }
     }
 }
+
+/// Error codes are sent as their number in [`super::ErrorFrame`]s
+impl serde::Serialize for ErrorCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(*self as u16)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ErrorCode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = u16::deserialize(deserializer)?;
+        Ok(quinn::VarInt::from(code).into())
+    }
+}
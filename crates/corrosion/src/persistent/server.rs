@@ -1,38 +1,605 @@
 use crate::Peer;
-use quilkin_types::IcaoCode;
+use quilkin_types::{Endpoint, IcaoCode};
 use quinn::{RecvStream, SendStream};
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    net::{Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex, atomic::Ordering},
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot, watch};
 
 use super::error::ErrorCode;
 
+mod stats;
+pub use stats::{ServerStats, ServerStatsSnapshot};
+
 /// The current version of the server stream
 ///
 /// - 0: Invalid
 /// - 1: The initial version
 ///   All frames are prefixed with a u16 length of the frame
-pub const VERSION: u16 = 1;
+/// - 2: The handshake response can report the load of the server, clients
+///   that send a version 1 handshake still receive a version 1 response
+/// - 3: The handshake request and response exchange [`Features`](super::Features)
+pub const VERSION: u16 = 3;
+
+/// The details a client sent in its handshake
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ClientHandshakeInfo {
+    /// The version of the client's handshake
+    pub version: u16,
+    pub qcmp_port: u16,
+    pub icao: IcaoCode,
+    /// The features that both the client and server support, which are none
+    /// for clients before version 3
+    pub features: super::Features,
+}
+
+/// Whether a client is allowed to connect, see [`AgentExecutor::authorize`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AuthDecision {
+    Accept,
+    Reject(super::RejectReason),
+}
 
 #[async_trait::async_trait]
 pub trait AgentExecutor: Sync + Send + Clone {
+    /// Decides whether a client is allowed to connect, called once its
+    /// handshake has been read, but before [`Self::connected`] and before the
+    /// handshake response is sent
+    ///
+    /// Rejected clients are sent a response declining the connection, and
+    /// neither [`Self::connected`] nor [`Self::disconnected`] are called for
+    /// them. The default accepts every client
+    async fn authorize(&self, _peer: Peer, _handshake: &ClientHandshakeInfo) -> AuthDecision {
+        AuthDecision::Accept
+    }
+    async fn connected(&self, peer: Peer, icao: IcaoCode, qcmp_port: u16);
+    /// Executes the changes from a single frame, failing with an error that
+    /// is sent to the client as an [`ErrorFrame`](super::ErrorFrame), the
+    /// connection is kept open either way
+    ///
+    /// The changes have been normalised by [`ServerChange::normalize`](super::ServerChange::normalize),
+    /// so each endpoint is removed, inserted, and updated at most once, in
+    /// that order
+    async fn try_execute(
+        &self,
+        peer: Peer,
+        statements: &[super::ServerChange],
+    ) -> Result<corro_types::api::ExecResult, ExecError>;
+    /// Executes the changes from multiple frames, possibly from different
+    /// peers, returning the result for each frame in the same order
+    ///
+    /// This is only used if [`ServerConfig::coalesce`] is set, in which case
+    /// implementations should apply all of the frames in a single transaction.
+    /// The default executes each frame individually
+    async fn execute_batch(
+        &self,
+        frames: &[(Peer, Vec<super::ServerChange>)],
+    ) -> Vec<Result<corro_types::api::ExecResult, ExecError>> {
+        let mut results = Vec::with_capacity(frames.len());
+        for (peer, statements) in frames {
+            results.push(self.try_execute(*peer, statements).await);
+        }
+        results
+    }
+    async fn disconnected(&self, peer: Peer);
+}
+
+/// An executor that can't fail to execute a frame, other than by responding
+/// with an [`ExecResult::Error`](corro_types::api::ExecResult::Error)
+///
+/// Every `InfallibleExecutor` is an [`AgentExecutor`], so executors written
+/// before [`AgentExecutor::try_execute`] existed only need to implement this
+/// trait instead
+#[async_trait::async_trait]
+pub trait InfallibleExecutor: Sync + Send + Clone {
+    /// See [`AgentExecutor::authorize`]
+    async fn authorize(&self, _peer: Peer, _handshake: &ClientHandshakeInfo) -> AuthDecision {
+        AuthDecision::Accept
+    }
     async fn connected(&self, peer: Peer, icao: IcaoCode, qcmp_port: u16);
+    /// Executes the changes from a single frame, see [`AgentExecutor::try_execute`]
     async fn execute(
         &self,
         peer: Peer,
         statements: &[super::ServerChange],
     ) -> corro_types::api::ExecResult;
+    /// See [`AgentExecutor::execute_batch`]
+    async fn execute_batch(
+        &self,
+        frames: &[(Peer, Vec<super::ServerChange>)],
+    ) -> Vec<corro_types::api::ExecResult> {
+        let mut results = Vec::with_capacity(frames.len());
+        for (peer, statements) in frames {
+            results.push(self.execute(*peer, statements).await);
+        }
+        results
+    }
     async fn disconnected(&self, peer: Peer);
 }
 
+#[async_trait::async_trait]
+impl<IE: InfallibleExecutor> AgentExecutor for IE {
+    #[inline]
+    async fn authorize(&self, peer: Peer, handshake: &ClientHandshakeInfo) -> AuthDecision {
+        InfallibleExecutor::authorize(self, peer, handshake).await
+    }
+    #[inline]
+    async fn connected(&self, peer: Peer, icao: IcaoCode, qcmp_port: u16) {
+        InfallibleExecutor::connected(self, peer, icao, qcmp_port).await;
+    }
+    #[inline]
+    async fn try_execute(
+        &self,
+        peer: Peer,
+        statements: &[super::ServerChange],
+    ) -> Result<corro_types::api::ExecResult, ExecError> {
+        Ok(self.execute(peer, statements).await)
+    }
+    #[inline]
+    async fn execute_batch(
+        &self,
+        frames: &[(Peer, Vec<super::ServerChange>)],
+    ) -> Vec<Result<corro_types::api::ExecResult, ExecError>> {
+        InfallibleExecutor::execute_batch(self, frames)
+            .await
+            .into_iter()
+            .map(Ok)
+            .collect()
+    }
+    #[inline]
+    async fn disconnected(&self, peer: Peer) {
+        InfallibleExecutor::disconnected(self, peer).await;
+    }
+}
+
+/// The maximum length of the message in the response created by [`exec_error`],
+/// longer messages are truncated
+pub const MAX_ERROR_LEN: usize = 1024;
+
+/// Creates the response for changes that failed to execute, eg. because the
+/// SQLite transaction failed
+///
+/// The error is sent to the client as the message of an [`ExecResult::Error`](corro_types::api::ExecResult::Error),
+/// truncated to [`MAX_ERROR_LEN`] bytes
+pub fn exec_error(error: impl std::fmt::Display) -> corro_types::api::ExecResult {
+    corro_types::api::ExecResult::Error {
+        error: truncate_error(error.to_string()),
+    }
+}
+
+/// Truncates an error message to [`MAX_ERROR_LEN`] bytes
+fn truncate_error(mut error: String) -> String {
+    if error.len() > MAX_ERROR_LEN {
+        let mut len = MAX_ERROR_LEN;
+        while !error.is_char_boundary(len) {
+            len -= 1;
+        }
+        error.truncate(len);
+    }
+
+    error
+}
+
+/// An error executing a frame, see [`AgentExecutor::try_execute`]
+///
+/// The error is sent to the client as an [`ErrorFrame`](super::ErrorFrame),
+/// with the message truncated to [`MAX_ERROR_LEN`] bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExecError {
+    /// The kind of error, eg. [`ErrorCode::BadRequest`] if the changes were
+    /// invalid, or [`ErrorCode::InternalServerError`] if the database failed
+    pub code: ErrorCode,
+    pub message: String,
+    /// Whether the same changes could succeed if they were sent again, eg.
+    /// because the database was busy
+    pub retryable: bool,
+}
+
+impl ExecError {
+    #[inline]
+    pub fn new(code: ErrorCode, message: impl std::fmt::Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            retryable: false,
+        }
+    }
+
+    /// An [`ErrorCode::InternalServerError`]
+    #[inline]
+    pub fn internal(message: impl std::fmt::Display) -> Self {
+        Self::new(ErrorCode::InternalServerError, message)
+    }
+
+    /// Marks the error as [retryable](Self::retryable)
+    #[inline]
+    pub fn retryable(mut self) -> Self {
+        self.retryable = true;
+        self
+    }
+}
+
+impl From<ExecError> for super::Response {
+    fn from(error: ExecError) -> Self {
+        Self::Error(super::ErrorFrame {
+            error: truncate_error(error.message),
+            code: Some(error.code),
+            retryable: error.retryable,
+        })
+    }
+}
+
+impl std::fmt::Display for ExecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)?;
+        if self.retryable {
+            f.write_str(" (retryable)")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+/// Polls the future, catching any panic, so that a panicking executor doesn't
+/// take down the task calling it
+async fn catch_unwind<F: Future>(future: F) -> std::thread::Result<F::Output> {
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(move |cx| {
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(panic) => std::task::Poll::Ready(Err(panic)),
+        }
+    })
+    .await
+}
+
+/// How long [`Server::shutdown`] waits for connections to close before
+/// aborting them
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// How the connections that were open when a [`Server`] was shut down closed
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// The connections that closed, and the executor was notified of their
+    /// disconnection, before the grace period elapsed
+    pub closed: usize,
+    /// The connections that were aborted when the grace period elapsed
+    pub aborted: usize,
+}
+
+/// The state of a [`Server`], see [`Server::state`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerState {
+    /// The server is accepting connections
+    Running,
+    /// The server is draining its connections after [`Server::shutdown`]
+    /// was called
+    ShuttingDown,
+    /// The server was shut down, and all of its connections closed
+    Closed,
+    /// The server stopped accepting connections without being shut down, it
+    /// needs to be recreated for agents to be able to connect
+    Failed(ServerError),
+}
+
+impl ServerState {
+    /// Whether the server has stopped accepting connections for good
+    #[inline]
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::Closed | Self::Failed(_))
+    }
+}
+
+/// An error that stopped a [`Server`] from accepting connections
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq)]
+pub enum ServerError {
+    #[error("the endpoint was closed while the server was running")]
+    EndpointClosed,
+}
+
+type FatalCallback = Box<dyn FnOnce(&ServerError) + Send>;
+
+/// The state of the server, shared with its accept loop
+struct Status {
+    state: watch::Sender<ServerState>,
+    on_fatal: Mutex<Option<FatalCallback>>,
+}
+
+impl Status {
+    /// Transitions to [`ServerState::Failed`], and invokes the callback
+    /// registered via [`Server::on_fatal`], if any
+    fn fail(&self, error: ServerError) {
+        let callback = {
+            let mut on_fatal = self.on_fatal.lock().unwrap();
+            self.state.send_replace(ServerState::Failed(error.clone()));
+            on_fatal.take()
+        };
+
+        if let Some(callback) = callback {
+            callback(&error);
+        }
+    }
+}
+
+/// Configuration for a [`Server`]
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+    /// The QUIC configuration, eg. one that uses TLS created with
+    /// [`tls_config`], if not set, sessions are not encrypted
+    pub quic: Option<quinn::ServerConfig>,
+    /// The maximum number of concurrent client connections, clients that
+    /// connect while the server is at capacity are closed with
+    /// [`ErrorCode::AtCapacity`] before the handshake
+    pub max_connections: Option<usize>,
+    /// The maximum number of concurrent connections from a single IP address,
+    /// enforced the same as [`Self::max_connections`]
+    ///
+    /// IPv4 addresses are counted together with their IPv4-mapped IPv6
+    /// equivalent, the same as a [`Peer`]
+    pub max_connections_per_ip: Option<usize>,
+    /// If set, and [`Self::max_connections`] is set, the handshake response
+    /// to version 2 clients includes the server's load, ie. the number of
+    /// connections as a percentage of the maximum
+    pub report_load: bool,
+    /// If set, connections that don't send a frame within this duration are
+    /// closed with [`ErrorCode::IdleTimeout`], eg. because the agent process
+    /// is frozen while its QUIC connection is still kept alive
+    pub idle_timeout: Option<Duration>,
+    /// If set, connections that send a frame longer than this many bytes are
+    /// closed with [`ErrorCode::PayloadTooLarge`], the length is checked
+    /// before the frame is read
+    pub max_frame_bytes: Option<usize>,
+    /// If set, frames with more changes than this are responded to with an
+    /// error rather than executed
+    pub max_changes_per_frame: Option<usize>,
+    /// If set, frames that change more endpoints than this, across all of
+    /// their changes, are responded to with an error rather than executed
+    pub max_endpoints_per_frame: Option<usize>,
+    /// The maximum number of frames executed concurrently for a connection
+    /// that uses [`Features::REQUEST_IDS`](super::Features::REQUEST_IDS),
+    /// defaults to [`DEFAULT_MAX_CONCURRENT_FRAMES`]
+    ///
+    /// The frames of other connections are executed one at a time
+    pub max_concurrent_frames: Option<usize>,
+    /// If set, frames received from all connections are accumulated and
+    /// applied together via [`AgentExecutor::execute_batch`]
+    pub coalesce: Option<CoalesceConfig>,
+    /// If set, clients that connect with an ICAO that isn't in the set are
+    /// declined during the handshake with [`RejectReason::Unauthorized`](super::RejectReason::Unauthorized),
+    /// and the connection is closed with [`ErrorCode::Unauthorized`]
+    pub allowed_icaos: Option<HashSet<IcaoCode>>,
+}
+
+/// The default for [`ServerConfig::max_concurrent_frames`]
+pub const DEFAULT_MAX_CONCURRENT_FRAMES: usize = 16;
+
+/// Limits on how many frames are accumulated before they are applied
+#[derive(Copy, Clone, Debug)]
+pub struct CoalesceConfig {
+    /// The maximum amount of time to wait for more frames after the first
+    /// frame of a batch is received
+    pub window: Duration,
+    /// The batch is applied as soon as it contains at least this many
+    /// changed servers
+    pub max_rows: usize,
+}
+
+/// The limits on the frames a client can send
+#[derive(Copy, Clone)]
+struct FrameLimits {
+    max_bytes: usize,
+    max_changes: usize,
+    max_endpoints: usize,
+}
+
+impl FrameLimits {
+    /// Checks the decoded changes of a frame, returning the error to respond
+    /// with if the frame is over a limit
+    fn check(&self, changes: &[super::ServerChange]) -> Result<(), ExecError> {
+        if changes.len() > self.max_changes {
+            return Err(ExecError::new(
+                ErrorCode::PayloadTooLarge,
+                format_args!(
+                    "the frame has {} changes, more than the maximum of {}",
+                    changes.len(),
+                    self.max_changes
+                ),
+            ));
+        }
+
+        let endpoints = changes.iter().map(|change| change.len()).sum::<usize>();
+        if endpoints > self.max_endpoints {
+            return Err(ExecError::new(
+                ErrorCode::PayloadTooLarge,
+                format_args!(
+                    "the frame changes {endpoints} endpoints, more than the maximum of {}",
+                    self.max_endpoints
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// A frame waiting to be applied by the coalescing task
+type Frame = (
+    Peer,
+    Vec<super::ServerChange>,
+    oneshot::Sender<Result<corro_types::api::ExecResult, ExecError>>,
+);
+
+/// A frame read by [`read_frames`]
+type ReadFrame = Result<bytes::Bytes, super::LengthReadError>;
+
+/// Counts the number of open connections, in total and per IP address
+#[derive(Clone)]
+struct Capacity {
+    stats: Arc<ServerStats>,
+    max: usize,
+    per_ip: Arc<Mutex<HashMap<Ipv6Addr, usize>>>,
+    max_per_ip: Option<usize>,
+    report_load: bool,
+}
+
+impl Capacity {
+    /// Reserves a slot for a connection from the address, failing if the
+    /// server, or the IP address, is at capacity
+    fn reserve(&self, addr: SocketAddr) -> Result<ConnectionSlot, InitialConnectionError> {
+        let ip = *crate::peer_from_socket_addr(addr).ip();
+
+        self.stats
+            .connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < self.max).then_some(active + 1)
+            })
+            .map_err(|_| InitialConnectionError::AtCapacity { max: self.max })?;
+
+        let mut slot = ConnectionSlot {
+            capacity: self.clone(),
+            ip: None,
+        };
+
+        if let Some(max) = self.max_per_ip {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            if per_ip.get(&ip).copied().unwrap_or_default() >= max {
+                return Err(InitialConnectionError::IpAtCapacity { ip, max });
+            }
+
+            *per_ip.entry(ip).or_default() += 1;
+            slot.ip = Some(ip);
+        }
+
+        Ok(slot)
+    }
+
+    /// The load reported to clients, if enabled and the server has a maximum
+    /// number of connections
+    fn load(&self) -> Option<u8> {
+        if !self.report_load || self.max == usize::MAX {
+            return None;
+        }
+
+        let active = self.stats.connections.load(Ordering::Acquire);
+        Some((active.saturating_mul(100) / self.max.max(1)).min(100) as u8)
+    }
+}
+
+/// A reserved connection slot, released when dropped
+struct ConnectionSlot {
+    capacity: Capacity,
+    ip: Option<Ipv6Addr>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.capacity
+            .stats
+            .connections
+            .fetch_sub(1, Ordering::AcqRel);
+
+        let Some(ip) = self.ip else {
+            return;
+        };
+        let mut per_ip = self.capacity.per_ip.lock().unwrap();
+        if let Some(count) = per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+/// The frames of a connection that are being executed
+#[derive(Default)]
+struct Executing {
+    /// Each resolves to the id of the frame, the endpoints it changes, and the
+    /// response to it
+    tasks: tokio::task::JoinSet<(u32, BTreeSet<Endpoint>, super::Response)>,
+    /// The endpoints changed by the frames being executed
+    changing: BTreeSet<Endpoint>,
+}
+
+impl Executing {
+    /// Releases the endpoints of a frame that has been executed, returning its
+    /// id and response
+    fn completed(
+        &mut self,
+        done: Result<(u32, BTreeSet<Endpoint>, super::Response), tokio::task::JoinError>,
+    ) -> Result<(u32, super::Response), IoLoopError> {
+        let (id, endpoints, response) = done?;
+        for endpoint in &endpoints {
+            self.changing.remove(endpoint);
+        }
+        Ok((id, response))
+    }
+}
+
+/// Reads frames from the stream until a read fails, or the receiver is dropped
+///
+/// Frames are read separately from the connection's I/O loop so that waiting
+/// for the next frame can be interrupted, eg. to respond to a frame that has
+/// been executed, without losing a partially read frame. The stream is
+/// returned so that it is closed with the rest of the connection
+async fn read_frames(mut recv: RecvStream, max: usize, tx: mpsc::Sender<ReadFrame>) -> RecvStream {
+    loop {
+        let res = tokio::select! {
+            res = super::read_length_prefixed_limited(&mut recv, max) => res,
+            () = tx.closed() => break,
+        };
+
+        let failed = res.is_err();
+        if tx.send(res).await.is_err() || failed {
+            break;
+        }
+    }
+
+    recv
+}
+
+/// Writes the response to a frame, prefixed with the frame's id if the client
+/// uses [`Features::REQUEST_IDS`](super::Features::REQUEST_IDS)
+async fn write_response(
+    send: &mut SendStream,
+    stats: &ServerStats,
+    id: Option<u32>,
+    response: &super::Response,
+) -> Result<(), IoLoopError> {
+    let response = match id {
+        Some(id) => super::write_request_id_prefixed_jsonb(id, response)?,
+        None => super::write_length_prefixed_jsonb(response)?,
+    };
+    let len = response.len();
+    send.write_chunk(response.freeze()).await?;
+    stats.sent(len);
+    Ok(())
+}
+
 pub struct Server {
-    endpoint: quinn::Endpoint,
-    task: tokio::task::JoinHandle<()>,
-    local_addr: SocketAddr,
+    /// The endpoint for each bind address, in the same order
+    endpoints: Vec<quinn::Endpoint>,
+    task: tokio::task::JoinHandle<ShutdownSummary>,
+    /// Set to the grace period when the server is shutting down
+    closing: watch::Sender<Option<Duration>>,
+    local_addrs: Vec<SocketAddr>,
+    stats: Arc<ServerStats>,
+    status: Arc<Status>,
+    #[cfg(feature = "tokio-metrics")]
+    monitor: tokio_metrics::TaskMonitor,
 }
 
 struct ValidClientHandshake {
+    connection: quinn::Connection,
     send: SendStream,
     recv: RecvStream,
     peer: Peer,
+    features: super::Features,
+    _slot: ConnectionSlot,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -45,6 +612,19 @@ enum InitialConnectionError {
     Handshake(#[from] super::HandshakeError),
     #[error(transparent)]
     Write(#[from] quinn::WriteError),
+    #[error("the server is at its capacity of {} connections", max)]
+    AtCapacity { max: usize },
+    #[error("{} is at its capacity of {} connections", ip, max)]
+    IpAtCapacity { ip: Ipv6Addr, max: usize },
+    #[error("the ICAO {} is not allowed to connect to this server", icao)]
+    Unauthorized { icao: IcaoCode },
+    #[error("the executor rejected the connection: {}", reason)]
+    Rejected { reason: super::RejectReason },
+    #[error(
+        "the client negotiated the ALPN protocol '{}'",
+        String::from_utf8_lossy(protocol)
+    )]
+    UnsupportedAlpn { protocol: Vec<u8> },
 }
 
 impl From<quinn::ReadError> for InitialConnectionError {
@@ -61,29 +641,182 @@ enum IoLoopError {
     Jsonb(#[from] serde_json::Error),
     #[error(transparent)]
     Write(#[from] quinn::WriteError),
+    #[error("no frame was received for {:?}", idle)]
+    IdleTimeout { idle: Duration },
+    #[error(transparent)]
+    Execute(#[from] tokio::task::JoinError),
 }
 
 impl From<IoLoopError> for ErrorCode {
     fn from(value: IoLoopError) -> Self {
         match value {
+            IoLoopError::IdleTimeout { .. } => Self::IdleTimeout,
             IoLoopError::Read(read) => (&read).into(),
             IoLoopError::Write(_) => Self::ClientClosed,
-            IoLoopError::Jsonb(_) => Self::InternalServerError,
+            IoLoopError::Jsonb(_) | IoLoopError::Execute(_) => Self::InternalServerError,
         }
     }
 }
 
+/// Creates a QUIC configuration that uses TLS with the certificate, and only
+/// accepts clients that offer the [`ALPN`](super::ALPN) protocol
+///
+/// This is the same as [`quinn::ServerConfig::with_single_cert`], other than
+/// the ALPN protocol
+pub fn tls_config(
+    cert_chain: Vec<quinn::rustls::pki_types::CertificateDer<'static>>,
+    key: quinn::rustls::pki_types::PrivateKeyDer<'static>,
+) -> Result<quinn::ServerConfig, quinn::rustls::Error> {
+    let provider = Arc::new(quinn::rustls::crypto::ring::default_provider());
+    let mut crypto = quinn::rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&quinn::rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)?;
+    crypto.max_early_data_size = u32::MAX;
+    crypto.alpn_protocols = vec![super::ALPN.to_vec()];
+
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(crypto)
+        .expect("the ring provider supports the initial cipher suite");
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// The ALPN protocol negotiated for a connection, `None` if the session isn't
+/// encrypted, or no protocol was negotiated
+fn negotiated_alpn(connection: &quinn::Connection) -> Option<Vec<u8>> {
+    connection
+        .handshake_data()?
+        .downcast::<quinn::crypto::rustls::HandshakeData>()
+        .ok()?
+        .protocol
+}
+
 impl Server {
+    #[inline]
     pub fn new_unencrypted(
         addr: SocketAddr,
         executor: impl AgentExecutor + 'static,
     ) -> std::io::Result<Self> {
-        let endpoint = quinn::Endpoint::server(quinn_plaintext::server_config(), addr)?;
+        Self::new(addr, ServerConfig::default(), executor)
+    }
+
+    /// Creates a server with the specified QUIC configuration, eg. one that
+    /// uses TLS
+    #[inline]
+    pub fn new_with_config(
+        addr: SocketAddr,
+        server_config: quinn::ServerConfig,
+        executor: impl AgentExecutor + 'static,
+    ) -> std::io::Result<Self> {
+        Self::new(
+            addr,
+            ServerConfig {
+                quic: Some(server_config),
+                ..Default::default()
+            },
+            executor,
+        )
+    }
+
+    /// Creates a server with the specified configuration
+    #[inline]
+    pub fn new(
+        addr: SocketAddr,
+        config: ServerConfig,
+        executor: impl AgentExecutor + 'static,
+    ) -> std::io::Result<Self> {
+        Self::bind(&[addr], config, executor)
+    }
+
+    /// Creates a server that accepts connections on each of the addresses,
+    /// eg. both an IPv4 and IPv6 address, or multiple interfaces
+    ///
+    /// Connections from every address share the executor and are counted
+    /// together against the configured limits
+    pub fn bind(
+        addrs: &[SocketAddr],
+        config: ServerConfig,
+        executor: impl AgentExecutor + 'static,
+    ) -> std::io::Result<Self> {
+        if addrs.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "at least one bind address is required",
+            ));
+        }
 
-        let local_addr = endpoint.local_addr()?;
-        let ep = endpoint.clone();
+        let server_config = config.quic.unwrap_or_else(quinn_plaintext::server_config);
+        let endpoints = addrs
+            .iter()
+            .map(|addr| quinn::Endpoint::server(server_config.clone(), *addr))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let capacity = Capacity {
+            stats: Default::default(),
+            max: config.max_connections.unwrap_or(usize::MAX),
+            per_ip: Default::default(),
+            max_per_ip: config.max_connections_per_ip,
+            report_load: config.report_load,
+        };
+        let stats = capacity.stats.clone();
+        let allowed_icaos = config.allowed_icaos.map(Arc::new);
+        let idle_timeout = config.idle_timeout;
+        let max_concurrent_frames = config
+            .max_concurrent_frames
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_FRAMES)
+            .max(1);
+        let limits = FrameLimits {
+            max_bytes: config.max_frame_bytes.unwrap_or(usize::MAX),
+            max_changes: config.max_changes_per_frame.unwrap_or(usize::MAX),
+            max_endpoints: config.max_endpoints_per_frame.unwrap_or(usize::MAX),
+        };
+
+        let coalescer = config.coalesce.map(|config| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::task::spawn(Self::coalesce(rx, executor.clone(), config));
+            tx
+        });
+
+        let local_addrs = endpoints
+            .iter()
+            .map(quinn::Endpoint::local_addr)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        #[cfg(feature = "tokio-metrics")]
+        let monitor = tokio_metrics::TaskMonitor::new();
+        #[cfg(feature = "tokio-metrics")]
+        let task_monitor = monitor.clone();
+        let eps = endpoints.clone();
+        let (closing, closing_rx) = watch::channel(None);
+        let mut stop_rx = closing_rx.clone();
+        let status = Arc::new(Status {
+            state: watch::Sender::new(ServerState::Running),
+            on_fatal: Mutex::new(None),
+        });
+        let task_status = status.clone();
         let task = tokio::task::spawn(async move {
-            while let Some(conn) = ep.accept().await {
+            let mut conns = tokio::task::JoinSet::new();
+            let grace = loop {
+                let conn = tokio::select! {
+                    // Checked first so that connections closed by the shutdown
+                    // are counted by the drain rather than reaped below
+                    biased;
+                    Ok(()) = stop_rx.changed() => {
+                        task_status.state.send_replace(ServerState::ShuttingDown);
+                        break stop_rx.borrow().unwrap_or(SHUTDOWN_GRACE);
+                    }
+                    conn = Self::accept(&eps) => match conn {
+                        Some(conn) => conn,
+                        // The endpoints are only closed by `shutdown_graceful`
+                        // once this loop has completed, so one was closed out
+                        // from under the server
+                        None => {
+                            tracing::error!("the server endpoint was closed unexpectedly");
+                            task_status.fail(ServerError::EndpointClosed);
+                            break SHUTDOWN_GRACE;
+                        }
+                    },
+                    // Reap the tasks of closed connections
+                    Some(_) = conns.join_next() => continue,
+                };
+
                 if !conn.remote_address_validated() {
                     let _impossible = conn.retry();
                     continue;
@@ -91,105 +824,495 @@ impl Server {
 
                 let peer_ip = conn.remote_address();
 
+                let stats = capacity.stats.clone();
+                let slot = match capacity.reserve(peer_ip) {
+                    Ok(slot) => slot,
+                    Err(error) => {
+                        tracing::debug!(%peer_ip, %error, "refusing peer connection");
+                        stats.refused();
+                        // The connection has to be established for the client
+                        // to receive the error code
+                        tokio::spawn(async move {
+                            if let Ok(connection) = conn.await {
+                                stats.closed_with(ErrorCode::AtCapacity);
+                                connection.close(ErrorCode::AtCapacity.into(), b"at capacity");
+                            }
+                        });
+                        continue;
+                    }
+                };
+                stats.accepted_connection();
+
                 let exec = executor.clone();
-                tokio::spawn(async move {
-                    match Self::complete_handshake(conn, &exec).await {
+                let coalescer = coalescer.clone();
+                let mut closing = closing_rx.clone();
+                let allowed_icaos = allowed_icaos.clone();
+                let conn_task = async move {
+                    match Self::complete_handshake(
+                        conn,
+                        &exec,
+                        &stats,
+                        slot,
+                        allowed_icaos.as_deref(),
+                    )
+                    .await
+                    {
                         Ok(vch) => {
+                            stats.handshake_accepted();
                             let ValidClientHandshake {
+                                connection,
                                 mut send,
-                                mut recv,
+                                recv,
                                 peer,
+                                features,
+                                _slot,
                             } = vch;
 
-                            let mut io_loop = async || -> Result<(), IoLoopError> {
+                            // Frames are only executed concurrently if the
+                            // client can match the responses to them
+                            let request_ids = features.contains(super::Features::REQUEST_IDS);
+                            let max_executing = if request_ids {
+                                max_concurrent_frames
+                            } else {
+                                1
+                            };
+                            let mut executing = Executing::default();
+
+                            let mut io_loop = async |mut frames: mpsc::Receiver<ReadFrame>| -> Result<(), IoLoopError> {
+                                // The timer is only polled if there is an idle timeout
+                                let timeout = idle_timeout.unwrap_or_default();
+                                let mut idle = std::pin::pin!(tokio::time::sleep(timeout));
+                                // Frames are numbered in the order they are received
+                                let mut next_id = 0u32;
+
                                 loop {
+                                    idle.as_mut().reset(tokio::time::Instant::now() + timeout);
+                                    let frame = tokio::select! {
+                                        res = frames.recv(), if executing.tasks.len() < max_executing => {
+                                            res.unwrap_or(Err(super::LengthReadError::StreamEnded))?
+                                        }
+                                        Some(done) = executing.tasks.join_next() => {
+                                            let (id, response) = executing.completed(done)?;
+                                            write_response(&mut send, &stats, request_ids.then_some(id), &response).await?;
+                                            continue;
+                                        }
+                                        // The server is shutting down, respond to the
+                                        // frames that are already being executed
+                                        Ok(_) = closing.wait_for(Option::is_some) => {
+                                            while let Some(done) = executing.tasks.join_next().await {
+                                                let (id, response) = executing.completed(done)?;
+                                                write_response(&mut send, &stats, request_ids.then_some(id), &response).await?;
+                                            }
+                                            return Ok(());
+                                        }
+                                        // The client is waiting on the frames being
+                                        // executed, so it isn't idle
+                                        _ = &mut idle, if idle_timeout.is_some() && executing.tasks.is_empty() => {
+                                            return Err(IoLoopError::IdleTimeout { idle: timeout });
+                                        }
+                                    };
+                                    let id = next_id;
+                                    next_id = next_id.wrapping_add(1);
+
+                                    stats.received(frame.len() + 2);
+                                    let start = Instant::now();
                                     let to_exec: Vec<super::ServerChange> =
-                                        super::read_length_prefixed_jsonb(&mut recv).await?;
+                                        serde_json::from_slice(&frame)
+                                            .map_err(super::LengthReadError::from)?;
+
+                                    if let Err(error) = limits.check(&to_exec) {
+                                        tracing::debug!(%peer, %error, "rejecting frame");
+                                        let response = super::Response::from(error);
+                                        write_response(
+                                            &mut send,
+                                            &stats,
+                                            request_ids.then_some(id),
+                                            &response,
+                                        )
+                                        .await?;
+                                        continue;
+                                    }
+
+                                    let to_exec = super::ServerChange::normalize(to_exec);
+                                    let endpoints = to_exec
+                                        .iter()
+                                        .flat_map(super::ServerChange::endpoints)
+                                        .cloned()
+                                        .collect::<BTreeSet<_>>();
 
-                                    let response = exec.execute(peer, &to_exec).await;
-                                    let response = super::write_length_prefixed_jsonb(&response)?;
-                                    send.write_chunk(response.freeze()).await?;
+                                    // Changes to an endpoint are applied in the order
+                                    // they were sent, so the frame waits for earlier
+                                    // frames that change the same endpoints
+                                    while !executing.changing.is_disjoint(&endpoints) {
+                                        let Some(done) = executing.tasks.join_next().await
+                                        else {
+                                            break;
+                                        };
+                                        let (done_id, response) = executing.completed(done)?;
+                                        write_response(
+                                            &mut send,
+                                            &stats,
+                                            request_ids.then_some(done_id),
+                                            &response,
+                                        )
+                                        .await?;
+                                    }
+
+                                    let exec = exec.clone();
+                                    let coalescer = coalescer.clone();
+                                    let stats = stats.clone();
+                                    executing.changing.extend(endpoints.iter().cloned());
+                                    executing.tasks.spawn(async move {
+                                        let response = if let Some(coalescer) = &coalescer {
+                                            let (tx, rx) = oneshot::channel();
+                                            let _ = coalescer.send((peer, to_exec, tx));
+                                            let response = rx.await.unwrap_or_else(|_| {
+                                                Err(ExecError::internal(
+                                                    "the batch containing the frame failed",
+                                                ))
+                                            });
+                                            response.map_or_else(Into::into, Into::into)
+                                        } else {
+                                            Self::execute(&exec, peer, &to_exec).await
+                                        };
+                                        stats.executed(start.elapsed());
+                                        (id, endpoints, response)
+                                    });
                                 }
                             };
 
-                            let code = if let Err(error) = io_loop().await {
+                            let (frames_tx, frames) = mpsc::channel(1);
+                            let (recv, res) = tokio::join!(
+                                read_frames(recv, limits.max_bytes, frames_tx),
+                                io_loop(frames),
+                            );
+                            let code = if let Err(error) = res {
                                 tracing::warn!(%peer, %error, "error handling peer connection");
                                 error.into()
                             } else {
                                 ErrorCode::Ok
                             };
 
+                            // The responses to frames that are still being
+                            // executed can't be sent, but the changes are
+                            // applied before the peer is disconnected
+                            while executing.tasks.join_next().await.is_some() {}
+
                             exec.disconnected(peer).await;
-                            Self::close(peer, code, send, recv).await;
+                            match code {
+                                // The peer may not be responsive enough to stop
+                                // the stream, so close the whole connection
+                                ErrorCode::IdleTimeout => {
+                                    stats.closed_with(code);
+                                    connection.close(code.into(), b"idle timeout");
+                                }
+                                // The loop only ends without an error when the
+                                // server is shutting down, so the connection
+                                // would be closed with the endpoint anyway
+                                ErrorCode::Ok => {
+                                    let _ = send.finish();
+                                    stats.closed_with(code);
+                                    connection.close(code.into(), b"server shutting down");
+                                }
+                                _ => Self::close(peer, code, &stats, send, recv).await,
+                            }
                         }
                         Err(error) => {
                             tracing::warn!(%peer_ip, %error, "error handling peer handshake");
+                            match error {
+                                InitialConnectionError::Unauthorized { .. }
+                                | InitialConnectionError::Rejected { .. } => {
+                                    stats.handshake_rejected();
+                                }
+                                _ => stats.handshake_failed(),
+                            }
                         }
                     }
-                });
-            }
+                };
+
+                #[cfg(feature = "tokio-metrics")]
+                let conn_task = task_monitor.instrument(conn_task);
+                conns.spawn(conn_task);
+            };
+
+            let summary = Self::drain(conns, grace).await;
+            task_status.state.send_if_modified(|state| {
+                if state.is_terminal() {
+                    return false;
+                }
+
+                *state = ServerState::Closed;
+                true
+            });
+            summary
         });
 
         Ok(Self {
-            endpoint,
+            endpoints,
             task,
-            local_addr,
+            closing,
+            local_addrs,
+            stats,
+            status,
+            #[cfg(feature = "tokio-metrics")]
+            monitor,
         })
     }
 
+    /// Accepts the next connection from any of the endpoints, or `None` once
+    /// any of them is closed
+    async fn accept(endpoints: &[quinn::Endpoint]) -> Option<quinn::Incoming> {
+        let mut accepts: Vec<_> = endpoints
+            .iter()
+            .map(|endpoint| Box::pin(endpoint.accept()))
+            .collect();
+
+        std::future::poll_fn(|cx| {
+            for accept in &mut accepts {
+                if let std::task::Poll::Ready(conn) = accept.as_mut().poll(cx) {
+                    return std::task::Poll::Ready(conn);
+                }
+            }
+
+            std::task::Poll::Pending
+        })
+        .await
+    }
+
+    /// Executes a single frame, responding with an error if the executor fails
+    /// or panics
+    async fn execute<AE: AgentExecutor>(
+        exec: &AE,
+        peer: Peer,
+        changes: &[super::ServerChange],
+    ) -> super::Response {
+        let error = match catch_unwind(exec.try_execute(peer, changes)).await {
+            Ok(Ok(response)) => return response.into(),
+            Ok(Err(error)) => error,
+            Err(_) => ExecError::internal("the executor panicked"),
+        };
+
+        tracing::warn!(%peer, %error, "failed to execute frame");
+        error.into()
+    }
+
+    /// Accumulates frames until the window elapses or the batch is large enough,
+    /// then applies them together
+    async fn coalesce<AE: AgentExecutor>(
+        mut rx: mpsc::UnboundedReceiver<Frame>,
+        exec: AE,
+        config: CoalesceConfig,
+    ) {
+        let rows = |changes: &[super::ServerChange]| -> usize {
+            changes.iter().map(|change| change.len()).sum()
+        };
+
+        let mut frames = Vec::new();
+        let mut waiters = Vec::new();
+
+        while let Some((peer, changes, tx)) = rx.recv().await {
+            let mut count = rows(&changes);
+            frames.push((peer, changes));
+            waiters.push(tx);
+
+            let deadline = tokio::time::Instant::now() + config.window;
+            while count < config.max_rows {
+                let Ok(Some((peer, changes, tx))) =
+                    tokio::time::timeout_at(deadline, rx.recv()).await
+                else {
+                    break;
+                };
+
+                count += rows(&changes);
+                frames.push((peer, changes));
+                waiters.push(tx);
+            }
+
+            tracing::trace!(
+                frames = frames.len(),
+                rows = count,
+                "applying coalesced frames"
+            );
+            let results = match catch_unwind(exec.execute_batch(&frames)).await {
+                Ok(results) => {
+                    debug_assert_eq!(results.len(), frames.len());
+                    results
+                }
+                // Every frame in the batch is failed below
+                Err(_) => {
+                    tracing::error!(frames = frames.len(), "the executor panicked");
+                    Vec::new()
+                }
+            };
+
+            // Any frames without a result are failed when their sender is dropped
+            for (tx, result) in waiters.drain(..).zip(results) {
+                let _ = tx.send(result);
+            }
+            waiters.clear();
+            frames.clear();
+        }
+    }
+
     async fn complete_handshake<AE>(
         conn: quinn::Incoming,
         exec: &AE,
+        stats: &ServerStats,
+        slot: ConnectionSlot,
+        allowed_icaos: Option<&HashSet<IcaoCode>>,
     ) -> Result<ValidClientHandshake, InitialConnectionError>
     where
         AE: AgentExecutor + 'static,
     {
-        let peer = match conn.remote_address().ip() {
-            IpAddr::V4(v4) => v4.to_ipv6_mapped(),
-            IpAddr::V6(v6) => v6,
-        };
-
-        let peer = std::net::SocketAddrV6::new(peer, conn.remote_address().port(), 0, 0);
+        let peer = crate::peer_from_socket_addr(conn.remote_address());
         tracing::debug!(%peer, "accepting peer connection");
 
         let connection = conn.await?;
+
+        // The TLS handshake fails if the client doesn't offer any of the
+        // server's protocols, but the server may be configured with other
+        // protocols than ours, eg. if it shares the port
+        if let Some(protocol) = negotiated_alpn(&connection) {
+            if protocol != super::ALPN {
+                stats.closed_with(ErrorCode::BadHandshake);
+                connection.close(ErrorCode::BadHandshake.into(), b"unsupported ALPN protocol");
+                return Err(InitialConnectionError::UnsupportedAlpn { protocol });
+            }
+        }
+
         let (mut send, mut recv) = connection.accept_bi().await?;
 
         let handshake_request = match super::read_length_prefixed(&mut recv).await {
             Ok(bytes) => bytes,
             Err(error) => {
-                Self::close(peer, (&error).into(), send, recv).await;
+                Self::close(peer, (&error).into(), stats, send, recv).await;
                 return Err(error.into());
             }
         };
 
         use super::ClientHandshake;
 
-        let (_version, info) = match ClientHandshake::read(VERSION, &handshake_request) {
+        let (version, info) = match ClientHandshake::read(VERSION, &handshake_request) {
             Ok(ch) => ch,
             Err(err) => {
-                Self::close(peer, ErrorCode::BadHandshake, send, recv).await;
+                Self::close(peer, ErrorCode::BadHandshake, stats, send, recv).await;
                 return Err(err.into());
             }
         };
 
-        let chunk = match &info {
-            ClientHandshake::V1(_v1) => {
-                let hs = super::ServerHandshakeResponseV1 { accept: true }.write();
-                super::write_length_prefixed(&hs)
+        let features = info.features().intersection(super::Features::SUPPORTED);
+
+        // The response uses the version the client asked for
+        let response = |reason: Option<super::RejectReason>| {
+            let accept = reason.is_none();
+            let load = accept.then(|| slot.capacity.load()).flatten();
+            match version {
+                1 => {
+                    let hs = super::ServerHandshakeResponseV1 { accept }.write();
+                    super::write_length_prefixed(&hs).freeze()
+                }
+                2 => {
+                    let hs = super::ServerHandshakeResponseV2 {
+                        accept,
+                        load,
+                        reason,
+                    }
+                    .write();
+                    super::write_length_prefixed(&hs).freeze()
+                }
+                _ => {
+                    let hs = super::ServerHandshakeResponseV3 {
+                        accept,
+                        load,
+                        reason,
+                        features,
+                    }
+                    .write();
+                    super::write_length_prefixed(&hs).freeze()
+                }
             }
         };
 
         let (qcmp_port, icao) = info.client_details();
+        if allowed_icaos.is_some_and(|allowed| !allowed.contains(&icao)) {
+            tracing::debug!(%peer, %icao, "rejecting peer connection, ICAO is not allowed");
+            let reason = super::RejectReason::Unauthorized;
+            Self::decline(
+                peer,
+                reason.into(),
+                stats,
+                response(Some(reason)),
+                &connection,
+                send,
+            )
+            .await;
+            return Err(InitialConnectionError::Unauthorized { icao });
+        }
+
+        let handshake = ClientHandshakeInfo {
+            version,
+            qcmp_port,
+            icao,
+            features,
+        };
+        if let AuthDecision::Reject(reason) = exec.authorize(peer, &handshake).await {
+            tracing::debug!(%peer, %icao, %reason, "rejecting peer connection");
+            Self::decline(
+                peer,
+                reason.into(),
+                stats,
+                response(Some(reason)),
+                &connection,
+                send,
+            )
+            .await;
+            return Err(InitialConnectionError::Rejected { reason });
+        }
+
         exec.connected(peer, icao, qcmp_port).await;
-        send.write_chunk(chunk.freeze()).await?;
+        send.write_chunk(response(None)).await?;
+
+        Ok(ValidClientHandshake {
+            connection,
+            send,
+            recv,
+            peer,
+            features,
+            _slot: slot,
+        })
+    }
 
-        Ok(ValidClientHandshake { send, recv, peer })
+    /// Sends a handshake response declining the connection, unlike [`Self::close`]
+    /// the stream is not reset so that the response is delivered, instead the
+    /// connection is closed with the code once the peer has stopped the stream
+    async fn decline(
+        peer: Peer,
+        code: ErrorCode,
+        stats: &ServerStats,
+        response: bytes::Bytes,
+        connection: &quinn::Connection,
+        mut send: SendStream,
+    ) {
+        tracing::debug!(%peer, %code, "declining peer connection...");
+        if send.write_chunk(response).await.is_ok() {
+            let _ = send.finish();
+            drop(send.stopped().await);
+        }
+        stats.closed_with(code);
+        connection.close(code.into(), code.to_string().as_bytes());
+        tracing::debug!(%peer, "peer connection declined");
     }
 
     #[inline]
-    async fn close(peer: Peer, code: ErrorCode, mut send: SendStream, recv: RecvStream) {
+    async fn close(
+        peer: Peer,
+        code: ErrorCode,
+        stats: &ServerStats,
+        mut send: SendStream,
+        recv: RecvStream,
+    ) {
         tracing::debug!(%peer, %code, "closing peer connection...");
+        stats.closed_with(code);
         let _ = send.finish();
         let _ = send.reset(code.into());
         drop(recv);
@@ -198,14 +1321,138 @@ impl Server {
         tracing::debug!(%peer, "peer connection closed");
     }
 
-    pub async fn shutdown(self, reason: &str) {
-        self.endpoint
-            .close(quinn::VarInt::from_u32(0), reason.as_bytes());
-        drop(self.task.await);
+    /// Waits up to the grace period for the connection tasks to complete,
+    /// aborting the rest
+    async fn drain(mut conns: tokio::task::JoinSet<()>, grace: Duration) -> ShutdownSummary {
+        let mut summary = ShutdownSummary::default();
+        let _ = tokio::time::timeout(grace, async {
+            while conns.join_next().await.is_some() {
+                summary.closed += 1;
+            }
+        })
+        .await;
+
+        summary.aborted = conns.len();
+        conns.shutdown().await;
+        summary
+    }
+
+    /// Shuts down the server with the default [grace period](SHUTDOWN_GRACE),
+    /// see [`Self::shutdown_graceful`]
+    #[inline]
+    pub async fn shutdown(self, reason: &str) -> ShutdownSummary {
+        self.shutdown_graceful(reason, SHUTDOWN_GRACE).await
+    }
+
+    /// Stops accepting connections, then closes the stream of every open
+    /// connection, waiting up to `grace` for each to close and for
+    /// [`AgentExecutor::disconnected`] to complete before aborting them
+    ///
+    /// The endpoints are only closed once every connection has either closed
+    /// or been aborted
+    pub async fn shutdown_graceful(self, reason: &str, grace: Duration) -> ShutdownSummary {
+        // New connections are refused while the open ones are drained
+        for endpoint in &self.endpoints {
+            endpoint.set_server_config(None);
+        }
+        let _ = self.closing.send(Some(grace));
+        let summary = self.task.await.unwrap_or_default();
+
+        for endpoint in &self.endpoints {
+            endpoint.close(quinn::VarInt::from_u32(0), reason.as_bytes());
+        }
+        summary
+    }
+
+    /// The current state of the server
+    #[inline]
+    pub fn state(&self) -> ServerState {
+        self.status.state.borrow().clone()
+    }
+
+    /// Waits until the server has either [closed](ServerState::Closed) or
+    /// [failed](ServerState::Failed), returning the final state
+    ///
+    /// The future doesn't borrow the server, so it can be awaited alongside
+    /// it, eg. to restart the server if it fails
+    pub fn closed(&self) -> impl Future<Output = ServerState> + Send + 'static {
+        let mut state = self.status.state.subscribe();
+        async move {
+            if let Ok(state) = state.wait_for(ServerState::is_terminal).await {
+                return state.clone();
+            }
+
+            // The server and its accept loop were dropped
+            state.borrow().clone()
+        }
+    }
+
+    /// Registers a callback that is invoked once if the server
+    /// [fails](ServerState::Failed), replacing any previous callback
+    ///
+    /// If the server has already failed the callback is invoked immediately
+    pub fn on_fatal(&self, callback: impl FnOnce(&ServerError) + Send + 'static) {
+        let mut on_fatal = self.status.on_fatal.lock().unwrap();
+        let failed = match &*self.status.state.borrow() {
+            ServerState::Failed(error) => Some(error.clone()),
+            _ => None,
+        };
+
+        if let Some(error) = failed {
+            drop(on_fatal);
+            callback(&error);
+            return;
+        }
+
+        *on_fatal = Some(Box::new(callback));
     }
 
+    /// The QUIC endpoint the server accepts connections on, the first one if
+    /// it was [bound](Self::bind) to multiple addresses
+    #[inline]
+    pub fn endpoint(&self) -> &quinn::Endpoint {
+        &self.endpoints[0]
+    }
+
+    /// The QUIC endpoints the server accepts connections on, in the same
+    /// order as the addresses it was [bound](Self::bind) to
+    #[inline]
+    pub fn endpoints(&self) -> &[quinn::Endpoint] {
+        &self.endpoints
+    }
+
+    /// The address the server is listening on, the first one if it was
+    /// [bound](Self::bind) to multiple addresses
     #[inline]
     pub fn local_addr(&self) -> SocketAddr {
-        self.local_addr
+        self.local_addrs[0]
+    }
+
+    /// The addresses the server is listening on, in the same order as the
+    /// addresses it was [bound](Self::bind) to
+    #[inline]
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.local_addrs.clone()
+    }
+
+    /// The number of connections that are currently open, including ones
+    /// that are still completing the handshake
+    #[inline]
+    pub fn connections(&self) -> usize {
+        self.stats.connections.load(Ordering::Acquire)
+    }
+
+    /// The statistics for the connections handled by the server
+    #[inline]
+    pub fn stats(&self) -> Arc<ServerStats> {
+        self.stats.clone()
+    }
+
+    /// The monitor for the tasks handling each peer connection, the metrics
+    /// are aggregated across all of the connections
+    #[cfg(feature = "tokio-metrics")]
+    #[inline]
+    pub fn task_monitor(&self) -> tokio_metrics::TaskMonitor {
+        self.monitor.clone()
     }
 }
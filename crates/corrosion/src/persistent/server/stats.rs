@@ -0,0 +1,150 @@
+//! Statistics for the connections handled by a [`super::Server`]
+
+use crate::persistent::{
+    ErrorCode,
+    client::{LatencyHistogram, LatencySnapshot},
+};
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// Statistics for the connections handled by a server, updated by the
+/// accept loop and the task for each connection
+#[derive(Default)]
+pub struct ServerStats {
+    /// The number of open connections, this is also what the connection
+    /// limits are enforced against
+    pub(super) connections: AtomicUsize,
+    total_connections: AtomicU64,
+    refused: AtomicU64,
+    handshakes_accepted: AtomicU64,
+    handshakes_rejected: AtomicU64,
+    handshakes_failed: AtomicU64,
+    frames_received: AtomicU64,
+    frames_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    /// The number of times each code in [`ErrorCode::ALL`] was sent
+    codes: [AtomicU64; ErrorCode::ALL.len()],
+    /// The latency from when a frame was read until it was executed
+    execute_latency: LatencyHistogram,
+}
+
+impl ServerStats {
+    #[inline]
+    pub(super) fn accepted_connection(&self) {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A connection was refused because the server, or its IP address, was
+    /// at capacity
+    #[inline]
+    pub(super) fn refused(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(super) fn handshake_accepted(&self) {
+        self.handshakes_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The client was rejected by the ICAO allow list or by the executor
+    #[inline]
+    pub(super) fn handshake_rejected(&self) {
+        self.handshakes_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(super) fn handshake_failed(&self) {
+        self.handshakes_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(super) fn received(&self, bytes: usize) {
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(super) fn sent(&self, bytes: usize) {
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(super) fn executed(&self, latency: Duration) {
+        self.execute_latency.record(latency);
+    }
+
+    /// The code was sent when closing a connection or resetting its stream
+    #[inline]
+    pub(super) fn closed_with(&self, code: ErrorCode) {
+        if let Some(index) = ErrorCode::ALL.iter().position(|c| *c == code) {
+            self.codes[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Takes a snapshot of the current statistics
+    pub fn snapshot(&self) -> ServerStatsSnapshot {
+        ServerStatsSnapshot {
+            connections: self.connections.load(Ordering::Acquire) as u64,
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            refused: self.refused.load(Ordering::Relaxed),
+            handshakes_accepted: self.handshakes_accepted.load(Ordering::Relaxed),
+            handshakes_rejected: self.handshakes_rejected.load(Ordering::Relaxed),
+            handshakes_failed: self.handshakes_failed.load(Ordering::Relaxed),
+            frames_received: self.frames_received.load(Ordering::Relaxed),
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            codes: ErrorCode::ALL
+                .iter()
+                .zip(&self.codes)
+                .filter_map(|(code, count)| {
+                    let count = count.load(Ordering::Relaxed);
+                    (count > 0).then_some((*code, count))
+                })
+                .collect(),
+            execute_latency: self.execute_latency.snapshot(),
+        }
+    }
+}
+
+/// A point in time view of [`ServerStats`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerStatsSnapshot {
+    /// The number of connections that are currently open, including ones
+    /// that are still completing the handshake
+    pub connections: u64,
+    /// The number of connections that were accepted, ie. not refused
+    pub total_connections: u64,
+    /// The number of connections that were refused because the server, or
+    /// the client's IP address, was at capacity
+    pub refused: u64,
+    /// The number of handshakes that completed, after which the client can
+    /// send frames
+    pub handshakes_accepted: u64,
+    /// The number of clients that were rejected because of their ICAO, or by
+    /// [`super::AgentExecutor::authorize`]
+    pub handshakes_rejected: u64,
+    /// The number of handshakes that couldn't be read or were invalid
+    pub handshakes_failed: u64,
+    /// The number of frames read from clients
+    pub frames_received: u64,
+    /// The number of responses written to clients
+    pub frames_sent: u64,
+    /// The number of bytes read from clients, including length prefixes
+    pub bytes_received: u64,
+    /// The number of bytes written to clients, including length prefixes
+    pub bytes_sent: u64,
+    /// The number of times each code was sent when closing a connection or
+    /// resetting its stream, codes that were never sent are omitted
+    pub codes: BTreeMap<ErrorCode, u64>,
+    /// The latency from when a frame was read until it was executed,
+    /// including the time spent waiting for the batch it was
+    /// [coalesced](super::ServerConfig::coalesce) into
+    pub execute_latency: LatencySnapshot,
+}
@@ -3,22 +3,26 @@ CREATE TABLE servers (
     -- hostname or IP + port
     endpoint varchar(264) not null primary key,
     -- icao code
-    icao char(4) not null default 'XXXX',
-    -- Token set. Since SQLite does not support arrays, we use a base64 encoded binary blob
-    tokens text,
+    icao char(4) not null default 'XXXX' CHECK (icao GLOB '[A-Z][A-Z0-9][A-Z0-9][A-Z0-9]'),
+    -- Token set. Since SQLite does not support arrays, we use a custom binary
+    -- encoding, stored as base64 text when written via the corrosion API, as
+    -- JSON has no binary type, or as a blob when written directly
+    tokens blob,
     -- The JSONB set of peers that contributed this server
     contributors blob,
     -- The timestamp of the last contributors update, either insertion or deletion
     cont_update timestamp
 );
 
+CREATE INDEX servers_icao ON servers (icao);
+
 CREATE TABLE dc (
     -- the IPv6 (or IPv4 mapped) address
     ip varchar(40) not null primary key,
     -- the QCMP port used for pinging
     port int not null default 0,
     -- icao code
-    icao char(4) not null default 'XXXX',
+    icao char(4) not null default 'XXXX' CHECK (icao GLOB '[A-Z][A-Z0-9][A-Z0-9][A-Z0-9]'),
     -- the JSONB set of servers that this peer contributed
     servers blob
 );
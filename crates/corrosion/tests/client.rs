@@ -0,0 +1,2505 @@
+//! Tests for the behavior of the persistent client that don't depend on the
+//! database, the executor just reports the number of changes it received
+
+use corrosion::{Peer, persistent as p};
+use quilkin_types::{Endpoint, IcaoCode};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+#[derive(Clone, Default)]
+struct CountingExecutor {
+    /// The number of milliseconds to wait before responding
+    delay_ms: Arc<AtomicU64>,
+    /// The number of milliseconds frames with more than one change wait, in
+    /// addition to `delay_ms`
+    bulk_delay_ms: Arc<AtomicU64>,
+    /// The total number of changes executed
+    executed: Arc<AtomicUsize>,
+    /// The number of frames executed
+    frames: Arc<AtomicUsize>,
+    /// The number of milliseconds to wait before completing a disconnection
+    disconnect_delay_ms: Arc<AtomicU64>,
+    /// The number of disconnections that completed
+    disconnected: Arc<AtomicUsize>,
+}
+
+#[async_trait::async_trait]
+impl p::server::InfallibleExecutor for CountingExecutor {
+    async fn connected(&self, _peer: Peer, _icao: IcaoCode, _qcmp_port: u16) {}
+
+    async fn execute(&self, _peer: Peer, statements: &[p::ServerChange]) -> p::ExecResult {
+        let mut delay = self.delay_ms.load(Ordering::Relaxed);
+        if statements.len() > 1 {
+            delay += self.bulk_delay_ms.load(Ordering::Relaxed);
+        }
+        if delay > 0 {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+
+        self.executed.fetch_add(statements.len(), Ordering::Relaxed);
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        p::ExecResult::Execute {
+            rows_affected: statements.len(),
+            time: 0.,
+        }
+    }
+
+    async fn disconnected(&self, _peer: Peer) {
+        let delay = self.disconnect_delay_ms.load(Ordering::Relaxed);
+        if delay > 0 {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+
+        self.disconnected.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn server(exec: CountingExecutor) -> p::server::Server {
+    p::server::Server::new_unencrypted((std::net::Ipv6Addr::LOCALHOST, 0).into(), exec).unwrap()
+}
+
+fn changes(count: usize) -> Vec<p::ServerChange> {
+    (0..count)
+        .map(|i| {
+            p::ServerChange::Remove(vec![Endpoint::new(
+                std::net::Ipv4Addr::new(1, 2, 3, i as u8).into(),
+                7777,
+            )])
+        })
+        .collect()
+}
+
+fn rows_affected(res: p::ExecResult) -> usize {
+    match res {
+        p::ExecResult::Execute { rows_affected, .. } => rows_affected,
+        other => panic!("unexpected result {other:?}"),
+    }
+}
+
+/// Tests that a transaction that takes too long times out, and that the late
+/// response doesn't get matched to a later transaction
+#[tokio::test]
+async fn transaction_timeout() {
+    let exec = CountingExecutor::default();
+    let server = server(exec.clone());
+
+    let client = p::client::Client::connect_insecure_with_config(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'T'; 4]),
+        p::client::ClientConfig {
+            transaction_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    exec.delay_ms.store(500, Ordering::Relaxed);
+    assert!(matches!(
+        client.transactions(&changes(1)).await,
+        Err(p::client::TransactionError::Timeout { .. })
+    ));
+
+    // An explicit timeout overrides the default
+    assert!(matches!(
+        client
+            .transactions_timeout(&changes(1), Duration::from_millis(10))
+            .await,
+        Err(p::client::TransactionError::Timeout { .. })
+    ));
+
+    exec.delay_ms.store(0, Ordering::Relaxed);
+    let res = client
+        .transactions_timeout(&changes(3), Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(rows_affected(res), 3);
+
+    let res = client.transactions(&changes(2)).await.unwrap();
+    assert_eq!(rows_affected(res), 2);
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Captures the output of a tracing subscriber
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    fn take(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+    }
+}
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Tests that each transaction is wrapped in a span recording the changes it
+/// contains and its outcome, and that failures are logged as errors
+#[tokio::test]
+async fn transaction_spans() {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_ansi(false)
+        .with_writer({
+            let logs = logs.clone();
+            move || logs.clone()
+        })
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let exec = CountingExecutor::default();
+    let server = server(exec.clone());
+
+    let client = p::client::Client::connect_insecure(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'L'; 4]),
+    )
+    .await
+    .unwrap();
+    logs.take();
+
+    let res = client.transactions(&changes(3)).await.unwrap();
+    assert_eq!(rows_affected(res), 3);
+
+    let success = logs.take();
+    let span = success
+        .lines()
+        .find(|line| line.contains("transaction{"))
+        .unwrap_or_else(|| panic!("no transaction span in:\n{success}"));
+    assert!(span.contains("inserts=0 removes=3 updates=0"), "{span}");
+    assert!(span.contains("bytes="), "{span}");
+    assert!(span.contains("outcome=\"executed\""), "{span}");
+    assert!(!success.contains("ERROR"), "{success}");
+
+    exec.delay_ms.store(500, Ordering::Relaxed);
+    assert!(matches!(
+        client
+            .transactions_timeout(&changes(1), Duration::from_millis(10))
+            .await,
+        Err(p::client::TransactionError::Timeout { .. })
+    ));
+
+    let failure = logs.take();
+    let error = failure
+        .lines()
+        .find(|line| line.contains("ERROR"))
+        .unwrap_or_else(|| panic!("no error in:\n{failure}"));
+    assert!(error.contains("transaction{"), "{error}");
+    assert!(error.contains("transaction failed"), "{error}");
+    assert!(
+        failure
+            .lines()
+            .any(|line| line.contains("transaction{") && line.contains("outcome=\"failed\"")),
+        "{failure}"
+    );
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that the client only uses the first bidirectional stream on the
+/// connection for all of its transactions
+#[tokio::test]
+async fn single_stream() {
+    let server = server(CountingExecutor::default());
+
+    let client = p::client::Client::connect_insecure(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'S'; 4]),
+    )
+    .await
+    .unwrap();
+
+    let stream_id = client.stream_id();
+    assert_eq!(stream_id.initiator(), quinn::Side::Client);
+    assert_eq!(stream_id.dir(), quinn::Dir::Bi);
+    assert_eq!(stream_id.index(), 0);
+
+    for i in 1..4 {
+        let res = client.transactions(&changes(i)).await.unwrap();
+        assert_eq!(rows_affected(res), i);
+        assert_eq!(client.stream_id(), stream_id);
+    }
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that the client binds to an address matching the server's family
+/// by default, and that incompatible bind addresses are rejected
+#[tokio::test]
+async fn bind_addresses() {
+    let exec = CountingExecutor::default();
+    let v4_server =
+        p::server::Server::new_unencrypted((std::net::Ipv4Addr::LOCALHOST, 0).into(), exec.clone())
+            .unwrap();
+    let v6_server = server(exec);
+    let icao = IcaoCode::new_testing([b'B'; 4]);
+
+    let connect = async |addr, config| {
+        p::client::Client::connect_insecure_with_config(addr, 2001, icao, config).await
+    };
+
+    let client = connect(v4_server.local_addr(), Default::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        client.local_addr().ip(),
+        std::net::IpAddr::from(std::net::Ipv4Addr::LOCALHOST)
+    );
+    assert_eq!(
+        rows_affected(client.transactions(&changes(1)).await.unwrap()),
+        1
+    );
+    client.shutdown().await;
+
+    // An IPv6 socket can only reach an IPv4 server if it is dual stack
+    let Err(err) = connect(
+        v4_server.local_addr(),
+        p::client::ClientConfig {
+            bind_addr: Some((std::net::Ipv6Addr::UNSPECIFIED, 0).into()),
+            ..Default::default()
+        },
+    )
+    .await
+    else {
+        panic!("expected IPv6 -> IPv4 connection to fail");
+    };
+    assert!(matches!(
+        err,
+        p::client::ConnectError::AddressFamilyMismatch { .. }
+    ));
+
+    let client = connect(
+        v4_server.local_addr(),
+        p::client::ClientConfig {
+            bind_addr: Some((std::net::Ipv6Addr::UNSPECIFIED, 0).into()),
+            dual_stack: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        rows_affected(client.transactions(&changes(2)).await.unwrap()),
+        2
+    );
+    client.shutdown().await;
+
+    let Err(err) = connect(
+        v6_server.local_addr(),
+        p::client::ClientConfig {
+            bind_addr: Some((std::net::Ipv4Addr::LOCALHOST, 0).into()),
+            ..Default::default()
+        },
+    )
+    .await
+    else {
+        panic!("expected IPv4 -> IPv6 connection to fail");
+    };
+    assert!(matches!(
+        err,
+        p::client::ConnectError::AddressFamilyMismatch { .. }
+    ));
+
+    v4_server.shutdown("done").await;
+    v6_server.shutdown("done").await;
+}
+
+/// Tests that changes submitted individually are all executed, and that each
+/// submission resolves once its batch completes
+#[tokio::test]
+async fn submits_changes() {
+    let exec = CountingExecutor::default();
+    let server = server(exec.clone());
+
+    let client = p::client::Client::connect_insecure(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'C'; 4]),
+    )
+    .await
+    .unwrap();
+
+    // Slow down the executor so that submissions queue up behind the in flight batch
+    exec.delay_ms.store(20, Ordering::Relaxed);
+
+    let mut submissions = tokio::task::JoinSet::new();
+    for change in changes(20) {
+        submissions.spawn(client.submit(change));
+    }
+
+    let mut completed = 0;
+    while let Some(res) = submissions.join_next().await {
+        res.unwrap().unwrap();
+        completed += 1;
+    }
+
+    assert_eq!(completed, 20);
+
+    assert_eq!(exec.executed.load(Ordering::Relaxed), 20);
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that the client's statistics are consistent with the transactions
+/// that were performed
+#[tokio::test]
+async fn records_stats() {
+    let exec = CountingExecutor::default();
+    let server = server(exec.clone());
+
+    let client = p::client::Client::connect_insecure_with_config(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'D'; 4]),
+        p::client::ClientConfig {
+            transaction_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    let stats = client.stats();
+    assert_eq!(stats.snapshot(), Default::default());
+
+    for i in 1..6 {
+        assert_eq!(
+            rows_affected(client.transactions(&changes(i)).await.unwrap()),
+            i
+        );
+    }
+
+    // The response to a timed out transaction is still read
+    exec.delay_ms.store(200, Ordering::Relaxed);
+    assert!(client.transactions(&changes(1)).await.is_err());
+    exec.delay_ms.store(0, Ordering::Relaxed);
+    client
+        .transactions_timeout(&changes(1), Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.in_flight, 0);
+    assert_eq!(snapshot.sent, 7);
+    assert_eq!(snapshot.sent, snapshot.completed + snapshot.failed);
+    assert!(snapshot.bytes_sent > 0);
+    assert!(snapshot.bytes_received > 0);
+    assert_eq!(snapshot.queued_latency.count, 7);
+    assert_eq!(snapshot.write_latency.count, 7);
+    assert!(snapshot.queued_latency.p99 >= Duration::from_millis(200));
+    assert!(snapshot.queued_latency.p50 <= snapshot.queued_latency.p99);
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that the typed convenience methods each send a single change
+#[tokio::test]
+async fn typed_changes() {
+    let exec = CountingExecutor::default();
+    let server = server(exec.clone());
+    let icao = IcaoCode::new_testing([b'E'; 4]);
+
+    let client = p::client::Client::connect_insecure(server.local_addr(), 2001, icao)
+        .await
+        .unwrap();
+
+    let endpoint = Endpoint::new(std::net::Ipv4Addr::new(1, 2, 3, 4).into(), 7777);
+
+    let res = client
+        .upsert_one(endpoint.clone(), icao, &[[1; 2]].into())
+        .await
+        .unwrap();
+    assert_eq!(rows_affected(res), 1);
+
+    let res = client
+        .update_servers(&[p::ServerUpdate {
+            endpoint: endpoint.clone(),
+            icao: None,
+            tokens: Some([[2; 2]].into()),
+        }])
+        .await
+        .unwrap();
+    assert_eq!(rows_affected(res), 1);
+
+    let res = client.remove_servers(&[endpoint]).await.unwrap();
+    assert_eq!(rows_affected(res), 1);
+
+    assert_eq!(exec.executed.load(Ordering::Relaxed), 3);
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that transactions queued while waiting for a response are coalesced
+/// into fewer frames when enabled
+#[tokio::test]
+async fn coalesces_transactions() {
+    let exec = CountingExecutor::default();
+    let server = server(exec.clone());
+
+    let client = Arc::new(
+        p::client::Client::connect_insecure_with_config(
+            server.local_addr(),
+            2001,
+            IcaoCode::new_testing([b'F'; 4]),
+            p::client::ClientConfig {
+                coalesce: Some(16),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap(),
+    );
+
+    // Block the I/O task on the response to the first frame so the rest queue up
+    exec.delay_ms.store(50, Ordering::Relaxed);
+
+    let mut transactions = tokio::task::JoinSet::new();
+    for change in changes(50) {
+        let client = client.clone();
+        transactions.spawn(async move { client.transactions(&[change]).await });
+    }
+
+    let mut completed = 0;
+    while let Some(res) = transactions.join_next().await {
+        // Each transaction receives the response for the entire frame
+        assert!(rows_affected(res.unwrap().unwrap()) >= 1);
+        completed += 1;
+    }
+
+    assert_eq!(completed, 50);
+    assert_eq!(exec.executed.load(Ordering::Relaxed), 50);
+
+    let frames = exec.frames.load(Ordering::Relaxed);
+    assert!(
+        frames <= 10,
+        "expected far fewer than 50 frames, got {frames}"
+    );
+
+    let snapshot = client.stats().snapshot();
+    assert_eq!(snapshot.sent, frames as u64);
+    assert_eq!(snapshot.coalesced, 50 - frames as u64);
+    assert_eq!(snapshot.in_flight, 0);
+
+    let Some(client) = Arc::into_inner(client) else {
+        panic!("client is still shared");
+    };
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that submitted changes are held until the flush policy's delay
+/// elapses, or its maximum number of changes is reached, unless they are
+/// flushed explicitly
+#[tokio::test]
+async fn flush_policy() {
+    let exec = CountingExecutor::default();
+    let server = server(exec.clone());
+
+    let client = p::client::Client::connect_insecure_with_config(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'F'; 4]),
+        p::client::ClientConfig {
+            flush: Some(p::client::FlushPolicy {
+                max_delay: Duration::from_millis(20),
+                max_changes: 3,
+                max_bytes: usize::MAX,
+            }),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    // Yielding lets the client's tasks run without advancing the paused clock,
+    // which otherwise skips ahead whenever they wait on the network
+    let sent = async |frames| {
+        while client.stats().snapshot().sent < frames {
+            tokio::task::yield_now().await;
+        }
+    };
+
+    tokio::time::pause();
+    let mut changes = changes(6).into_iter();
+
+    // A change submitted during the delay joins the pending batch
+    let start = tokio::time::Instant::now();
+    let first = client.submit(changes.next().unwrap());
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(client.stats().snapshot().sent, 0);
+    let second = client.submit(changes.next().unwrap());
+    first.await.unwrap();
+    second.await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert_eq!(exec.frames.load(Ordering::Relaxed), 1);
+    assert_eq!(exec.executed.load(Ordering::Relaxed), 2);
+
+    // Flushing sends the pending batch without waiting for the delay
+    let third = client.submit(changes.next().unwrap());
+    client.flush();
+    let flushed = tokio::time::Instant::now();
+    sent(2).await;
+    assert_eq!(flushed.elapsed(), Duration::ZERO);
+    third.await.unwrap();
+
+    // As does reaching the maximum number of changes
+    let full: Vec<_> = changes.map(|change| client.submit(change)).collect();
+    let filled = tokio::time::Instant::now();
+    sent(3).await;
+    assert_eq!(filled.elapsed(), Duration::ZERO);
+    for submission in full {
+        submission.await.unwrap();
+    }
+
+    assert_eq!(exec.frames.load(Ordering::Relaxed), 3);
+    assert_eq!(exec.executed.load(Ordering::Relaxed), 6);
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that a graceful shutdown sends all queued work before closing
+#[tokio::test]
+async fn shutdown_graceful_completes() {
+    let exec = CountingExecutor::default();
+    let server = server(exec.clone());
+
+    let client = p::client::Client::connect_insecure(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'G'; 4]),
+    )
+    .await
+    .unwrap();
+
+    exec.delay_ms.store(20, Ordering::Relaxed);
+
+    let submissions: Vec<_> = changes(10)
+        .into_iter()
+        .map(|change| client.submit(change))
+        .collect();
+
+    let summary = client.shutdown_graceful(Duration::from_secs(5)).await;
+    assert_eq!(summary.abandoned, 0);
+    assert!(summary.completed >= 1);
+
+    for submission in submissions {
+        submission.await.unwrap();
+    }
+    assert_eq!(exec.executed.load(Ordering::Relaxed), 10);
+
+    server.shutdown("done").await;
+}
+
+/// Tests that a graceful shutdown honors its deadline if the server doesn't
+/// respond
+#[tokio::test]
+async fn shutdown_graceful_deadline() {
+    let exec = CountingExecutor::default();
+    let server = server(exec.clone());
+
+    let client = p::client::Client::connect_insecure(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'H'; 4]),
+    )
+    .await
+    .unwrap();
+
+    exec.delay_ms.store(2000, Ordering::Relaxed);
+
+    let submission = client.submit(changes(1).pop().unwrap());
+
+    // Ensure the change has actually been written before shutting down
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let start = std::time::Instant::now();
+    let summary = client.shutdown_graceful(Duration::from_millis(100)).await;
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(
+        summary,
+        p::client::ShutdownSummary {
+            completed: 0,
+            abandoned: 1,
+            unsent: Vec::new(),
+        }
+    );
+
+    assert!(submission.await.is_err());
+
+    server.shutdown("done").await;
+}
+
+/// Tests that the changes that weren't sent before the shutdown deadline are
+/// returned to the caller, and that a server that never responds doesn't delay
+/// the shutdown past the deadline
+#[tokio::test]
+async fn shutdown_graceful_unsent() {
+    let exec = CountingExecutor::default();
+    let server = server(exec.clone());
+
+    let mut client = p::client::Client::connect_insecure(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'U'; 4]),
+    )
+    .await
+    .unwrap();
+
+    exec.delay_ms.store(5000, Ordering::Relaxed);
+
+    let stats = client.stats();
+    let wait_for = async |condition: &dyn Fn(p::client::ClientStatsSnapshot) -> bool| {
+        while !condition(stats.snapshot()) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    };
+
+    // The first change is sent, but never responded to
+    let mut changes = changes(4).into_iter();
+    let first = client.submit(changes.next().unwrap());
+    wait_for(&|stats| stats.sent == 1).await;
+
+    // The resync is queued behind it, but can't be sent until the first
+    // change is responded to
+    let upsert = || p::ServerUpsert {
+        endpoint: Endpoint::new(
+            quilkin_types::AddressKind::Name("resync.example".into()),
+            7777,
+        ),
+        icao: IcaoCode::new_testing([b'U'; 4]),
+        tokens: [[9; 4]].into(),
+    };
+    client.enable_resync(Duration::from_millis(10), move || vec![upsert()]);
+    wait_for(&|stats| stats.in_flight == 2).await;
+
+    // As are the remaining changes, which are waiting to be batched
+    let rest: Vec<_> = changes.map(|change| client.submit(change)).collect();
+
+    let start = std::time::Instant::now();
+    let summary = client.shutdown_graceful(Duration::from_millis(100)).await;
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    let mut unsent = vec![p::ServerChange::Insert(vec![upsert()])];
+    unsent.extend(self::changes(4).into_iter().skip(1));
+    assert_eq!(
+        summary,
+        p::client::ShutdownSummary {
+            completed: 0,
+            abandoned: 1,
+            unsent,
+        }
+    );
+
+    assert!(first.await.is_err());
+    for submission in rest {
+        assert!(submission.await.is_err());
+    }
+
+    // Don't wait for the delayed execution to finish
+    server
+        .shutdown_graceful("done", Duration::from_millis(100))
+        .await;
+}
+
+/// Tests that connecting to multiple addresses skips an address that never
+/// responds in favor of a live one
+#[tokio::test]
+async fn connects_to_live_address() {
+    let server = server(CountingExecutor::default());
+
+    // A socket that is bound, but never responds
+    let dead = std::net::UdpSocket::bind((std::net::Ipv6Addr::LOCALHOST, 0)).unwrap();
+
+    let client = p::client::Client::connect_to_any(
+        &[dead.local_addr().unwrap(), server.local_addr()],
+        "localhost",
+        2001,
+        IcaoCode::new_testing([b'I'; 4]),
+        Default::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(client.remote_addr(), server.local_addr());
+    assert_eq!(
+        rows_affected(client.transactions(&changes(1)).await.unwrap()),
+        1
+    );
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that a TLS session is only established if the server's certificate
+/// is valid for the name being connected to
+#[tokio::test]
+async fn validates_certificate_name() {
+    use quinn::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["relay.test".into()]).unwrap();
+    let cert = CertificateDer::from(cert.der().to_vec());
+
+    let server_config = quinn::ServerConfig::with_single_cert(
+        vec![cert.clone()],
+        PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into(),
+    )
+    .unwrap();
+    let server = p::server::Server::new_with_config(
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+        server_config,
+        CountingExecutor::default(),
+    )
+    .unwrap();
+
+    let mut roots = quinn::rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let config = p::client::ClientConfig {
+        tls_roots: Some(Arc::new(roots)),
+        ..Default::default()
+    };
+    let icao = IcaoCode::new_testing([b'J'; 4]);
+
+    let client = p::client::Client::connect_to_any(
+        &[server.local_addr()],
+        "relay.test",
+        2001,
+        icao,
+        config.clone(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        rows_affected(client.transactions(&changes(2)).await.unwrap()),
+        2
+    );
+    client.shutdown().await;
+
+    let Err(err) =
+        p::client::Client::connect_to_any(&[server.local_addr()], "other.test", 2001, icao, config)
+            .await
+    else {
+        panic!("expected the certificate name mismatch to be rejected");
+    };
+    let p::client::ConnectError::AllAddressesFailed(errors) = err else {
+        panic!("unexpected error {err}");
+    };
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, server.local_addr());
+
+    server.shutdown("done").await;
+}
+
+/// Tests that TLS sessions negotiate the protocol's ALPN, and that a client
+/// offering a different protocol is rejected by the TLS handshake, before the
+/// protocol handshake
+#[tokio::test]
+async fn rejects_wrong_alpn() {
+    use quinn::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["relay.test".into()]).unwrap();
+    let cert = CertificateDer::from(cert.der().to_vec());
+
+    let exec = CountingExecutor::default();
+    let server = p::server::Server::new_with_config(
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+        p::server::tls_config(
+            vec![cert.clone()],
+            PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into(),
+        )
+        .unwrap(),
+        exec.clone(),
+    )
+    .unwrap();
+
+    let mut roots = quinn::rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let roots = Arc::new(roots);
+    let icao = IcaoCode::new_testing([b'J'; 4]);
+
+    let client = p::client::Client::connect_to_any(
+        &[server.local_addr()],
+        "relay.test",
+        2001,
+        icao,
+        p::client::ClientConfig {
+            tls_roots: Some(roots.clone()),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        rows_affected(client.transactions(&changes(2)).await.unwrap()),
+        2
+    );
+    client.shutdown().await;
+
+    let mut crypto = quinn::rustls::ClientConfig::builder_with_provider(Arc::new(
+        quinn::rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&quinn::rustls::version::TLS13])
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    crypto.alpn_protocols = vec![b"not-quilkin/1".to_vec()];
+    let client_config = quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap(),
+    ));
+
+    let ep = p::client::EndpointBuilder::new((std::net::Ipv6Addr::LOCALHOST, 0).into())
+        .build()
+        .unwrap();
+    let err = ep
+        .connect_with(client_config, server.local_addr(), "relay.test")
+        .unwrap()
+        .await
+        .unwrap_err();
+    // 120 is the TLS no_application_protocol alert
+    let quinn::ConnectionError::ConnectionClosed(close) = err else {
+        panic!("unexpected error {err}");
+    };
+    assert_eq!(close.error_code, quinn::TransportErrorCode::crypto(120));
+
+    // Only the first client completed the protocol handshake
+    assert_eq!(server.stats().snapshot().handshakes_accepted, 1);
+    assert_eq!(exec.frames.load(Ordering::Relaxed), 1);
+
+    server.shutdown("done").await;
+}
+
+/// Tests that multiple clients can share a single endpoint, and that shutting
+/// down one client doesn't affect the others
+#[tokio::test]
+async fn shared_endpoint() {
+    let first_exec = CountingExecutor::default();
+    let second_exec = CountingExecutor::default();
+    let first = server(first_exec.clone());
+    let second = server(second_exec.clone());
+
+    let ep = p::client::EndpointBuilder::new((std::net::Ipv6Addr::LOCALHOST, 0).into())
+        .build()
+        .unwrap();
+    let icao = IcaoCode::new_testing([b'E'; 4]);
+
+    let connect = async |addr| {
+        p::client::Client::connect_with_endpoint(&ep, addr, 2001, icao, Default::default())
+            .await
+            .unwrap()
+    };
+
+    let first_client = connect(first.local_addr()).await;
+    let second_client = connect(second.local_addr()).await;
+    assert_eq!(first_client.local_addr(), ep.local_addr().unwrap());
+    assert_eq!(second_client.local_addr(), ep.local_addr().unwrap());
+
+    let run = async |client: &p::client::Client| {
+        for i in 1..5 {
+            assert_eq!(
+                rows_affected(client.transactions(&changes(i)).await.unwrap()),
+                i
+            );
+        }
+    };
+    tokio::join!(run(&first_client), run(&second_client));
+
+    assert_eq!(first_exec.executed.load(Ordering::Relaxed), 10);
+    assert_eq!(second_exec.executed.load(Ordering::Relaxed), 10);
+
+    first_client.shutdown().await;
+
+    // The endpoint is still usable by the remaining client, and new ones
+    assert_eq!(
+        rows_affected(second_client.transactions(&changes(3)).await.unwrap()),
+        3
+    );
+    let third_client = connect(first.local_addr()).await;
+    assert_eq!(
+        rows_affected(third_client.transactions(&changes(2)).await.unwrap()),
+        2
+    );
+
+    second_client.shutdown().await;
+    third_client.shutdown().await;
+    first.shutdown("done").await;
+    second.shutdown("done").await;
+}
+
+/// Tests that a server at capacity refuses connections before the handshake
+#[tokio::test]
+async fn refused_at_capacity() {
+    let server_with_capacity = |max_connections| {
+        p::server::Server::new(
+            (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+            p::server::ServerConfig {
+                max_connections: Some(max_connections),
+                ..Default::default()
+            },
+            CountingExecutor::default(),
+        )
+        .unwrap()
+    };
+    let icao = IcaoCode::new_testing([b'F'; 4]);
+
+    let full = server_with_capacity(0);
+    let Err(err) = p::client::Client::connect_insecure(full.local_addr(), 2001, icao).await else {
+        panic!("expected the connection to be refused");
+    };
+    assert!(matches!(
+        err,
+        p::client::ConnectError::Rejected {
+            reason: Some(p::RejectReason::AtCapacity)
+        }
+    ));
+    assert_eq!(
+        err.to_string(),
+        "the server declined the connection: the server is at capacity"
+    );
+    full.shutdown("done").await;
+
+    let single = server_with_capacity(1);
+    let client = p::client::Client::connect_insecure(single.local_addr(), 2001, icao)
+        .await
+        .unwrap();
+    let Err(err) = p::client::Client::connect_insecure(single.local_addr(), 2001, icao).await
+    else {
+        panic!("expected the second connection to be refused");
+    };
+    assert!(matches!(
+        err,
+        p::client::ConnectError::Rejected {
+            reason: Some(p::RejectReason::AtCapacity)
+        }
+    ));
+
+    // The refused connection doesn't affect the accepted one
+    assert_eq!(
+        rows_affected(client.transactions(&changes(2)).await.unwrap()),
+        2
+    );
+
+    client.shutdown().await;
+    single.shutdown("done").await;
+}
+
+/// Tests that connections over the per IP limit are refused, counting IPv4
+/// addresses the same as their IPv4-mapped equivalent, while connections from
+/// other addresses are still accepted
+#[tokio::test]
+async fn refused_per_ip() {
+    let server = p::server::Server::new(
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        p::server::ServerConfig {
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        },
+        CountingExecutor::default(),
+    )
+    .unwrap();
+    let port = server.local_addr().port();
+    let icao = IcaoCode::new_testing([b'F'; 4]);
+
+    let v4 = (std::net::Ipv4Addr::LOCALHOST, port).into();
+    let v6 = (std::net::Ipv6Addr::LOCALHOST, port).into();
+
+    // A dual stack socket connects to the IPv4 address via its IPv4-mapped
+    // equivalent
+    let connect = async |addr, dual_stack| {
+        let config = if dual_stack {
+            p::client::ClientConfig {
+                bind_addr: Some((std::net::Ipv6Addr::UNSPECIFIED, 0).into()),
+                dual_stack: true,
+                ..Default::default()
+            }
+        } else {
+            Default::default()
+        };
+        p::client::Client::connect_insecure_with_config(addr, 2001, icao, config).await
+    };
+
+    let first = connect(v4, false).await.unwrap();
+    let second = connect(v4, true).await.unwrap();
+    assert_eq!(server.connections(), 2);
+
+    for dual_stack in [false, true] {
+        let Err(err) = connect(v4, dual_stack).await else {
+            panic!("expected the connection to be refused");
+        };
+        assert!(matches!(
+            err,
+            p::client::ConnectError::Rejected {
+                reason: Some(p::RejectReason::AtCapacity)
+            }
+        ));
+    }
+
+    let third = connect(v6, false).await.unwrap();
+    assert_eq!(server.connections(), 3);
+
+    // The refused connections don't affect the accepted ones
+    for client in [&first, &second, &third] {
+        assert_eq!(
+            rows_affected(client.transactions(&changes(2)).await.unwrap()),
+            2
+        );
+    }
+
+    // Slots are released when connections close
+    first.shutdown().await;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.connections() > 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the connection should have been released");
+    let fourth = connect(v4, false).await.unwrap();
+
+    second.shutdown().await;
+    third.shutdown().await;
+    fourth.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that a server bound to multiple addresses accepts connections on each
+/// of them, and tracks them together
+#[tokio::test]
+async fn binds_multiple_addresses() {
+    let exec = CountingExecutor::default();
+    let server = p::server::Server::bind(
+        &[
+            (std::net::Ipv4Addr::LOCALHOST, 0).into(),
+            (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+        ],
+        Default::default(),
+        exec.clone(),
+    )
+    .unwrap();
+    let icao = IcaoCode::new_testing([b'M'; 4]);
+
+    let addrs = server.local_addrs();
+    assert_eq!(addrs.len(), 2);
+    assert!(addrs[0].is_ipv4());
+    assert!(addrs[1].is_ipv6());
+    assert_eq!(server.local_addr(), addrs[0]);
+
+    let mut clients = Vec::new();
+    for addr in &addrs {
+        clients.push(
+            p::client::Client::connect_insecure(*addr, 2001, icao)
+                .await
+                .unwrap(),
+        );
+    }
+    assert_eq!(server.connections(), 2);
+
+    for (i, client) in clients.iter().enumerate() {
+        assert_eq!(
+            rows_affected(client.transactions(&changes(i + 1)).await.unwrap()),
+            i + 1
+        );
+    }
+    assert_eq!(exec.frames.load(Ordering::Relaxed), 2);
+    assert_eq!(exec.executed.load(Ordering::Relaxed), 3);
+
+    let summary = server.shutdown("done").await;
+    assert_eq!(summary.closed, 2);
+    for client in clients {
+        client.shutdown().await;
+    }
+
+    assert!(
+        p::server::Server::bind(&[], Default::default(), exec).is_err(),
+        "a server needs at least one address"
+    );
+}
+
+/// Tests that the load reported by the server reflects the number of
+/// connections to it
+#[tokio::test]
+async fn reports_load() {
+    let server = p::server::Server::new(
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+        p::server::ServerConfig {
+            max_connections: Some(4),
+            report_load: true,
+            ..Default::default()
+        },
+        CountingExecutor::default(),
+    )
+    .unwrap();
+    let icao = IcaoCode::new_testing([b'L'; 4]);
+    let connect = || p::client::Client::connect_insecure(server.local_addr(), 2001, icao);
+
+    // The load includes the connection it is reported to
+    let first = connect().await.unwrap();
+    assert_eq!(first.server_load(), Some(25));
+    let second = connect().await.unwrap();
+    assert_eq!(second.server_load(), Some(50));
+    assert_eq!(first.server_load(), Some(25));
+
+    first.shutdown().await;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.connections() > 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the connection should have been released");
+
+    let third = connect().await.unwrap();
+    assert_eq!(third.server_load(), Some(50));
+
+    second.shutdown().await;
+    third.shutdown().await;
+    server.shutdown("done").await;
+
+    // Servers only report their load if configured to
+    let server = p::server::Server::new(
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+        p::server::ServerConfig {
+            max_connections: Some(4),
+            ..Default::default()
+        },
+        CountingExecutor::default(),
+    )
+    .unwrap();
+    let client = p::client::Client::connect_insecure(server.local_addr(), 2001, icao)
+        .await
+        .unwrap();
+    assert_eq!(client.server_load(), None);
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that shutting down the server waits for the executor to be notified
+/// of every disconnection, aborting the connections that exceed the grace period
+#[tokio::test]
+async fn shutdown_drains_connections() {
+    let exec = CountingExecutor::default();
+    let srv = server(exec.clone());
+    let icao = IcaoCode::new_testing([b'D'; 4]);
+
+    let first = p::client::Client::connect_insecure(srv.local_addr(), 2001, icao)
+        .await
+        .unwrap();
+    let second = p::client::Client::connect_insecure(srv.local_addr(), 2001, icao)
+        .await
+        .unwrap();
+    assert_eq!(
+        rows_affected(second.transactions(&changes(1)).await.unwrap()),
+        1
+    );
+
+    exec.disconnect_delay_ms.store(50, Ordering::Relaxed);
+    let summary = srv.shutdown("done").await;
+    assert_eq!(
+        summary,
+        p::server::ShutdownSummary {
+            closed: 2,
+            aborted: 0
+        }
+    );
+    assert_eq!(exec.disconnected.load(Ordering::Relaxed), 2);
+
+    first.shutdown().await;
+    second.shutdown().await;
+
+    // Connections that don't close within the grace period are aborted
+    let exec = CountingExecutor::default();
+    let srv = server(exec.clone());
+    let client = p::client::Client::connect_insecure(srv.local_addr(), 2001, icao)
+        .await
+        .unwrap();
+
+    exec.disconnect_delay_ms.store(10_000, Ordering::Relaxed);
+    let summary = srv
+        .shutdown_graceful("done", Duration::from_millis(100))
+        .await;
+    assert_eq!(
+        summary,
+        p::server::ShutdownSummary {
+            closed: 0,
+            aborted: 1
+        }
+    );
+    assert_eq!(exec.disconnected.load(Ordering::Relaxed), 0);
+
+    client.shutdown().await;
+}
+
+/// Tests that the server reports a failure if its endpoint is closed while it
+/// is running, and that a server that is shut down is closed
+#[tokio::test]
+async fn server_state() {
+    use p::server::{ServerError, ServerState};
+
+    let exec = CountingExecutor::default();
+    let srv = server(exec.clone());
+    let icao = IcaoCode::new_testing([b'F'; 4]);
+    assert_eq!(srv.state(), ServerState::Running);
+
+    let client = p::client::Client::connect_insecure(srv.local_addr(), 2001, icao)
+        .await
+        .unwrap();
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    srv.on_fatal(move |error| {
+        let _ = tx.send(error.clone());
+    });
+    let closed = tokio::spawn(srv.closed());
+
+    srv.endpoint()
+        .close(quinn::VarInt::from_u32(0), b"out from under");
+    let state = tokio::time::timeout(Duration::from_secs(5), closed)
+        .await
+        .expect("the failure should be observed")
+        .unwrap();
+    assert_eq!(state, ServerState::Failed(ServerError::EndpointClosed));
+    assert_eq!(srv.state(), state);
+    assert_eq!(rx.await.unwrap(), ServerError::EndpointClosed);
+
+    // Callbacks registered after the failure are invoked immediately
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    srv.on_fatal(move |error| {
+        let _ = tx.send(error.clone());
+    });
+    assert_eq!(rx.await.unwrap(), ServerError::EndpointClosed);
+
+    client.shutdown().await;
+    srv.shutdown("done").await;
+
+    // Shutting down isn't a failure
+    let srv = server(exec);
+    let closed = srv.closed();
+    srv.on_fatal(|error| panic!("unexpected failure {error}"));
+    srv.shutdown("done").await;
+    assert_eq!(closed.await, ServerState::Closed);
+}
+
+/// Tests that connection errors are classified by whether they timed out, were
+/// refused, or could succeed if tried again
+#[test]
+fn connect_error_classification() {
+    use p::client::{ConnectError as E, StreamError};
+    use quinn::ConnectionError as Ce;
+
+    let closed = |error_code| {
+        Ce::ConnectionClosed(quinn::ConnectionClose {
+            error_code,
+            frame_type: None,
+            reason: Default::default(),
+        })
+    };
+    let addr: std::net::SocketAddr = (std::net::Ipv6Addr::LOCALHOST, 7777).into();
+
+    // (error, is_timeout, is_refused, is_retryable)
+    let cases = [
+        (E::Connection(Ce::TimedOut), true, false, true),
+        (
+            E::Write(StreamError::Write(quinn::WriteError::ConnectionLost(
+                Ce::TimedOut,
+            ))),
+            true,
+            false,
+            true,
+        ),
+        (
+            E::Connection(closed(quinn::TransportErrorCode::CONNECTION_REFUSED)),
+            false,
+            true,
+            true,
+        ),
+        (
+            E::Connection(closed(quinn::TransportErrorCode::PROTOCOL_VIOLATION)),
+            false,
+            false,
+            true,
+        ),
+        (E::Connection(Ce::Reset), false, false, true),
+        (E::Connection(Ce::LocallyClosed), false, false, false),
+        (E::Connection(Ce::VersionMismatch), false, false, false),
+        (
+            E::Connect(quinn::ConnectError::InvalidServerName("".into())),
+            false,
+            false,
+            false,
+        ),
+        (
+            E::Rejected {
+                reason: Some(p::RejectReason::AtCapacity),
+            },
+            false,
+            true,
+            true,
+        ),
+        (E::Rejected { reason: None }, false, true, true),
+        (
+            E::Rejected {
+                reason: Some(p::RejectReason::Unauthorized),
+            },
+            false,
+            true,
+            false,
+        ),
+        (
+            E::NoAddresses {
+                host: "nowhere.example".into(),
+            },
+            false,
+            false,
+            false,
+        ),
+        (
+            E::UnsupportedVersion { ours: 2, theirs: 3 },
+            false,
+            false,
+            false,
+        ),
+        (
+            E::AllAddressesFailed(vec![
+                (addr, E::Connection(Ce::TimedOut)),
+                (
+                    addr,
+                    E::Rejected {
+                        reason: Some(p::RejectReason::Unauthorized),
+                    },
+                ),
+            ]),
+            false,
+            false,
+            true,
+        ),
+        (
+            E::AllAddressesFailed(vec![
+                (addr, E::Connection(Ce::TimedOut)),
+                (addr, E::Connection(Ce::TimedOut)),
+            ]),
+            true,
+            false,
+            true,
+        ),
+    ];
+
+    for (error, timeout, refused, retryable) in cases {
+        assert_eq!(error.is_timeout(), timeout, "{error:?}");
+        assert_eq!(error.is_refused(), refused, "{error:?}");
+        assert_eq!(error.is_retryable(), retryable, "{error:?}");
+    }
+}
+
+/// Tests that the connection status can be queried without sending anything,
+/// and that it reflects the server closing the connection
+#[tokio::test]
+async fn connection_status() {
+    let server = server(CountingExecutor::default());
+
+    let client = p::client::Client::connect_insecure(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'C'; 4]),
+    )
+    .await
+    .unwrap();
+
+    assert!(client.is_connected());
+    assert_eq!(client.peer_version(), p::client::VERSION);
+    assert_eq!(client.remote_addr(), server.local_addr());
+
+    let connected = client.last_activity();
+    assert!(connected <= std::time::Instant::now());
+
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(
+        rows_affected(client.transactions(&changes(1)).await.unwrap()),
+        1
+    );
+    let active = client.last_activity();
+    assert!(active > connected);
+    assert!(active <= std::time::Instant::now());
+
+    server.shutdown("done").await;
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while client.is_connected() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the client should notice the server closed the connection");
+
+    assert_eq!(client.stats().snapshot().sent, 1);
+    assert_eq!(client.last_activity(), active);
+
+    client.shutdown().await;
+}
+
+/// Tests that a server with an ICAO allowlist rejects clients from other
+/// regions during the handshake
+#[tokio::test]
+async fn rejects_disallowed_icao() {
+    let allowed = IcaoCode::new_testing([b'A'; 4]);
+    let server = p::server::Server::new(
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+        p::server::ServerConfig {
+            allowed_icaos: Some([allowed].into()),
+            ..Default::default()
+        },
+        CountingExecutor::default(),
+    )
+    .unwrap();
+
+    let Err(err) = p::client::Client::connect_insecure(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'B'; 4]),
+    )
+    .await
+    else {
+        panic!("expected the connection to be rejected");
+    };
+    // The server declines the handshake with a reason, rather than resetting
+    // the stream
+    assert!(matches!(
+        err,
+        p::client::ConnectError::Rejected {
+            reason: Some(p::RejectReason::Unauthorized)
+        }
+    ));
+
+    let client = p::client::Client::connect_insecure(server.local_addr(), 2001, allowed)
+        .await
+        .unwrap();
+    assert_eq!(
+        rows_affected(client.transactions(&changes(1)).await.unwrap()),
+        1
+    );
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that the client reports the error code the server reset the stream
+/// with, and that a client that is shutdown reports that instead
+#[tokio::test]
+async fn close_reason() {
+    let server = server(CountingExecutor::default());
+    let icao = IcaoCode::new_testing([b'C'; 4]);
+
+    let client = p::client::Client::connect_insecure(server.local_addr(), 2001, icao)
+        .await
+        .unwrap();
+    assert!(client.close_reason().is_none());
+    assert!(matches!(
+        client.shutdown().await,
+        Some(p::client::CloseReason::Shutdown)
+    ));
+    server.shutdown("done").await;
+
+    // A server that resets the stream after receiving the first transaction
+    let ep = quinn::Endpoint::server(
+        quinn_plaintext::server_config(),
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+    )
+    .unwrap();
+    let addr = ep.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let conn = ep.accept().await.unwrap().await.unwrap();
+        let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+        p::read_length_prefixed(&mut recv).await.unwrap();
+        let hs = p::ServerHandshakeResponseV1 { accept: true }.write();
+        send.write_chunk(p::write_length_prefixed(&hs).freeze())
+            .await
+            .unwrap();
+        p::read_length_prefixed(&mut recv).await.unwrap();
+        send.reset(p::ErrorCode::PayloadTooLarge.into()).unwrap();
+        conn.closed().await;
+    });
+
+    let client = p::client::Client::connect_insecure(addr, 2001, icao)
+        .await
+        .unwrap();
+    assert!(client.transactions(&changes(1)).await.is_err());
+
+    let reason = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(reason) = client.close_reason() {
+                break reason;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(matches!(
+        reason,
+        p::client::CloseReason::Reset(p::ErrorCode::PayloadTooLarge)
+    ));
+    assert!(matches!(
+        client.shutdown().await,
+        Some(p::client::CloseReason::Reset(p::ErrorCode::PayloadTooLarge))
+    ));
+
+    server.await.unwrap();
+}
+
+/// Tests that idempotent transactions are retried on a new stream if the
+/// stream fails, while removes fail with the original error
+#[tokio::test]
+async fn retries_idempotent_transactions() {
+    // A server that resets the stream instead of responding to the 1st and 3rd frames
+    let ep = quinn::Endpoint::server(
+        quinn_plaintext::server_config(),
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+    )
+    .unwrap();
+    let addr = ep.local_addr().unwrap();
+    let frames = Arc::new(AtomicUsize::new(0));
+    let server_frames = frames.clone();
+    let server = tokio::spawn(async move {
+        while let Some(incoming) = ep.accept().await {
+            let frames = server_frames.clone();
+            tokio::spawn(async move {
+                let conn = incoming.await.unwrap();
+                let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+                p::read_length_prefixed(&mut recv).await.unwrap();
+                let hs = p::ServerHandshakeResponseV1 { accept: true }.write();
+                send.write_chunk(p::write_length_prefixed(&hs).freeze())
+                    .await
+                    .unwrap();
+
+                while let Ok(changes) =
+                    p::read_length_prefixed_jsonb::<Vec<p::ServerChange>>(&mut recv).await
+                {
+                    if matches!(frames.fetch_add(1, Ordering::Relaxed), 0 | 2) {
+                        send.reset(p::ErrorCode::InternalServerError.into())
+                            .unwrap();
+                        break;
+                    }
+
+                    let res = p::write_length_prefixed_jsonb(&p::ExecResult::Execute {
+                        rows_affected: changes.len(),
+                        time: 0.,
+                    })
+                    .unwrap();
+                    send.write_chunk(res.freeze()).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let client = p::client::Client::connect_insecure_with_config(
+        addr,
+        2001,
+        IcaoCode::new_testing([b'R'; 4]),
+        p::client::ClientConfig {
+            max_retries: 1,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    let endpoint = Endpoint::new(std::net::Ipv4Addr::new(1, 2, 3, 4).into(), 7777);
+    let upsert = async || {
+        client
+            .upsert_one(
+                endpoint.clone(),
+                IcaoCode::new_testing([b'R'; 4]),
+                &Default::default(),
+            )
+            .await
+    };
+
+    // The first frame fails, but is sent again on a new stream
+    assert_eq!(rows_affected(upsert().await.unwrap()), 1);
+
+    // The third frame fails, removes are not retried
+    let Err(p::client::TransactionError::Stream(error)) =
+        client.remove_servers(&[endpoint.clone()]).await
+    else {
+        panic!("expected the remove to fail");
+    };
+    assert!(error.is_retryable());
+
+    // The stream was still reopened for later transactions
+    assert_eq!(rows_affected(upsert().await.unwrap()), 1);
+    assert_eq!(frames.load(Ordering::Relaxed), 4);
+
+    let stats = client.stats().snapshot();
+    assert_eq!(stats.retried, 1);
+    assert_eq!(stats.reconnects, 2);
+    assert_eq!(stats.in_flight, 0);
+
+    client.shutdown().await;
+    server.abort();
+}
+
+/// Tests that the version and features negotiated with the server follow the
+/// connection when the stream is reopened, eg. on a server that was
+/// downgraded in the meantime
+#[tokio::test]
+async fn renegotiates_after_reconnect() {
+    // A server that speaks version 3 and resets the first stream once it
+    // receives a frame, then speaks version 2
+    let ep = quinn::Endpoint::server(
+        quinn_plaintext::server_config(),
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+    )
+    .unwrap();
+    let addr = ep.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let mut first = true;
+        while let Some(incoming) = ep.accept().await {
+            let reset = std::mem::take(&mut first);
+            tokio::spawn(async move {
+                let conn = incoming.await.unwrap();
+                let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+                p::read_length_prefixed(&mut recv).await.unwrap();
+                let hs = if reset {
+                    p::write_length_prefixed(
+                        &p::ServerHandshakeResponseV3 {
+                            accept: true,
+                            load: None,
+                            reason: None,
+                            features: p::Features::SUPPORTED,
+                        }
+                        .write(),
+                    )
+                } else {
+                    p::write_length_prefixed(
+                        &p::ServerHandshakeResponseV2 {
+                            accept: true,
+                            load: None,
+                            reason: None,
+                        }
+                        .write(),
+                    )
+                };
+                send.write_chunk(hs.freeze()).await.unwrap();
+
+                while let Ok(changes) =
+                    p::read_length_prefixed_jsonb::<Vec<p::ServerChange>>(&mut recv).await
+                {
+                    if reset {
+                        send.reset(p::ErrorCode::InternalServerError.into())
+                            .unwrap();
+                        break;
+                    }
+
+                    let res = p::write_length_prefixed_jsonb(&p::ExecResult::Execute {
+                        rows_affected: changes.len(),
+                        time: 0.,
+                    })
+                    .unwrap();
+                    send.write_chunk(res.freeze()).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let client = p::client::Client::connect_insecure_with_config(
+        addr,
+        2001,
+        IcaoCode::new_testing([b'V'; 4]),
+        p::client::ClientConfig {
+            max_retries: 1,
+            transaction_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(client.peer_version(), 3);
+    assert_eq!(client.features(), p::Features::SUPPORTED);
+
+    // The upsert is sent again on the new stream
+    let upsert = [p::ServerUpsert {
+        endpoint: Endpoint::new(std::net::Ipv4Addr::new(1, 2, 3, 4).into(), 7777),
+        icao: IcaoCode::new_testing([b'V'; 4]),
+        tokens: Default::default(),
+    }];
+    assert_eq!(
+        rows_affected(client.upsert_servers(&upsert).await.unwrap()),
+        1
+    );
+    assert_eq!(client.stats().snapshot().reconnects, 1);
+
+    assert_eq!(client.peer_version(), 2);
+    assert_eq!(client.features(), p::Features::NONE);
+
+    client.shutdown().await;
+    server.abort();
+}
+
+/// Tests that keep-alives keep an idle connection open, and that without them
+/// it is closed once the idle timeout elapses
+#[tokio::test]
+async fn keep_alive() {
+    let server = server(CountingExecutor::default());
+    let icao = IcaoCode::new_testing([b'K'; 4]);
+
+    let connect = async |keep_alive_interval| {
+        p::client::Client::connect_insecure_with_config(
+            server.local_addr(),
+            2001,
+            icao,
+            p::client::ClientConfig {
+                keep_alive_interval,
+                idle_timeout: Some(Duration::from_millis(300)),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap()
+    };
+
+    let kept = connect(Some(Duration::from_millis(100))).await;
+    let idle = connect(None).await;
+
+    assert_eq!(
+        kept.transport_timeouts(),
+        Some(p::client::TransportTimeouts {
+            keep_alive_interval: Some(Duration::from_millis(100)),
+            idle_timeout: Some(Duration::from_millis(300)),
+        })
+    );
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    assert_eq!(
+        rows_affected(kept.transactions(&changes(1)).await.unwrap()),
+        1
+    );
+    assert!(kept.close_reason().is_none());
+
+    assert!(idle.transactions(&changes(1)).await.is_err());
+    assert!(matches!(
+        idle.close_reason(),
+        Some(p::client::CloseReason::Error(_))
+    ));
+
+    kept.shutdown().await;
+    idle.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// An executor that fails or panics depending on the port of the first
+/// endpoint it is sent
+#[derive(Clone)]
+struct FaultyExecutor;
+
+#[async_trait::async_trait]
+impl p::server::AgentExecutor for FaultyExecutor {
+    async fn connected(&self, _peer: Peer, _icao: IcaoCode, _qcmp_port: u16) {}
+
+    async fn try_execute(
+        &self,
+        _peer: Peer,
+        statements: &[p::ServerChange],
+    ) -> Result<p::ExecResult, p::server::ExecError> {
+        let p::ServerChange::Remove(endpoints) = &statements[0] else {
+            unreachable!();
+        };
+
+        match endpoints[0].port {
+            1 => Err(p::server::ExecError::internal("database is locked").retryable()),
+            2 => panic!("the executor is broken"),
+            _ => Ok(p::ExecResult::Execute {
+                rows_affected: statements.len(),
+                time: 0.,
+            }),
+        }
+    }
+
+    async fn disconnected(&self, _peer: Peer) {}
+}
+
+/// Tests that executor errors and panics are sent to the client as errors,
+/// and that the connection can still be used afterwards
+#[tokio::test]
+async fn executor_errors() {
+    for coalesce in [false, true] {
+        let server = p::server::Server::new(
+            (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+            p::server::ServerConfig {
+                coalesce: coalesce.then_some(p::server::CoalesceConfig {
+                    window: Duration::from_millis(1),
+                    max_rows: 1,
+                }),
+                ..Default::default()
+            },
+            FaultyExecutor,
+        )
+        .unwrap();
+        let client = p::client::Client::connect_insecure(
+            server.local_addr(),
+            2001,
+            IcaoCode::new_testing([b'E'; 4]),
+        )
+        .await
+        .unwrap();
+
+        let remove = |port| {
+            [p::ServerChange::Remove(vec![Endpoint::new(
+                std::net::Ipv4Addr::LOCALHOST.into(),
+                port,
+            )])]
+        };
+
+        let rejected = async |port| match client.transactions(&remove(port)).await {
+            Err(p::client::TransactionError::Rejected {
+                message,
+                code,
+                retryable,
+            }) => (message, code, retryable),
+            other => panic!("expected the transaction to be rejected: {other:?}"),
+        };
+
+        // The code and retryable flag are sent as fields of the error frame,
+        // rather than as part of the message
+        assert_eq!(
+            rejected(1).await,
+            (
+                "database is locked".to_owned(),
+                Some(p::ErrorCode::InternalServerError),
+                true
+            )
+        );
+
+        let message = if coalesce {
+            "the batch containing the frame failed"
+        } else {
+            "the executor panicked"
+        };
+        assert_eq!(
+            rejected(2).await,
+            (
+                message.to_owned(),
+                Some(p::ErrorCode::InternalServerError),
+                false
+            )
+        );
+
+        assert_eq!(
+            rows_affected(client.transactions(&remove(3)).await.unwrap()),
+            1
+        );
+        assert!(client.is_connected());
+
+        client.shutdown().await;
+        server.shutdown("done").await;
+    }
+}
+
+/// An executor that keeps the set of endpoints it was sent in memory
+#[derive(Clone, Default)]
+struct StateExecutor {
+    servers: Arc<std::sync::Mutex<std::collections::BTreeSet<String>>>,
+}
+
+#[async_trait::async_trait]
+impl p::server::InfallibleExecutor for StateExecutor {
+    async fn connected(&self, _peer: Peer, _icao: IcaoCode, _qcmp_port: u16) {}
+
+    async fn execute(&self, _peer: Peer, statements: &[p::ServerChange]) -> p::ExecResult {
+        let mut servers = self.servers.lock().unwrap();
+        for change in statements {
+            match change {
+                p::ServerChange::Insert(upserts) => {
+                    servers.extend(upserts.iter().map(|upsert| upsert.endpoint.to_string()));
+                }
+                p::ServerChange::Remove(endpoints) => {
+                    for endpoint in endpoints {
+                        servers.remove(&endpoint.to_string());
+                    }
+                }
+                p::ServerChange::Update(_) => {}
+            }
+        }
+
+        p::ExecResult::Execute {
+            rows_affected: statements.len(),
+            time: 0.,
+        }
+    }
+
+    async fn disconnected(&self, _peer: Peer) {}
+}
+
+/// Tests that a periodic resync repairs a server whose state diverged from
+/// what the client wants it to be
+#[tokio::test]
+async fn resyncs_divergent_server() {
+    let exec = StateExecutor::default();
+    let server =
+        p::server::Server::new_unencrypted((std::net::Ipv6Addr::LOCALHOST, 0).into(), exec.clone())
+            .unwrap();
+    let icao = IcaoCode::new_testing([b'S'; 4]);
+
+    let mut client = p::client::Client::connect_insecure(server.local_addr(), 2001, icao)
+        .await
+        .unwrap();
+
+    let endpoint = |i| Endpoint::new(std::net::Ipv4Addr::new(1, 2, 3, i).into(), 7777);
+    let desired = Arc::new(std::sync::Mutex::new(vec![endpoint(1), endpoint(2)]));
+
+    {
+        let desired = desired.clone();
+        client.enable_resync(Duration::from_millis(50), move || {
+            desired
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|endpoint| p::ServerUpsert {
+                    endpoint: endpoint.clone(),
+                    icao,
+                    tokens: Default::default(),
+                })
+                .collect()
+        });
+    }
+
+    let converged = async |expected: &[Endpoint]| {
+        let expected: Vec<_> = expected.iter().map(|ep| ep.to_string()).collect();
+        for _ in 0..100 {
+            if exec.servers.lock().unwrap().iter().eq(&expected) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        panic!(
+            "server has {:?}, expected {expected:?}",
+            exec.servers.lock().unwrap()
+        );
+    };
+
+    converged(&[endpoint(1), endpoint(2)]).await;
+
+    // The server loses a server it should have, and keeps one it shouldn't
+    *exec.servers.lock().unwrap() = [endpoint(1).to_string()].into();
+    *desired.lock().unwrap() = vec![endpoint(2)];
+
+    converged(&[endpoint(2)]).await;
+
+    let stats = client.stats().snapshot();
+    assert!(stats.resyncs >= 2);
+    assert_eq!(stats.failed_resyncs, 0);
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that the servers are replayed as soon as the stream is reopened, so a
+/// server that restarted with an empty database is restored without the
+/// caller doing anything
+#[tokio::test]
+async fn replays_after_reconnect() {
+    let server = p::server::Server::new_unencrypted(
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+        StateExecutor::default(),
+    )
+    .unwrap();
+    let addr = server.local_addr();
+    let icao = IcaoCode::new_testing([b'R'; 4]);
+
+    let mut client = p::client::Client::connect_insecure_with_config(
+        addr,
+        2001,
+        icao,
+        p::client::ClientConfig {
+            // Enough attempts to reopen the stream while the server restarts
+            max_retries: 5,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    let servers = move || -> Vec<_> {
+        (1..=2)
+            .map(|i| p::ServerUpsert {
+                endpoint: Endpoint::new(std::net::Ipv4Addr::new(1, 2, 3, i).into(), 7777),
+                icao,
+                tokens: Default::default(),
+            })
+            .collect()
+    };
+    let expected: Vec<_> = servers()
+        .iter()
+        .map(|upsert| upsert.endpoint.to_string())
+        .collect();
+
+    // Long enough that only the replay can restore the servers
+    client.enable_resync(Duration::from_secs(3600), servers);
+    client.upsert_servers(&servers()).await.unwrap();
+
+    // Restart the server on the same address with nothing in it
+    server.shutdown("restarting").await;
+    let exec = StateExecutor::default();
+    let server = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match p::server::Server::new_unencrypted(addr, exec.clone()) {
+                Ok(server) => break server,
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !exec.servers.lock().unwrap().iter().eq(&expected) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let stats = client.stats().snapshot();
+    assert_eq!(stats.reconnects, 1);
+    assert_eq!(stats.resyncs, 1);
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that a client with a reconnect policy reopens its stream when the
+/// server restarts while the client is idle, without retrying transactions
+#[tokio::test]
+async fn reconnects_after_server_restart() {
+    let server = server(CountingExecutor::default());
+    let addr = server.local_addr();
+
+    let client = p::client::Client::connect_insecure_with_config(
+        addr,
+        2001,
+        IcaoCode::new_testing([b'C'; 4]),
+        p::client::ClientConfig {
+            reconnect: Some(p::client::ReconnectPolicy {
+                max_attempts: 100,
+                base_delay: Duration::from_millis(10),
+            }),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        rows_affected(client.transactions(&changes(1)).await.unwrap()),
+        1
+    );
+
+    server.shutdown("restarting").await;
+    let server = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match p::server::Server::new_unencrypted(addr, CountingExecutor::default()) {
+                Ok(server) => break server,
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while client.stats().snapshot().reconnects == 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    assert!(client.is_connected());
+    assert_eq!(
+        rows_affected(client.transactions(&changes(2)).await.unwrap()),
+        2
+    );
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that the I/O tasks of both the client and server are instrumented
+#[cfg(feature = "tokio-metrics")]
+#[tokio::test]
+async fn task_monitors() {
+    let server = server(CountingExecutor::default());
+    let client = p::client::Client::connect_insecure(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'M'; 4]),
+    )
+    .await
+    .unwrap();
+
+    for _ in 0..3 {
+        assert_eq!(
+            rows_affected(client.transactions(&changes(1)).await.unwrap()),
+            1
+        );
+    }
+
+    assert!(client.task_monitor().cumulative().total_poll_count > 0);
+    assert!(server.task_monitor().cumulative().total_poll_count > 0);
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that path statistics are populated by traffic, both on demand and
+/// by the periodic sampler
+#[tokio::test]
+async fn path_stats() {
+    let server = server(CountingExecutor::default());
+    let client = p::client::Client::connect_insecure_with_config(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'P'; 4]),
+        p::client::ClientConfig {
+            path_stats_interval: Some(Duration::from_millis(20)),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    let before = client.path_stats();
+    assert!(before.rtt > Duration::ZERO);
+    assert!(before.cwnd > 0);
+
+    for _ in 0..5 {
+        assert_eq!(
+            rows_affected(client.transactions(&changes(1)).await.unwrap()),
+            1
+        );
+    }
+
+    let after = client.path_stats();
+    assert!(after.sent_packets > before.sent_packets);
+    assert!(after.lost_packets >= before.lost_packets);
+    assert!(after.congestion_events >= before.congestion_events);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let sampled = client.stats().snapshot().path;
+    assert!(sampled.sent_packets >= after.sent_packets);
+    assert!(sampled.rtt > Duration::ZERO);
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that additional streams can be opened on the client's connection
+/// without disturbing its transactions
+#[tokio::test]
+async fn extra_streams() {
+    let ep = quinn::Endpoint::server(
+        quinn_plaintext::server_config(),
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+    )
+    .unwrap();
+    let addr = ep.local_addr().unwrap();
+    let (uni_tx, uni_rx) = tokio::sync::oneshot::channel();
+    let server = tokio::spawn(async move {
+        let conn = ep.accept().await.unwrap().await.unwrap();
+        let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+        p::read_length_prefixed(&mut recv).await.unwrap();
+        let hs = p::ServerHandshakeResponseV1 { accept: true }.write();
+        send.write_chunk(p::write_length_prefixed(&hs).freeze())
+            .await
+            .unwrap();
+
+        let uni_conn = conn.clone();
+        tokio::spawn(async move {
+            let mut uni = uni_conn.accept_uni().await.unwrap();
+            let _ = uni_tx.send(uni.read_to_end(64).await.unwrap());
+        });
+
+        while let Ok(changes) =
+            p::read_length_prefixed_jsonb::<Vec<p::ServerChange>>(&mut recv).await
+        {
+            let res = p::write_length_prefixed_jsonb(&p::ExecResult::Execute {
+                rows_affected: changes.len(),
+                time: 0.,
+            })
+            .unwrap();
+            send.write_chunk(res.freeze()).await.unwrap();
+        }
+    });
+
+    let client = p::client::Client::connect_insecure(addr, 2001, IcaoCode::new_testing([b'U'; 4]))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        rows_affected(client.transactions(&changes(1)).await.unwrap()),
+        1
+    );
+
+    let mut uni = client.connection().open_uni().await.unwrap();
+    uni.write_all(b"control").await.unwrap();
+    uni.finish().unwrap();
+
+    assert_eq!(
+        rows_affected(client.transactions(&changes(2)).await.unwrap()),
+        2
+    );
+    assert_eq!(uni_rx.await.unwrap(), b"control");
+    assert_eq!(client.stats().snapshot().completed, 2);
+
+    client.shutdown().await;
+    server.abort();
+}
+
+/// Tests that multiple frames are written before the first response is
+/// received, and that the responses are still matched to the correct requests
+#[tokio::test]
+async fn pipelines_transactions() {
+    const FRAMES: usize = 3;
+
+    // A server that only responds once it has received several frames
+    let ep = quinn::Endpoint::server(
+        quinn_plaintext::server_config(),
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+    )
+    .unwrap();
+    let addr = ep.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let conn = ep.accept().await.unwrap().await.unwrap();
+        let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+        p::read_length_prefixed(&mut recv).await.unwrap();
+        let hs = p::ServerHandshakeResponseV1 { accept: true }.write();
+        send.write_chunk(p::write_length_prefixed(&hs).freeze())
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < FRAMES {
+            let changes = p::read_length_prefixed_jsonb::<Vec<p::ServerChange>>(&mut recv)
+                .await
+                .unwrap();
+            received.push(changes.len());
+        }
+
+        for rows_affected in received {
+            let res = p::write_length_prefixed_jsonb(&p::ExecResult::Execute {
+                rows_affected,
+                time: 0.,
+            })
+            .unwrap();
+            send.write_chunk(res.freeze()).await.unwrap();
+        }
+
+        // Drop the connection once the client closes its stream so that it
+        // can finish shutting down
+        let _ = recv.received_reset().await;
+    });
+
+    let client = p::client::Client::connect_insecure_with_config(
+        addr,
+        2001,
+        IcaoCode::new_testing([b'I'; 4]),
+        p::client::ClientConfig {
+            max_in_flight: Some(FRAMES),
+            transaction_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    let frames: Vec<_> = (1..=FRAMES).map(changes).collect();
+    let (a, b, c) = tokio::join!(
+        client.transactions(&frames[0]),
+        client.transactions(&frames[1]),
+        client.transactions(&frames[2]),
+    );
+    assert_eq!(rows_affected(a.unwrap()), 1);
+    assert_eq!(rows_affected(b.unwrap()), 2);
+    assert_eq!(rows_affected(c.unwrap()), 3);
+
+    let stats = client.stats().snapshot();
+    assert_eq!(stats.sent, FRAMES as u64);
+    assert_eq!(stats.completed, FRAMES as u64);
+    assert_eq!(stats.in_flight, 0);
+
+    client.shutdown().await;
+    server.await.unwrap();
+}
+
+/// Tests that every frame in flight when the stream fails is sent again on the
+/// new stream, in the original order
+#[tokio::test]
+async fn retries_pipelined_transactions() {
+    // A server that resets the first stream once it has received 2 frames
+    let ep = quinn::Endpoint::server(
+        quinn_plaintext::server_config(),
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+    )
+    .unwrap();
+    let addr = ep.local_addr().unwrap();
+    let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let server_order = order.clone();
+    let server = tokio::spawn(async move {
+        let mut first = true;
+        while let Some(incoming) = ep.accept().await {
+            let reset = std::mem::take(&mut first);
+            let order = server_order.clone();
+            tokio::spawn(async move {
+                let conn = incoming.await.unwrap();
+                let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+                p::read_length_prefixed(&mut recv).await.unwrap();
+                let hs = p::ServerHandshakeResponseV1 { accept: true }.write();
+                send.write_chunk(p::write_length_prefixed(&hs).freeze())
+                    .await
+                    .unwrap();
+
+                let mut received = 0;
+                while let Ok(changes) =
+                    p::read_length_prefixed_jsonb::<Vec<p::ServerChange>>(&mut recv).await
+                {
+                    received += 1;
+                    if reset {
+                        if received == 2 {
+                            send.reset(p::ErrorCode::InternalServerError.into())
+                                .unwrap();
+                            break;
+                        }
+                        continue;
+                    }
+
+                    order.lock().unwrap().push(changes[0].len());
+                    let res = p::write_length_prefixed_jsonb(&p::ExecResult::Execute {
+                        rows_affected: changes.len(),
+                        time: 0.,
+                    })
+                    .unwrap();
+                    send.write_chunk(res.freeze()).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let client = p::client::Client::connect_insecure_with_config(
+        addr,
+        2001,
+        IcaoCode::new_testing([b'R'; 4]),
+        p::client::ClientConfig {
+            max_in_flight: Some(2),
+            max_retries: 1,
+            transaction_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    let upserts = |count: u8| -> Vec<_> {
+        (0..count)
+            .map(|i| p::ServerUpsert {
+                endpoint: Endpoint::new(std::net::Ipv4Addr::new(1, 2, 3, i).into(), 7777),
+                icao: IcaoCode::new_testing([b'R'; 4]),
+                tokens: Default::default(),
+            })
+            .collect()
+    };
+    let (one, two) = (upserts(1), upserts(2));
+
+    let (a, b) = tokio::join!(client.upsert_servers(&one), client.upsert_servers(&two));
+    assert_eq!(rows_affected(a.unwrap()), 1);
+    assert_eq!(rows_affected(b.unwrap()), 1);
+    assert_eq!(*order.lock().unwrap(), [1, 2]);
+
+    let stats = client.stats().snapshot();
+    assert_eq!(stats.retried, 2);
+    assert_eq!(stats.reconnects, 1);
+    assert_eq!(stats.in_flight, 0);
+
+    client.shutdown().await;
+    server.abort();
+}
+
+/// Tests that with request ids a slow frame doesn't delay the response to a
+/// small frame sent after it, unless they change the same endpoints
+#[tokio::test]
+async fn responds_by_request_id() {
+    let exec = CountingExecutor::default();
+    let server = server(exec.clone());
+
+    let client = p::client::Client::connect_insecure_with_config(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing([b'Q'; 4]),
+        p::client::ClientConfig {
+            max_in_flight: Some(2),
+            transaction_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert!(client.features().contains(p::Features::REQUEST_IDS));
+
+    exec.bulk_delay_ms.store(500, Ordering::Relaxed);
+
+    // The small frame is executed, and responded to, while the bulk frame
+    // is still being executed
+    let bulk = changes(10);
+    let small = [p::ServerChange::Remove(vec![Endpoint::new(
+        std::net::Ipv4Addr::new(4, 3, 2, 1).into(),
+        7777,
+    )])];
+    let (bulk_res, (small_res, executed)) = tokio::join!(client.transactions(&bulk), async {
+        let res = client.transactions(&small).await;
+        (res, exec.executed.load(Ordering::Relaxed))
+    });
+    assert_eq!(rows_affected(small_res.unwrap()), 1);
+    assert_eq!(executed, 1);
+    assert_eq!(rows_affected(bulk_res.unwrap()), 10);
+
+    // A small frame that changes one of the same endpoints waits for the bulk
+    // frame, so the changes are applied in the order they were sent
+    let (bulk_res, (small_res, executed)) = tokio::join!(client.transactions(&bulk), async {
+        let res = client.transactions(&changes(1)).await;
+        (res, exec.executed.load(Ordering::Relaxed))
+    });
+    assert_eq!(rows_affected(small_res.unwrap()), 1);
+    assert_eq!(executed, 22);
+    assert_eq!(rows_affected(bulk_res.unwrap()), 10);
+
+    let stats = client.stats().snapshot();
+    assert_eq!(stats.completed, 4);
+    assert_eq!(stats.in_flight, 0);
+
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
@@ -3,7 +3,7 @@
 use corro_api_types::SqliteValue;
 use corro_types::{agent::SplitPool, api::Statement};
 use corrosion::client::{
-    read::{FromSqlValue, ServerRow},
+    read::{ContributedServers, FromSqlValue, ServerRow},
     write::UpdateBuilder,
 };
 use corrosion_utils as tu;
@@ -34,6 +34,21 @@ async fn read_server_row(id: usize, sp: &SplitPool) -> ServerRow {
     .unwrap()
 }
 
+/// Reads the set of servers contributed by each datacenter
+async fn read_dc_servers(sp: &SplitPool) -> Vec<ContributedServers> {
+    let conn = sp.read().await.unwrap();
+    let mut statement = conn
+        .prepare("SELECT json(servers) FROM dc ORDER BY ip")
+        .unwrap();
+    statement
+        .query_map([], |row| {
+            Ok(ContributedServers::from_sql(&[row.get::<_, SqliteValue>(0).unwrap()]).unwrap())
+        })
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
 fn make_row(i: u32) -> ServerRow {
     let address = match i % 3 {
         0 => AddressKind::Ip(Ipv4Addr::from_bits(i).into()),
@@ -71,7 +86,7 @@ async fn prep(name: &str, count: u32) -> SplitPool {
             exec_all(s.statements, &sp).await;
         }
 
-        s.upsert(&row.endpoint, row.icao, &row.tokens);
+        s.upsert(&row.endpoint, row.icao, &row.tokens).unwrap();
     }
 
     if !s.statements.is_empty() {
@@ -103,6 +118,88 @@ async fn inserts_and_reads_servers() {
     }
 }
 
+/// Tests that ad-hoc column selections can be read into tuples
+#[tokio::test]
+async fn reads_tuples() {
+    let sp = prep("reads_tuples", 3).await;
+    let conn = sp.read().await.unwrap();
+
+    let mut statement = conn
+        .prepare("SELECT endpoint,icao FROM servers ORDER BY rowid")
+        .unwrap();
+    let rows: Vec<(Endpoint, IcaoCode)> = statement
+        .query_map([], |row| {
+            let v = [
+                row.get::<_, SqliteValue>(0).unwrap(),
+                row.get::<_, SqliteValue>(1).unwrap(),
+            ];
+            Ok(FromSqlValue::from_sql(&v).unwrap())
+        })
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+    let expected: Vec<_> = (0..3)
+        .map(|i| {
+            let row = make_row(i);
+            (row.endpoint, row.icao)
+        })
+        .collect();
+    assert_eq!(rows, expected);
+
+    // Elements that read multiple columns are followed by the next element
+    let (row, endpoint): (ServerRow, Endpoint) = conn
+        .query_row(
+            "SELECT endpoint,icao,tokens,endpoint FROM servers WHERE rowid = 2",
+            [],
+            |row| {
+                let v: Vec<_> = (0..4)
+                    .map(|i| row.get::<_, SqliteValue>(i).unwrap())
+                    .collect();
+                Ok(FromSqlValue::from_sql(&v).unwrap())
+            },
+        )
+        .unwrap();
+    assert_eq!(row, make_row(1));
+    assert_eq!(endpoint, row.endpoint);
+
+    // Missing columns are an error rather than a panic
+    let v = [SqliteValue::Text("|1.2.3.4:7777".into())];
+    assert!(<(Endpoint, IcaoCode)>::from_sql(&v).is_err());
+}
+
+/// Tests that ICAO codes containing digits, and lowercase codes, survive a
+/// write and read through the `servers` table
+#[tokio::test]
+async fn round_trips_alphanumeric_icao() {
+    let sp = tu::new_split_pool("round_trips_alphanumeric_icao", corrosion::schema::SCHEMA).await;
+
+    let codes = ["K2A5", "e123", "EGLL"];
+    let rows: Vec<_> = codes
+        .iter()
+        .enumerate()
+        .map(|(i, code)| ServerRow {
+            icao: code.parse().unwrap(),
+            ..make_row(i as u32)
+        })
+        .collect();
+
+    let mut v = smallvec::SmallVec::<[_; 6]>::new();
+    {
+        let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
+        for row in &rows {
+            s.upsert(&row.endpoint, row.icao, &row.tokens).unwrap();
+        }
+        exec_all(s.statements, &sp).await;
+    }
+
+    for (i, (row, code)) in rows.iter().zip(codes).enumerate() {
+        let read = read_server_row(i + 1, &sp).await;
+        assert_eq!(&read, row);
+        assert_eq!(read.icao.as_ref(), code.to_ascii_uppercase());
+    }
+}
+
 /// Tests that servers that have no datacenter contributors are reaped after
 /// some amount of time
 #[tokio::test]
@@ -147,7 +244,8 @@ async fn collects_old_servers() {
             },
             IcaoCode::new_testing([b'V'; 4]),
             &[8888u64.to_ne_bytes()].into(),
-        );
+        )
+        .unwrap();
 
         exec_all(s.statements, &sp).await;
     }
@@ -155,7 +253,7 @@ async fn collects_old_servers() {
     // Do the actual removal of the servers with no contributors that are older than 30 minutes
     {
         let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
-        s.reap_old(std::time::Duration::from_secs(60 * 30));
+        s.reap_old(std::time::Duration::from_secs(60 * 30), None);
         exec_all(s.statements, &sp).await;
     }
 
@@ -187,6 +285,106 @@ async fn collects_old_servers() {
     insta::assert_snapshot!("only_one", only_row);
 }
 
+/// Tests that servers are reaped once they are strictly older than the
+/// maximum age, measured from a fixed time
+#[tokio::test]
+async fn reaps_with_fixed_time() {
+    let sp = prep("reaps_with_fixed_time", 10).await;
+    let removed = time::UtcDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+    let max_age = std::time::Duration::from_secs(60 * 30);
+
+    let mut v = smallvec::SmallVec::<[_; 2]>::new();
+    {
+        let mut dc = corrosion::client::write::Datacenter(&mut v);
+        dc.remove(PREP_PEER, Some(removed));
+        exec_all(dc.0, &sp).await;
+    }
+
+    let count = async || {
+        let r = sp.read().await.unwrap();
+        r.query_row("SELECT COUNT(*) FROM servers", [], |r| r.get::<_, u32>(0))
+            .unwrap()
+    };
+
+    for (now, expected) in [
+        (removed, 10),
+        (removed + max_age, 10),
+        (removed + max_age + std::time::Duration::from_secs(1), 0),
+    ] {
+        let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
+        s.reap_old(max_age, Some(now));
+        exec_all(s.statements, &sp).await;
+        assert_eq!(count().await, expected, "{}", now.unix_timestamp());
+    }
+}
+
+/// Tests that removing a datacenter removes it as a contributor from every
+/// server it contributed, and only those servers
+#[tokio::test]
+async fn remove_prunes_all_contributed_servers() {
+    use corrosion::client::write::{Datacenter, Server};
+
+    let sp = prep("remove_prunes_all_contributed_servers", 3).await;
+    let other = SocketAddrV6::new(Ipv6Addr::from_bits(0xbbffeeff), 8999, 0, 0);
+    let mut v = smallvec::SmallVec::<[_; 2]>::new();
+
+    // The other datacenter contributes one of the same servers, and one of its own
+    {
+        let mut s = Server::for_peer(other, &mut v);
+        for row in [make_row(1), make_row(3)] {
+            s.upsert(&row.endpoint, row.icao, &row.tokens).unwrap();
+        }
+        exec_all(s.statements, &sp).await;
+    }
+
+    let contributors = async || {
+        let conn = sp.read().await.unwrap();
+        let mut statement = conn
+            .prepare("SELECT json(contributors),cont_update FROM servers ORDER BY rowid")
+            .unwrap();
+        statement
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    };
+
+    let before = contributors().await;
+    let prep_peer = format!("\"{}\":{{}}", PREP_PEER.ip());
+    let other_peer = format!("\"{}\":{{}}", other.ip());
+    assert_eq!(
+        before.iter().map(|(c, _)| c.clone()).collect::<Vec<_>>(),
+        [
+            format!("{{{prep_peer}}}"),
+            format!("{{{prep_peer},{other_peer}}}"),
+            format!("{{{prep_peer}}}"),
+            format!("{{{other_peer}}}"),
+        ]
+    );
+
+    let fake_time = time::UtcDateTime::now() - std::time::Duration::from_secs(60 * 60);
+    {
+        let mut dc = Datacenter(&mut v);
+        dc.remove(PREP_PEER, Some(fake_time));
+        exec_all(dc.0, &sp).await;
+    }
+
+    let after = contributors().await;
+    let removed = fake_time.unix_timestamp();
+    assert_eq!(
+        after,
+        [
+            ("{}".to_owned(), removed),
+            (format!("{{{other_peer}}}"), removed),
+            ("{}".to_owned(), removed),
+            before[3].clone(),
+        ]
+    );
+    assert_eq!(read_dc_servers(&sp).await.len(), 1);
+}
+
 /// Tests that servers can be updated
 #[tokio::test]
 async fn updates_servers() {
@@ -223,7 +421,8 @@ async fn updates_servers() {
     // Update just the ICAO
     {
         let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
-        s.update(UpdateBuilder::new(&ep).update_icao(IcaoCode::new_testing([b'Z'; 4])));
+        s.update(UpdateBuilder::new(&ep).update_icao(IcaoCode::new_testing([b'Z'; 4])))
+            .unwrap();
         exec_all(s.statements, &sp).await;
     }
 
@@ -232,7 +431,8 @@ async fn updates_servers() {
     // Update just the tokenset
     {
         let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
-        s.update(UpdateBuilder::new(&ep).update_tokens(&[[b'Z'; 20]; 1].into()));
+        s.update(UpdateBuilder::new(&ep).update_tokens(&[[b'Z'; 20]; 1].into()))
+            .unwrap();
         exec_all(s.statements, &sp).await;
     }
 
@@ -245,13 +445,278 @@ async fn updates_servers() {
             UpdateBuilder::new(&ep)
                 .update_icao(IcaoCode::new_testing([b'Y'; 4]))
                 .update_tokens(&[[b'Y'; 10]; 1].into()),
-        );
+        )
+        .unwrap();
         exec_all(s.statements, &sp).await;
     }
 
     insta::assert_snapshot!("update_both_us", only_row().await);
 }
 
+/// Tests that upserts and updates with more tokens than the limit, or tokens
+/// that can't be encoded, are rejected without creating any statements
+#[tokio::test]
+async fn rejects_too_many_tokens() {
+    use corrosion::client::write::{
+        InvalidTokens, MAX_ENCODED_TOKENS, MAX_TOKEN_LEN, Server, TokenEncodeError, TooManyTokens,
+    };
+    use quilkin_types::TokenSet;
+
+    let sp = prep("rejects_too_many_tokens", 1).await;
+    let ep = Endpoint {
+        address: AddressKind::Ip(std::net::Ipv4Addr::from_bits(0).into()),
+        port: 0,
+    };
+    let icao = IcaoCode::new_testing([b'T'; 4]);
+    let tokens =
+        |count: u32| -> TokenSet { (0..count).map(|i| i.to_ne_bytes().to_vec()).collect() };
+
+    let mut v = smallvec::SmallVec::<[_; 2]>::new();
+    {
+        let mut s = Server::for_peer(PREP_PEER, &mut v).max_tokens_per_server(4);
+        let too_many = InvalidTokens::TooMany(TooManyTokens {
+            endpoint: ep.clone(),
+            count: 5,
+            max: 4,
+        });
+        assert_eq!(s.upsert(&ep, icao, &tokens(5)), Err(too_many.clone()));
+        assert_eq!(
+            s.update(UpdateBuilder::new(&ep).update_tokens(&tokens(5))),
+            Err(too_many)
+        );
+        assert!(s.statements.is_empty());
+
+        // Updates that don't change the tokens aren't affected
+        s.update(UpdateBuilder::new(&ep).update_icao(icao)).unwrap();
+        s.update(UpdateBuilder::new(&ep).update_tokens(&tokens(4)))
+            .unwrap();
+        exec_all(s.statements, &sp).await;
+    }
+    assert_eq!(read_server_row(1, &sp).await.tokens, tokens(4));
+
+    // By default the limit is the most that can be encoded, large sets are
+    // encoded with a varint count
+    let large: TokenSet = (0..300u32)
+        .map(|i| vec![i as u8; 1 + i as usize % 5])
+        .collect();
+    {
+        let mut s = Server::for_peer(PREP_PEER, &mut v);
+        s.update(UpdateBuilder::new(&ep).update_tokens(&large))
+            .unwrap();
+        exec_all(s.statements, &sp).await;
+    }
+    assert_eq!(read_server_row(1, &sp).await.tokens, large);
+
+    // Sets that can't be encoded are rejected rather than panicking, even if
+    // the limit is set higher
+    let too_many = tokens(MAX_ENCODED_TOKENS as u32 + 1);
+    {
+        let mut s = Server::for_peer(PREP_PEER, &mut v).max_tokens_per_server(usize::MAX);
+        let capped = InvalidTokens::TooMany(TooManyTokens {
+            endpoint: ep.clone(),
+            count: MAX_ENCODED_TOKENS + 1,
+            max: MAX_ENCODED_TOKENS,
+        });
+        assert_eq!(s.upsert(&ep, icao, &too_many), Err(capped.clone()));
+        assert_eq!(
+            s.update(UpdateBuilder::new(&ep).update_tokens(&too_many)),
+            Err(capped)
+        );
+
+        let too_long: TokenSet = [vec![1; 4], vec![2; MAX_TOKEN_LEN + 1]]
+            .into_iter()
+            .collect();
+        let unencodable = InvalidTokens::Unencodable {
+            endpoint: ep.clone(),
+            error: TokenEncodeError::TokenTooLong {
+                index: 1,
+                len: MAX_TOKEN_LEN + 1,
+            },
+        };
+        let other = Endpoint {
+            port: 1,
+            ..ep.clone()
+        };
+        let mut rows = vec![(other, icao, &large), (ep.clone(), icao, &too_long)];
+        assert_eq!(s.upsert_many(&rows), Err(unencodable.clone()));
+        assert_eq!(s.upsert(&ep, icao, &too_long), Err(unencodable.clone()));
+        assert_eq!(
+            s.update(UpdateBuilder::new(&ep).update_tokens(&too_long)),
+            Err(unencodable)
+        );
+        assert!(s.statements.is_empty());
+
+        // The same rows are written once the tokens can be encoded
+        rows[1].2 = &large;
+        s.upsert_many(&rows).unwrap();
+        assert!(!s.statements.is_empty());
+    }
+
+    // Executors can reject a whole frame before creating any statements
+    let changes = [
+        corrosion::persistent::ServerChange::Remove(vec![ep.clone()]),
+        corrosion::persistent::ServerChange::Update(vec![corrosion::persistent::ServerUpdate {
+            endpoint: ep.clone(),
+            icao: None,
+            tokens: Some(tokens(3)),
+        }]),
+    ];
+    corrosion::persistent::ServerChange::check_tokens(&changes, 3).unwrap();
+    assert_eq!(
+        corrosion::persistent::ServerChange::check_tokens(&changes, 2)
+            .unwrap_err()
+            .count,
+        3
+    );
+}
+
+/// Tests that normalised batches with conflicting operations on existing
+/// servers apply every write, rather than collapsing them into upserts that
+/// don't change the server
+#[tokio::test]
+async fn applies_normalized_conflicts() {
+    use corrosion::client::write::Server;
+    use corrosion::persistent::{ServerChange, ServerUpdate, ServerUpsert};
+
+    let sp = prep("applies_normalized_conflicts", 2).await;
+    let updated = make_row(0);
+    let replaced = make_row(1);
+    let icao = IcaoCode::new_testing([b'N'; 4]);
+
+    let batch = vec![
+        ServerChange::Insert(vec![ServerUpsert {
+            endpoint: updated.endpoint.clone(),
+            icao: updated.icao,
+            tokens: [[9; 4]].into(),
+        }]),
+        ServerChange::Update(vec![ServerUpdate {
+            endpoint: updated.endpoint.clone(),
+            icao: Some(icao),
+            tokens: Some([[7; 4]].into()),
+        }]),
+        ServerChange::Remove(vec![replaced.endpoint.clone()]),
+        ServerChange::Insert(vec![ServerUpsert {
+            endpoint: replaced.endpoint.clone(),
+            icao,
+            tokens: [[8; 4]].into(),
+        }]),
+    ];
+
+    let mut v = smallvec::SmallVec::<[_; 8]>::new();
+    {
+        let mut s = Server::for_peer(PREP_PEER, &mut v);
+        for change in ServerChange::normalize(batch) {
+            match change {
+                ServerChange::Remove(endpoints) => {
+                    for endpoint in &endpoints {
+                        s.remove_immediate(endpoint);
+                    }
+                }
+                ServerChange::Insert(upserts) => {
+                    for upsert in &upserts {
+                        s.upsert(&upsert.endpoint, upsert.icao, &upsert.tokens)
+                            .unwrap();
+                    }
+                }
+                ServerChange::Update(updates) => {
+                    for update in &updates {
+                        let mut builder = UpdateBuilder::new(&update.endpoint);
+                        if let Some(icao) = update.icao {
+                            builder = builder.update_icao(icao);
+                        }
+                        if let Some(tokens) = &update.tokens {
+                            builder = builder.update_tokens(tokens);
+                        }
+                        s.update(builder).unwrap();
+                    }
+                }
+            }
+        }
+        exec_all(s.statements, &sp).await;
+    }
+
+    let rows: Vec<ServerRow> = {
+        let conn = sp.read().await.unwrap();
+        let mut statement = conn
+            .prepare("SELECT endpoint,icao,tokens FROM servers ORDER BY rowid")
+            .unwrap();
+        statement
+            .query_map([], |row| {
+                let v: Vec<_> = (0..3)
+                    .map(|i| row.get::<_, SqliteValue>(i).unwrap())
+                    .collect();
+                Ok(ServerRow::from_sql(&v).unwrap())
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    };
+    assert_eq!(rows.len(), 2);
+
+    // The update is applied after the upsert, which only touched the server
+    let row = rows.iter().find(|row| row.endpoint == updated.endpoint);
+    let row = row.expect("updated server should exist");
+    assert_eq!(row.icao, icao);
+    assert_eq!(row.tokens, quilkin_types::TokenSet::from([[7; 4]]));
+
+    // The server was removed before being inserted again with the new ICAO
+    let row = rows.iter().find(|row| row.endpoint == replaced.endpoint);
+    let row = row.expect("replaced server should exist");
+    assert_eq!(row.icao, icao);
+    assert_eq!(row.tokens, quilkin_types::TokenSet::from([[8; 4]]));
+}
+
+/// Tests that touching a server only updates when it was last contributed to
+#[tokio::test]
+async fn touches_servers() {
+    use corrosion::client::write::Server;
+
+    let sp = prep("touches_servers", 1).await;
+    let row = make_row(0);
+
+    let columns = async || -> (Vec<u8>, Vec<u8>, Vec<u8>, i64) {
+        let conn = sp.read().await.unwrap();
+        conn.query_row(
+            "SELECT CAST(icao AS BLOB),CAST(tokens AS BLOB),contributors,cont_update FROM servers WHERE rowid = 1",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )
+        .unwrap()
+    };
+
+    {
+        let conn = sp.write_priority().await.unwrap();
+        conn.execute("UPDATE servers SET cont_update = 1000 WHERE rowid = 1", [])
+            .unwrap();
+    }
+    let before = columns().await;
+    assert_eq!(before.3, 1000);
+
+    let mut v = smallvec::SmallVec::<[_; 2]>::new();
+
+    // A peer that doesn't contribute to the server can't keep it alive
+    let other = SocketAddrV6::new(Ipv6Addr::from_bits(0xbbbb), 8999, 0, 0);
+    {
+        let mut s = Server::for_peer(other, &mut v);
+        s.touch(&row.endpoint);
+        exec_all(s.statements, &sp).await;
+    }
+    assert_eq!(columns().await, before);
+
+    {
+        let mut s = Server::for_peer(PREP_PEER, &mut v);
+        s.touch(&row.endpoint);
+        exec_all(s.statements, &sp).await;
+    }
+
+    let after = columns().await;
+    assert_eq!(
+        (&after.0, &after.1, &after.2),
+        (&before.0, &before.1, &before.2)
+    );
+    assert!(after.3 > before.3);
+}
+
 /// Tests that datacenters can be updated
 #[tokio::test]
 async fn updates_datacenters() {
@@ -303,3 +768,908 @@ async fn updates_datacenters() {
 
     insta::assert_snapshot!("update_both_ud", only_row().await);
 }
+
+/// Tests that an agent identified by a stable id isn't recorded as a new
+/// contributor when its IP changes, and that contributions recorded under its
+/// IP can be migrated to the id
+#[tokio::test]
+async fn contributor_ids_survive_ip_changes() {
+    use corrosion::client::write::{ContributorId, Server};
+
+    let sp = prep("contributor_ids_survive_ip_changes", 1).await;
+    let row = make_row(0);
+    let id = ContributorId::new("agent-1").unwrap();
+
+    let contributors = async || {
+        let conn = sp.read().await.unwrap();
+        conn.query_row(
+            "SELECT json(contributors) FROM servers WHERE rowid = 1",
+            [],
+            |r| r.get::<_, String>(0),
+        )
+        .unwrap()
+    };
+
+    let mut v = smallvec::SmallVec::<[_; 2]>::new();
+
+    // Migrate the contributions recorded under the IP to the id
+    {
+        let mut s = Server::for_peer_with_id(PREP_PEER, id.clone(), &mut v);
+        s.migrate_contributor(&PREP_PEER.into());
+        exec_all(s.statements, &sp).await;
+    }
+
+    assert_eq!(contributors().await, r#"{"agent-1":{}}"#);
+
+    // The same agent reconnects from a different IP
+    for peer in [
+        SocketAddrV6::new(Ipv6Addr::from_bits(0xbbffeeff), 8999, 0, 0),
+        SocketAddrV6::new(Ipv6Addr::from_bits(0xccffeeff), 9000, 0, 0),
+    ] {
+        let mut s = Server::for_peer_with_id(peer, id.clone(), &mut v);
+        s.upsert(&row.endpoint, row.icao, &row.tokens).unwrap();
+        exec_all(s.statements, &sp).await;
+
+        assert_eq!(contributors().await, r#"{"agent-1":{}}"#);
+    }
+
+    {
+        let mut dc = corrosion::client::write::Datacenter(&mut v);
+        dc.remove_with_id(PREP_PEER, &id, None);
+        exec_all(dc.0, &sp).await;
+    }
+
+    assert_eq!(contributors().await, "{}");
+
+    assert!(ContributorId::new("").is_none());
+    assert!(ContributorId::new(r#"agent"}"#).is_none());
+}
+
+/// Tests that renaming a server keeps its contributors and updates the
+/// datacenters that contributed it
+#[tokio::test]
+async fn renames_servers() {
+    use corrosion::client::write::Server;
+
+    let sp = prep("renames_servers", 1).await;
+    let row = make_row(0);
+    let other = SocketAddrV6::new(Ipv6Addr::from_bits(0xbbffeeff), 8999, 0, 0);
+
+    let mut v = smallvec::SmallVec::<[_; 2]>::new();
+
+    {
+        let mut s = Server::for_peer(other, &mut v);
+        s.upsert(&row.endpoint, row.icao, &row.tokens).unwrap();
+        exec_all(s.statements, &sp).await;
+    }
+
+    let contributors = async || {
+        let conn = sp.read().await.unwrap();
+        conn.query_row(
+            "SELECT json(contributors),cont_update,(SELECT COUNT(*) FROM servers) FROM servers WHERE rowid = 1",
+            [],
+            |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    r.get::<_, i64>(1)?,
+                    r.get::<_, u32>(2)?,
+                ))
+            },
+        )
+        .unwrap()
+    };
+
+    let before = contributors().await;
+    assert_eq!(before.2, 1);
+
+    let renamed = Endpoint {
+        address: AddressKind::Name("renamed.boop.net".into()),
+        port: row.endpoint.port,
+    };
+
+    {
+        let mut s = Server::for_peer(PREP_PEER, &mut v);
+        s.rename(&row.endpoint, &renamed);
+        exec_all(s.statements, &sp).await;
+    }
+
+    assert_eq!(contributors().await, before);
+    let expected = ContributedServers([renamed.clone()].into());
+    assert_eq!(read_dc_servers(&sp).await, [expected.clone(), expected]);
+    assert_eq!(read_server_row(1, &sp).await.endpoint, renamed);
+}
+
+/// Tests that the DB itself rejects invalid ICAO codes, regardless of the
+/// validation done by [`IcaoCode`]
+#[tokio::test]
+async fn rejects_invalid_icao() {
+    let sp = prep("rejects_invalid_icao", 0).await;
+    let conn = sp.write_priority().await.unwrap();
+
+    for icao in ["xxxx", "XX", "XXXXX", "XX1X"] {
+        assert!(
+            conn.execute(
+                "INSERT INTO servers (endpoint,icao) VALUES ('1.2.3.4:7777',?)",
+                [icao],
+            )
+            .is_err(),
+            "server icao '{icao}' should have been rejected"
+        );
+        assert!(
+            conn.execute("INSERT INTO dc (ip,icao) VALUES ('::1',?)", [icao])
+                .is_err(),
+            "datacenter icao '{icao}' should have been rejected"
+        );
+    }
+
+    conn.execute(
+        "INSERT INTO servers (endpoint,icao) VALUES ('1.2.3.4:7777','ABCD')",
+        [],
+    )
+    .unwrap();
+    conn.execute("INSERT INTO dc (ip) VALUES ('::1')", [])
+        .unwrap();
+}
+
+/// Tests that the exported servers can be parsed back into the rows they
+/// were exported from
+#[tokio::test]
+async fn exports_ndjson() {
+    const COUNT: u32 = 100;
+    let sp = prep("exports_ndjson", COUNT).await;
+
+    let mut exported = Vec::new();
+    {
+        let conn = sp.read().await.unwrap();
+        assert_eq!(
+            corrosion::client::read::export_ndjson(&conn, &mut exported).unwrap(),
+            COUNT as usize
+        );
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Line {
+        endpoint: String,
+        icao: IcaoCode,
+        tokens: quilkin_types::TokenSet,
+    }
+
+    let exported = String::from_utf8(exported).unwrap();
+    let mut lines = 0;
+    for (i, line) in exported.lines().enumerate() {
+        let line: Line = serde_json::from_str(line).unwrap();
+        let (address, port) = line.endpoint.rsplit_once(':').unwrap();
+        let row = ServerRow {
+            endpoint: Endpoint::new(address.parse().unwrap(), port.parse().unwrap()),
+            icao: line.icao,
+            tokens: line.tokens,
+        };
+
+        assert_eq!(row, make_row(i as u32));
+        lines += 1;
+    }
+
+    assert_eq!(lines, COUNT);
+}
+
+/// Tests that importing the output of the exporter into a fresh DB results
+/// in the same table
+#[tokio::test]
+async fn imports_ndjson() {
+    use corrosion::client::{read::export_ndjson, write::import_ndjson};
+
+    const COUNT: u32 = 100;
+    let original = prep("imports_ndjson_original", COUNT).await;
+
+    let export = async |sp: &SplitPool| {
+        let conn = sp.read().await.unwrap();
+        let mut exported = Vec::new();
+        export_ndjson(&conn, &mut exported).unwrap();
+        exported
+    };
+
+    let exported = export(&original).await;
+
+    let imported = tu::new_split_pool("imports_ndjson_imported", corrosion::schema::SCHEMA).await;
+    let mut v = smallvec::SmallVec::<[_; 16]>::new();
+    let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
+
+    for upsert in import_ndjson(exported.as_slice()) {
+        let upsert = upsert.unwrap();
+        s.upsert(&upsert.endpoint, upsert.icao, &upsert.tokens)
+            .unwrap();
+
+        if s.statements.len() == 16 {
+            exec_all(s.statements, &imported).await;
+        }
+    }
+
+    exec_all(s.statements, &imported).await;
+
+    assert_eq!(
+        String::from_utf8(export(&imported).await).unwrap(),
+        String::from_utf8(exported).unwrap()
+    );
+
+    // Malformed lines report the line they were on
+    let malformed = b"{\"endpoint\":\"1.2.3.4:7777\",\"icao\":\"ABCD\",\"tokens\":[]}\n\n{\"endpoint\":\"nope\",\"icao\":\"ABCD\",\"tokens\":[]}\n{\"endpoint\":\"1.2.3.4:7777\"}\n";
+    let results: Vec<_> = import_ndjson(&malformed[..]).collect();
+    assert_eq!(results.len(), 3);
+    assert!(results[0].is_ok());
+    assert!(matches!(
+        results[1],
+        Err(corrosion::client::write::ImportError::InvalidEndpoint { line: 3, .. })
+    ));
+    assert!(matches!(
+        results[2],
+        Err(corrosion::client::write::ImportError::Json { line: 4, .. })
+    ));
+}
+
+/// Tests that the dc patches for many upserts from the same peer can be
+/// collapsed into a single statement
+#[tokio::test]
+async fn collapses_dc_patches() {
+    const COUNT: u32 = 50;
+    let sp = tu::new_split_pool("collapses_dc_patches", corrosion::schema::SCHEMA).await;
+
+    let mut v = smallvec::SmallVec::<[_; 64]>::new();
+    let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v).collapse_dc_patches();
+
+    for i in 0..COUNT {
+        let row = make_row(i);
+        s.upsert(&row.endpoint, row.icao, &row.tokens).unwrap();
+    }
+    s.flush();
+
+    // One statement per server, and a single dc patch
+    assert_eq!(s.statements.len(), COUNT as usize + 1);
+    assert_eq!(
+        s.statements
+            .iter()
+            .filter(|s| s.query().contains("INTO dc"))
+            .count(),
+        1
+    );
+
+    exec_all(s.statements, &sp).await;
+
+    let conn = sp.read().await.unwrap();
+    let (servers, keys) = conn
+        .query_row(
+            "SELECT (SELECT COUNT(*) FROM servers), (SELECT COUNT(*) FROM dc JOIN json_each(dc.servers))",
+            [],
+            |r| Ok((r.get::<_, u32>(0)?, r.get::<_, u32>(1)?)),
+        )
+        .unwrap();
+    assert_eq!(servers, COUNT);
+    assert_eq!(keys, COUNT);
+}
+
+/// Dumps the servers and dc tables, other than the update times, so that the
+/// state of two databases can be compared
+async fn dump_tables(sp: &SplitPool) -> Vec<String> {
+    let conn = sp.read().await.unwrap();
+    let mut statement = conn
+        .prepare(
+            "SELECT endpoint || ' ' || icao || ' ' || ifnull(tokens,'') || ' ' || json(contributors) FROM servers
+            UNION ALL
+            SELECT ip || ' ' || port || ' ' || icao || ' ' || json(servers) FROM dc",
+        )
+        .unwrap();
+    let mut rows: Vec<String> = statement
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    rows.sort();
+    rows
+}
+
+/// Tests that inserting many servers with a single statement results in the
+/// same state as upserting each of them
+#[tokio::test]
+async fn upserts_many() {
+    const COUNT: u32 = 1000;
+    let looped = prep("upserts_many_looped", COUNT).await;
+    let batched = tu::new_split_pool("upserts_many_batched", corrosion::schema::SCHEMA).await;
+
+    let rows: Vec<_> = (0..COUNT).map(make_row).collect();
+    let many: Vec<_> = rows
+        .iter()
+        .map(|row| (row.endpoint.clone(), row.icao, &row.tokens))
+        .collect();
+
+    let mut v = smallvec::SmallVec::<[_; 2]>::new();
+    {
+        let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
+        s.upsert_many(&many).unwrap();
+        // One statement for the servers, and one for the dc
+        assert_eq!(s.statements.len(), 2);
+        exec_all(s.statements, &batched).await;
+    }
+
+    let dumped = dump_tables(&looped).await;
+    assert_eq!(dumped.len(), COUNT as usize + 1);
+    assert_eq!(dumped, dump_tables(&batched).await);
+
+    // Upserts of existing servers, and servers with another ICAO than the dc,
+    // are handled the same as individual upserts
+    let zzzz = IcaoCode::new_testing(*b"ZZZZ");
+    let more: Vec<_> = (COUNT - 2..COUNT + 4)
+        .map(|i| {
+            let row = make_row(i);
+            let icao = if i % 2 == 0 { zzzz } else { row.icao };
+            (row.endpoint, icao, row.tokens)
+        })
+        .collect();
+
+    {
+        let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
+        for (endpoint, icao, tokens) in &more {
+            s.upsert(endpoint, *icao, tokens).unwrap();
+        }
+        exec_all(s.statements, &looped).await;
+    }
+
+    {
+        let more: Vec<_> = more
+            .iter()
+            .map(|(endpoint, icao, tokens)| (endpoint.clone(), *icao, tokens))
+            .collect();
+        let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
+        s.upsert_many(&more).unwrap();
+        assert_eq!(s.statements.len(), 2);
+        exec_all(s.statements, &batched).await;
+    }
+
+    assert_eq!(dump_tables(&looped).await, dump_tables(&batched).await);
+
+    // Servers over the token limit fail before any statements are created
+    let mut s =
+        corrosion::client::write::Server::for_peer(PREP_PEER, &mut v).max_tokens_per_server(0);
+    assert!(s.upsert_many(&many[..3]).is_err());
+    assert!(s.statements.is_empty());
+}
+
+/// Tests that removing many peers at once has the same result as removing
+/// each of them
+#[tokio::test]
+async fn removes_many_datacenters() {
+    use corrosion::client::write::{Datacenter, Server};
+
+    let peers: Vec<_> = (1..=3u128)
+        .map(|i| SocketAddrV6::new(Ipv6Addr::from_bits(0xaaff0000 | i), 8999, 0, 0))
+        .collect();
+
+    // Each peer contributes 6 servers, overlapping with the next peer's
+    let seed = async |name: &str| {
+        let sp = tu::new_split_pool(name, corrosion::schema::SCHEMA).await;
+        let mut v = smallvec::SmallVec::<[_; 12]>::new();
+        for (i, peer) in peers.iter().enumerate() {
+            let mut s = Server::for_peer(*peer, &mut v);
+            for row in (i as u32 * 3..i as u32 * 3 + 6).map(make_row) {
+                s.upsert(&row.endpoint, row.icao, &row.tokens).unwrap();
+            }
+            exec_all(s.statements, &sp).await;
+        }
+        sp
+    };
+
+    let individual = seed("removes_many_datacenters_individual").await;
+    let many = seed("removes_many_datacenters_many").await;
+    let fake_time = time::UtcDateTime::now() - std::time::Duration::from_secs(60 * 60);
+
+    let mut v = smallvec::SmallVec::<[_; 4]>::new();
+    {
+        let mut dc = Datacenter(&mut v);
+        for peer in &peers[..2] {
+            dc.remove(*peer, Some(fake_time));
+        }
+        exec_all(dc.0, &individual).await;
+    }
+    {
+        let mut dc = Datacenter(&mut v);
+        dc.remove_many(&peers[..2], Some(fake_time));
+        assert_eq!(dc.0.len(), 2);
+        exec_all(dc.0, &many).await;
+    }
+
+    let dumped = dump_tables(&many).await;
+    assert_eq!(dumped, dump_tables(&individual).await);
+
+    let conn = many.read().await.unwrap();
+    assert_eq!(
+        conn.query_row("SELECT COUNT(*) FROM dc", [], |r| r.get::<_, u32>(0))
+            .unwrap(),
+        1
+    );
+
+    // Only the servers the remaining peer contributes still have contributors
+    let mut statement = conn
+        .prepare("SELECT json(contributors), cont_update FROM servers ORDER BY rowid")
+        .unwrap();
+    let rows: Vec<(String, i64)> = statement
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(rows.len(), 12);
+
+    let remaining = format!("{{\"{}\":{{}}}}", peers[2].ip());
+    for (i, (contributors, cont_update)) in rows.into_iter().enumerate() {
+        if i < 6 {
+            assert_eq!(contributors, "{}", "server {i}");
+        } else {
+            assert_eq!(contributors, remaining, "server {i}");
+        }
+
+        if i < 9 {
+            assert_eq!(cont_update, fake_time.unix_timestamp(), "server {i}");
+        }
+    }
+}
+
+/// Tests that the servers contributed by a datacenter can be read back as
+/// endpoints, and that removing a server removes it from the set
+#[tokio::test]
+async fn reads_contributed_servers() {
+    let sp = prep("reads_contributed_servers", 6).await;
+
+    // Both IP and hostname endpoints
+    let mut expected: std::collections::BTreeSet<_> =
+        (0..6).map(|i| make_row(i).endpoint).collect();
+    assert!(
+        expected
+            .iter()
+            .any(|ep| matches!(ep.address, AddressKind::Name(_)))
+    );
+    assert_eq!(
+        read_dc_servers(&sp).await,
+        [ContributedServers(expected.clone())]
+    );
+
+    let mut v = smallvec::SmallVec::<[_; 4]>::new();
+    {
+        let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
+        s.remove_deferred(&make_row(1).endpoint);
+        s.remove_immediate(&make_row(2).endpoint);
+        exec_all(s.statements, &sp).await;
+    }
+
+    expected.remove(&make_row(1).endpoint);
+    expected.remove(&make_row(2).endpoint);
+    assert_eq!(read_dc_servers(&sp).await, [ContributedServers(expected)]);
+
+    // A datacenter that was never given any servers
+    assert_eq!(
+        ContributedServers::from_sql(&[SqliteValue::Null]).unwrap(),
+        ContributedServers::default()
+    );
+}
+
+/// Tests that `dc.servers` keys written before servers were keyed by their
+/// compact endpoint are rejected when read, and can be migrated
+#[tokio::test]
+async fn migrates_legacy_server_keys() {
+    let sp = prep("migrates_legacy_server_keys", 6).await;
+
+    // Legacy keys for each kind of address, and a server that no longer exists
+    {
+        let conn = sp.write_priority().await.unwrap();
+        conn.execute(
+            r#"UPDATE dc SET servers = jsonb('{"|0.0.0.0:0":{},"0.0.0.3":{},"boop.4.net":{},"::5":{},"gone.net":{}}')"#,
+            [],
+        )
+        .unwrap();
+    }
+
+    {
+        let conn = sp.read().await.unwrap();
+        let servers: SqliteValue = conn
+            .query_row("SELECT json(servers) FROM dc", [], |row| row.get(0))
+            .unwrap();
+        let error = ContributedServers::from_sql(&[servers]).unwrap_err();
+        assert!(
+            error.to_string().contains("legacy key"),
+            "unexpected error: {error}"
+        );
+    }
+
+    let mut v = smallvec::SmallVec::<[_; 2]>::new();
+    let mut dc = corrosion::client::write::Datacenter(&mut v);
+    dc.migrate_server_keys();
+    exec_all(dc.0, &sp).await;
+
+    let mut expected: std::collections::BTreeSet<_> = [0, 3, 4, 5]
+        .into_iter()
+        .map(|i| make_row(i).endpoint)
+        .collect();
+    assert_eq!(
+        read_dc_servers(&sp).await,
+        [ContributedServers(expected.clone())]
+    );
+
+    // Migrating again changes nothing
+    let mut dc = corrosion::client::write::Datacenter(&mut v);
+    dc.migrate_server_keys();
+    exec_all(dc.0, &sp).await;
+    assert_eq!(
+        read_dc_servers(&sp).await,
+        [ContributedServers(expected.clone())]
+    );
+
+    // Migrated keys can be removed
+    {
+        let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
+        s.remove_immediate(&make_row(5).endpoint);
+        exec_all(s.statements, &sp).await;
+    }
+    expected.remove(&make_row(5).endpoint);
+    assert_eq!(read_dc_servers(&sp).await, [ContributedServers(expected)]);
+}
+
+/// Tests that statements can be applied to a plain SQLite connection without
+/// a corrosion pool
+#[test]
+fn executes_on_plain_connection() {
+    use corrosion::client::write;
+
+    let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+    conn.execute_batch(corrosion::schema::SCHEMA).unwrap();
+
+    const COUNT: u32 = 10;
+    let mut statements = write::Statements::<{ COUNT as usize }>::new();
+    {
+        let mut s = write::Server::for_peer(PREP_PEER, &mut statements);
+        for i in 0..COUNT {
+            let row = make_row(i);
+            s.upsert(&row.endpoint, row.icao, &row.tokens).unwrap();
+        }
+    }
+
+    let tx = conn.transaction().unwrap();
+    assert!(write::execute_all_conn(&tx, &statements).unwrap() >= COUNT as usize);
+    tx.commit().unwrap();
+
+    let mut statement = conn
+        .prepare("SELECT endpoint,icao,tokens FROM servers ORDER BY rowid")
+        .unwrap();
+    let rows: Vec<_> = statement
+        .query_map([], |row| {
+            let values: Vec<_> = (0..3)
+                .map(|i| row.get::<_, SqliteValue>(i).unwrap())
+                .collect();
+            Ok(ServerRow::from_sql(&values).unwrap())
+        })
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(rows, (0..COUNT).map(make_row).collect::<Vec<_>>());
+}
+
+/// Tests that token sets round trip whether they are stored as base64 text or
+/// as blobs, and that both forms can be in the table at the same time
+#[tokio::test]
+async fn round_trips_token_storage_forms() {
+    let sp = tu::new_split_pool("round_trips_token_storage_forms", corrosion::schema::SCHEMA).await;
+
+    let mut rows: Vec<_> = (0..6u32)
+        .map(|i| {
+            let mut row = make_row(i);
+            row.tokens = match i % 3 {
+                // Empty sets are NULL in both forms
+                0 => Default::default(),
+                1 => [vec![i as u8; 300]].into(),
+                _ => (0..i).map(|t| vec![t as u8; 1 + t as usize]).collect(),
+            };
+            row
+        })
+        .collect();
+
+    let mut v = smallvec::SmallVec::<[_; 2]>::new();
+    for (i, row) in rows.iter().enumerate() {
+        let s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
+        let mut s = if i % 2 == 0 { s.blob_tokens() } else { s };
+        s.upsert(&row.endpoint, row.icao, &row.tokens).unwrap();
+        exec_all(s.statements, &sp).await;
+    }
+
+    // Updates can write either form, whichever form the row had
+    for (i, row) in rows.iter_mut().enumerate().take(2) {
+        row.tokens = [*b"updated"].into();
+        let s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
+        let mut s = if i % 2 == 1 { s.blob_tokens() } else { s };
+        s.update(UpdateBuilder::new(&row.endpoint).update_tokens(&row.tokens))
+            .unwrap();
+        exec_all(s.statements, &sp).await;
+    }
+
+    {
+        let conn = sp.read().await.unwrap();
+        let kinds: Vec<String> = conn
+            .prepare("SELECT typeof(tokens) FROM servers ORDER BY rowid")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(kinds, ["text", "blob", "blob", "null", "blob", "text"]);
+    }
+
+    for (i, row) in rows.iter().enumerate() {
+        assert_eq!(&read_server_row(i + 1, &sp).await, row);
+    }
+
+    // Exports read both forms
+    let mut exported = Vec::new();
+    {
+        let conn = sp.read().await.unwrap();
+        assert_eq!(
+            corrosion::client::read::export_ndjson(&conn, &mut exported).unwrap(),
+            rows.len()
+        );
+    }
+    let imported: Vec<_> = corrosion::client::write::import_ndjson(exported.as_slice())
+        .map(|upsert| upsert.unwrap().tokens)
+        .collect();
+    assert_eq!(
+        imported,
+        rows.iter()
+            .map(|row| row.tokens.clone())
+            .collect::<Vec<_>>()
+    );
+}
+
+/// Tests that IPv6 addresses differing only in scope id are stored as
+/// different servers
+#[tokio::test]
+async fn stores_scoped_endpoints() {
+    let sp = tu::new_split_pool("stores_scoped_endpoints", corrosion::schema::SCHEMA).await;
+
+    let ip = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+    let rows: Vec<_> = [2, 3, 0]
+        .into_iter()
+        .map(|scope_id| ServerRow {
+            endpoint: Endpoint::new(AddressKind::scoped(ip, scope_id), 7777),
+            icao: IcaoCode::new_testing([b'S'; 4]),
+            tokens: [[scope_id as u8; 2]].into(),
+        })
+        .collect();
+
+    let mut v = smallvec::SmallVec::<[_; 2]>::new();
+    for row in &rows {
+        let mut s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
+        s.upsert(&row.endpoint, row.icao, &row.tokens).unwrap();
+        exec_all(s.statements, &sp).await;
+    }
+
+    {
+        let conn = sp.read().await.unwrap();
+        let endpoints: Vec<String> = conn
+            .prepare("SELECT endpoint FROM servers ORDER BY rowid")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            endpoints,
+            ["|fe80::1%2:7777", "|fe80::1%3:7777", "|fe80::1:7777"]
+        );
+    }
+
+    for (i, row) in rows.iter().enumerate() {
+        assert_eq!(&read_server_row(i + 1, &sp).await, row);
+    }
+}
+
+/// Tests that servers can be found by a prefix of their tokens, whichever form
+/// the tokens are stored in
+#[tokio::test]
+async fn finds_servers_by_token_prefix() {
+    use corrosion::client::read::{register_has_token_prefix, servers_with_token_prefix};
+
+    let sp = tu::new_split_pool("finds_servers_by_token_prefix", corrosion::schema::SCHEMA).await;
+
+    let servers: Vec<(Endpoint, quilkin_types::TokenSet)> = vec![
+        (
+            Endpoint::new(Ipv4Addr::new(1, 1, 1, 1).into(), 7777),
+            [*b"tenant-a/1", *b"tenant-b/1"].into(),
+        ),
+        (
+            Endpoint::new(Ipv4Addr::new(2, 2, 2, 2).into(), 7777),
+            [b"tenant-b/2".to_vec()].into(),
+        ),
+        (
+            Endpoint::new("boop.tenant-a.net".into(), 7777),
+            [b"tenant-a/3".to_vec(), b"x".to_vec()].into(),
+        ),
+        (
+            Endpoint::new(Ipv4Addr::new(4, 4, 4, 4).into(), 7777),
+            Default::default(),
+        ),
+    ];
+
+    let mut v = smallvec::SmallVec::<[_; 2]>::new();
+    for (i, (endpoint, tokens)) in servers.iter().enumerate() {
+        let s = corrosion::client::write::Server::for_peer(PREP_PEER, &mut v);
+        let mut s = if i % 2 == 0 { s.blob_tokens() } else { s };
+        s.upsert(endpoint, IcaoCode::new_testing([b'T'; 4]), tokens)
+            .unwrap();
+        exec_all(s.statements, &sp).await;
+    }
+
+    let conn = sp.read().await.unwrap();
+    assert_eq!(
+        servers_with_token_prefix(&conn, b"tenant-a/").unwrap(),
+        [servers[0].0.clone(), servers[2].0.clone()]
+    );
+    assert_eq!(
+        servers_with_token_prefix(&conn, b"tenant-b/").unwrap(),
+        [servers[0].0.clone(), servers[1].0.clone()]
+    );
+    assert_eq!(
+        servers_with_token_prefix(&conn, b"tenant-a/3").unwrap(),
+        [servers[2].0.clone()]
+    );
+    assert!(
+        servers_with_token_prefix(&conn, b"tenant-c/")
+            .unwrap()
+            .is_empty()
+    );
+    // Every token starts with an empty prefix, but a server without tokens
+    // still doesn't match
+    assert_eq!(servers_with_token_prefix(&conn, b"").unwrap().len(), 3);
+
+    // The function can also be used in queries directly
+    register_has_token_prefix(&conn).unwrap();
+    let count: u32 = conn
+        .query_row(
+            "SELECT count(*) FROM servers WHERE has_token_prefix(tokens, ?)",
+            [b"tenant-".as_slice()],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(count, 3);
+}
+
+/// Tests that the contributors of a server are counted
+#[tokio::test]
+async fn counts_contributors() {
+    use corrosion::client::{read::contributor_count, write};
+
+    let sp = tu::new_split_pool("counts_contributors", corrosion::schema::SCHEMA).await;
+
+    let endpoint = Endpoint::new(Ipv4Addr::new(1, 2, 3, 4).into(), 7777);
+    let icao = IcaoCode::new_testing([b'C'; 4]);
+
+    let mut v = smallvec::SmallVec::<[_; 2]>::new();
+    for i in 1..=3 {
+        let peer = SocketAddrV6::new(Ipv6Addr::from_bits(i), 8999, 0, 0);
+        let mut s = write::Server::for_peer(peer, &mut v);
+        s.upsert(&endpoint, icao, &[[i as u8; 4]].into()).unwrap();
+        exec_all(s.statements, &sp).await;
+    }
+
+    let conn = sp.read().await.unwrap();
+    assert_eq!(contributor_count(&conn, &endpoint).unwrap(), 3);
+
+    let unknown = Endpoint::new(Ipv4Addr::new(1, 2, 3, 5).into(), 7777);
+    assert_eq!(contributor_count(&conn, &unknown).unwrap(), 0);
+}
+
+/// Tests that servers are counted by their ICAO, including servers with the
+/// default ICAO
+#[tokio::test]
+async fn counts_by_icao() {
+    use corrosion::client::{read::counts_by_icao, write};
+
+    let sp = tu::new_split_pool("counts_by_icao", corrosion::schema::SCHEMA).await;
+
+    let expected: std::collections::BTreeMap<_, _> = [
+        (IcaoCode::new_testing(*b"EGLL"), 5),
+        (IcaoCode::new_testing(*b"KJFK"), 3),
+        (IcaoCode::new_testing(*b"K2A5"), 1),
+        (IcaoCode::default(), 2),
+    ]
+    .into_iter()
+    .collect();
+
+    {
+        let conn = sp.read().await.unwrap();
+        assert!(counts_by_icao(&conn).unwrap().is_empty());
+    }
+
+    let mut v = smallvec::SmallVec::<[_; 22]>::new();
+    {
+        let mut s = write::Server::for_peer(PREP_PEER, &mut v);
+        let mut i = 0;
+        for (icao, count) in &expected {
+            for _ in 0..*count {
+                let row = make_row(i);
+                s.upsert(&row.endpoint, *icao, &row.tokens).unwrap();
+                i += 1;
+            }
+        }
+        exec_all(s.statements, &sp).await;
+    }
+
+    let conn = sp.read().await.unwrap();
+    assert_eq!(counts_by_icao(&conn).unwrap(), expected);
+}
+
+/// Tests that updates that don't change any columns don't create statements,
+/// rather than statements with invalid SQL
+#[test]
+fn empty_updates_are_noops() {
+    use corrosion::client::write::{Datacenter, Server};
+
+    let endpoint = make_row(1).endpoint;
+    let mut v = smallvec::SmallVec::<[_; 1]>::new();
+
+    Server::for_peer(PREP_PEER, &mut v)
+        .update(UpdateBuilder::new(&endpoint))
+        .unwrap();
+    assert!(v.is_empty());
+
+    Datacenter(&mut v).update(PREP_PEER, None, None);
+    assert!(v.is_empty());
+}
+
+/// Tests that each writer method creates exactly the number of statements it
+/// advertises, so buffers sized with them never spill
+#[test]
+fn statement_counts() {
+    use corrosion::client::write::{Datacenter, Server};
+
+    let row = make_row(1);
+    let renamed = make_row(2);
+
+    fn check<const N: usize>(
+        name: &str,
+        write: impl FnOnce(&mut smallvec::SmallVec<[Statement; N]>),
+    ) {
+        let mut v = smallvec::SmallVec::<[Statement; N]>::new();
+        write(&mut v);
+        assert_eq!(v.len(), N, "{name}");
+        assert!(!v.spilled(), "{name}");
+    }
+
+    check::<{ Server::STATEMENTS_PER_UPSERT }>("upsert", |v| {
+        Server::for_peer(PREP_PEER, v)
+            .upsert(&row.endpoint, row.icao, &row.tokens)
+            .unwrap();
+    });
+    check::<{ Server::STATEMENTS_PER_REMOVE }>("remove_immediate", |v| {
+        Server::for_peer(PREP_PEER, v).remove_immediate(&row.endpoint);
+    });
+    check::<{ Server::STATEMENTS_PER_REMOVE }>("remove_deferred", |v| {
+        Server::for_peer(PREP_PEER, v).remove_deferred(&row.endpoint);
+    });
+    check::<{ Server::STATEMENTS_PER_UPDATE }>("update", |v| {
+        Server::for_peer(PREP_PEER, v)
+            .update(
+                UpdateBuilder::new(&row.endpoint)
+                    .update_icao(row.icao)
+                    .update_tokens(&row.tokens),
+            )
+            .unwrap();
+    });
+    check::<{ Server::STATEMENTS_PER_TOUCH }>("touch", |v| {
+        Server::for_peer(PREP_PEER, v).touch(&row.endpoint);
+    });
+    check::<{ Server::STATEMENTS_PER_RENAME }>("rename", |v| {
+        Server::for_peer(PREP_PEER, v).rename(&row.endpoint, &renamed.endpoint);
+    });
+
+    check::<{ Datacenter::STATEMENTS_PER_INSERT }>("dc insert", |v| {
+        Datacenter(v).insert(PREP_PEER, 7600, row.icao);
+    });
+    check::<{ Datacenter::STATEMENTS_PER_REMOVE }>("dc remove", |v| {
+        Datacenter(v).remove(PREP_PEER, None);
+    });
+    check::<{ Datacenter::STATEMENTS_PER_UPDATE }>("dc update", |v| {
+        Datacenter(v).update(PREP_PEER, Some(7601), None);
+    });
+}
@@ -0,0 +1,215 @@
+//! Tests for the length prefixed frames sent over the persistent stream
+
+use corrosion::persistent::*;
+use quilkin_types::{AddressKind, Endpoint, IcaoCode};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+fn upsert(i: u32) -> ServerUpsert {
+    ServerUpsert {
+        endpoint: Endpoint::new(Ipv4Addr::from_bits(i).into(), i as u16),
+        icao: IcaoCode::new_testing([b'A' + (i % 26) as u8; 4]),
+        tokens: [i.to_ne_bytes()].into(),
+    }
+}
+
+/// Tests that the computed wire length of a batch matches the length of the
+/// frame that is actually written
+#[test]
+fn wire_len_matches_frame() {
+    let batches = [
+        Vec::new(),
+        vec![ServerChange::Insert(Vec::new())],
+        vec![ServerChange::Insert((0..10).map(upsert).collect())],
+        vec![
+            ServerChange::Remove(vec![
+                Endpoint::new(Ipv6Addr::from_bits(0xf0ccac1a).into(), 2004),
+                Endpoint::new(AddressKind::Name("game.boop.com".into()), 2005),
+            ]),
+            ServerChange::Update(vec![ServerUpdate {
+                endpoint: Endpoint::new(Ipv4Addr::new(1, 2, 3, 4).into(), 2002),
+                icao: Some(IcaoCode::new_testing([b'X'; 4])),
+                tokens: None,
+            }]),
+            ServerChange::Insert((100..300).map(upsert).collect()),
+        ],
+    ];
+
+    for batch in &batches {
+        let frame = write_length_prefixed_jsonb(&batch).unwrap();
+        assert_eq!(ServerChange::wire_len(batch).unwrap(), frame.len());
+    }
+}
+
+/// Tests that the request id of a response is included in the frame length,
+/// and is split from the JSON that follows it
+#[test]
+fn request_id_prefixed_frame() {
+    let response = ExecResult::Execute {
+        rows_affected: 3,
+        time: 0.,
+    };
+    let plain = write_length_prefixed_jsonb(&response).unwrap();
+    let frame = write_request_id_prefixed_jsonb(0xf0cacc1a, &response).unwrap();
+    assert_eq!(frame.len(), plain.len() + 4);
+    assert_eq!(
+        u16::from_ne_bytes([frame[0], frame[1]]) as usize,
+        frame.len() - 2
+    );
+
+    let (id, json) = split_request_id(frame.freeze().slice(2..)).unwrap();
+    assert_eq!(id, 0xf0cacc1a);
+    assert_eq!(json, plain[2..]);
+
+    assert!(split_request_id(bytes::Bytes::from_static(b"{}")).is_none());
+}
+
+/// Tests that a batch rejects an endpoint being the subject of more than one
+/// change, and only produces non-empty changes
+#[test]
+fn change_batch_conflicts() {
+    let batch = ChangeBatch::new();
+    assert!(batch.is_empty());
+    assert!(batch.into_changes().is_empty());
+
+    let mut batch = ChangeBatch::new();
+    batch.upsert(upsert(1)).unwrap().upsert(upsert(2)).unwrap();
+
+    let err = batch
+        .update(ServerUpdate {
+            endpoint: upsert(1).endpoint,
+            icao: None,
+            tokens: None,
+        })
+        .err()
+        .expect("endpoint should conflict");
+    assert_eq!(err.existing, ChangeKind::Insert);
+    assert_eq!(err.new, ChangeKind::Update);
+
+    let err = batch
+        .upsert(upsert(2))
+        .err()
+        .expect("endpoint should conflict");
+    assert_eq!(err.existing, ChangeKind::Insert);
+    assert_eq!(err.new, ChangeKind::Insert);
+
+    batch.remove(upsert(3).endpoint).unwrap();
+    let err = batch
+        .upsert(upsert(3))
+        .err()
+        .expect("endpoint should conflict");
+    assert_eq!(err.existing, ChangeKind::Remove);
+
+    let changes = batch.into_changes();
+    assert_eq!(changes.len(), 2);
+    assert!(matches!(&changes[0], ServerChange::Insert(i) if i.len() == 2));
+    assert!(matches!(&changes[1], ServerChange::Remove(r) if r.len() == 1));
+}
+
+/// Tests that conflicting operations on the same endpoint are normalised into
+/// at most one remove, insert, and update, without losing any writes
+#[test]
+fn normalizes_conflicts() {
+    let update = |i: u32, icao: Option<u8>, tokens: Option<u32>| ServerUpdate {
+        endpoint: upsert(i).endpoint,
+        icao: icao.map(|c| IcaoCode::new_testing([c; 4])),
+        tokens: tokens.map(|t| [t.to_ne_bytes()].into()),
+    };
+
+    // Batches without conflicts are untouched, including their order
+    let batch = vec![
+        ServerChange::Remove(vec![upsert(1).endpoint]),
+        ServerChange::Insert(vec![upsert(2)]),
+    ];
+    assert_eq!(
+        ServerChange::normalize(batch),
+        vec![
+            ServerChange::Remove(vec![upsert(1).endpoint]),
+            ServerChange::Insert(vec![upsert(2)]),
+        ]
+    );
+
+    let batch = vec![
+        // Insert then update, both are kept as an upsert of an existing
+        // server doesn't change its ICAO or tokens
+        ServerChange::Insert(vec![upsert(1), upsert(2), upsert(3)]),
+        ServerChange::Update(vec![update(1, Some(b'X'), None)]),
+        // Insert then remove, the remove wins
+        ServerChange::Remove(vec![upsert(2).endpoint]),
+        // Remove then insert, both are kept so the server is replaced
+        ServerChange::Remove(vec![upsert(4).endpoint]),
+        ServerChange::Insert(vec![upsert(4)]),
+        // Update then update, the fields are merged with the later winning
+        ServerChange::Update(vec![update(5, Some(b'Y'), Some(1)), update(6, None, None)]),
+        ServerChange::Update(vec![update(5, None, Some(2))]),
+        // Update then remove then update, the remove replaces the first
+        // update, but the second is kept as a deferred remove may leave the
+        // server in place
+        ServerChange::Remove(vec![upsert(6).endpoint]),
+        ServerChange::Update(vec![update(6, Some(b'Z'), None)]),
+    ];
+
+    // Removes are applied before inserts, which are applied before updates
+    assert_eq!(
+        ServerChange::normalize(batch),
+        vec![
+            ServerChange::Remove(vec![
+                upsert(2).endpoint,
+                upsert(4).endpoint,
+                upsert(6).endpoint
+            ]),
+            ServerChange::Insert(vec![upsert(1), upsert(3), upsert(4)]),
+            ServerChange::Update(vec![
+                update(1, Some(b'X'), None),
+                update(5, Some(b'Y'), Some(2)),
+                update(6, Some(b'Z'), None),
+            ]),
+        ]
+    );
+}
+
+/// Tests that an empty frame is read as empty, rather than as the end of the
+/// stream, including when it is the last frame before the stream is finished
+#[tokio::test]
+async fn empty_frame() {
+    let server = quinn::Endpoint::server(
+        quinn_plaintext::server_config(),
+        (Ipv6Addr::LOCALHOST, 0).into(),
+    )
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let reader = tokio::spawn(async move {
+        let conn = server.accept().await.unwrap().await.unwrap();
+        let mut recv = conn.accept_uni().await.unwrap();
+        let mut frames = Vec::new();
+        for _ in 0..3 {
+            frames.push(read_length_prefixed(&mut recv).await.unwrap());
+        }
+        frames
+    });
+
+    let client = quinn::Endpoint::client((Ipv6Addr::LOCALHOST, 0).into()).unwrap();
+    let conn = client
+        .connect_with(quinn_plaintext::client_config(), addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+    let mut send = conn.open_uni().await.unwrap();
+    send.write_chunk(write_length_prefixed(&[]).freeze())
+        .await
+        .unwrap();
+    send.write_chunk(write_length_prefixed(b"next").freeze())
+        .await
+        .unwrap();
+    send.write_chunk(write_length_prefixed(&[]).freeze())
+        .await
+        .unwrap();
+    send.finish().unwrap();
+
+    let frames = reader.await.unwrap();
+    assert!(frames[0].is_empty());
+    assert_eq!(&frames[1][..], b"next");
+    assert!(frames[2].is_empty());
+
+    conn.close(0u32.into(), b"done");
+}
@@ -1,5 +1,9 @@
-use corrosion::persistent::*;
+use corrosion::{Peer, persistent::*};
 use quilkin_types::IcaoCode;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 #[test]
 fn version1_handshake() {
@@ -19,6 +23,521 @@ fn version1_handshake() {
     let shs = ServerHandshakeResponseV1 { accept: true }.write();
 
     let shs = ServerHandshake::read(1, &shs).unwrap();
-    let ServerHandshake::V1(v1) = shs;
+    let ServerHandshake::V1(v1) = shs else {
+        panic!("expected a version 1 response");
+    };
     assert!(v1.accept);
 }
+
+/// Tests that an invalid ICAO in a handshake reports the offending byte
+#[test]
+fn invalid_icao_handshake() {
+    let mut chs = ClientHandshakeRequestV1 {
+        qcmp_port: 8998,
+        icao: IcaoCode::new_testing(*b"EGLL"),
+    }
+    .write();
+    chs[10] = b'-';
+
+    assert!(matches!(
+        ClientHandshake::read(1, &chs),
+        Err(HandshakeError::InvalidIcao(
+            quilkin_types::IcaoError::InvalidCharacter {
+                character: '-',
+                index: 2
+            }
+        ))
+    ));
+}
+
+#[test]
+fn version2_handshake() {
+    let icao = IcaoCode::new_testing([b'H'; 4]);
+
+    let chs = ClientHandshakeRequestV1 {
+        qcmp_port: 8998,
+        icao,
+    }
+    .write_as(2);
+
+    let (version, chs) = ClientHandshake::read(2, &chs).unwrap();
+
+    assert_eq!(version, 2);
+    assert_eq!(chs.client_details(), (8998, icao));
+
+    for load in [None, Some(0), Some(42), Some(100)] {
+        let shs = ServerHandshakeResponseV2 {
+            accept: true,
+            load,
+            reason: None,
+        }
+        .write();
+
+        let ServerHandshake::V2(v2) = ServerHandshake::read(2, &shs).unwrap() else {
+            panic!("expected a version 2 response");
+        };
+        assert!(v2.accept);
+        assert_eq!(v2.load, load);
+        assert_eq!(v2.reason, None);
+    }
+
+    for reason in [RejectReason::AtCapacity, RejectReason::Unauthorized] {
+        let shs = ServerHandshakeResponseV2 {
+            accept: false,
+            load: None,
+            reason: Some(reason),
+        }
+        .write();
+
+        let ServerHandshake::V2(v2) = ServerHandshake::read(2, &shs).unwrap() else {
+            panic!("expected a version 2 response");
+        };
+        assert!(!v2.accept);
+        assert_eq!(v2.reason, Some(reason));
+    }
+
+    // Loads over 100% and unknown reasons are invalid
+    for (index, invalid) in [(7, 101), (8, 3)] {
+        let mut shs = ServerHandshakeResponseV2 {
+            accept: true,
+            load: None,
+            reason: None,
+        }
+        .write();
+        shs[index] = invalid;
+        assert!(matches!(
+            ServerHandshake::read(2, &shs),
+            Err(HandshakeError::InvalidResponse)
+        ));
+    }
+}
+
+#[test]
+fn version3_handshake() {
+    let icao = IcaoCode::new_testing([b'H'; 4]);
+
+    for features in [Features::NONE, Features(1), Features(0xdead_beef)] {
+        let chs = ClientHandshakeRequestV3 {
+            qcmp_port: 8998,
+            icao,
+            features,
+        }
+        .write();
+
+        let (version, chs) = ClientHandshake::read(3, &chs).unwrap();
+
+        assert_eq!(version, 3);
+        assert_eq!(chs.client_details(), (8998, icao));
+        assert_eq!(chs.features(), features);
+
+        let shs = ServerHandshakeResponseV3 {
+            accept: true,
+            load: Some(42),
+            reason: None,
+            features,
+        }
+        .write();
+
+        let ServerHandshake::V3(v3) = ServerHandshake::read(3, &shs).unwrap() else {
+            panic!("expected a version 3 response");
+        };
+        assert!(v3.accept);
+        assert_eq!(v3.load, Some(42));
+        assert_eq!(v3.reason, None);
+        assert_eq!(v3.features, features);
+    }
+
+    let shs = ServerHandshakeResponseV3 {
+        accept: false,
+        load: None,
+        reason: Some(RejectReason::AtCapacity),
+        features: Features::NONE,
+    }
+    .write();
+    let ServerHandshake::V3(v3) = ServerHandshake::read(3, &shs).unwrap() else {
+        panic!("expected a version 3 response");
+    };
+    assert!(!v3.accept);
+    assert_eq!(v3.reason, Some(RejectReason::AtCapacity));
+
+    // Handshakes before version 3 have no features
+    let chs = ClientHandshakeRequestV1 {
+        qcmp_port: 8998,
+        icao,
+    }
+    .write_as(2);
+    let (_, chs) = ClientHandshake::read(3, &chs).unwrap();
+    assert_eq!(chs.features(), Features::NONE);
+
+    // Truncated features are an error
+    let chs = ClientHandshakeRequestV3 {
+        qcmp_port: 8998,
+        icao,
+        features: Features(1),
+    }
+    .write();
+    assert!(matches!(
+        ClientHandshake::read(3, &chs[..14]),
+        Err(HandshakeError::InsufficientLength {
+            length: 8,
+            expected: 10
+        })
+    ));
+
+    assert!(Features(0b110).contains(Features(0b100)));
+    assert!(!Features(0b110).contains(Features(0b101)));
+    assert_eq!(
+        Features(0b110).intersection(Features(0b011)),
+        Features(0b010)
+    );
+}
+
+/// Tests that both IPv4 and IPv6 addresses are converted to the IPv6 form
+/// used for peers, with the port preserved
+#[test]
+fn canonical_peer() {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+    let v4 = SocketAddr::from((Ipv4Addr::new(10, 1, 2, 3), 7777));
+    let peer = corrosion::peer_from_socket_addr(v4);
+    assert_eq!(*peer.ip(), Ipv4Addr::new(10, 1, 2, 3).to_ipv6_mapped());
+    assert_eq!(peer.ip().to_ipv4_mapped(), Some(Ipv4Addr::new(10, 1, 2, 3)));
+    assert_eq!(peer.port(), 7777);
+
+    let ip = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+    let v6 = SocketAddr::from(SocketAddrV6::new(ip, 8888, 5, 2));
+    assert_eq!(
+        corrosion::peer_from_socket_addr(v6),
+        SocketAddrV6::new(ip, 8888, 0, 0)
+    );
+
+    // The mapped form of an IPv4 address is the same peer as the address
+    let mapped = SocketAddr::from((Ipv4Addr::new(10, 1, 2, 3).to_ipv6_mapped(), 7777));
+    assert_eq!(corrosion::peer_from_socket_addr(mapped), peer);
+}
+
+#[derive(Clone, Default)]
+struct HandshakeExecutor {
+    connected: Arc<Mutex<Option<(IcaoCode, u16)>>>,
+}
+
+#[async_trait::async_trait]
+impl server::InfallibleExecutor for HandshakeExecutor {
+    async fn connected(&self, _peer: Peer, icao: IcaoCode, qcmp_port: u16) {
+        *self.connected.lock().unwrap() = Some((icao, qcmp_port));
+    }
+
+    async fn execute(&self, _peer: Peer, statements: &[ServerChange]) -> ExecResult {
+        ExecResult::Execute {
+            rows_affected: statements.len(),
+            time: 0.,
+        }
+    }
+
+    async fn disconnected(&self, _peer: Peer) {}
+}
+
+/// Tests that the server reassembles a handshake that arrives split across
+/// multiple writes, rather than failing on the first partial chunk
+#[tokio::test]
+async fn split_handshake() {
+    let exec = HandshakeExecutor::default();
+    let server =
+        server::Server::new_unencrypted((std::net::Ipv6Addr::LOCALHOST, 0).into(), exec.clone())
+            .unwrap();
+
+    let ep = quinn::Endpoint::client((std::net::Ipv6Addr::LOCALHOST, 0).into()).unwrap();
+    let conn = ep
+        .connect_with(
+            quinn_plaintext::client_config(),
+            server.local_addr(),
+            "localhost",
+        )
+        .unwrap()
+        .await
+        .unwrap();
+    let (mut send, mut recv) = conn.open_bi().await.unwrap();
+
+    let icao = IcaoCode::new_testing([b'S'; 4]);
+    let hs = write_length_prefixed(
+        &ClientHandshakeRequestV1 {
+            qcmp_port: 8998,
+            icao,
+        }
+        .write(),
+    )
+    .freeze();
+
+    // Write the length, magic and version, then the rest of the handshake
+    // separately, giving the first write time to be sent on its own
+    send.write_chunk(hs.slice(..8)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    send.write_chunk(hs.slice(8..)).await.unwrap();
+
+    let response = read_length_prefixed(&mut recv).await.unwrap();
+    // The server responds with the version of the client's handshake
+    let ServerHandshake::V1(v1) = ServerHandshake::read(1, &response).unwrap() else {
+        panic!("expected a version 1 response");
+    };
+    assert!(v1.accept);
+    assert_eq!(*exec.connected.lock().unwrap(), Some((icao, 8998)));
+
+    // Frames after the handshake are reassembled the same way
+    let frame =
+        write_length_prefixed_jsonb(&[ServerChange::Remove(vec![quilkin_types::Endpoint::new(
+            std::net::Ipv4Addr::LOCALHOST.into(),
+            7777,
+        )])])
+        .unwrap()
+        .freeze();
+    send.write_chunk(frame.slice(..5)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    send.write_chunk(frame.slice(5..)).await.unwrap();
+
+    let response: ExecResult = read_length_prefixed_jsonb(&mut recv).await.unwrap();
+    assert!(matches!(
+        response,
+        ExecResult::Execute {
+            rows_affected: 1,
+            ..
+        }
+    ));
+
+    conn.close(0u32.into(), b"done");
+    server.shutdown("done").await;
+}
+
+/// Connects to the server and completes a version 1 handshake with raw streams
+async fn raw_connect(
+    addr: std::net::SocketAddr,
+) -> (quinn::Connection, quinn::SendStream, quinn::RecvStream) {
+    let ep = quinn::Endpoint::client((std::net::Ipv6Addr::LOCALHOST, 0).into()).unwrap();
+    let conn = ep
+        .connect_with(quinn_plaintext::client_config(), addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+    let (mut send, mut recv) = conn.open_bi().await.unwrap();
+
+    let hs = ClientHandshakeRequestV1 {
+        qcmp_port: 8998,
+        icao: IcaoCode::new_testing([b'L'; 4]),
+    }
+    .write();
+    send.write_chunk(write_length_prefixed(&hs).freeze())
+        .await
+        .unwrap();
+    let response = read_length_prefixed(&mut recv).await.unwrap();
+    let ServerHandshake::V1(v1) = ServerHandshake::read(1, &response).unwrap() else {
+        panic!("expected a version 1 response");
+    };
+    assert!(v1.accept);
+
+    (conn, send, recv)
+}
+
+/// Tests that a frame whose declared length is over the limit is rejected as
+/// soon as the length is read, rather than waiting for the frame
+#[tokio::test]
+async fn rejects_oversized_frame() {
+    let server = server::Server::new(
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+        server::ServerConfig {
+            max_frame_bytes: Some(64),
+            ..Default::default()
+        },
+        HandshakeExecutor::default(),
+    )
+    .unwrap();
+    let (conn, mut send, mut recv) = raw_connect(server.local_addr()).await;
+
+    // Only the length is sent, so without the limit the server would wait
+    // for the rest of the frame
+    send.write_all(&u16::MAX.to_ne_bytes()).await.unwrap();
+
+    let error = tokio::time::timeout(Duration::from_secs(5), read_length_prefixed(&mut recv))
+        .await
+        .expect("the frame should have been rejected immediately")
+        .unwrap_err();
+    let LengthReadError::ReadExact(quinn::ReadExactError::ReadError(quinn::ReadError::Reset(code))) =
+        error
+    else {
+        panic!("unexpected error {error:?}");
+    };
+    assert_eq!(ErrorCode::from(code), ErrorCode::PayloadTooLarge);
+    assert_eq!(
+        server
+            .stats()
+            .snapshot()
+            .codes
+            .get(&ErrorCode::PayloadTooLarge),
+        Some(&1)
+    );
+
+    conn.close(0u32.into(), b"done");
+    server.shutdown("done").await;
+}
+
+/// Tests that frames with too many changes or endpoints are responded to with
+/// an error without closing the connection
+#[tokio::test]
+async fn rejects_frames_over_limits() {
+    let server = server::Server::new(
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+        server::ServerConfig {
+            max_changes_per_frame: Some(2),
+            max_endpoints_per_frame: Some(3),
+            ..Default::default()
+        },
+        HandshakeExecutor::default(),
+    )
+    .unwrap();
+    let (conn, mut send, mut recv) = raw_connect(server.local_addr()).await;
+
+    let remove = |count: u16| {
+        ServerChange::Remove(
+            (0..count)
+                .map(|port| {
+                    quilkin_types::Endpoint::new(std::net::Ipv4Addr::LOCALHOST.into(), port)
+                })
+                .collect(),
+        )
+    };
+
+    let mut transact = async |changes: &[ServerChange]| -> ExecResult {
+        send.write_chunk(write_length_prefixed_jsonb(&changes).unwrap().freeze())
+            .await
+            .unwrap();
+        read_length_prefixed_jsonb(&mut recv).await.unwrap()
+    };
+
+    for (changes, expected) in [
+        (
+            vec![remove(1), remove(1), remove(1)],
+            "the frame has 3 changes, more than the maximum of 2",
+        ),
+        (
+            vec![remove(2), remove(2)],
+            "the frame changes 4 endpoints, more than the maximum of 3",
+        ),
+    ] {
+        // The rejection is an error frame, which clients that only read an
+        // `ExecResult` can still read the message of
+        let ExecResult::Error { error } = transact(&changes).await else {
+            panic!("expected the frame to be rejected");
+        };
+        assert_eq!(error, expected);
+    }
+
+    // The connection is still usable after the rejections
+    assert!(matches!(
+        transact(&[remove(3)]).await,
+        ExecResult::Execute {
+            rows_affected: 1,
+            ..
+        }
+    ));
+
+    conn.close(0u32.into(), b"done");
+    server.shutdown("done").await;
+}
+
+/// Starts a server that responds to the handshake of the first connection
+/// with `response`, regardless of the handshake the client sent
+fn fake_server(response: Vec<u8>) -> (std::net::SocketAddr, tokio::task::JoinHandle<()>) {
+    let ep = quinn::Endpoint::server(
+        quinn_plaintext::server_config(),
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+    )
+    .unwrap();
+    let addr = ep.local_addr().unwrap();
+
+    let task = tokio::spawn(async move {
+        let conn = ep.accept().await.unwrap().await.unwrap();
+        let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+        read_length_prefixed(&mut recv).await.unwrap();
+
+        send.write_chunk(write_length_prefixed(&response).freeze())
+            .await
+            .unwrap();
+
+        // Wait for the client to close its end of the stream
+        while let Ok(Some(_)) = recv.read_chunk(usize::MAX, true).await {}
+    });
+
+    (addr, task)
+}
+
+/// Creates a raw server handshake response with the specified version
+fn response(version: u16, accept: bool) -> Vec<u8> {
+    let mut response = MAGIC.to_vec();
+    response.extend_from_slice(&version.to_le_bytes());
+    response.push(accept as u8);
+    if version >= 2 {
+        // No load or reason
+        response.extend_from_slice(&[u8::MAX, 0]);
+    }
+    if version == 3 {
+        // No features
+        response.extend_from_slice(&[0; 4]);
+    }
+    response
+}
+
+/// Tests that the client uses the version of the server's handshake response,
+/// and fails to connect if it's a version the client doesn't implement
+#[tokio::test]
+async fn negotiated_version() {
+    for version in [1u16, 2, 3, 4, 99] {
+        let (addr, server) = fake_server(response(version, true));
+
+        let res =
+            client::Client::connect_insecure(addr, 2001, IcaoCode::new_testing([b'V'; 4])).await;
+
+        match (version, res) {
+            (1..=3, Ok(client)) => {
+                assert_eq!(client.peer_version(), version);
+                client.shutdown().await;
+            }
+            (
+                theirs,
+                Err(client::ConnectError::UnsupportedVersion {
+                    ours: client::VERSION,
+                    theirs: claimed,
+                }),
+            ) if theirs > 3 => assert_eq!(claimed, theirs),
+            (version, Err(error)) => panic!("unexpected error for version {version}: {error}"),
+            (version, Ok(_)) => panic!("connected with unimplemented version {version}"),
+        }
+
+        server.abort();
+    }
+}
+
+/// Tests that a handshake response declining the connection is reported as
+/// a rejection
+#[tokio::test]
+async fn declined_handshake() {
+    let (addr, server) = fake_server(response(1, false));
+
+    let Err(error) =
+        client::Client::connect_insecure(addr, 2001, IcaoCode::new_testing([b'D'; 4])).await
+    else {
+        panic!("expected the connection to be declined");
+    };
+    assert!(matches!(
+        error,
+        client::ConnectError::Rejected { reason: None }
+    ));
+    assert_eq!(error.to_string(), "the server declined the connection");
+
+    let with_reason = client::ConnectError::Rejected {
+        reason: Some(RejectReason::AtCapacity),
+    };
+    assert_eq!(
+        with_reason.to_string(),
+        "the server declined the connection: the server is at capacity"
+    );
+
+    server.abort();
+}
@@ -4,10 +4,82 @@
 use corrosion::{Peer, client as c, persistent as p};
 use corrosion_utils as tu;
 use quilkin_types::{Endpoint, IcaoCode};
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
 
 #[derive(Clone)]
 struct InstaPrinter {
     db: corro_types::agent::SplitPool,
+    /// The number of transactions used to execute changes
+    transactions: Arc<AtomicUsize>,
+    icao_policy: p::IcaoMismatchPolicy,
+    /// Clients with this ICAO aren't authorized to connect
+    denied: Option<IcaoCode>,
+}
+
+impl InstaPrinter {
+    async fn new(name: &str) -> Self {
+        Self {
+            db: tu::new_split_pool(name, corrosion::schema::SCHEMA).await,
+            transactions: Default::default(),
+            icao_policy: Default::default(),
+            denied: None,
+        }
+    }
+
+    /// Checks the changes against the ICAO of the peer's datacenter
+    async fn check_icao(&self, peer: Peer, changes: &[p::ServerChange]) -> Result<(), String> {
+        let dc = {
+            let conn = self.db.read().await.unwrap();
+            conn.query_row(
+                "SELECT icao FROM dc WHERE ip = ?",
+                [peer.ip().to_string()],
+                |row| row.get::<_, String>(0),
+            )
+            .unwrap()
+        };
+
+        p::ServerChange::check_icao(changes, dc.parse().unwrap(), self.icao_policy)
+            .map_err(|error| error.to_string())
+    }
+}
+
+fn statements<const N: usize>(
+    peer: Peer,
+    changes: &[p::ServerChange],
+    v: &mut smallvec::SmallVec<[corro_types::api::Statement; N]>,
+) {
+    let mut srv = c::write::Server::for_peer(peer, v);
+
+    for s in changes {
+        match s {
+            p::ServerChange::Insert(i) => {
+                for i in i {
+                    srv.upsert(&i.endpoint, i.icao, &i.tokens).unwrap();
+                }
+            }
+            p::ServerChange::Remove(r) => {
+                for r in r {
+                    srv.remove_immediate(r);
+                }
+            }
+            p::ServerChange::Update(u) => {
+                for u in u {
+                    let mut ub = c::write::UpdateBuilder::new(&u.endpoint);
+                    if let Some(icao) = u.icao {
+                        ub = ub.update_icao(icao);
+                    }
+
+                    if let Some(ts) = &u.tokens {
+                        ub = ub.update_tokens(ts);
+                    }
+                    srv.update(ub).unwrap();
+                }
+            }
+        }
+    }
 }
 
 impl InstaPrinter {
@@ -23,12 +95,20 @@ impl InstaPrinter {
             prow.add_cell(tu::Cell::new(&srow.get::<_, String>(2).unwrap()));
         });
         let statement = conn
-            .prepare("SELECT ip,icao,json(servers) FROM dc")
+            .prepare("SELECT ip,icao,json(servers) AS servers FROM dc")
             .unwrap();
         let dc = tu::query_to_string(statement, |srow, prow| {
+            use c::read::FromSqlValue as _;
+
             prow.add_cell(tu::Cell::new(&srow.get::<_, String>(0).unwrap()));
             prow.add_cell(tu::Cell::new(&srow.get::<_, String>(1).unwrap()));
-            prow.add_cell(tu::Cell::new(&srow.get::<_, String>(2).unwrap()));
+
+            let servers = c::read::ContributedServers::from_sql(&[srow
+                .get::<_, corro_api_types::SqliteValue>(2)
+                .unwrap()])
+            .unwrap();
+            let servers: Vec<_> = servers.0.iter().map(|ep| ep.to_string()).collect();
+            prow.add_cell(tu::Cell::new(&servers.join(", ")));
         });
 
         servers.push('\n');
@@ -38,7 +118,19 @@ impl InstaPrinter {
 }
 
 #[async_trait::async_trait]
-impl p::server::AgentExecutor for InstaPrinter {
+impl p::server::InfallibleExecutor for InstaPrinter {
+    async fn authorize(
+        &self,
+        _peer: Peer,
+        handshake: &p::server::ClientHandshakeInfo,
+    ) -> p::server::AuthDecision {
+        if self.denied == Some(handshake.icao) {
+            p::server::AuthDecision::Reject(p::RejectReason::Unauthorized)
+        } else {
+            p::server::AuthDecision::Accept
+        }
+    }
+
     async fn connected(&self, peer: Peer, icao: IcaoCode, qcmp_port: u16) {
         let mut dc = smallvec::SmallVec::<[_; 1]>::new();
         let mut dc = c::write::Datacenter(&mut dc);
@@ -52,47 +144,28 @@ impl p::server::AgentExecutor for InstaPrinter {
         }
     }
 
-    async fn execute(&self, peer: Peer, statements: &[p::ServerChange]) -> p::ExecResult {
-        let mut v = smallvec::SmallVec::<[_; 20]>::new();
-        {
-            let mut srv = c::write::Server::for_peer(peer, &mut v);
-
-            for s in statements {
-                match s {
-                    p::ServerChange::Insert(i) => {
-                        for i in i {
-                            srv.upsert(&i.endpoint, i.icao, &i.tokens);
-                        }
-                    }
-                    p::ServerChange::Remove(r) => {
-                        for r in r {
-                            srv.remove_immediate(r);
-                        }
-                    }
-                    p::ServerChange::Update(u) => {
-                        for u in u {
-                            let mut ub = c::write::UpdateBuilder::new(&u.endpoint);
-                            if let Some(icao) = u.icao {
-                                ub = ub.update_icao(icao);
-                            }
-
-                            if let Some(ts) = &u.tokens {
-                                ub = ub.update_tokens(ts);
-                            }
-                            srv.update(ub);
-                        }
-                    }
-                }
-            }
+    async fn execute(&self, peer: Peer, changes: &[p::ServerChange]) -> p::ExecResult {
+        if let Err(error) = self.check_icao(peer, changes).await {
+            return p::ExecResult::Error { error };
         }
 
+        let mut v = smallvec::SmallVec::<[_; 20]>::new();
+        statements(peer, changes, &mut v);
+
         let rows_affected = {
             let mut conn = self.db.write_normal().await.unwrap();
-            let tx = conn.transaction().unwrap();
-            let rows = tu::exec(&tx, v.iter()).unwrap();
-            tx.commit().unwrap();
-            rows
+            let res = conn.transaction().and_then(|tx| {
+                let rows = tu::exec(&tx, v.iter())?;
+                tx.commit()?;
+                Ok(rows)
+            });
+
+            match res {
+                Ok(rows) => rows,
+                Err(error) => return p::server::exec_error(error),
+            }
         };
+        self.transactions.fetch_add(1, Ordering::Relaxed);
 
         p::ExecResult::Execute {
             rows_affected,
@@ -100,6 +173,35 @@ impl p::server::AgentExecutor for InstaPrinter {
         }
     }
 
+    async fn execute_batch(&self, frames: &[(Peer, Vec<p::ServerChange>)]) -> Vec<p::ExecResult> {
+        let mut checks = Vec::with_capacity(frames.len());
+        for (peer, changes) in frames {
+            checks.push(self.check_icao(*peer, changes).await);
+        }
+
+        let mut conn = self.db.write_normal().await.unwrap();
+        let tx = conn.transaction().unwrap();
+
+        let mut results = Vec::with_capacity(frames.len());
+        for ((peer, changes), check) in frames.iter().zip(checks) {
+            if let Err(error) = check {
+                results.push(p::ExecResult::Error { error });
+                continue;
+            }
+
+            let mut v = smallvec::SmallVec::<[_; 20]>::new();
+            statements(*peer, changes, &mut v);
+            results.push(p::ExecResult::Execute {
+                rows_affected: tu::exec(&tx, v.iter()).unwrap(),
+                time: 0.,
+            });
+        }
+
+        tx.commit().unwrap();
+        self.transactions.fetch_add(1, Ordering::Relaxed);
+        results
+    }
+
     async fn disconnected(&self, peer: Peer) {
         let mut dc = smallvec::SmallVec::<[_; 1]>::new();
         let mut dc = c::write::Datacenter(&mut dc);
@@ -116,9 +218,7 @@ impl p::server::AgentExecutor for InstaPrinter {
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn test_quic_stream() {
-    let ip = InstaPrinter {
-        db: tu::new_split_pool("quic-basic", corrosion::schema::SCHEMA).await,
-    };
+    let ip = InstaPrinter::new("quic-basic").await;
 
     let server =
         p::server::Server::new_unencrypted((std::net::Ipv6Addr::LOCALHOST, 0).into(), ip.clone())
@@ -133,7 +233,7 @@ async fn test_quic_stream() {
     insta::assert_snapshot!("connect", ip.print().await);
 
     client
-        .transactions(&[p::ServerChange::Insert(vec![
+        .upsert_servers(&[
             p::ServerUpsert {
                 endpoint: Endpoint {
                     address: std::net::Ipv4Addr::new(1, 2, 3, 4).into(),
@@ -166,32 +266,451 @@ async fn test_quic_stream() {
                 icao,
                 tokens: [[50; 5]].into(),
             },
-        ])])
+        ])
         .await
         .unwrap();
 
     insta::assert_snapshot!("initial_insert", ip.print().await);
 
-    client
-        .transactions(&[
-            p::ServerChange::Remove(vec![Endpoint {
-                address: std::net::Ipv4Addr::new(9, 9, 9, 9).into(),
-                port: 2003,
-            }]),
-            p::ServerChange::Update(vec![p::ServerUpdate {
+    let mut batch = p::ChangeBatch::new();
+    batch
+        .remove(Endpoint {
+            address: std::net::Ipv4Addr::new(9, 9, 9, 9).into(),
+            port: 2003,
+        })
+        .unwrap()
+        .update(p::ServerUpdate {
+            endpoint: Endpoint {
+                address: std::net::Ipv6Addr::from_bits(0xf0ccac1a).into(),
+                port: 2004,
+            },
+            icao: Some(IcaoCode::new_testing([b'X'; 4])),
+            tokens: None,
+        })
+        .unwrap();
+    client.apply_batch(batch).await.unwrap();
+
+    insta::assert_snapshot!("remove_and_update", ip.print().await);
+
+    let stats = server.stats().snapshot();
+    assert_eq!(stats.connections, 1);
+    assert_eq!(stats.total_connections, 1);
+    assert_eq!(stats.handshakes_accepted, 1);
+    assert_eq!(stats.handshakes_rejected + stats.handshakes_failed, 0);
+    assert_eq!(stats.frames_received, 2);
+    assert_eq!(stats.frames_sent, 2);
+    assert!(stats.bytes_received > stats.bytes_sent);
+    assert_eq!(stats.execute_latency.count, 2);
+    assert!(stats.codes.is_empty(), "{:?}", stats.codes);
+
+    client.shutdown().await;
+    insta::assert_snapshot!("disconnect", ip.print().await);
+}
+
+/// Tests that frames from many connections are coalesced into fewer database
+/// transactions, and that every change is still applied
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn coalesces_writes() {
+    const CLIENTS: u32 = 8;
+    const FRAMES: u32 = 5;
+
+    let ip = InstaPrinter::new("quic-coalesce").await;
+
+    let server = p::server::Server::new(
+        (std::net::Ipv4Addr::LOCALHOST, 0).into(),
+        p::server::ServerConfig {
+            coalesce: Some(p::server::CoalesceConfig {
+                window: std::time::Duration::from_millis(20),
+                max_rows: 1000,
+            }),
+            ..Default::default()
+        },
+        ip.clone(),
+    )
+    .unwrap();
+
+    let icao = IcaoCode::new_testing([b'W'; 4]);
+
+    // Each agent needs a distinct IP as the datacenter is keyed by it
+    let mut clients = Vec::new();
+    for i in 0..CLIENTS {
+        clients.push(
+            p::client::Client::connect_insecure_with_config(
+                server.local_addr(),
+                2001,
+                icao,
+                p::client::ClientConfig {
+                    bind_addr: Some((std::net::Ipv4Addr::new(127, 0, 0, i as u8 + 1), 0).into()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap(),
+        );
+    }
+
+    let tasks = clients.into_iter().enumerate().map(|(i, client)| {
+        tokio::spawn(async move {
+            for f in 0..FRAMES {
+                let res = client
+                    .upsert_one(
+                        Endpoint {
+                            address: std::net::Ipv4Addr::new(10, 0, i as u8, f as u8).into(),
+                            port: 7777,
+                        },
+                        icao,
+                        &[[f as u8; 2]].into(),
+                    )
+                    .await
+                    .unwrap();
+                assert!(matches!(res, p::ExecResult::Execute { .. }));
+            }
+
+            client.shutdown().await;
+        })
+    });
+
+    for task in tasks.collect::<Vec<_>>() {
+        task.await.unwrap();
+    }
+
+    let servers = {
+        let conn = ip.db.read().await.unwrap();
+        conn.query_row("SELECT COUNT(*) FROM servers", [], |row| {
+            row.get::<_, u32>(0)
+        })
+        .unwrap()
+    };
+    assert_eq!(servers, CLIENTS * FRAMES);
+
+    let transactions = ip.transactions.load(Ordering::Relaxed) as u32;
+    assert!(
+        transactions < CLIENTS * FRAMES,
+        "{transactions} transactions were used for {} frames",
+        CLIENTS * FRAMES
+    );
+
+    server.shutdown("done").await;
+}
+
+/// Tests that upserting a server whose ICAO differs from the datacenter's is
+/// either applied or rejected, depending on the executor's policy
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn icao_mismatch() {
+    let egll = IcaoCode::new_testing(*b"EGLL");
+    let ksfo = IcaoCode::new_testing(*b"KSFO");
+
+    for (name, icao_policy) in [
+        ("quic-icao-warn", p::IcaoMismatchPolicy::Warn),
+        ("quic-icao-reject", p::IcaoMismatchPolicy::Reject),
+    ] {
+        let ip = InstaPrinter {
+            icao_policy,
+            ..InstaPrinter::new(name).await
+        };
+
+        let server = p::server::Server::new_unencrypted(
+            (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+            ip.clone(),
+        )
+        .unwrap();
+
+        let client = p::client::Client::connect_insecure(server.local_addr(), 2001, egll)
+            .await
+            .unwrap();
+
+        let res = client
+            .upsert_servers(&[p::ServerUpsert {
                 endpoint: Endpoint {
-                    address: std::net::Ipv6Addr::from_bits(0xf0ccac1a).into(),
-                    port: 2004,
+                    address: std::net::Ipv4Addr::new(1, 2, 3, 4).into(),
+                    port: 2002,
                 },
-                icao: Some(IcaoCode::new_testing([b'X'; 4])),
-                tokens: None,
-            }]),
+                icao: ksfo,
+                tokens: [[20; 2]].into(),
+            }])
+            .await;
+
+        let servers = {
+            let conn = ip.db.read().await.unwrap();
+            conn.query_row("SELECT COUNT(*) FROM servers", [], |row| {
+                row.get::<_, u32>(0)
+            })
+            .unwrap()
+        };
+
+        match icao_policy {
+            p::IcaoMismatchPolicy::Reject => {
+                let Err(p::client::TransactionError::Rejected { message, .. }) = res else {
+                    panic!("expected the upsert to be rejected, got {res:?}");
+                };
+                assert!(
+                    message.contains("KSFO") && message.contains("EGLL"),
+                    "{message}"
+                );
+                assert_eq!(servers, 0);
+            }
+            _ => {
+                assert!(matches!(res, Ok(p::ExecResult::Execute { .. })), "{res:?}");
+                assert_eq!(servers, 1);
+            }
+        }
+
+        client.shutdown().await;
+        server.shutdown("done").await;
+    }
+}
+
+/// Tests that a SQLite error executing the changes is sent back to the client,
+/// and that nothing from the failed transaction is applied
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn constraint_violation() {
+    let ip = InstaPrinter::new("quic-constraint").await;
+    {
+        let conn = ip.db.write_priority().await.unwrap();
+        conn.execute_batch(
+            "CREATE TRIGGER reserved_port BEFORE INSERT ON servers
+             WHEN NEW.endpoint LIKE '%:9999'
+             BEGIN SELECT RAISE(ABORT, 'port 9999 is reserved'); END",
+        )
+        .unwrap();
+    }
+
+    let server =
+        p::server::Server::new_unencrypted((std::net::Ipv6Addr::LOCALHOST, 0).into(), ip.clone())
+            .unwrap();
+
+    let icao = IcaoCode::new_testing(*b"EGLL");
+    let client = p::client::Client::connect_insecure(server.local_addr(), 2001, icao)
+        .await
+        .unwrap();
+
+    let res = client
+        .upsert_servers(&[
+            p::ServerUpsert {
+                endpoint: Endpoint::new(std::net::Ipv4Addr::new(1, 2, 3, 4).into(), 2002),
+                icao,
+                tokens: [[20; 2]].into(),
+            },
+            p::ServerUpsert {
+                endpoint: Endpoint::new(std::net::Ipv4Addr::new(1, 2, 3, 5).into(), 9999),
+                icao,
+                tokens: [[30; 3]].into(),
+            },
         ])
+        .await;
+    let Err(p::client::TransactionError::Rejected { message, .. }) = res else {
+        panic!("expected the upsert to be rejected, got {res:?}");
+    };
+    assert!(message.contains("port 9999 is reserved"), "{message}");
+
+    let servers = {
+        let conn = ip.db.read().await.unwrap();
+        conn.query_row("SELECT COUNT(*) FROM servers", [], |row| {
+            row.get::<_, u32>(0)
+        })
+        .unwrap()
+    };
+    assert_eq!(servers, 0);
+
+    // The stream is still usable after a rejected transaction
+    client
+        .upsert_servers(&[p::ServerUpsert {
+            endpoint: Endpoint::new(std::net::Ipv4Addr::new(1, 2, 3, 4).into(), 2002),
+            icao,
+            tokens: [[20; 2]].into(),
+        }])
         .await
         .unwrap();
 
-    insta::assert_snapshot!("remove_and_update", ip.print().await);
+    client.shutdown().await;
+    server.shutdown("done").await;
+}
+
+/// Tests that long error messages are truncated without splitting a character
+#[test]
+fn truncates_exec_errors() {
+    let p::ExecResult::Error { error } = p::server::exec_error("short") else {
+        unreachable!();
+    };
+    assert_eq!(error, "short");
+
+    let long = "é".repeat(p::server::MAX_ERROR_LEN);
+    let p::ExecResult::Error { error } = p::server::exec_error(&long) else {
+        unreachable!();
+    };
+    assert_eq!(error.len(), p::server::MAX_ERROR_LEN);
+    assert!(long.starts_with(&error));
+
+    let long = format!("a{long}");
+    let p::ExecResult::Error { error } = p::server::exec_error(&long) else {
+        unreachable!();
+    };
+    assert_eq!(error.len(), p::server::MAX_ERROR_LEN - 1);
+}
+
+/// Tests that a client connecting to a server that is at capacity is rejected
+/// without being recorded as a datacenter
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn declined_connection() {
+    let ip = InstaPrinter::new("quic-declined").await;
+
+    let server = p::server::Server::new(
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+        p::server::ServerConfig {
+            max_connections: Some(0),
+            ..Default::default()
+        },
+        ip.clone(),
+    )
+    .unwrap();
+
+    let Err(error) = p::client::Client::connect_insecure(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing(*b"EGLL"),
+    )
+    .await
+    else {
+        panic!("expected the connection to be declined");
+    };
+    assert!(matches!(
+        error,
+        p::client::ConnectError::Rejected {
+            reason: Some(p::RejectReason::AtCapacity)
+        }
+    ));
+    assert_eq!(
+        error.to_string(),
+        "the server declined the connection: the server is at capacity"
+    );
+
+    let dcs = {
+        let conn = ip.db.read().await.unwrap();
+        conn.query_row("SELECT COUNT(*) FROM dc", [], |row| row.get::<_, u32>(0))
+            .unwrap()
+    };
+    assert_eq!(dcs, 0);
+
+    server.shutdown("done").await;
+}
+
+/// Tests that clients the executor doesn't authorize are declined with its
+/// reason, without being recorded as a datacenter, while others connect
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn unauthorized_connection() {
+    let mut ip = InstaPrinter::new("quic-unauthorized").await;
+    ip.denied = Some(IcaoCode::new_testing(*b"EGLL"));
+
+    let server =
+        p::server::Server::new_unencrypted((std::net::Ipv6Addr::LOCALHOST, 0).into(), ip.clone())
+            .unwrap();
+
+    let Err(error) = p::client::Client::connect_insecure(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing(*b"EGLL"),
+    )
+    .await
+    else {
+        panic!("expected the connection to be declined");
+    };
+    assert!(matches!(
+        error,
+        p::client::ConnectError::Rejected {
+            reason: Some(p::RejectReason::Unauthorized)
+        }
+    ));
+
+    let dcs = async || -> Vec<String> {
+        let conn = ip.db.read().await.unwrap();
+        let mut statement = conn.prepare("SELECT icao FROM dc").unwrap();
+        statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    };
+    assert!(dcs().await.is_empty());
+
+    let client = p::client::Client::connect_insecure(
+        server.local_addr(),
+        2001,
+        IcaoCode::new_testing(*b"KSEA"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(dcs().await, ["KSEA"]);
 
     client.shutdown().await;
-    insta::assert_snapshot!("disconnect", ip.print().await);
+    server.shutdown("done").await;
+}
+
+/// Tests that a connection that doesn't send frames within the idle timeout
+/// is closed and cleaned up, while frames keep resetting the timer
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn idle_timeout() {
+    let ip = InstaPrinter::new("quic-idle").await;
+
+    let server = p::server::Server::new(
+        (std::net::Ipv6Addr::LOCALHOST, 0).into(),
+        p::server::ServerConfig {
+            idle_timeout: Some(std::time::Duration::from_millis(300)),
+            ..Default::default()
+        },
+        ip.clone(),
+    )
+    .unwrap();
+
+    let icao = IcaoCode::new_testing(*b"EGLL");
+    let client = p::client::Client::connect_insecure(server.local_addr(), 2001, icao)
+        .await
+        .unwrap();
+
+    let dcs = async || -> u32 {
+        let conn = ip.db.read().await.unwrap();
+        conn.query_row("SELECT COUNT(*) FROM dc", [], |row| row.get(0))
+            .unwrap()
+    };
+
+    // Sending frames more often than the timeout keeps the connection open
+    for i in 0..6 {
+        client
+            .upsert_servers(&[p::ServerUpsert {
+                endpoint: Endpoint::new(std::net::Ipv4Addr::new(1, 2, 3, 4).into(), 2002),
+                icao,
+                tokens: [[i; 2]].into(),
+            }])
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert!(client.is_connected());
+    assert_eq!(dcs().await, 1);
+
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while dcs().await > 0 || client.is_connected() {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("the idle connection should have been closed");
+
+    let reason = client.connection().close_reason();
+    let Some(quinn::ConnectionError::ApplicationClosed(close)) = reason else {
+        panic!("unexpected close reason {reason:?}");
+    };
+    assert_eq!(
+        p::ErrorCode::from(close.error_code),
+        p::ErrorCode::IdleTimeout
+    );
+
+    let stats = server.stats().snapshot();
+    assert_eq!(stats.frames_received, 6);
+    assert_eq!(
+        stats.codes,
+        [(p::ErrorCode::IdleTimeout, 1)].into_iter().collect()
+    );
+
+    client.shutdown().await;
+    server.shutdown("done").await;
 }
@@ -0,0 +1,630 @@
+//! Tests for the read helpers that don't require a database
+
+use corro_api_types::{ChangeId, RowId, SqliteValue};
+use corrosion::client::{
+    read::{
+        ChangeType, FromSqlValue as _, QueryEvent, Resumed, ServerEvent, ServerRow,
+        ServerSubscription,
+    },
+    write::ToSqlParam as _,
+};
+use quilkin_types::{Endpoint, IcaoCode, TokenSet};
+
+fn row(tokens: TokenSet) -> ServerRow {
+    ServerRow {
+        endpoint: Endpoint::new(std::net::Ipv4Addr::new(1, 2, 3, 4).into(), 7777),
+        icao: IcaoCode::new_testing([b'R'; 4]),
+        tokens,
+    }
+}
+
+/// Tests that tokens are matched against the server's token set
+#[test]
+fn accepts_tokens() {
+    let server = row([[1u8; 4], [2; 4], [3; 4]].into());
+
+    assert!(server.accepts_token(&[2; 4]));
+    assert!(!server.accepts_token(&[4; 4]));
+    assert!(!server.accepts_token(&[2; 3]));
+
+    assert!(server.accepts_any(&[&[9; 4], &[3; 4]]));
+    assert!(!server.accepts_any(&[&[9; 4], &[8; 4]]));
+    assert!(!server.accepts_any(&[]));
+
+    let empty = row(TokenSet::default());
+    assert!(!empty.accepts_token(&[1; 4]));
+    assert!(!empty.accepts_token(&[]));
+    assert!(!empty.accepts_any(&[&[1; 4], &[]]));
+}
+
+fn change(kind: ChangeType, row: &ServerRow) -> QueryEvent {
+    let text = |param| match param {
+        corro_api_types::SqliteParam::Text(text) => SqliteValue::Text(text),
+        other => panic!("unexpected param {other:?}"),
+    };
+
+    QueryEvent::Change(
+        kind,
+        RowId(1),
+        vec![
+            text(row.endpoint.to_sql()),
+            text(row.icao.to_sql()),
+            text(row.tokens.to_sql()),
+        ],
+        ChangeId(1),
+    )
+}
+
+/// Tests that duplicate change events are only emitted once when deduplication
+/// is enabled
+#[test]
+fn dedups_subscription_changes() {
+    let server = row([[1u8; 4]].into());
+    let updated = row([[2u8; 4]].into());
+
+    let events = [
+        change(ChangeType::Insert, &server),
+        change(ChangeType::Insert, &server),
+        change(ChangeType::Update, &server),
+        change(ChangeType::Update, &updated),
+        change(ChangeType::Delete, &updated),
+        change(ChangeType::Delete, &updated),
+    ];
+
+    let mut sub = ServerSubscription::new().dedup_changes(true);
+    let emitted: Vec<_> = events
+        .iter()
+        .cloned()
+        .filter_map(|event| sub.process(event).unwrap())
+        .collect();
+    assert_eq!(
+        emitted,
+        [
+            ServerEvent::Insert(row([[1u8; 4]].into())),
+            ServerEvent::Update(row([[2u8; 4]].into())),
+            ServerEvent::Delete(row([[2u8; 4]].into())),
+        ]
+    );
+    assert!(sub.servers().is_empty());
+
+    // Without deduplication every change is emitted
+    let mut sub = ServerSubscription::new();
+    assert_eq!(
+        events
+            .into_iter()
+            .filter_map(|event| sub.process(event).unwrap())
+            .count(),
+        6
+    );
+}
+
+fn server(last: u8, tokens: TokenSet) -> ServerRow {
+    ServerRow {
+        endpoint: Endpoint::new(std::net::Ipv4Addr::new(1, 2, 3, last).into(), 7777),
+        ..row(tokens)
+    }
+}
+
+/// Tests that changes received while a subscription is paused are applied in
+/// order when it is resumed
+#[test]
+fn pauses_and_resumes() {
+    let mut sub = ServerSubscription::new();
+    assert_eq!(
+        sub.process(change(ChangeType::Insert, &server(1, [[1u8; 4]].into())))
+            .unwrap(),
+        Some(ServerEvent::Insert(server(1, [[1u8; 4]].into())))
+    );
+
+    sub.pause(10);
+    assert!(sub.is_paused());
+
+    let changes = [
+        (ChangeType::Insert, server(2, [[2u8; 4]].into())),
+        (ChangeType::Update, server(1, [[3u8; 4]].into())),
+        (ChangeType::Insert, server(3, [[4u8; 4]].into())),
+        (ChangeType::Delete, server(2, [[2u8; 4]].into())),
+    ];
+    for (kind, row) in &changes {
+        assert!(sub.process(change(*kind, row)).unwrap().is_none());
+    }
+
+    // Nothing is applied until the subscription is resumed
+    assert_eq!(sub.servers().len(), 1);
+
+    let Resumed::Events(events) = sub.resume().unwrap() else {
+        panic!("the buffer should not have overflowed");
+    };
+    assert!(!sub.is_paused());
+    assert_eq!(
+        events,
+        [
+            ServerEvent::Insert(server(2, [[2u8; 4]].into())),
+            ServerEvent::Update(server(1, [[3u8; 4]].into())),
+            ServerEvent::Insert(server(3, [[4u8; 4]].into())),
+            ServerEvent::Delete(server(2, [[2u8; 4]].into())),
+        ]
+    );
+    assert_eq!(sub.servers().len(), 2);
+
+    // Later changes are processed immediately
+    assert_eq!(
+        sub.process(change(ChangeType::Delete, &server(3, [[4u8; 4]].into())))
+            .unwrap(),
+        Some(ServerEvent::Delete(server(3, [[4u8; 4]].into())))
+    );
+}
+
+/// Tests that a subscription that overflows its buffer while paused must be
+/// resubscribed, and that the new snapshot is diffed against the servers from
+/// before the pause
+#[test]
+fn resyncs_after_overflow() {
+    let mut sub = ServerSubscription::new();
+    for last in 1..=3 {
+        sub.process(change(
+            ChangeType::Insert,
+            &server(last, [[last; 4]].into()),
+        ))
+        .unwrap();
+    }
+
+    sub.pause(2);
+    for last in 4..=6 {
+        assert!(
+            sub.process(change(
+                ChangeType::Insert,
+                &server(last, [[last; 4]].into())
+            ))
+            .unwrap()
+            .is_none()
+        );
+    }
+    assert_eq!(sub.resume().unwrap(), Resumed::Resubscribe);
+    assert_eq!(sub.servers().len(), 3);
+
+    let events = sub.resync([
+        server(1, [[1u8; 4]].into()),
+        server(2, [[9u8; 4]].into()),
+        server(4, [[4u8; 4]].into()),
+    ]);
+    assert_eq!(
+        events,
+        [
+            ServerEvent::Update(server(2, [[9u8; 4]].into())),
+            ServerEvent::Insert(server(4, [[4u8; 4]].into())),
+            ServerEvent::Delete(server(3, [[3u8; 4]].into())),
+        ]
+    );
+    assert_eq!(
+        sub.servers().keys().cloned().collect::<Vec<_>>(),
+        [1, 2, 4].map(|last| server(last, TokenSet::default()).endpoint)
+    );
+}
+
+/// Tests that an ICAO column that isn't a valid code, or even valid UTF-8, is
+/// reported as an invalid ICAO
+#[test]
+fn rejects_invalid_icao_bytes() {
+    let server = row([[1u8; 4]].into());
+    let QueryEvent::Change(_, _, mut values, _) = change(ChangeType::Insert, &server) else {
+        unreachable!();
+    };
+
+    values[1] = SqliteValue::Blob(b"RRRR".as_slice().into());
+    assert_eq!(
+        ServerRow::from_sql(&values).unwrap(),
+        row([[1u8; 4]].into())
+    );
+
+    // Lowercase codes are normalized rather than rejected
+    values[1] = SqliteValue::Blob(b"rrrr".as_slice().into());
+    assert_eq!(
+        ServerRow::from_sql(&values).unwrap(),
+        row([[1u8; 4]].into())
+    );
+
+    for invalid in [&[0xff, b'G', b'L', b'L'][..], b"1GLL", b"EGL"] {
+        values[1] = SqliteValue::Blob(invalid.into());
+        let error = format!("{:#}", ServerRow::from_sql(&values).unwrap_err());
+        assert!(
+            error.starts_with("column 'icao' holds an invalid ICAO code"),
+            "{error}"
+        );
+    }
+
+    values[1] = SqliteValue::Integer(1);
+    assert!(ServerRow::from_sql(&values).is_err());
+}
+
+/// Tests that parsing endpoints gives the same results, and errors, as simply
+/// parsing the IP as an `IpAddr`, or an `Ipv6Addr` and scope id for scoped IPs
+#[test]
+fn parse_endpoint_unchanged() {
+    use corrosion::client::read::parse_endpoint;
+    use eyre::ContextCompat as _;
+
+    fn reference(addr: &str) -> eyre::Result<Endpoint> {
+        let (addr, port) = addr.rsplit_once(':').context("missing ':'")?;
+        let port = port.parse()?;
+        if let Some(ip) = addr.strip_prefix('|') {
+            if let Some((ip, scope_id)) = ip.split_once('%') {
+                return Ok(Endpoint::new(
+                    quilkin_types::AddressKind::scoped(ip.parse()?, scope_id.parse()?),
+                    port,
+                ));
+            }
+
+            let ip = ip.parse()?;
+            Ok(Endpoint::new(quilkin_types::AddressKind::Ip(ip), port))
+        } else {
+            Ok(Endpoint::new(
+                quilkin_types::AddressKind::Name(addr.to_owned()),
+                port,
+            ))
+        }
+    }
+
+    let mut endpoints = vec![
+        "|1.2.3.4:7777".to_owned(),
+        "|0.0.0.0:0".to_owned(),
+        "|255.255.255.255:65535".to_owned(),
+        "|::1:7777".to_owned(),
+        "|:::7777".to_owned(),
+        "|::ffff:1.2.3.4:7777".to_owned(),
+        "|fe80::1%2:7777".to_owned(),
+        "|[::1]:7777".to_owned(),
+        "|1.2.3:7777".to_owned(),
+        "|1.2.3.4.5:7777".to_owned(),
+        "|01.2.3.4:7777".to_owned(),
+        "|1:2:3:4:5:6:7:8:9:7777".to_owned(),
+        "|:7777".to_owned(),
+        "|1.2.3.4:".to_owned(),
+        "|1.2.3.4:+80".to_owned(),
+        "|1.2.3.4:65536".to_owned(),
+        "|1.2.3.4".to_owned(),
+        "|game.boop.com:7777".to_owned(),
+        "game.boop.com:7777".to_owned(),
+        "1.2.3.4:7777".to_owned(),
+        ":7777".to_owned(),
+        "no-port".to_owned(),
+        String::new(),
+    ];
+    for i in 0..1000u32 {
+        endpoints.push(format!(
+            "|{}:{}",
+            std::net::Ipv4Addr::from_bits(i.wrapping_mul(2654435761)),
+            i
+        ));
+        endpoints.push(format!(
+            "|{}:{}",
+            std::net::Ipv6Addr::from_bits(((i as u128) << 64) | i as u128),
+            i
+        ));
+        endpoints.push(format!("game-{i}.example.com:{i}"));
+    }
+
+    for ep in &endpoints {
+        match (parse_endpoint(ep), reference(ep)) {
+            (Ok(parsed), Ok(expected)) => assert_eq!(parsed, expected, "{ep}"),
+            (Err(parsed), Err(expected)) => {
+                assert_eq!(parsed.to_string(), expected.to_string(), "{ep}");
+            }
+            (parsed, expected) => panic!("{ep}: {parsed:?} != {expected:?}"),
+        }
+    }
+}
+
+fn encode_tokens(ts: &TokenSet) -> Vec<u8> {
+    match ts.to_sql() {
+        corro_api_types::SqliteParam::Text(text) => {
+            data_encoding::BASE64_NOPAD.decode(text.as_bytes()).unwrap()
+        }
+        corro_api_types::SqliteParam::Null => Vec::new(),
+        other => panic!("unexpected token set encoding {other:?}"),
+    }
+}
+
+fn decode_tokens(blob: &[u8]) -> eyre::Result<TokenSet> {
+    corrosion::client::read::deserialize_token_set(&data_encoding::BASE64_NOPAD.encode(blob))
+}
+
+fn round_trip(ts: &TokenSet) -> TokenSet {
+    decode_tokens(&encode_tokens(ts)).unwrap()
+}
+
+/// Tests that token sets of any size round trip through the encoding used for
+/// the `tokens` column, including ones too large for a single byte count
+#[test]
+fn token_sets_round_trip() {
+    use corrosion::client::write::MAX_SAME_LEN_TOKEN_LEN;
+
+    // Either side of the largest count that fit in the single byte count of
+    // the old format
+    for count in [0, 1, 2, 126, 127, 128, 1000] {
+        let same: TokenSet = (0..count as u32)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect();
+        assert_eq!(round_trip(&same), same, "{count} tokens of the same length");
+
+        let varied: TokenSet = (0..count as u32)
+            .map(|i| {
+                let mut tok = i.to_be_bytes().to_vec();
+                tok.resize(4 + i as usize % 3, 0xaa);
+                tok
+            })
+            .collect();
+        assert_eq!(
+            round_trip(&varied),
+            varied,
+            "{count} tokens of varied lengths"
+        );
+
+        // Tokens that all have the same length, but are too long for the
+        // length to be encoded in the first byte
+        let long: TokenSet = (0..count as u32)
+            .map(|i| {
+                let mut tok = i.to_be_bytes().to_vec();
+                tok.resize(MAX_SAME_LEN_TOKEN_LEN + 1, 0xbb);
+                tok
+            })
+            .collect();
+        assert_eq!(round_trip(&long), long, "{count} long tokens");
+    }
+
+    // Truncated sets are errors, not panics
+    let large: TokenSet = (0..1000u32)
+        .map(|i| i.to_le_bytes()[..2 + i as usize % 3].to_vec())
+        .collect();
+    let mut blob = encode_tokens(&large);
+    assert_eq!(
+        blob[0],
+        corrosion::client::write::VARINT_FORMAT,
+        "expected a varint count"
+    );
+    blob.truncate(blob.len() - 1);
+    assert!(decode_tokens(&blob).is_err());
+    assert!(decode_tokens(&blob[..2]).is_err());
+    assert!(decode_tokens(&[0]).is_err());
+    assert!(decode_tokens(&[0, 0x80]).is_err());
+    assert!(decode_tokens(&[2, 4, 1]).is_err());
+    assert!(decode_tokens(&[0x80, 2, 4, 1]).is_err());
+    assert!(decode_tokens(&[0x80, 2, 0x81]).is_err());
+}
+
+/// Tests that large sets, and long tokens, round trip through the varint
+/// encoding
+#[test]
+fn token_sets_round_trip_varint() {
+    for count in [0, 1, 200, 10_000] {
+        for len in [1, 2, 127, 128, 255, 256, 1000, 4096] {
+            // Tokens of the same length, and of varied lengths up to `len`
+            let same: TokenSet = (0..count as u32)
+                .map(|i| {
+                    let mut tok = i.to_le_bytes()[..len.min(4)].to_vec();
+                    tok.resize(len, 0xcc);
+                    tok
+                })
+                .collect();
+            assert_eq!(round_trip(&same), same, "{count} tokens of length {len}");
+
+            let varied: TokenSet = (0..count as u32)
+                .map(|i| {
+                    let mut tok = i.to_le_bytes().to_vec();
+                    tok.resize(4 + (i as usize * 7919) % len, 0xdd);
+                    tok
+                })
+                .collect();
+            assert_eq!(
+                round_trip(&varied),
+                varied,
+                "{count} tokens of up to {len} bytes"
+            );
+        }
+    }
+}
+
+/// Tests that blobs written before token lengths were varints are still read
+#[test]
+fn reads_old_token_set_formats() {
+    let expected: TokenSet = [vec![1, 2, 3, 4], vec![5]].into();
+    assert_eq!(decode_tokens(&[2, 1, 5, 4, 1, 2, 3, 4]).unwrap(), expected);
+
+    // A 0 followed by a varint count, with single byte lengths
+    let expected: TokenSet = (0..200u32)
+        .map(|i| i.to_le_bytes()[..1 + i as usize % 4].to_vec())
+        .collect();
+    let mut blob = vec![0, 0xc8, 0x01];
+    for tok in &expected.0 {
+        blob.push(tok.len() as u8);
+        blob.extend_from_slice(tok);
+    }
+    assert_eq!(decode_tokens(&blob).unwrap(), expected);
+}
+
+/// Tests that decoding into a reused set and buffer gives the same sets as
+/// [`corrosion::client::read::deserialize_token_set`], without leaving tokens
+/// from previous decodes behind
+#[test]
+fn decodes_tokens_into_reused_set() {
+    use corrosion::client::read::{decode_tokens_into, deserialize_token_set};
+
+    let varied: TokenSet = (0..300u32)
+        .map(|i| i.to_le_bytes()[..1 + i as usize % 4].to_vec())
+        .collect();
+    let mut old = vec![0, 0xc8, 0x01];
+    for tok in varied.0.iter().take(200) {
+        old.push(tok.len() as u8);
+        old.extend_from_slice(tok);
+    }
+
+    let blobs = [
+        // Single token
+        encode_tokens(&[vec![9; 16]].into()),
+        // Tokens of the same length
+        encode_tokens(&(0..50u32).map(|i| i.to_be_bytes().to_vec()).collect()),
+        // Varint count and lengths
+        encode_tokens(&varied),
+        // Single byte lengths
+        vec![2, 1, 5, 4, 1, 2, 3, 4],
+        old,
+        // Empty
+        Vec::new(),
+    ];
+
+    let mut scratch = Vec::new();
+    let mut out: TokenSet = (0..500u32).map(|i| i.to_ne_bytes().to_vec()).collect();
+
+    for blob in blobs.iter().chain(blobs.iter().rev()) {
+        let encoded = data_encoding::BASE64_NOPAD.encode(blob);
+        decode_tokens_into(&encoded, &mut scratch, &mut out).unwrap();
+        assert_eq!(out, deserialize_token_set(&encoded).unwrap());
+    }
+
+    assert!(decode_tokens_into("!", &mut scratch, &mut out).is_err());
+}
+
+/// Tests that token sets over the limits are errors, rather than being
+/// encoded into a blob that doesn't decode to the same set, and that blobs
+/// over the limits aren't decoded
+#[test]
+fn rejects_unencodable_token_sets() {
+    use corrosion::client::write::{
+        MAX_ENCODED_TOKENS, MAX_TOKEN_LEN, TokenEncodeError, try_token_set_to_sql,
+        try_token_set_to_sql_blob,
+    };
+
+    fn varint(blob: &mut Vec<u8>, mut value: usize) {
+        while value >= 0x80 {
+            blob.push(0x80 | (value & 0x7f) as u8);
+            value >>= 7;
+        }
+        blob.push(value as u8);
+    }
+
+    // Tokens longer than a byte can express are encoded
+    let ts: TokenSet = [vec![1; 4], vec![2; 256]].into();
+    assert_eq!(round_trip(&ts), ts);
+
+    // Tokens up to the limit are encoded, whatever the format
+    let ts: TokenSet = [vec![1; 4], vec![2; MAX_TOKEN_LEN]].into();
+    assert_eq!(round_trip(&ts), ts);
+    let ts: TokenSet = [vec![3; MAX_TOKEN_LEN]].into();
+    assert_eq!(round_trip(&ts), ts);
+
+    // Tokens over the limit aren't, including the only token in a set, or
+    // tokens that all have the same length
+    let ts: TokenSet = [vec![1; 4], vec![2; MAX_TOKEN_LEN + 1]].into();
+    let too_long = TokenEncodeError::TokenTooLong {
+        index: 1,
+        len: MAX_TOKEN_LEN + 1,
+    };
+    assert_eq!(try_token_set_to_sql(&ts).unwrap_err(), too_long);
+    assert_eq!(try_token_set_to_sql_blob(&ts).unwrap_err(), too_long);
+
+    let ts: TokenSet = [vec![3; MAX_TOKEN_LEN + 1]].into();
+    assert_eq!(
+        try_token_set_to_sql(&ts).unwrap_err(),
+        TokenEncodeError::TokenTooLong {
+            index: 0,
+            len: MAX_TOKEN_LEN + 1,
+        }
+    );
+
+    let ts: TokenSet = (0..2u8).map(|i| vec![i; MAX_TOKEN_LEN + 1]).collect();
+    assert!(matches!(
+        try_token_set_to_sql(&ts).unwrap_err(),
+        TokenEncodeError::TokenTooLong { index: 0, .. }
+    ));
+
+    // Sets up to the limit are encoded, sets over it aren't
+    let mut ts: TokenSet = (0..MAX_ENCODED_TOKENS as u32)
+        .map(|i| i.to_le_bytes().to_vec())
+        .collect();
+    assert_eq!(round_trip(&ts), ts);
+
+    ts.0.insert(vec![0xff; 5]);
+    let too_many = TokenEncodeError::TooManyTokens {
+        count: MAX_ENCODED_TOKENS + 1,
+    };
+    assert_eq!(try_token_set_to_sql(&ts).unwrap_err(), too_many);
+    assert_eq!(try_token_set_to_sql_blob(&ts).unwrap_err(), too_many);
+
+    // Blobs over the limits aren't decoded, even if they contain all of the
+    // data they claim to
+    let mut blob = vec![corrosion::client::write::VARINT_FORMAT];
+    varint(&mut blob, 1);
+    varint(&mut blob, MAX_TOKEN_LEN + 1);
+    blob.resize(blob.len() + MAX_TOKEN_LEN + 1, 0xee);
+    assert!(decode_tokens(&blob).is_err());
+
+    let mut blob = vec![corrosion::client::write::VARINT_FORMAT];
+    varint(&mut blob, MAX_ENCODED_TOKENS + 1);
+    for i in 0..=MAX_ENCODED_TOKENS as u32 {
+        varint(&mut blob, 4);
+        blob.extend_from_slice(&i.to_le_bytes());
+    }
+    assert!(decode_tokens(&blob).is_err());
+
+    let mut blob = vec![0];
+    varint(&mut blob, MAX_ENCODED_TOKENS + 1);
+    assert!(decode_tokens(&blob).is_err());
+}
+
+/// Tests that endpoints written by humans are parsed, and that the errors
+/// for ones that can't be say which part is invalid
+#[test]
+fn parses_lenient_endpoints() {
+    use corrosion::client::read::parse_endpoint_lenient as parse;
+    use quilkin_types::AddressKind;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let ip = |ip: std::net::IpAddr, port| Endpoint::new(AddressKind::Ip(ip), port);
+
+    assert_eq!(
+        parse("[2001:db8::1]:7777").unwrap(),
+        ip(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(), 7777)
+    );
+    assert_eq!(
+        parse("[::ffff:1.2.3.4]:80").unwrap(),
+        ip(Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped().into(), 80)
+    );
+    assert_eq!(
+        parse("1.2.3.4:7777").unwrap(),
+        ip(Ipv4Addr::new(1, 2, 3, 4).into(), 7777)
+    );
+    assert_eq!(
+        parse("game.boop.com:7777").unwrap(),
+        Endpoint::new(AddressKind::Name("game.boop.com".into()), 7777)
+    );
+
+    // The form written to the database is still accepted
+    for addr in ["|1.2.3.4:7777", "|::1:7777", "game.boop.com:7777"] {
+        assert_eq!(
+            parse(addr).unwrap(),
+            corrosion::client::read::parse_endpoint(addr).unwrap()
+        );
+    }
+
+    let error = |addr: &str| format!("{:#}", parse(addr).unwrap_err());
+
+    // Splitting an unbracketed IPv6 address on the last ':' would otherwise
+    // parse as a hostname with the last segment as the port
+    assert_eq!(
+        error("2001:db8::1:7777"),
+        "the IPv6 address in endpoint '2001:db8::1:7777' must be enclosed in brackets, eg. '[2001:db8::1]:7777'"
+    );
+    assert!(error("::1").contains("must be enclosed in brackets"));
+
+    assert!(
+        error("[2001:db8::1]")
+            .starts_with("missing ']:<port>' after the IPv6 address in endpoint '[2001:db8::1]'")
+    );
+    assert!(error("[2001:db8::zz]:7777").starts_with("invalid IPv6 address '2001:db8::zz'"));
+    assert!(error("[1.2.3.4]:7777").starts_with("invalid IPv6 address '1.2.3.4'"));
+    assert!(error("1.2.3.4:65536").starts_with("invalid port '65536' in endpoint '1.2.3.4:65536'"));
+    assert!(error("[::1]:").starts_with("invalid port ''"));
+    assert!(error("game.boop.com").starts_with("missing ':<port>' in endpoint 'game.boop.com'"));
+    assert!(error(":7777").starts_with("missing host in endpoint ':7777'"));
+    assert!(error("|1.2.3.4.5:7777").starts_with("invalid endpoint '|1.2.3.4.5:7777'"));
+}
@@ -58,7 +58,7 @@ async fn server_subscriptions() {
         let mut s = write::Server::for_peer(peer, &mut states);
 
         for (ep, srv) in &server_set {
-            s.upsert(ep, srv.icao, &srv.tokens);
+            s.upsert(ep, srv.icao, &srv.tokens).unwrap();
         }
     }
 
@@ -113,7 +113,7 @@ async fn server_subscriptions() {
             },
         );
         let srv = server_set.get(&key).unwrap();
-        s.upsert(&key, srv.icao, &srv.tokens);
+        s.upsert(&key, srv.icao, &srv.tokens).unwrap();
     }
 
     pool.transaction(states.iter()).await;
@@ -151,7 +151,8 @@ async fn server_subscriptions() {
         };
         let srv = server_set.get_mut(&key).unwrap();
         srv.icao = IcaoCode::new_testing([b'Y'; 4]);
-        s.update(UpdateBuilder::new(&key).update_icao(srv.icao));
+        s.update(UpdateBuilder::new(&key).update_icao(srv.icao))
+            .unwrap();
     }
 
     pool.transaction(states.iter()).await;
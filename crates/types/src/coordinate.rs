@@ -0,0 +1,177 @@
+use std::fmt;
+
+/// The scale applied to degrees for the fixed-point representation
+const SCALE: f64 = 10_000_000.0;
+const MAX_LAT_E7: i32 = 90 * 10_000_000;
+const MAX_LON_E7: i32 = 180 * 10_000_000;
+
+/// A latitude and longitude, stored as fixed-point degrees scaled by 10^7
+///
+/// This is the representation used in the handshake. Converting from degrees
+/// rounds to the nearest 10^-7 of a degree, so round-tripping degrees through
+/// a coordinate is accurate to within 0.5 * 10^-7 degrees, or roughly 5.6mm
+/// at the equator
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Coordinate {
+    lat_e7: i32,
+    lon_e7: i32,
+}
+
+impl Coordinate {
+    /// Creates a coordinate from fixed-point degrees scaled by 10^7
+    pub fn from_e7(lat_e7: i32, lon_e7: i32) -> Result<Self, CoordinateError> {
+        if !(-MAX_LAT_E7..=MAX_LAT_E7).contains(&lat_e7) {
+            return Err(CoordinateError::LatitudeOutOfRange {
+                lat: lat_e7 as f64 / SCALE,
+            });
+        }
+
+        if !(-MAX_LON_E7..=MAX_LON_E7).contains(&lon_e7) {
+            return Err(CoordinateError::LongitudeOutOfRange {
+                lon: lon_e7 as f64 / SCALE,
+            });
+        }
+
+        Ok(Self { lat_e7, lon_e7 })
+    }
+
+    /// Creates a coordinate from degrees, latitude must be within ±90 and
+    /// longitude within ±180
+    pub fn from_degrees(lat: f64, lon: f64) -> Result<Self, CoordinateError> {
+        if !lat.is_finite() || !lon.is_finite() {
+            return Err(CoordinateError::NotFinite);
+        }
+
+        if !(-90.0..=90.0).contains(&lat) {
+            return Err(CoordinateError::LatitudeOutOfRange { lat });
+        }
+
+        if !(-180.0..=180.0).contains(&lon) {
+            return Err(CoordinateError::LongitudeOutOfRange { lon });
+        }
+
+        Ok(Self {
+            lat_e7: (lat * SCALE).round() as i32,
+            lon_e7: (lon * SCALE).round() as i32,
+        })
+    }
+
+    /// Returns the latitude and longitude in degrees
+    #[inline]
+    pub fn to_degrees(self) -> (f64, f64) {
+        (self.lat_e7 as f64 / SCALE, self.lon_e7 as f64 / SCALE)
+    }
+
+    #[inline]
+    pub fn lat_e7(self) -> i32 {
+        self.lat_e7
+    }
+
+    #[inline]
+    pub fn lon_e7(self) -> i32 {
+        self.lon_e7
+    }
+
+    /// Writes the coordinate as its little endian latitude followed by its
+    /// little endian longitude
+    #[inline]
+    pub fn write(self) -> [u8; 8] {
+        let mut buf = [0u8; 8];
+        buf[..4].copy_from_slice(&self.lat_e7.to_le_bytes());
+        buf[4..].copy_from_slice(&self.lon_e7.to_le_bytes());
+        buf
+    }
+
+    /// Reads a coordinate written by [`Self::write`]
+    #[inline]
+    pub fn read(buf: [u8; 8]) -> Result<Self, CoordinateError> {
+        let lat_e7 = i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let lon_e7 = i32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        Self::from_e7(lat_e7, lon_e7)
+    }
+}
+
+impl fmt::Display for Coordinate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (lat, lon) = self.to_degrees();
+        write!(f, "{lat:.7},{lon:.7}")
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CoordinateError {
+    NotFinite,
+    LatitudeOutOfRange { lat: f64 },
+    LongitudeOutOfRange { lon: f64 },
+}
+
+impl fmt::Display for CoordinateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFinite => f.write_str("latitude and longitude must be finite"),
+            Self::LatitudeOutOfRange { lat } => {
+                write!(f, "latitude {lat} is not within ±90 degrees")
+            }
+            Self::LongitudeOutOfRange { lon } => {
+                write!(f, "longitude {lon} is not within ±180 degrees")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CoordinateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The maximum error when round tripping degrees
+    const PRECISION: f64 = 0.5 / SCALE;
+
+    #[test]
+    fn round_trips_degrees() {
+        for (lat, lon) in [
+            (0.0, 0.0),
+            (51.4700223, -0.4542955),
+            (-33.9399228, 151.1752764),
+            (90.0, 180.0),
+            (-90.0, -180.0),
+            (12.345678949, -98.765432151),
+        ] {
+            let coord = Coordinate::from_degrees(lat, lon).unwrap();
+            let (rlat, rlon) = coord.to_degrees();
+            assert!((rlat - lat).abs() <= PRECISION, "{lat} -> {rlat}");
+            assert!((rlon - lon).abs() <= PRECISION, "{lon} -> {rlon}");
+
+            assert_eq!(Coordinate::read(coord.write()).unwrap(), coord);
+        }
+    }
+
+    #[test]
+    fn validates_range() {
+        assert!(matches!(
+            Coordinate::from_degrees(90.1, 0.0),
+            Err(CoordinateError::LatitudeOutOfRange { .. })
+        ));
+        assert!(matches!(
+            Coordinate::from_degrees(0.0, -180.1),
+            Err(CoordinateError::LongitudeOutOfRange { .. })
+        ));
+        assert_eq!(
+            Coordinate::from_degrees(f64::NAN, 0.0),
+            Err(CoordinateError::NotFinite)
+        );
+        assert!(matches!(
+            Coordinate::from_e7(MAX_LAT_E7 + 1, 0),
+            Err(CoordinateError::LatitudeOutOfRange { .. })
+        ));
+        assert!(Coordinate::read(Coordinate::from_e7(0, MAX_LON_E7).unwrap().write()).is_ok());
+
+        let mut invalid = [0u8; 8];
+        invalid[4..].copy_from_slice(&i32::MIN.to_le_bytes());
+        assert!(matches!(
+            Coordinate::read(invalid),
+            Err(CoordinateError::LongitudeOutOfRange { .. })
+        ));
+    }
+}
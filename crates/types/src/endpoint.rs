@@ -1,6 +1,6 @@
 use std::{
     fmt,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
 /// The kind of address, such as Domain Name or IP address. **Note** that
@@ -13,6 +13,40 @@ use std::{
 pub enum AddressKind {
     Name(String),
     Ip(IpAddr),
+    /// An IPv6 address with the non-zero scope id of the interface it's
+    /// reachable on, eg. `fe80::1%2` for a link-local address
+    ///
+    /// Interface names such as `fe80::1%eth0` are only meaningful on the host
+    /// they're from, so they must be resolved to their index there, eg. with
+    /// `if_nametoindex`, and are rejected when parsing
+    ScopedIp(Ipv6Addr, u32),
+}
+
+impl AddressKind {
+    /// An IPv6 address with a scope id, a scope id of `0` is no scope
+    #[inline]
+    pub fn scoped(ip: Ipv6Addr, scope_id: u32) -> Self {
+        if scope_id == 0 {
+            Self::Ip(ip.into())
+        } else {
+            Self::ScopedIp(ip, scope_id)
+        }
+    }
+
+    /// Parses an IPv6 address with a scope id, eg. `fe80::1%2`, or `None` if
+    /// the string isn't an IPv6 address followed by a `%`
+    fn parse_scoped(s: &str) -> Option<Result<Self, AddressParseError>> {
+        let (ip, scope_id) = s.split_once('%')?;
+        let ip = ip.parse().ok()?;
+        Some(
+            scope_id
+                .parse()
+                .map(|scope_id| Self::scoped(ip, scope_id))
+                .map_err(|_| AddressParseError::InvalidScopeId {
+                    address: s.to_owned(),
+                }),
+        )
+    }
 }
 
 impl From<IpAddr> for AddressKind {
@@ -46,7 +80,7 @@ impl<'s> From<&'s str> for AddressKind {
 }
 
 impl std::str::FromStr for AddressKind {
-    type Err = std::convert::Infallible;
+    type Err = AddressParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         // check for wrapping "[..]" in an ipv6 host
@@ -56,6 +90,10 @@ impl std::str::FromStr for AddressKind {
             host = host[1..len - 1].to_string();
         }
 
+        if let Some(scoped) = Self::parse_scoped(&host) {
+            return scoped;
+        }
+
         Ok(host
             .parse()
             .map_or_else(|_err| Self::Name(s.to_owned()), Self::Ip))
@@ -67,10 +105,33 @@ impl fmt::Display for AddressKind {
         match self {
             Self::Name(name) => name.fmt(f),
             Self::Ip(ip) => ip.fmt(f),
+            Self::ScopedIp(ip, scope_id) => write!(f, "{ip}%{scope_id}"),
+        }
+    }
+}
+
+/// An error parsing an [`AddressKind`], any string that isn't an IP address
+/// is otherwise a name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressParseError {
+    /// The scope of an IPv6 address isn't a numeric interface index, see
+    /// [`AddressKind::ScopedIp`]
+    InvalidScopeId { address: String },
+}
+
+impl fmt::Display for AddressParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidScopeId { address } => write!(
+                f,
+                "the scope id of '{address}' is not a numeric interface index"
+            ),
         }
     }
 }
 
+impl std::error::Error for AddressParseError {}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, PartialOrd, Eq, Ord)]
 pub struct Endpoint {
     #[serde(rename = "a")]
@@ -84,6 +145,51 @@ impl Endpoint {
     pub fn new(address: AddressKind, port: u16) -> Self {
         Self { address, port }
     }
+
+    /// The same endpoint with a different port
+    #[inline]
+    pub const fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// The same endpoint with a different address
+    ///
+    /// Unlike [`Self::with_port`] this can't be `const`, as the previous
+    /// address may be a name that has to be dropped
+    #[inline]
+    pub fn with_address(mut self, address: AddressKind) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Creates an IP endpoint from a socket address
+    ///
+    /// IPv4 addresses are mapped to IPv6, the same as the canonical form of
+    /// a peer's address, the scope id of IPv6 addresses is kept, but their
+    /// flow info is discarded
+    #[inline]
+    pub fn from_socket_addr(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(v4) => Self::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+            SocketAddr::V6(v6) => {
+                Self::new(AddressKind::scoped(*v6.ip(), v6.scope_id()), v6.port())
+            }
+        }
+    }
+
+    /// The socket address of the endpoint, or `None` if the address is a
+    /// name rather than an IP
+    #[inline]
+    pub fn to_socket_addr(&self) -> Option<SocketAddr> {
+        match &self.address {
+            AddressKind::Ip(ip) => Some(SocketAddr::new(*ip, self.port)),
+            AddressKind::ScopedIp(ip, scope_id) => {
+                Some(std::net::SocketAddrV6::new(*ip, self.port, 0, *scope_id).into())
+            }
+            AddressKind::Name(_) => None,
+        }
+    }
 }
 
 impl fmt::Display for Endpoint {
@@ -91,3 +197,302 @@ impl fmt::Display for Endpoint {
         write!(f, "{}:{}", self.address, self.port)
     }
 }
+
+/// Tags identifying the kind of address in the binary encoding of an [`Endpoint`]
+const TAG_V4: u8 = 0;
+const TAG_V6: u8 = 1;
+const TAG_NAME: u8 = 2;
+const TAG_SCOPED_V6: u8 = 3;
+
+impl Endpoint {
+    /// The maximum length of an encoded endpoint, a name with the maximum
+    /// length of 255 bytes
+    pub const MAX_ENCODED_LEN: usize = 1 + 1 + u8::MAX as usize + 2;
+
+    /// The length of the binary encoding of this endpoint
+    #[inline]
+    pub fn encoded_len(&self) -> usize {
+        let address = match &self.address {
+            AddressKind::Ip(IpAddr::V4(_)) => 4,
+            AddressKind::Ip(IpAddr::V6(_)) => 16,
+            AddressKind::ScopedIp(..) => 16 + 4,
+            AddressKind::Name(name) => 1 + name.len(),
+        };
+
+        1 + address + 2
+    }
+
+    /// Appends the binary encoding of this endpoint to `buf`
+    ///
+    /// The encoding is a one byte tag for the kind of address, followed by
+    /// either the 4 or 16 bytes of the IP, the 16 bytes of a scoped IP and its
+    /// little endian scope id, or a name prefixed by its one byte length,
+    /// followed by the little endian port
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<(), EndpointCodecError> {
+        match &self.address {
+            AddressKind::Ip(IpAddr::V4(ip)) => {
+                buf.push(TAG_V4);
+                buf.extend_from_slice(&ip.octets());
+            }
+            AddressKind::Ip(IpAddr::V6(ip)) => {
+                buf.push(TAG_V6);
+                buf.extend_from_slice(&ip.octets());
+            }
+            AddressKind::ScopedIp(ip, scope_id) => {
+                buf.push(TAG_SCOPED_V6);
+                buf.extend_from_slice(&ip.octets());
+                buf.extend_from_slice(&scope_id.to_le_bytes());
+            }
+            AddressKind::Name(name) => {
+                let len = u8::try_from(name.len())
+                    .map_err(|_| EndpointCodecError::NameTooLong { len: name.len() })?;
+                buf.push(TAG_NAME);
+                buf.push(len);
+                buf.extend_from_slice(name.as_bytes());
+            }
+        }
+
+        buf.extend_from_slice(&self.port.to_le_bytes());
+        Ok(())
+    }
+
+    /// Decodes an endpoint written by [`Self::encode`] from the start of `buf`,
+    /// returning it and the number of bytes that were read
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize), EndpointCodecError> {
+        let take = |offset: usize, len: usize| {
+            buf.get(offset..offset + len)
+                .ok_or(EndpointCodecError::InsufficientLength {
+                    length: buf.len(),
+                    expected: offset + len,
+                })
+        };
+
+        let (address, offset) = match take(0, 1)?[0] {
+            TAG_V4 => {
+                let octets: [u8; 4] = take(1, 4)?.try_into().unwrap();
+                (AddressKind::Ip(Ipv4Addr::from(octets).into()), 5)
+            }
+            TAG_V6 => {
+                let octets: [u8; 16] = take(1, 16)?.try_into().unwrap();
+                (AddressKind::Ip(Ipv6Addr::from(octets).into()), 17)
+            }
+            TAG_SCOPED_V6 => {
+                let octets: [u8; 16] = take(1, 16)?.try_into().unwrap();
+                let scope_id = u32::from_le_bytes(take(17, 4)?.try_into().unwrap());
+                (AddressKind::scoped(Ipv6Addr::from(octets), scope_id), 21)
+            }
+            TAG_NAME => {
+                let len = take(1, 1)?[0] as usize;
+                let name = std::str::from_utf8(take(2, len)?)
+                    .map_err(|_| EndpointCodecError::InvalidName)?;
+                (AddressKind::Name(name.to_owned()), 2 + len)
+            }
+            tag => return Err(EndpointCodecError::UnknownTag { tag }),
+        };
+
+        let port = take(offset, 2)?;
+        let port = u16::from_le_bytes([port[0], port[1]]);
+
+        Ok((Self { address, port }, offset + 2))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EndpointCodecError {
+    /// The name is longer than the 255 bytes that can be encoded
+    NameTooLong {
+        len: usize,
+    },
+    InsufficientLength {
+        length: usize,
+        expected: usize,
+    },
+    UnknownTag {
+        tag: u8,
+    },
+    /// The encoded name is not valid UTF-8
+    InvalidName,
+}
+
+impl fmt::Display for EndpointCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NameTooLong { len } => {
+                write!(f, "name is {len} bytes, longer than the maximum of 255")
+            }
+            Self::InsufficientLength { length, expected } => {
+                write!(
+                    f,
+                    "expected length of {expected} but only received {length}"
+                )
+            }
+            Self::UnknownTag { tag } => write!(f, "unknown address tag {tag}"),
+            Self::InvalidName => f.write_str("name is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for EndpointCodecError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_encoding() {
+        for endpoint in [
+            Endpoint::new(Ipv4Addr::new(1, 2, 3, 4).into(), 7777),
+            Endpoint::new(Ipv6Addr::from_bits(0xf0ccac1a).into(), 2004),
+            Endpoint::new("game.boop.com".into(), u16::MAX),
+            Endpoint::new("".into(), 0),
+            Endpoint::new(AddressKind::ScopedIp("fe80::1".parse().unwrap(), 2), 7777),
+        ] {
+            let mut buf = vec![0xff];
+            endpoint.encode(&mut buf).unwrap();
+            assert_eq!(buf.len() - 1, endpoint.encoded_len());
+
+            let (decoded, read) = Endpoint::decode(&buf[1..]).unwrap();
+            assert_eq!(decoded, endpoint);
+            assert_eq!(read, endpoint.encoded_len());
+        }
+
+        let v4 = Endpoint::new(Ipv4Addr::new(1, 2, 3, 4).into(), 7777);
+        assert!(v4.encoded_len() <= 7);
+    }
+
+    #[test]
+    fn converts_socket_addrs() {
+        use std::net::{SocketAddrV4, SocketAddrV6};
+
+        let v4 = Ipv4Addr::new(1, 2, 3, 4);
+        let endpoint = Endpoint::from_socket_addr(SocketAddrV4::new(v4, 7777).into());
+        assert_eq!(endpoint, Endpoint::new(v4.to_ipv6_mapped().into(), 7777));
+        assert_eq!(
+            endpoint.to_socket_addr(),
+            Some((v4.to_ipv6_mapped(), 7777).into())
+        );
+
+        let v6 = Ipv6Addr::from_bits(0xf0ccac1a);
+        let endpoint = Endpoint::from_socket_addr(SocketAddrV6::new(v6, 2004, 1, 0).into());
+        assert_eq!(endpoint, Endpoint::new(v6.into(), 2004));
+        assert_eq!(endpoint.to_socket_addr(), Some((v6, 2004).into()));
+
+        // The scope id is kept, so link-local peers on different interfaces
+        // are different endpoints, but the flow info isn't
+        let scoped = SocketAddrV6::new(v6, 2004, 1, 2);
+        let endpoint = Endpoint::from_socket_addr(scoped.into());
+        assert_eq!(endpoint, Endpoint::new(AddressKind::ScopedIp(v6, 2), 2004));
+        assert_eq!(
+            endpoint.to_socket_addr(),
+            Some(SocketAddrV6::new(v6, 2004, 0, 2).into())
+        );
+        assert_ne!(
+            endpoint,
+            Endpoint::from_socket_addr(SocketAddrV6::new(v6, 2004, 0, 3).into())
+        );
+
+        // An IPv4 endpoint that wasn't created from a socket address isn't mapped
+        assert_eq!(
+            Endpoint::new(v4.into(), 80).to_socket_addr(),
+            Some((v4, 80).into())
+        );
+
+        assert_eq!(
+            Endpoint::new("game.boop.com".into(), 7777).to_socket_addr(),
+            None
+        );
+    }
+
+    #[test]
+    fn scoped_addresses() {
+        let ip: Ipv6Addr = "fe80::1".parse().unwrap();
+
+        assert_eq!(
+            "fe80::1%2".parse::<AddressKind>().unwrap(),
+            AddressKind::ScopedIp(ip, 2)
+        );
+        assert_eq!(
+            "[fe80::1%2]".parse::<AddressKind>().unwrap(),
+            AddressKind::ScopedIp(ip, 2)
+        );
+        assert_eq!(AddressKind::ScopedIp(ip, 2).to_string(), "fe80::1%2");
+
+        // A zero scope is no scope, and interface names are rejected rather
+        // than being mistaken for hostnames
+        assert_eq!(
+            "fe80::1%0".parse::<AddressKind>().unwrap(),
+            AddressKind::Ip(ip.into())
+        );
+        assert_eq!(
+            "fe80::1%eth0".parse::<AddressKind>().unwrap_err(),
+            AddressParseError::InvalidScopeId {
+                address: "fe80::1%eth0".into()
+            }
+        );
+        assert!("[fe80::1%eth0]".parse::<AddressKind>().is_err());
+
+        let scoped = Endpoint::new(AddressKind::ScopedIp(ip, 2), 7777);
+        assert_ne!(scoped, Endpoint::new(AddressKind::ScopedIp(ip, 3), 7777));
+        assert_ne!(scoped, Endpoint::new(ip.into(), 7777));
+        assert_eq!(
+            scoped.to_socket_addr(),
+            Some(std::net::SocketAddrV6::new(ip, 7777, 0, 2).into())
+        );
+    }
+
+    #[test]
+    fn builds_endpoints() {
+        const BASE: Endpoint = Endpoint {
+            address: AddressKind::Ip(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))),
+            port: 7777,
+        }
+        .with_port(7778);
+        assert_eq!(BASE, Endpoint::new(Ipv4Addr::new(1, 2, 3, 4).into(), 7778));
+
+        let named = Endpoint::new("game.boop.com".into(), 7777);
+        let ports: Vec<_> = (0..3).map(|i| named.clone().with_port(8000 + i)).collect();
+        for (i, endpoint) in ports.iter().enumerate() {
+            assert_eq!(endpoint.address, named.address);
+            assert_eq!(endpoint.port, 8000 + i as u16);
+        }
+
+        assert_eq!(
+            named.with_address(Ipv6Addr::LOCALHOST.into()),
+            Endpoint::new(Ipv6Addr::LOCALHOST.into(), 7777)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_encoding() {
+        let long = Endpoint::new("a".repeat(256).into(), 1);
+        assert_eq!(
+            long.encode(&mut Vec::new()),
+            Err(EndpointCodecError::NameTooLong { len: 256 })
+        );
+
+        let max = Endpoint::new("a".repeat(255).into(), 1);
+        assert_eq!(max.encoded_len(), Endpoint::MAX_ENCODED_LEN);
+
+        let mut buf = Vec::new();
+        Endpoint::new(Ipv6Addr::LOCALHOST.into(), 1)
+            .encode(&mut buf)
+            .unwrap();
+        assert_eq!(
+            Endpoint::decode(&buf[..buf.len() - 1]),
+            Err(EndpointCodecError::InsufficientLength {
+                length: 18,
+                expected: 19
+            })
+        );
+
+        assert_eq!(
+            Endpoint::decode(&[9, 0, 0]),
+            Err(EndpointCodecError::UnknownTag { tag: 9 })
+        );
+        assert_eq!(
+            Endpoint::decode(&[TAG_NAME, 2, 0xc3, 0x28, 0, 0]),
+            Err(EndpointCodecError::InvalidName)
+        );
+        assert!(Endpoint::decode(&[]).is_err());
+    }
+}
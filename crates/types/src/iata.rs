@@ -0,0 +1,319 @@
+use crate::IcaoCode;
+use std::fmt;
+
+/// A three letter IATA airport code
+///
+/// Some data sources only provide IATA codes, this type allows them to be
+/// validated and then converted to an [`IcaoCode`] if the airport is known
+#[derive(Copy, Clone, Hash, Eq, PartialEq)]
+pub struct IataCode([u8; 3]);
+
+const VALID_RANGE: std::ops::RangeInclusive<u8> = b'A'..=b'Z';
+
+/// Creates an ICAO code for [`KNOWN`], each of which is checked to be valid
+/// when it is compiled
+const fn icao(code: [u8; 4]) -> IcaoCode {
+    IcaoCode::new_const(code)
+}
+
+/// Known IATA -> ICAO mappings, sorted by IATA code so they can be binary searched
+const KNOWN: &[([u8; 3], IcaoCode)] = &[
+    (*b"AMS", icao(*b"EHAM")),
+    (*b"ARN", icao(*b"ESSA")),
+    (*b"ATL", icao(*b"KATL")),
+    (*b"BOG", icao(*b"SKBO")),
+    (*b"BOM", icao(*b"VABB")),
+    (*b"CDG", icao(*b"LFPG")),
+    (*b"DFW", icao(*b"KDFW")),
+    (*b"DUB", icao(*b"EIDW")),
+    (*b"DXB", icao(*b"OMDB")),
+    (*b"EZE", icao(*b"SAEZ")),
+    (*b"FRA", icao(*b"EDDF")),
+    (*b"GRU", icao(*b"SBGR")),
+    (*b"HKG", icao(*b"VHHH")),
+    (*b"HND", icao(*b"RJTT")),
+    (*b"IAD", icao(*b"KIAD")),
+    (*b"ICN", icao(*b"RKSI")),
+    (*b"JFK", icao(*b"KJFK")),
+    (*b"JNB", icao(*b"FAOR")),
+    (*b"LAX", icao(*b"KLAX")),
+    (*b"LHR", icao(*b"EGLL")),
+    (*b"MAD", icao(*b"LEMD")),
+    (*b"MEL", icao(*b"YMML")),
+    (*b"MIA", icao(*b"KMIA")),
+    (*b"MXP", icao(*b"LIMC")),
+    (*b"NRT", icao(*b"RJAA")),
+    (*b"ORD", icao(*b"KORD")),
+    (*b"PEK", icao(*b"ZBAA")),
+    (*b"SCL", icao(*b"SCEL")),
+    (*b"SEA", icao(*b"KSEA")),
+    (*b"SFO", icao(*b"KSFO")),
+    (*b"SIN", icao(*b"WSSS")),
+    (*b"SYD", icao(*b"YSSY")),
+    (*b"WAW", icao(*b"EPWA")),
+    (*b"YYZ", icao(*b"CYYZ")),
+    (*b"ZRH", icao(*b"LSZH")),
+];
+
+impl IataCode {
+    /// Creates a new Iata from raw bytes
+    ///
+    /// This is meant for testing, and asserts if any of the characters are not valid
+    pub fn new_testing(code: [u8; 3]) -> Self {
+        for c in code {
+            assert!(VALID_RANGE.contains(&c));
+        }
+
+        Self(code)
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<str> for IataCode {
+    fn as_ref(&self) -> &str {
+        // SAFETY: We don't allow this to be constructed with an invalid utf-8 string
+        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    }
+}
+
+#[derive(Debug)]
+pub enum IataError {
+    InvalidLength {
+        len: usize,
+    },
+    InvalidCharacter {
+        character: char,
+        index: usize,
+    },
+    /// The code is valid, but there is no known ICAO code for it
+    Unmapped {
+        code: IataCode,
+    },
+}
+
+impl fmt::Display for IataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength { len } => {
+                write!(f, "expected a length of 3 but got a length of {len}")
+            }
+            Self::InvalidCharacter { character, index } => write!(
+                f,
+                "invalid character '{character}' was found at index {index}"
+            ),
+            Self::Unmapped { code } => {
+                write!(f, "IATA code '{code}' does not have a known ICAO code")
+            }
+        }
+    }
+}
+
+impl std::error::Error for IataError {
+    fn description(&self) -> &str {
+        match self {
+            Self::InvalidLength { .. } => "the length of the code was invalid",
+            Self::InvalidCharacter { .. } => "an invalid character was found",
+            Self::Unmapped { .. } => "the code has no known ICAO code",
+        }
+    }
+}
+
+impl<'s> TryFrom<&'s [u8]> for IataCode {
+    type Error = IataError;
+
+    fn try_from(value: &'s [u8]) -> Result<Self, Self::Error> {
+        if value.len() != 3 {
+            return Err(IataError::InvalidLength { len: value.len() });
+        }
+
+        for (index, c) in value.iter().enumerate() {
+            if !VALID_RANGE.contains(c) {
+                return Err(IataError::InvalidCharacter {
+                    character: *c as char,
+                    index,
+                });
+            }
+        }
+
+        let mut c = [0u8; 3];
+        c.copy_from_slice(value);
+
+        Ok(Self(c))
+    }
+}
+
+impl std::str::FromStr for IataCode {
+    type Err = IataError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        const VALID_RANGE: std::ops::RangeInclusive<char> = 'A'..='Z';
+        let mut arr = [0; 3];
+
+        if input.len() != 3 {
+            return Err(IataError::InvalidLength { len: input.len() });
+        }
+
+        for (index, character) in input.chars().enumerate() {
+            if !VALID_RANGE.contains(&character) {
+                return Err(IataError::InvalidCharacter { character, index });
+            }
+
+            arr[index] = character as u8;
+        }
+
+        Ok(Self(arr))
+    }
+}
+
+impl TryFrom<IataCode> for IcaoCode {
+    type Error = IataError;
+
+    fn try_from(value: IataCode) -> Result<Self, Self::Error> {
+        let index = KNOWN
+            .binary_search_by(|(iata, _)| iata.cmp(&value.0))
+            .map_err(|_| IataError::Unmapped { code: value })?;
+
+        Ok(KNOWN[index].1)
+    }
+}
+
+impl fmt::Display for IataCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_ref())
+    }
+}
+
+impl fmt::Debug for IataCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_ref())
+    }
+}
+
+impl serde::Serialize for IataCode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_ref())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for IataCode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct IataVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for IataVisitor {
+            type Value = IataCode;
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a 3-character, uppercase, alphabetical ASCII IATA code")
+            }
+
+            fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                v.parse().map_err(serde::de::Error::custom)
+            }
+
+            fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                v.parse().map_err(serde::de::Error::custom)
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                v.parse().map_err(serde::de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_str(IataVisitor)
+    }
+}
+
+impl schemars::JsonSchema for IataCode {
+    fn schema_name() -> String {
+        "IataCode".into()
+    }
+
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn json_schema(r#gen: &mut schemars::r#gen::SchemaGenerator) -> schemars::schema::Schema {
+        let mut schema = r#gen.subschema_for::<String>();
+        if let schemars::schema::Schema::Object(schema_object) = &mut schema {
+            if schema_object.has_type(schemars::schema::InstanceType::String) {
+                let validation = schema_object.string();
+                validation.pattern = Some(r"^[A-Z]{3}$".to_string());
+            }
+        }
+        schema
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_is_sorted() {
+        assert!(KNOWN.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn known_icaos_are_valid() {
+        for (_, icao) in KNOWN {
+            assert_eq!(IcaoCode::new((*icao).into()).unwrap(), *icao);
+        }
+    }
+
+    #[test]
+    fn parses() {
+        let lhr: IataCode = "LHR".parse().unwrap();
+        assert_eq!(lhr, IataCode::new_testing(*b"LHR"));
+        assert_eq!(lhr, IataCode::try_from(&b"LHR"[..]).unwrap());
+
+        assert!(matches!(
+            "LHRX".parse::<IataCode>(),
+            Err(IataError::InvalidLength { len: 4 })
+        ));
+        assert!(matches!(
+            "LhR".parse::<IataCode>(),
+            Err(IataError::InvalidCharacter {
+                character: 'h',
+                index: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn serde() {
+        let sfo = IataCode::new_testing(*b"SFO");
+        let json = serde_json::to_string(&sfo).unwrap();
+        assert_eq!(json, r#""SFO""#);
+        assert_eq!(serde_json::from_str::<IataCode>(&json).unwrap(), sfo);
+        assert!(serde_json::from_str::<IataCode>(r#""SF0""#).is_err());
+    }
+
+    #[test]
+    fn converts_to_icao() {
+        let icao = IcaoCode::try_from(IataCode::new_testing(*b"LHR")).unwrap();
+        assert_eq!(icao, IcaoCode::new_testing(*b"EGLL"));
+
+        assert!(matches!(
+            IcaoCode::try_from(IataCode::new_testing(*b"QQQ")),
+            Err(IataError::Unmapped { .. })
+        ));
+    }
+}
@@ -1,32 +1,147 @@
 use std::fmt;
 
-#[derive(Copy, Clone, Hash, Eq, PartialEq)]
+/// Codes are ordered alphabetically
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct IcaoCode([u8; 4]);
 
 const VALID_RANGE: std::ops::RangeInclusive<u8> = b'A'..=b'Z';
 
+/// Whether the uppercase character is valid at the index, the first character
+/// must be a letter, but the rest can also be digits, eg. `K2A5`
+#[inline]
+const fn is_valid(index: usize, c: u8) -> bool {
+    c.is_ascii_uppercase() || (index > 0 && c.is_ascii_digit())
+}
+
 impl IcaoCode {
+    /// Creates a new Icao from raw bytes, failing if any of the characters
+    /// are not valid
+    ///
+    /// Unlike parsing, lowercase letters are rejected rather than converted
+    #[inline]
+    pub fn new(code: [u8; 4]) -> Result<Self, IcaoError> {
+        for (index, c) in code.into_iter().enumerate() {
+            if !is_valid(index, c) {
+                return Err(IcaoError::InvalidCharacter {
+                    character: c as char,
+                    index,
+                });
+            }
+        }
+
+        Ok(Self(code))
+    }
+
+    /// Creates a new Icao from raw bytes without checking they are valid
+    ///
+    /// This doesn't validate the code, so it is up to the caller to only pass
+    /// an uppercase ASCII letter followed by 3 uppercase ASCII letters or
+    /// digits. An invalid code is still memory safe, but may not round trip
+    /// through parsing, and is displayed as an empty string if it isn't
+    /// utf-8. Constants should use [`Self::new_const`] instead
+    #[inline]
+    pub const fn new_unchecked(code: [u8; 4]) -> Self {
+        Self(code)
+    }
+
+    /// Creates a new Icao from raw bytes, panicking if any of the characters
+    /// are not valid
+    ///
+    /// This is meant for constants, where an invalid code fails to compile
+    pub const fn new_const(code: [u8; 4]) -> Self {
+        let mut index = 0;
+        while index < code.len() {
+            assert!(is_valid(index, code[index]), "invalid ICAO code");
+            index += 1;
+        }
+
+        Self(code)
+    }
+
     /// Creates a new Icao from raw bytes
     ///
     /// This is meant for testing, and asserts if any of the characters are not valid
     pub fn new_testing(code: [u8; 4]) -> Self {
-        for c in code {
-            assert!(VALID_RANGE.contains(&c));
+        match Self::new(code) {
+            Ok(code) => code,
+            Err(error) => panic!("{error}"),
         }
-
-        Self(code)
     }
 
     #[inline]
     pub fn as_bytes(&self) -> &[u8] {
         &self.0
     }
+
+    /// The code as characters, eg. for formatting in a UI
+    #[inline]
+    pub fn as_chars(&self) -> [char; 4] {
+        self.0.map(char::from)
+    }
+
+    /// The character at the index, or `None` if the index is not less than 4
+    #[inline]
+    pub fn nth(&self, index: usize) -> Option<char> {
+        self.0.get(index).copied().map(char::from)
+    }
+
+    /// The first character of the code, which is the region it is in
+    ///
+    /// ICAO codes are hierarchical, the first letter is a large region, eg.
+    /// `E` is northern Europe and `K` is the contiguous United States, and
+    /// the second letter is usually a country within it, see [`Self::subregion`].
+    /// Grouping by the region doesn't need the full code
+    #[inline]
+    pub const fn region(self) -> u8 {
+        self.0[0]
+    }
+
+    /// The first two characters of the code, which is usually the country
+    /// within the [region](Self::region), eg. `EG` is the United Kingdom
+    #[inline]
+    pub const fn subregion(self) -> [u8; 2] {
+        [self.0[0], self.0[1]]
+    }
+
+    /// Parses a code written by a human, eg. in a config file, converting
+    /// lowercase letters to uppercase
+    ///
+    /// The [`FromStr`](std::str::FromStr) implementation already accepts
+    /// lowercase letters, this is the explicit spelling for callers that
+    /// rely on the normalization
+    #[inline]
+    pub fn parse_lenient(input: &str) -> Result<Self, IcaoError> {
+        input.parse()
+    }
+
+    /// The code's bytes packed into a native-endian `u32`, eg. as an integer
+    /// key or a 4-byte wire field
+    #[inline]
+    pub fn to_u32(self) -> u32 {
+        u32::from_ne_bytes(self.0)
+    }
+
+    /// Unpacks a code created by [`Self::to_u32`]
+    ///
+    /// Unlike parsing, lowercase letters are rejected, so that every valid
+    /// `u32` maps to exactly one code
+    #[inline]
+    pub fn from_u32(v: u32) -> Result<Self, IcaoError> {
+        Self::new(v.to_ne_bytes())
+    }
+}
+
+impl From<IcaoCode> for [u8; 4] {
+    #[inline]
+    fn from(code: IcaoCode) -> Self {
+        code.0
+    }
 }
 
 impl AsRef<str> for IcaoCode {
     fn as_ref(&self) -> &str {
-        // SAFETY: We don't allow this to be constructed with an invalid utf-8 string
-        unsafe { std::str::from_utf8_unchecked(&self.0) }
+        // Only a code created with `new_unchecked` can be invalid utf-8
+        std::str::from_utf8(&self.0).unwrap_or_default()
     }
 }
 
@@ -68,32 +183,35 @@ impl std::error::Error for IcaoError {
 impl<'s> TryFrom<&'s [u8]> for IcaoCode {
     type Error = IcaoError;
 
+    /// Parses a code, lowercase letters are accepted and converted to uppercase
     fn try_from(value: &'s [u8]) -> Result<Self, Self::Error> {
         if value.len() != 4 {
             return Err(IcaoError::InvalidLength { len: value.len() });
         }
 
+        let mut arr = [0u8; 4];
+
         for (index, c) in value.iter().enumerate() {
-            if !VALID_RANGE.contains(c) {
+            let upper = c.to_ascii_uppercase();
+            if !is_valid(index, upper) {
                 return Err(IcaoError::InvalidCharacter {
                     character: *c as char,
                     index,
                 });
             }
-        }
 
-        let mut c = [0u8; 4];
-        c.copy_from_slice(value);
+            arr[index] = upper;
+        }
 
-        Ok(Self(c))
+        Ok(Self(arr))
     }
 }
 
 impl std::str::FromStr for IcaoCode {
     type Err = IcaoError;
 
+    /// Parses a code, lowercase letters are accepted and converted to uppercase
     fn from_str(input: &str) -> Result<Self, Self::Err> {
-        const VALID_RANGE: std::ops::RangeInclusive<char> = 'A'..='Z';
         let mut arr = [0; 4];
 
         if input.len() != 4 {
@@ -101,11 +219,12 @@ impl std::str::FromStr for IcaoCode {
         }
 
         for (index, character) in input.chars().enumerate() {
-            if !VALID_RANGE.contains(&character) {
+            let upper = character.to_ascii_uppercase();
+            if !upper.is_ascii() || !is_valid(index, upper as u8) {
                 return Err(IcaoError::InvalidCharacter { character, index });
             }
 
-            arr[index] = character as u8;
+            arr[index] = upper as u8;
         }
 
         Ok(Self(arr))
@@ -143,7 +262,7 @@ impl<'de> serde::Deserialize<'de> for IcaoCode {
         impl<'de> serde::de::Visitor<'de> for IcaoVisitor {
             type Value = IcaoCode;
             fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                f.write_str("a 4-character, uppercase, alphabetical ASCII ICAO code")
+                f.write_str("a 4-character, alphanumeric ASCII ICAO code starting with a letter")
             }
 
             fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E>
@@ -186,9 +305,220 @@ impl schemars::JsonSchema for IcaoCode {
         if let schemars::schema::Schema::Object(schema_object) = &mut schema {
             if schema_object.has_type(schemars::schema::InstanceType::String) {
                 let validation = schema_object.string();
-                validation.pattern = Some(r"^[A-Z]{4}$".to_string());
+                validation.pattern = Some(r"^[A-Za-z][A-Za-z0-9]{3}$".to_string());
             }
         }
         schema
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chars() {
+        let icao = IcaoCode::new_testing(*b"EGLL");
+        assert_eq!(icao.as_chars(), ['E', 'G', 'L', 'L']);
+        assert_eq!(icao.as_chars().iter().collect::<String>(), icao.as_ref());
+
+        assert_eq!(icao.nth(0), Some('E'));
+        assert_eq!(icao.nth(3), Some('L'));
+        assert_eq!(icao.nth(4), None);
+        assert_eq!(icao.nth(usize::MAX), None);
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let egll = IcaoCode::new_testing(*b"EGLL");
+
+        for code in ["egll", "EGLL", "eGlL", "EGLl"] {
+            assert_eq!(code.parse::<IcaoCode>().unwrap(), egll);
+            assert_eq!(IcaoCode::try_from(code.as_bytes()).unwrap(), egll);
+        }
+        assert_eq!("egll".parse::<IcaoCode>().unwrap().as_ref(), "EGLL");
+
+        for (code, character, index) in [
+            ("1GLL", '1', 0),
+            ("eg-l", '-', 2),
+            ("@GLL", '@', 0),
+            ("EGL[", '[', 3),
+            ("egl{", '{', 3),
+        ] {
+            assert!(matches!(
+                code.parse::<IcaoCode>(),
+                Err(IcaoError::InvalidCharacter { character: c, index: i }) if c == character && i == index
+            ));
+            assert!(matches!(
+                IcaoCode::try_from(code.as_bytes()),
+                Err(IcaoError::InvalidCharacter { character: c, index: i }) if c == character && i == index
+            ));
+        }
+
+        // Non-ASCII characters that have an ASCII uppercase aren't accepted
+        assert!("EGLſ".parse::<IcaoCode>().is_err());
+        assert!(IcaoCode::try_from(&[b'E', b'G', b'L', 0xe9][..]).is_err());
+    }
+
+    #[test]
+    fn parse_lenient() {
+        let lhr = IcaoCode::new_testing(*b"ELHR");
+
+        for code in ["ELHR", "elhr", "ElHr", "eLHR"] {
+            let parsed = IcaoCode::parse_lenient(code).unwrap();
+            assert_eq!(parsed, lhr);
+            assert_eq!(parsed.as_ref(), "ELHR");
+        }
+
+        for code in ["", "LHR", "ELHRX"] {
+            assert!(matches!(
+                IcaoCode::parse_lenient(code),
+                Err(IcaoError::InvalidLength { len }) if len == code.len()
+            ));
+        }
+
+        assert!(matches!(
+            IcaoCode::parse_lenient("el_r"),
+            Err(IcaoError::InvalidCharacter {
+                character: '_',
+                index: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn ordering() {
+        let mut codes: Vec<IcaoCode> = ["LFPG", "EGLL", "KJFK", "EGKK", "RJTT", "AAAA", "ZZZZ"]
+            .into_iter()
+            .map(|c| c.parse().unwrap())
+            .collect();
+        codes.sort();
+
+        let sorted: Vec<_> = codes.iter().map(|c| c.as_ref()).collect();
+        assert_eq!(
+            sorted,
+            ["AAAA", "EGKK", "EGLL", "KJFK", "LFPG", "RJTT", "ZZZZ"]
+        );
+
+        let set: std::collections::BTreeSet<IcaoCode> = ["EGLL", "egll", "KJFK", "EGLL", "kjfk"]
+            .into_iter()
+            .map(|c| c.parse().unwrap())
+            .collect();
+        assert_eq!(set.len(), 2);
+        assert_eq!(
+            set.into_iter().collect::<Vec<_>>(),
+            [
+                IcaoCode::new_testing(*b"EGLL"),
+                IcaoCode::new_testing(*b"KJFK")
+            ]
+        );
+    }
+
+    #[test]
+    fn digits() {
+        for code in ["K2A5", "k2a5", "E123", "Y9ZZ"] {
+            let parsed = code.parse::<IcaoCode>().unwrap();
+            assert_eq!(parsed.as_ref(), code.to_ascii_uppercase());
+            assert_eq!(IcaoCode::try_from(code.as_bytes()).unwrap(), parsed);
+            assert_eq!(
+                serde_json::from_str::<IcaoCode>(&format!("\"{code}\"")).unwrap(),
+                parsed
+            );
+        }
+
+        assert_eq!(IcaoCode::new_testing(*b"K2A5").as_ref(), "K2A5");
+
+        for code in ["2A5K", "0000"] {
+            assert!(matches!(
+                code.parse::<IcaoCode>(),
+                Err(IcaoError::InvalidCharacter { index: 0, .. })
+            ));
+            assert!(matches!(
+                IcaoCode::try_from(code.as_bytes()),
+                Err(IcaoError::InvalidCharacter { index: 0, .. })
+            ));
+        }
+
+        // Non-ASCII digits aren't accepted
+        assert!("E١23".parse::<IcaoCode>().is_err());
+    }
+
+    #[test]
+    fn u32_round_trip() {
+        let rest: Vec<u8> = VALID_RANGE.chain(b'0'..=b'9').collect();
+
+        for a in VALID_RANGE {
+            for &b in &rest {
+                for &c in &rest {
+                    for &d in &rest {
+                        let code = IcaoCode::new_testing([a, b, c, d]);
+                        assert_eq!(IcaoCode::from_u32(code.to_u32()).unwrap(), code);
+                    }
+                }
+            }
+        }
+
+        let egll = IcaoCode::new_testing(*b"EGLL");
+        assert_eq!(egll.to_u32(), u32::from_ne_bytes(*b"EGLL"));
+
+        for (bytes, character, index) in [
+            (*b"egll", 'e', 0),
+            (*b"1GLL", '1', 0),
+            (*b"EG-L", '-', 2),
+            ([b'E', b'G', b'L', 0], '\0', 3),
+        ] {
+            assert!(matches!(
+                IcaoCode::from_u32(u32::from_ne_bytes(bytes)),
+                Err(IcaoError::InvalidCharacter { character: c, index: i }) if c == character && i == index
+            ));
+        }
+    }
+
+    #[test]
+    fn new_boundaries() {
+        for code in [*b"AAAA", *b"ZZZZ", *b"A000", *b"Z999", *b"A0Z9"] {
+            let icao = IcaoCode::new(code).unwrap();
+            assert_eq!(<[u8; 4]>::from(icao), code);
+            assert_eq!(icao, IcaoCode::new_unchecked(code));
+            assert_eq!(icao, IcaoCode::new_const(code));
+        }
+
+        for (code, character, index) in [
+            (*b"@AAA", '@', 0),
+            (*b"[AAA", '[', 0),
+            (*b"0AAA", '0', 0),
+            (*b"9AAA", '9', 0),
+            (*b"A/AA", '/', 1),
+            (*b"AA:A", ':', 2),
+            (*b"AAA@", '@', 3),
+            (*b"AAA[", '[', 3),
+            (*b"aAAA", 'a', 0),
+            (*b"AAAz", 'z', 3),
+            ([b'A', 0x80, b'A', b'A'], '\u{80}', 1),
+        ] {
+            assert!(matches!(
+                IcaoCode::new(code),
+                Err(IcaoError::InvalidCharacter { character: c, index: i }) if c == character && i == index
+            ));
+        }
+    }
+
+    #[test]
+    fn regions() {
+        for (code, region, subregion) in [
+            (*b"BOOP", b'B', *b"BO"),
+            (*b"YSSY", b'Y', *b"YS"),
+            (*b"ZBAA", b'Z', *b"ZB"),
+            (*b"XXXX", b'X', *b"XX"),
+            (*b"EGLL", b'E', *b"EG"),
+            (*b"K2A5", b'K', *b"K2"),
+        ] {
+            let icao = IcaoCode::new(code).unwrap();
+            assert_eq!(icao.region(), region);
+            assert_eq!(icao.subregion(), subregion);
+        }
+
+        const DEFAULT_REGION: u8 = IcaoCode([b'X'; 4]).region();
+        assert_eq!(IcaoCode::default().region(), DEFAULT_REGION);
+    }
+}
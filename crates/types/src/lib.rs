@@ -1,7 +1,11 @@
+mod coordinate;
 mod endpoint;
+mod iata;
 mod icao;
 mod tokens;
 
-pub use endpoint::{AddressKind, Endpoint};
+pub use coordinate::{Coordinate, CoordinateError};
+pub use endpoint::{AddressKind, AddressParseError, Endpoint, EndpointCodecError};
+pub use iata::{IataCode, IataError};
 pub use icao::{IcaoCode, IcaoError};
 pub use tokens::TokenSet;
@@ -8,6 +8,79 @@ impl TokenSet {
     pub fn iter(&self) -> std::collections::btree_set::Iter<'_, Vec<u8>> {
         self.0.iter()
     }
+
+    /// The tokens in `self` that aren't in `other`
+    ///
+    /// Like the other set operations, the tokens are borrowed rather than
+    /// copied, collect them into a [`TokenSet`] if an owned set is needed
+    ///
+    /// ```
+    /// use quilkin_types::TokenSet;
+    ///
+    /// let pushed = TokenSet::from([*b"ab", *b"cd"]);
+    /// let reported = TokenSet::from([*b"cd", *b"ab"]);
+    ///
+    /// // The update's tokens are only sent if they actually changed
+    /// let changed = reported.symmetric_difference(&pushed).next().is_some();
+    /// let tokens = changed.then(|| reported.clone());
+    /// assert!(tokens.is_none());
+    ///
+    /// let reported = TokenSet::from([*b"ab", *b"ef"]);
+    /// assert_eq!(
+    ///     reported.difference(&pushed).cloned().collect::<TokenSet>(),
+    ///     TokenSet::from([*b"ef"])
+    /// );
+    /// assert_eq!(
+    ///     pushed.difference(&reported).cloned().collect::<TokenSet>(),
+    ///     TokenSet::from([*b"cd"])
+    /// );
+    /// ```
+    #[inline]
+    pub fn difference<'a>(
+        &'a self,
+        other: &'a TokenSet,
+    ) -> std::collections::btree_set::Difference<'a, Vec<u8>> {
+        self.0.difference(&other.0)
+    }
+
+    /// The tokens that are in `self` or `other`, but not both
+    #[inline]
+    pub fn symmetric_difference<'a>(
+        &'a self,
+        other: &'a TokenSet,
+    ) -> std::collections::btree_set::SymmetricDifference<'a, Vec<u8>> {
+        self.0.symmetric_difference(&other.0)
+    }
+
+    /// The tokens that are in both `self` and `other`
+    #[inline]
+    pub fn intersection<'a>(
+        &'a self,
+        other: &'a TokenSet,
+    ) -> std::collections::btree_set::Intersection<'a, Vec<u8>> {
+        self.0.intersection(&other.0)
+    }
+
+    /// The tokens that are in `self`, `other`, or both
+    #[inline]
+    pub fn union<'a>(
+        &'a self,
+        other: &'a TokenSet,
+    ) -> std::collections::btree_set::Union<'a, Vec<u8>> {
+        self.0.union(&other.0)
+    }
+
+    /// Whether every token in `self` is also in `other`
+    #[inline]
+    pub fn is_subset(&self, other: &TokenSet) -> bool {
+        self.0.is_subset(&other.0)
+    }
+
+    /// Whether every token in `other` is also in `self`
+    #[inline]
+    pub fn is_superset(&self, other: &TokenSet) -> bool {
+        self.0.is_superset(&other.0)
+    }
 }
 
 impl IntoIterator for TokenSet {
@@ -93,3 +166,44 @@ impl<'de> serde::Deserialize<'de> for TokenSet {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that the set operations match the ones on [`BTreeSet`]
+    #[test]
+    fn set_operations() {
+        let sets: Vec<TokenSet> = vec![
+            TokenSet::default(),
+            [*b"a"].into(),
+            [vec![1, 2], vec![3], vec![4, 5, 6]].into(),
+            [vec![3], vec![4, 5, 6], vec![7]].into(),
+            (0..100u8).map(|i| vec![i; 1 + i as usize % 3]).collect(),
+            (50..150u8).map(|i| vec![i; 1 + i as usize % 3]).collect(),
+        ];
+
+        for a in &sets {
+            for b in &sets {
+                let (sa, sb) = (&a.0, &b.0);
+
+                assert!(a.difference(b).eq(sa.difference(sb)));
+                assert!(a.symmetric_difference(b).eq(sa.symmetric_difference(sb)));
+                assert!(a.intersection(b).eq(sa.intersection(sb)));
+                assert!(a.union(b).eq(sa.union(sb)));
+                assert_eq!(a.is_subset(b), sa.is_subset(sb));
+                assert_eq!(a.is_superset(b), sa.is_superset(sb));
+            }
+        }
+
+        assert!(sets[2].is_subset(&sets[2]) && sets[2].is_superset(&sets[2]));
+        assert!(sets[0].is_subset(&sets[1]) && !sets[0].is_superset(&sets[1]));
+        assert_eq!(
+            sets[2]
+                .intersection(&sets[3])
+                .cloned()
+                .collect::<TokenSet>(),
+            [vec![3], vec![4, 5, 6]].into()
+        );
+    }
+}
@@ -328,7 +328,7 @@ mod tests {
                 IpAddr::V6(_) => ipv4_echo_addr,
             },
             // we're not testing this, since DNS resolves to IP.
-            AddressKind::Name(_) => unreachable!(),
+            AddressKind::Name(_) | AddressKind::ScopedIp(..) => unreachable!(),
         };
 
         socket.send_to(msg.as_bytes(), &opp_addr).await.unwrap();
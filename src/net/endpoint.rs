@@ -104,6 +104,16 @@ impl Endpoint {
                     proto::host::Inner::Ipv6(proto::Ipv6 { first, second })
                 }
             },
+            // The proto has no room for the scope id, which is only meaningful
+            // on the host that created it anyway
+            AddressKind::ScopedIp(v6, _) => {
+                let ip = u128::from_be_bytes(v6.octets());
+
+                let first = ((ip >> 64) & 0xffffffffffffffff) as u64;
+                let second = (ip & 0xffffffffffffffff) as u64;
+
+                proto::host::Inner::Ipv6(proto::Ipv6 { first, second })
+            }
         };
 
         proto::Endpoint {
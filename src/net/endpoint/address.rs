@@ -17,7 +17,7 @@
 use std::{
     convert::{TryFrom, TryInto},
     fmt,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
     str::FromStr,
 };
 
@@ -68,6 +68,9 @@ impl EndpointAddress {
 
         let ip = match &self.host {
             AddressKind::Ip(ip) => *ip,
+            AddressKind::ScopedIp(ip, scope_id) => {
+                return Ok(SocketAddrV6::new(*ip, self.port, 0, *scope_id).into());
+            }
             AddressKind::Name(name) => {
                 static CACHE: Lazy<crate::collections::ttl::TtlMap<String, IpAddr>> =
                     Lazy::new(<_>::default);
@@ -128,8 +131,7 @@ impl FromStr for EndpointAddress {
             .host_str()
             .map(String::from)
             .ok_or(ParseError::EmptyHost)?;
-        // Infallible
-        let host = host.parse::<AddressKind>().unwrap();
+        let host = host.parse::<AddressKind>()?;
         let port = url.port().ok_or(ParseError::EmptyPort)?;
 
         Ok(Self { host, port })
@@ -148,6 +150,8 @@ pub enum ParseError {
     InvalidUrl(#[from] url::ParseError),
     #[error("No paths allowed in URLs, it must include only the hostname and port")]
     PathsNotAllowed,
+    #[error("invalid address: {0}")]
+    InvalidAddress(#[from] quilkin_types::AddressParseError),
 }
 
 impl From<SocketAddr> for EndpointAddress {
@@ -295,10 +299,10 @@ impl TryFrom<crate::generated::envoy::config::endpoint::v3::Endpoint> for Endpoi
 
 impl fmt::Display for EndpointAddress {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if let AddressKind::Ip(IpAddr::V6(ip)) = self.host {
-            write!(f, "[{}]:{}", ip, self.port)
-        } else {
-            write!(f, "{}:{}", self.host, self.port)
+        match self.host {
+            AddressKind::Ip(IpAddr::V6(ip)) => write!(f, "[{}]:{}", ip, self.port),
+            AddressKind::ScopedIp(ip, scope_id) => write!(f, "[{ip}%{scope_id}]:{}", self.port),
+            _ => write!(f, "{}:{}", self.host, self.port),
         }
     }
 }
@@ -333,6 +337,7 @@ mod tests {
         let endpoint = "127.0.12.1:4567".parse::<EndpointAddress>().unwrap();
         match endpoint.host {
             AddressKind::Name(_) => panic!("Shouldn't be a name"),
+            AddressKind::ScopedIp(..) => panic!("Shouldn't be scoped"),
             AddressKind::Ip(ip) => assert_eq!("127.0.12.1", ip.to_string()),
         };
         assert_eq!(4567, endpoint.port);
@@ -343,6 +348,7 @@ mod tests {
             .unwrap();
         match endpoint.host {
             AddressKind::Name(_) => panic!("Shouldn't be a name"),
+            AddressKind::ScopedIp(..) => panic!("Shouldn't be scoped"),
             AddressKind::Ip(ip) => {
                 assert_eq!("2345:425:2ca1::567:5673:24b5", ip.to_string());
             }
@@ -356,6 +362,7 @@ mod tests {
 
         match ak {
             AddressKind::Name(_) => panic!("Shouldn't be a name"),
+            AddressKind::ScopedIp(..) => panic!("Shouldn't be scoped"),
             AddressKind::Ip(ip) => assert_eq!("127.0.12.1", ip.to_string()),
         }
 
@@ -365,6 +372,7 @@ mod tests {
             .unwrap();
         match ak {
             AddressKind::Name(_) => panic!("Shouldn't be a name"),
+            AddressKind::ScopedIp(..) => panic!("Shouldn't be scoped"),
             AddressKind::Ip(ip) => {
                 assert_eq!("2345:425:2ca1::567:5673:24b5", ip.to_string());
             }
@@ -375,6 +383,7 @@ mod tests {
             .unwrap();
         match ak {
             AddressKind::Name(_) => panic!("Shouldn't be a name"),
+            AddressKind::ScopedIp(..) => panic!("Shouldn't be scoped"),
             AddressKind::Ip(ip) => {
                 assert_eq!("2345:425:2ca1::567:5673:24b5", ip.to_string());
             }
@@ -385,7 +394,7 @@ mod tests {
             AddressKind::Name(name) => {
                 assert_eq!("my.domain.com", name);
             }
-            AddressKind::Ip(_) => panic!("shouldn't be an ip"),
+            AddressKind::Ip(_) | AddressKind::ScopedIp(..) => panic!("shouldn't be an ip"),
         };
     }
 }